@@ -0,0 +1,358 @@
+//! End-to-end benchmarks of storing, committing, opening and restoring
+//! a stash, over different backends and file size distributions.
+//!
+//! Run with `cargo bench --bench e2e`. After every run, the mean time of
+//! each benchmark is exported to `target/criterion/e2e.json`.
+//!
+//! To catch regressions, save a baseline before a change with
+//! `cargo bench --bench e2e -- --save-baseline main`, and compare to it
+//! with `cargo bench --bench e2e -- --baseline main`. Comparing fails if
+//! a benchmark got slower by more than `ZEROSTASH_BENCH_THRESHOLD`
+//! percent, 10 by default.
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use infinitree::{
+    backends::{test::InMemoryBackend, Backend, Directory},
+    crypto::UsernamePassword,
+    Infinitree,
+};
+use rand::{Rng, RngCore};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::runtime::Runtime;
+use zerostash_files::{restore, store, Files};
+
+const THREADS: usize = 4;
+
+/// How the size of the benchmarked files is distributed
+#[derive(Clone, Copy)]
+enum Distribution {
+    /// Lots of small files, eg. source code
+    Small,
+    /// Sizes spread evenly over orders of magnitude
+    Mixed,
+    /// A few large files, eg. disk images
+    Large,
+}
+
+impl Distribution {
+    const ALL: [Self; 3] = [Self::Small, Self::Mixed, Self::Large];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Mixed => "mixed",
+            Self::Large => "large",
+        }
+    }
+
+    fn sizes(self) -> Vec<usize> {
+        let mut rng = rand::thread_rng();
+        match self {
+            Self::Small => vec![4 * 1024; 2_000],
+            Self::Mixed => (0..200)
+                .map(|_| {
+                    let size: usize = 1 << rng.gen_range(10..=20);
+                    size + rng.gen_range(0..size)
+                })
+                .collect(),
+            Self::Large => vec![16 * 1024 * 1024; 4],
+        }
+    }
+
+    /// Write random files to a new directory under `base`.
+    fn generate(self, base: &Path) -> PathBuf {
+        let dir = base.join(self.name());
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut rng = rand::thread_rng();
+        for (i, size) in self.sizes().into_iter().enumerate() {
+            // spread the files over a few directories
+            let parent = dir.join(format!("{}", i % 16));
+            fs::create_dir_all(&parent).unwrap();
+
+            let mut data = vec![0; size];
+            rng.fill_bytes(&mut data);
+            fs::write(parent.join(format!("{i}.bin")), &data).unwrap();
+        }
+
+        dir
+    }
+}
+
+#[derive(Clone, Copy)]
+enum BackendKind {
+    Memory,
+    Directory,
+}
+
+impl BackendKind {
+    const ALL: [Self; 2] = [Self::Memory, Self::Directory];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            Self::Directory => "directory",
+        }
+    }
+
+    /// A new, empty backend. The directory backend replaces the one
+    /// created before, so only one is on the disk at a time.
+    fn create(self, base: &Path) -> Arc<dyn Backend> {
+        match self {
+            Self::Memory => InMemoryBackend::shared(),
+            Self::Directory => {
+                let path = base.join("stash");
+                _ = fs::remove_dir_all(&path);
+                fs::create_dir_all(&path).unwrap();
+                Directory::new(path).unwrap()
+            }
+        }
+    }
+}
+
+fn key() -> UsernamePassword {
+    let key = "abcdef1234567890abcdef1234567890".to_string();
+    UsernamePassword::with_credentials(key.clone(), key).unwrap()
+}
+
+struct Setup {
+    base: PathBuf,
+    runtime: Runtime,
+    inputs: Vec<(Distribution, PathBuf)>,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let base = std::env::temp_dir().join(format!("zerostash-e2e-{}", std::process::id()));
+        let inputs = Distribution::ALL
+            .into_iter()
+            .map(|dist| (dist, dist.generate(&base.join("input"))))
+            .collect();
+
+        Self {
+            base,
+            runtime: Runtime::new().unwrap(),
+            inputs,
+        }
+    }
+
+    fn options(path: &Path) -> store::Options {
+        store::Options {
+            paths: vec![path.to_path_buf()],
+            ..Default::default()
+        }
+    }
+
+    /// A stash on `backend` with `path` stored, but not committed
+    fn stored(&self, backend: Arc<dyn Backend>, path: &Path) -> Infinitree<Files> {
+        let stash = Infinitree::<Files>::empty(backend, key()).unwrap();
+        self.runtime
+            .block_on(Self::options(path).add_recursive(&stash, THREADS))
+            .unwrap();
+        stash
+    }
+
+    fn committed(&self, backend: Arc<dyn Backend>, path: &Path) -> Infinitree<Files> {
+        let mut stash = self.stored(backend, path);
+        stash.commit(None).unwrap();
+        stash.backend().sync().unwrap();
+        stash
+    }
+
+    /// Run `bench` for every backend and distribution.
+    fn each(
+        &self,
+        c: &mut Criterion,
+        group: &str,
+        mut bench: impl FnMut(&mut criterion::Bencher, BackendKind, &Path),
+    ) {
+        let mut group = c.benchmark_group(group);
+        group.sample_size(10);
+
+        for backend in BackendKind::ALL {
+            for (dist, path) in self.inputs.iter() {
+                group.bench_function(BenchmarkId::new(backend.name(), dist.name()), |b| {
+                    bench(b, backend, path)
+                });
+            }
+        }
+
+        group.finish();
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        _ = fs::remove_dir_all(&self.base);
+    }
+}
+
+fn store(c: &mut Criterion, setup: &Setup) {
+    setup.each(c, "store", |b, backend, path| {
+        b.iter_batched(
+            || Infinitree::<Files>::empty(backend.create(&setup.base), key()).unwrap(),
+            |stash| {
+                setup
+                    .runtime
+                    .block_on(Setup::options(path).add_recursive(&stash, THREADS))
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn commit(c: &mut Criterion, setup: &Setup) {
+    setup.each(c, "commit", |b, backend, path| {
+        b.iter_batched(
+            || setup.stored(backend.create(&setup.base), path),
+            |mut stash| {
+                stash.commit(None).unwrap();
+                stash.backend().sync().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn open(c: &mut Criterion, setup: &Setup) {
+    setup.each(c, "open", |b, backend, path| {
+        let storage = backend.create(&setup.base);
+        setup.committed(storage.clone(), path);
+
+        b.iter(|| {
+            let stash = Infinitree::<Files>::open(storage.clone(), key()).unwrap();
+            stash.load_all().unwrap();
+        })
+    });
+}
+
+fn restore(c: &mut Criterion, setup: &Setup) {
+    setup.each(c, "restore", |b, backend, path| {
+        let stash = setup.committed(backend.create(&setup.base), path);
+        let target = setup.base.join("restore");
+
+        b.iter_batched(
+            || {
+                _ = fs::remove_dir_all(&target);
+                fs::create_dir_all(&target).unwrap();
+                restore::Options {
+                    globs: vec!["*".into()],
+                    chdir: Some(target.clone()),
+                    ..Default::default()
+                }
+            },
+            |options| {
+                setup
+                    .runtime
+                    .block_on(options.from_iter(&stash, THREADS))
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    let setup = Setup::new();
+
+    store(&mut criterion, &setup);
+    commit(&mut criterion, &setup);
+    open(&mut criterion, &setup);
+    restore(&mut criterion, &setup);
+
+    drop(setup);
+    criterion.final_summary();
+
+    let baseline = std::env::args().any(|arg| arg.starts_with("--baseline"));
+    if !results::export(baseline) {
+        std::process::exit(1);
+    }
+}
+
+/// Collect what criterion measured into a single JSON file
+mod results {
+    use serde_json::{json, Value};
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+    };
+
+    const GROUPS: [&str; 4] = ["store", "commit", "open", "restore"];
+
+    fn criterion_dir() -> PathBuf {
+        if let Some(home) = env::var_os("CRITERION_HOME") {
+            return home.into();
+        }
+
+        let target = match env::var_os("CARGO_TARGET_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(env!("CARGO_MANIFEST_DIR")).join("../target"),
+        };
+        target.join("criterion")
+    }
+
+    fn mean(path: &Path) -> Option<f64> {
+        let estimates: Value = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        estimates["mean"]["point_estimate"].as_f64()
+    }
+
+    /// Write the results to `e2e.json`. When comparing to a baseline,
+    /// returns false if something got slower than the threshold.
+    pub(super) fn export(compare: bool) -> bool {
+        let threshold = env::var("ZEROSTASH_BENCH_THRESHOLD")
+            .ok()
+            .and_then(|t| t.parse::<f64>().ok())
+            .unwrap_or(10.0);
+        let dir = criterion_dir();
+
+        let mut benchmarks = vec![];
+        let mut regressions = vec![];
+        for group in GROUPS {
+            let Ok(backends) = fs::read_dir(dir.join(group)) else {
+                continue;
+            };
+
+            for bench in backends
+                .flatten()
+                .flat_map(|backend| fs::read_dir(backend.path()).into_iter().flatten())
+                .flatten()
+            {
+                let path = bench.path();
+                let Some(mean_ns) = mean(&path.join("new/estimates.json")) else {
+                    continue;
+                };
+
+                let id = path.strip_prefix(&dir).unwrap().to_string_lossy();
+                let change = match compare {
+                    true => mean(&path.join("change/estimates.json")),
+                    false => None,
+                };
+                if change.is_some_and(|c| c * 100.0 > threshold) {
+                    regressions.push(id.to_string());
+                }
+
+                benchmarks.push(json!({
+                    "id": id,
+                    "mean_ns": mean_ns,
+                    "change": change,
+                }));
+            }
+        }
+
+        let output = dir.join("e2e.json");
+        let results = json!({ "threshold": threshold, "benchmarks": benchmarks });
+        if let Err(error) = fs::write(&output, serde_json::to_vec_pretty(&results).unwrap()) {
+            eprintln!("failed to write {}: {error}", output.display());
+        }
+
+        for id in regressions.iter() {
+            eprintln!("{id} is more than {threshold}% slower than the baseline");
+        }
+        regressions.is_empty()
+    }
+}
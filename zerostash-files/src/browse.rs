@@ -0,0 +1,164 @@
+//! Read-only access to a stash, for bindings to other languages
+//!
+//! Everything happens on the calling thread: nothing is spawned, and no
+//! async runtime is needed, so the API can be wrapped for mobile
+//! platforms as-is. Only the tree of the last commit is loaded.
+use crate::{crypto_error::read_chunk, Entry, Files, Node};
+use anyhow::{bail, Context};
+use infinitree::{backends::Backend, Infinitree};
+use std::sync::Arc;
+
+/// An entry in a directory listing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size of a file, 0 for directories
+    pub size: u64,
+}
+
+pub struct Browser {
+    stash: Infinitree<Files>,
+}
+
+impl Browser {
+    /// Open the stash at `backend`
+    pub fn open(backend: Arc<dyn Backend>, key: infinitree::Key) -> anyhow::Result<Self> {
+        Self::new(Infinitree::open(backend, key)?)
+    }
+
+    /// Browse an open stash
+    pub fn new(stash: Infinitree<Files>) -> anyhow::Result<Self> {
+        stash.load(stash.index().tree())?;
+        Ok(Self { stash })
+    }
+
+    fn node(&self, path: &str) -> anyhow::Result<Arc<Node>> {
+        let path = path.trim_matches('/');
+        let path = if path.is_empty() { "/" } else { path };
+
+        self.stash
+            .index()
+            .tree
+            .node_by_path(path)
+            .ok()
+            .flatten()
+            .with_context(|| format!("{path}: no such file or directory"))
+    }
+
+    /// List the directory at `path`, sorted by name. The root is `/`.
+    pub fn list(&self, path: &str) -> anyhow::Result<Vec<DirEntry>> {
+        let node = self.node(path)?;
+        let Node::Directory { entries, .. } = node.as_ref() else {
+            bail!("{path}: not a directory");
+        };
+
+        let index = self.stash.index();
+        let mut list = vec![];
+        entries.scan(|name, noderef| {
+            if let Some(child) = index.tree.node_by_ref(noderef) {
+                list.push(DirEntry {
+                    name: name.clone(),
+                    is_dir: child.is_dir(),
+                    size: child.as_file().map(|entry| entry.size).unwrap_or(0),
+                });
+            }
+        });
+
+        list.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    /// Metadata of the file at `path`
+    pub fn file(&self, path: &str) -> anyhow::Result<Arc<Entry>> {
+        self.node(path)?
+            .as_file()
+            .with_context(|| format!("{path}: not a file"))
+    }
+
+    /// Read at most `len` bytes from `offset` of the file at `path`.
+    ///
+    /// Only the chunks that overlap the range are fetched. Returns
+    /// fewer bytes at the end of the file.
+    pub fn read(&self, path: &str, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+        let entry = self.file(path)?;
+        let end = offset.saturating_add(len as u64).min(entry.size);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        let mut reader = self.stash.storage_reader()?;
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        let mut chunks = entry.chunks.iter().peekable();
+
+        while let Some((start, pointer)) = chunks.next() {
+            let start = *start;
+            let chunk_end = chunks.peek().map(|(o, _)| **o).unwrap_or(entry.size);
+            if chunk_end <= offset {
+                continue;
+            }
+            if start >= end {
+                break;
+            }
+
+            let mut buf = vec![0; (chunk_end - start) as usize];
+            let data = read_chunk(
+                &mut reader,
+                &self.stash.index().dictionaries,
+                pointer,
+                &mut buf,
+            )?;
+
+            // files extended without writing have no chunks in the gap
+            let from = start.max(offset);
+            out.resize((from - offset) as usize, 0);
+
+            let to = ((end.min(chunk_end) - start) as usize).min(data.len());
+            out.extend_from_slice(&data[(from - start) as usize..to]);
+        }
+
+        out.resize((end - offset) as usize, 0);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn browse_committed_tree() {
+        use super::{Browser, DirEntry};
+        use crate::{Entry, Files};
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("browse".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        let sparse = Entry {
+            size: 4,
+            ..Default::default()
+        };
+        stash
+            .index()
+            .tree
+            .insert_file("dir/sparse", sparse)
+            .unwrap();
+        stash.commit(None).unwrap();
+
+        let browser = Browser::new(Infinitree::<Files>::open(storage, key()).unwrap()).unwrap();
+        assert_eq!(
+            browser.list("/").unwrap(),
+            vec![DirEntry {
+                name: "dir".into(),
+                is_dir: true,
+                size: 0
+            }]
+        );
+        assert_eq!(browser.list("/dir").unwrap()[0].size, 4);
+        assert_eq!(browser.read("/dir/sparse", 1, 10).unwrap(), vec![0; 3]);
+        assert!(browser.list("/dir/sparse").is_err());
+    }
+}
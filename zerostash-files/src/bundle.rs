@@ -0,0 +1,340 @@
+//! Single file archives of all objects in a stash
+//!
+//! A bundle is meant for cold storage, eg. on tape, so it's written in
+//! one pass, and can be piped anywhere:
+//!
+//! ```text
+//! MAGIC | object... | index | trailer
+//! ```
+//!
+//! Objects are copied as they are stored, so they stay encrypted. The
+//! root object of the stash is carried along like any other object.
+//!
+//! The index lists the id, position and checksum of every object. The
+//! fixed size trailer at the end points to the index, so it can be
+//! found without scanning the whole bundle, and carries a MAC of the
+//! index, keyed by the hasher of the stash. A bundle can only be
+//! extracted after its index was [verified](Bundle::verify) with the
+//! key of the stash, which [`Bundle`] can open the stash from as a
+//! read only backend.
+use crate::{checksum::checksum, migrate::object_from_bytes, object_size::MAX_OBJECT_SIZE};
+use anyhow::{bail, ensure, Context, Result};
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::{ObjectId, ReadObject, WriteObject},
+    Digest, Hasher,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::debug;
+
+const MAGIC: &[u8; 8] = b"0SBUNDL2";
+const TRAILER_MAGIC: &[u8; 8] = b"0SBEND02";
+const TRAILER_LEN: u64 = 8 + 8 + 32 + 8;
+
+/// Indexes are about 100 bytes per object, so this is plenty for
+/// petabytes of objects
+const MAX_INDEX_LEN: u64 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub id: ObjectId,
+    pub offset: u64,
+    pub len: u64,
+    pub checksum: Digest,
+}
+
+fn mac(mut hasher: Hasher, encoded: &[u8]) -> Digest {
+    hasher.update(MAGIC);
+    hasher.update(encoded);
+    *hasher.finalize().as_bytes()
+}
+
+/// Write every object in `objects` from `backend` into a bundle.
+///
+/// `hasher` is the keyed hasher of the stash, which authenticates the
+/// index. Returns the index of the bundle.
+pub fn write(
+    backend: &dyn Backend,
+    objects: impl IntoIterator<Item = ObjectId>,
+    hasher: Hasher,
+    mut out: impl Write,
+) -> Result<Vec<IndexEntry>> {
+    out.write_all(MAGIC)?;
+    let mut offset = MAGIC.len() as u64;
+    let mut index = vec![];
+
+    for id in objects {
+        let object = backend
+            .read_object(&id)
+            .with_context(|| format!("Failed to read object {id}"))?;
+        let data = object.as_inner();
+
+        out.write_all(data)?;
+        index.push(IndexEntry {
+            id,
+            offset,
+            len: data.len() as u64,
+            checksum: checksum(data),
+        });
+        offset += data.len() as u64;
+        debug!(%id, "bundled object");
+    }
+
+    let encoded = rmp_serde::to_vec(&index)?;
+    out.write_all(&encoded)?;
+
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(&(encoded.len() as u64).to_le_bytes())?;
+    out.write_all(&mac(hasher, &encoded))?;
+    out.write_all(TRAILER_MAGIC)?;
+    out.flush()?;
+
+    Ok(index)
+}
+
+/// A bundle opened for reading
+pub struct Bundle<R> {
+    reader: Mutex<R>,
+    index: Vec<IndexEntry>,
+    positions: HashMap<ObjectId, usize>,
+    encoded: Vec<u8>,
+    mac: Digest,
+    verified: AtomicBool,
+}
+
+impl<R: Read + Seek> Bundle<R> {
+    /// Read the index of the bundle.
+    ///
+    /// The index is only checked to be consistent with the size of the
+    /// bundle. It must be [verified](Self::verify) before the bundle
+    /// can be extracted.
+    pub fn open(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.rewind()?;
+        reader.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "Not a stash bundle");
+
+        let size = reader.seek(SeekFrom::End(0))?;
+        ensure!(
+            size >= MAGIC.len() as u64 + TRAILER_LEN,
+            "The bundle is truncated"
+        );
+
+        let mut trailer = [0; TRAILER_LEN as usize];
+        reader.seek(SeekFrom::Start(size - TRAILER_LEN))?;
+        reader
+            .read_exact(&mut trailer)
+            .context("The bundle is truncated")?;
+        if &trailer[48..] != TRAILER_MAGIC {
+            bail!("The bundle is truncated");
+        }
+
+        let offset = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+        let len = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+        ensure!(
+            len <= MAX_INDEX_LEN
+                && offset >= MAGIC.len() as u64
+                && offset.checked_add(len) == Some(size - TRAILER_LEN),
+            "The trailer of the bundle is damaged"
+        );
+
+        let mut encoded = vec![0; len as usize];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut encoded)?;
+
+        let index: Vec<IndexEntry> =
+            rmp_serde::from_slice(&encoded).context("The index of the bundle is damaged")?;
+        for entry in index.iter() {
+            ensure!(
+                entry.len <= MAX_OBJECT_SIZE
+                    && entry.offset >= MAGIC.len() as u64
+                    && entry
+                        .offset
+                        .checked_add(entry.len)
+                        .is_some_and(|end| end <= offset),
+                "The index of the bundle is damaged"
+            );
+        }
+
+        Ok(Self {
+            reader: Mutex::new(reader),
+            positions: index.iter().enumerate().map(|(n, e)| (e.id, n)).collect(),
+            index,
+            encoded,
+            mac: trailer[16..48].try_into().unwrap(),
+            verified: AtomicBool::new(false),
+        })
+    }
+
+    pub fn index(&self) -> &[IndexEntry] {
+        &self.index
+    }
+
+    /// Check the index with the keyed hasher of the stash.
+    pub fn verify(&self, hasher: Hasher) -> Result<()> {
+        let expected = mac(hasher, &self.encoded);
+
+        // don't leak how much of the MAC was right
+        let diff = expected
+            .iter()
+            .zip(self.mac.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        ensure!(
+            diff == 0,
+            "The index of the bundle was not written with the key of the stash"
+        );
+
+        self.verified.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Read the object at `entry`, and check its contents.
+    pub fn read(&self, entry: &IndexEntry) -> Result<Vec<u8>> {
+        let mut data = vec![0; entry.len as usize];
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(entry.offset))?;
+        reader.read_exact(&mut data)?;
+
+        ensure!(
+            checksum(&data) == entry.checksum,
+            "Object {} is damaged in the bundle",
+            entry.id
+        );
+        Ok(data)
+    }
+
+    /// Write all objects in the bundle to `backend`.
+    pub fn extract(&self, backend: &dyn Backend) -> Result<usize> {
+        ensure!(
+            self.verified.load(Ordering::Acquire),
+            "The index of the bundle must be verified before it's extracted"
+        );
+
+        for entry in self.index.iter() {
+            let data = self.read(entry)?;
+            backend.write_object(&object_from_bytes(entry.id, &data))?;
+            debug!(id = %entry.id, "extracted object");
+        }
+
+        backend.sync()?;
+        Ok(self.index.len())
+    }
+}
+
+/// The objects of a bundle can be read by a stash, to verify the index
+/// with its key.
+impl<R: Read + Seek + Send + 'static> Backend for Bundle<R> {
+    fn write_object(&self, _object: &WriteObject) -> infinitree::backends::Result<()> {
+        Err(read_only())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> infinitree::backends::Result<Arc<ReadObject>> {
+        let entry = self
+            .positions
+            .get(id)
+            .map(|n| &self.index[*n])
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let data = self.read(entry).map_err(io::Error::other)?;
+
+        Ok(Arc::new(object_from_bytes(*id, &data).into()))
+    }
+
+    fn preload(&self, _objects: &[ObjectId]) -> infinitree::backends::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _objects: &[ObjectId]) -> infinitree::backends::Result<()> {
+        Err(read_only())
+    }
+
+    fn keep_warm(&self, _objects: &[ObjectId]) -> infinitree::backends::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&self) -> infinitree::backends::Result<()> {
+        Ok(())
+    }
+}
+
+fn read_only() -> BackendError {
+    io::Error::new(io::ErrorKind::PermissionDenied, "bundles are read only").into()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn bundle_roundtrip() {
+        use super::{write, Bundle, MAGIC, TRAILER_LEN};
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+            Hasher,
+        };
+        use std::io::Cursor;
+
+        let src = InMemoryBackend::shared();
+        let dst = InMemoryBackend::shared();
+        let hasher = || Hasher::new_keyed(&[1; 32]);
+
+        let ids = (0..3)
+            .map(|n| {
+                let id = ObjectId::from_bytes(rand::random());
+                src.write_object(&object_from_bytes(id, &[n; 100])).unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        src.sync().unwrap();
+
+        let mut out = vec![];
+        write(src.as_ref(), ids.clone(), hasher(), &mut out).unwrap();
+
+        // nothing is extracted until the index is verified
+        let bundle = Bundle::open(Cursor::new(out.clone())).unwrap();
+        assert!(bundle.extract(dst.as_ref()).is_err());
+        assert!(bundle.verify(Hasher::new_keyed(&[2; 32])).is_err());
+        bundle.verify(hasher()).unwrap();
+        assert_eq!(bundle.extract(dst.as_ref()).unwrap(), 3);
+        for id in ids.iter() {
+            assert_eq!(
+                src.read_object(id).unwrap().as_inner(),
+                bundle.read_object(id).unwrap().as_inner()
+            );
+            assert_eq!(
+                src.read_object(id).unwrap().as_inner(),
+                dst.read_object(id).unwrap().as_inner()
+            );
+        }
+
+        // flipping a bit of an object is caught
+        let entry = bundle.index()[1].clone();
+        let mut damaged = out.clone();
+        damaged[entry.offset as usize] ^= 1;
+        let damaged = Bundle::open(Cursor::new(damaged)).unwrap();
+        assert!(damaged.read(&entry).is_err());
+
+        // so is a changed index: the last byte of the index is in the
+        // checksum of the last object, and still decodes when flipped
+        let mut tampered = out.clone();
+        tampered[out.len() - TRAILER_LEN as usize - 1] ^= 1;
+        let tampered = Bundle::open(Cursor::new(tampered)).unwrap();
+        assert!(tampered.verify(hasher()).is_err());
+
+        // and lengths past the end of the bundle
+
+        let mut oversized = out.clone();
+        let len_at = out.len() - TRAILER_LEN as usize + 8;
+        oversized[len_at..len_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Bundle::open(Cursor::new(oversized)).is_err());
+
+        assert!(Bundle::open(Cursor::new(MAGIC.to_vec())).is_err());
+    }
+}
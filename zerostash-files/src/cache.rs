@@ -0,0 +1,559 @@
+//! Keep recently used objects in a local directory
+//!
+//! [`Cache`] writes objects through to the upstream backend, and keeps a
+//! copy of everything it writes or reads in the cache directory, up to
+//! a size limit. When the cache is full, the least recently used
+//! objects are evicted first.
+//!
+//! Objects are written to the cache before they are written upstream,
+//! so they can be read back right away. Until the upstream write is
+//! done, they are not evicted, and reads from upstream, deletes and
+//! [`Backend::sync`] wait for it to finish.
+//!
+//! The root object of a stash is the only object that's rewritten in
+//! place, so a cached copy may be from an older commit, eg. one made on
+//! another machine. Opening a stash always reads the root first, so
+//! the first object read through the cache is always read from
+//! upstream, and replaces the cached copy if it's a newer generation.
+//! An object that's written again replaces its cached copy as well.
+//!
+//! Pinned objects, eg. the index of the stash that's read every time
+//! it's opened, are only evicted when nothing else is left. Pins are
+//! listed in the `pinned` file of the cache directory, and hit and miss
+//! counts are kept in `stats.json` next to it.
+use crate::{chunk_index::digest_from_hex, disk_space, list, migrate::to_write_object};
+use infinitree::{
+    backends::{Backend, Directory, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::SystemTime,
+};
+use tracing::{debug, warn};
+
+const PINNED: &str = "pinned";
+const STATS: &str = "stats.json";
+
+/// Counters of cache use, accumulated over every run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    /// Reads served from the cache
+    pub hits: u64,
+    /// Reads that had to go to the upstream backend
+    pub misses: u64,
+    /// Objects removed to make room
+    pub evictions: u64,
+    /// Pinned objects removed because nothing else was left
+    pub pinned_evictions: u64,
+}
+
+impl CacheStats {
+    /// Read the counters of the cache in `path`.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read(path.as_ref().join(STATS)) {
+            Ok(data) => serde_json::from_slice(&data).map_err(Into::into),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    pinned_evictions: AtomicU64,
+}
+
+impl Counters {
+    fn take(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.swap(0, Ordering::Relaxed),
+            misses: self.misses.swap(0, Ordering::Relaxed),
+            evictions: self.evictions.swap(0, Ordering::Relaxed),
+            pinned_evictions: self.pinned_evictions.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+/// How an object got into the cache
+///
+/// Objects written by a commit are mostly data that's rarely read
+/// back, so they are evicted before anything that was read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Written,
+    Read,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    kind: Kind,
+    used: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct State {
+    objects: HashMap<ObjectId, Slot>,
+    pinned: HashSet<ObjectId>,
+    /// Objects that are still being written upstream
+    writing: HashSet<ObjectId>,
+    /// The first object read, which is the root of the stash
+    root: Option<ObjectId>,
+    clock: u64,
+    /// Total size of the cached objects
+    bytes: u64,
+}
+
+impl State {
+    /// Record a use of `id`, which takes up `size` bytes. Returns true
+    /// if it was cached already.
+    fn touch(&mut self, id: ObjectId, kind: Kind, size: u64) -> bool {
+        self.clock += 1;
+        let used = self.clock;
+
+        match self.objects.get_mut(&id) {
+            Some(slot) => {
+                slot.kind = slot.kind.max(kind);
+                slot.used = used;
+                self.bytes = self.bytes - slot.size + size;
+                slot.size = size;
+                true
+            }
+            None => {
+                self.objects.insert(id, Slot { kind, used, size });
+                self.bytes += size;
+                false
+            }
+        }
+    }
+
+    fn remove(&mut self, id: &ObjectId) {
+        if let Some(slot) = self.objects.remove(id) {
+            self.bytes -= slot.size;
+        }
+    }
+
+    /// The object to evict: the least recently used one of the lowest
+    /// kind that's not pinned, if there's any. Objects that are being
+    /// written upstream are never evicted.
+    fn victim(&self) -> Option<(ObjectId, bool)> {
+        let lru = |pinned: bool| {
+            self.objects
+                .iter()
+                .filter(|(id, _)| !self.writing.contains(id))
+                .filter(|(id, _)| self.pinned.contains(id) == pinned)
+                .min_by_key(|(_, slot)| (slot.kind, slot.used))
+                .map(|(id, _)| (*id, pinned))
+        };
+
+        lru(false).or_else(|| lru(true))
+    }
+}
+
+pub struct Cache {
+    path: PathBuf,
+    max_size: u64,
+    local: Arc<Directory>,
+    upstream: Arc<dyn list::Storage>,
+    state: Mutex<State>,
+    written: Condvar,
+    counters: Counters,
+}
+
+impl Cache {
+    /// Cache objects from `upstream` in the directory at `path`, up to
+    /// `max_size` bytes.
+    pub fn new(
+        path: impl AsRef<Path>,
+        max_size: usize,
+        upstream: Arc<dyn list::Storage>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let local = Directory::new(&path)?;
+        let mut state = State {
+            pinned: read_pins(&path)?,
+            ..Default::default()
+        };
+
+        // objects are ordered by their modification time from earlier runs
+        let mut existing = vec![];
+        for id in list::Directory::new(&path)? {
+            let id = id?;
+            let metadata = fs::metadata(path.join(id.to_string()));
+            let modified = metadata
+                .as_ref()
+                .ok()
+                .and_then(|m| m.modified().ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let size = metadata.map_or(0, |m| m.len());
+            existing.push((modified, id, size));
+        }
+        existing.sort();
+        let used = existing.iter().map(|(.., size)| size).sum();
+        check_space(&path, max_size, used);
+        for (_, id, size) in existing {
+            state.touch(id, Kind::Read, size);
+        }
+
+        Ok(Arc::new(Self {
+            path,
+            max_size: max_size as u64,
+            local,
+            upstream,
+            state: Mutex::new(state),
+            written: Condvar::new(),
+            counters: Counters::default(),
+        }))
+    }
+
+    /// Store a copy of `object`, evicting others if the cache is full.
+    /// A cached copy is only overwritten if `replace` is set.
+    fn insert(&self, object: &WriteObject, kind: Kind, replace: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.touch(*object.id(), kind, object.as_inner().len() as u64) && !replace {
+            return;
+        }
+
+        // the object that was just added is always kept
+        while state.bytes > self.max_size && state.objects.len() > 1 {
+            let Some((victim, pinned)) = state.victim() else {
+                break;
+            };
+
+            if pinned {
+                warn!(id = %victim, "cache is too small for the pinned objects");
+                self.counters
+                    .pinned_evictions
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+
+            state.remove(&victim);
+            if let Err(error) = self.local.delete(&[victim]) {
+                warn!(%error, id = %victim, "failed to evict object from cache");
+            }
+        }
+
+        if let Err(error) = self.local.write_object(object) {
+            warn!(%error, id = %object.id(), "failed to write object to cache");
+            state.remove(object.id());
+        }
+    }
+
+    /// Whether the cached copy of `object` has the same contents.
+    fn is_current(&self, object: &ReadObject) -> bool {
+        self.local
+            .read_object(object.id())
+            .is_ok_and(|cached| cached.as_inner() == object.as_inner())
+    }
+
+    /// Block until none of `objects` are being written upstream.
+    fn wait_for(&self, objects: &[ObjectId]) {
+        let mut state = self.state.lock().unwrap();
+        while objects.iter().any(|id| state.writing.contains(id)) {
+            state = self.written.wait(state).unwrap();
+        }
+    }
+
+    fn save_stats(&self) -> io::Result<()> {
+        let session = self.counters.take();
+        let mut stats = CacheStats::read(&self.path)?;
+        stats.hits += session.hits;
+        stats.misses += session.misses;
+        stats.evictions += session.evictions;
+        stats.pinned_evictions += session.pinned_evictions;
+
+        fs::write(self.path.join(STATS), serde_json::to_vec(&stats)?)
+    }
+}
+
+impl Backend for Cache {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let id = *object.id();
+        self.wait_for(&[id]);
+        self.state.lock().unwrap().writing.insert(id);
+        self.insert(object, Kind::Written, true);
+
+        let result = self.upstream.write_object(object);
+
+        self.state.lock().unwrap().writing.remove(&id);
+        self.written.notify_all();
+        result
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let (root, cached) = {
+            let mut state = self.state.lock().unwrap();
+            let root = *state.root.get_or_insert(*id) == *id;
+            (root, state.objects.contains_key(id))
+        };
+
+        if root {
+            self.wait_for(std::slice::from_ref(id));
+            let object = self.upstream.read_object(id)?;
+            if cached && !self.is_current(&object) {
+                debug!(%id, "replacing an older generation of the root object");
+            }
+            self.insert(&to_write_object(&object), Kind::Read, true);
+
+            return Ok(object);
+        }
+
+        if cached {
+            match self.local.read_object(id) {
+                Ok(object) => {
+                    self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                    let size = object.as_inner().len() as u64;
+                    self.state.lock().unwrap().touch(*id, Kind::Read, size);
+                    return Ok(object);
+                }
+                Err(error) => {
+                    warn!(%error, %id, "failed to read object from cache");
+                    self.state.lock().unwrap().remove(id);
+                }
+            }
+        }
+
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        self.wait_for(std::slice::from_ref(id));
+        let object = self.upstream.read_object(id)?;
+        self.insert(&to_write_object(&object), Kind::Read, false);
+        debug!(%id, "cached object");
+
+        Ok(object)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let missing = objects
+            .iter()
+            .filter(|id| !state.objects.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        drop(state);
+
+        self.upstream.preload(&missing)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.wait_for(objects);
+        let mut state = self.state.lock().unwrap();
+        for id in objects {
+            state.remove(id);
+        }
+        drop(state);
+
+        _ = self.local.delete(objects);
+        self.upstream.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            while !state.writing.is_empty() {
+                state = self.written.wait(state).unwrap();
+            }
+        }
+
+        self.upstream.sync()?;
+        if let Err(error) = self.save_stats() {
+            warn!(%error, "failed to save cache statistics");
+        }
+        Ok(())
+    }
+}
+
+impl list::Storage for Cache {
+    fn list(&self) -> io::Result<list::Objects> {
+        // everything in the cache is also upstream
+        self.upstream.list()
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        if let Err(error) = self.save_stats() {
+            warn!(%error, "failed to save cache statistics");
+        }
+    }
+}
+
+/// Warn if the cache can't grow to `max_size`, counting the `used`
+/// bytes it already takes up as free.
+fn check_space(path: &Path, max_size: usize, used: u64) {
+    match disk_space::available(path) {
+        Ok(Some(available)) if available + used < max_size as u64 => warn!(
+            ?path,
+            max_size,
+            available,
+            "the cache is larger than the free space, it will fill up the disk"
+        ),
+        Ok(_) => {}
+        Err(error) => debug!(%error, ?path, "failed to check the free space for the cache"),
+    }
+}
+
+fn read_pins(path: &Path) -> io::Result<HashSet<ObjectId>> {
+    let contents = match fs::read_to_string(path.join(PINNED)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| digest_from_hex(line.trim()).ok())
+        .map(ObjectId::from_bytes)
+        .collect())
+}
+
+/// Pin `objects` in the cache at `path`, in addition to the ones that
+/// are pinned already. Returns the number of pinned objects.
+pub fn pin(
+    path: impl AsRef<Path>,
+    objects: impl IntoIterator<Item = ObjectId>,
+) -> io::Result<usize> {
+    let path = path.as_ref();
+    let mut pinned = read_pins(path)?;
+    pinned.extend(objects);
+
+    let mut lines = pinned.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+    lines.sort();
+    lines.push(String::new());
+    fs::write(path.join(PINNED), lines.join("\n"))?;
+
+    Ok(pinned.len())
+}
+
+/// Remove every object, pin and counter from the cache at `path`.
+/// Returns the number of objects removed.
+pub fn clear(path: impl AsRef<Path>) -> io::Result<usize> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for id in list::Directory::new(path)? {
+        fs::remove_file(path.join(id?.to_string()))?;
+        removed += 1;
+    }
+
+    for file in [PINNED, STATS] {
+        match fs::remove_file(path.join(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn eviction_order() {
+        use super::{Kind, State};
+        use infinitree::object::ObjectId;
+
+        let ids = (0..4)
+            .map(|_| ObjectId::from_bytes(rand::random()))
+            .collect::<Vec<_>>();
+
+        let mut state = State::default();
+        state.touch(ids[0], Kind::Read, 100);
+        state.touch(ids[1], Kind::Read, 200);
+        state.touch(ids[2], Kind::Read, 300);
+        state.touch(ids[3], Kind::Written, 400);
+        state.pinned.insert(ids[0]);
+        assert_eq!(state.bytes, 1000);
+
+        // written objects go first, then the least recently read
+        assert_eq!(state.victim(), Some((ids[3], false)));
+        state.remove(&ids[3]);
+        state.touch(ids[1], Kind::Written, 200);
+        assert_eq!(state.bytes, 600);
+        assert_eq!(state.victim(), Some((ids[2], false)));
+
+        // objects are kept until they're written upstream
+        state.writing.insert(ids[2]);
+        assert_eq!(state.victim(), Some((ids[1], false)));
+        state.writing.clear();
+
+        // pinned objects only when nothing else is left
+        state.objects.retain(|id, _| *id == ids[0]);
+        assert_eq!(state.victim(), Some((ids[0], true)));
+    }
+    #[test]
+    fn root_is_read_from_upstream() {
+        use super::Cache;
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("zerostash-cache-{}", rand::random::<u64>()));
+        let upstream = InMemoryBackend::shared();
+        let (root, data) = (
+            ObjectId::from_bytes(rand::random()),
+            ObjectId::from_bytes(rand::random()),
+        );
+        let read = |cache: &Cache, id| cache.read_object(id).unwrap().as_inner()[..3].to_vec();
+
+        let cache = Cache::new(&path, 1 << 30, upstream.clone()).unwrap();
+        cache
+            .write_object(&object_from_bytes(root, b"gen"))
+            .unwrap();
+        cache
+            .write_object(&object_from_bytes(data, b"dat"))
+            .unwrap();
+        assert_eq!(read(&cache, &root), b"gen");
+
+        // a commit rewrites the root in place
+        cache
+            .write_object(&object_from_bytes(root, b"new"))
+            .unwrap();
+        assert_eq!(read(&cache, &root), b"new");
+        drop(cache);
+
+        // another machine commits, the cached root is from an older generation
+        upstream
+            .write_object(&object_from_bytes(root, b"gn2"))
+            .unwrap();
+        let cache = Cache::new(&path, 1 << 30, upstream.clone()).unwrap();
+        assert_eq!(read(&cache, &root), b"gn2");
+        assert_eq!(read(&cache, &data), b"dat");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
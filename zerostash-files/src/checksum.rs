@@ -0,0 +1,186 @@
+//! Unkeyed object checksums for detecting bit rot
+//!
+//! Authenticated encryption only detects a damaged object when it's
+//! decrypted with the right key. [`Checksummed`] records an unkeyed
+//! hash of every object it writes in a local checksum log, and checks
+//! objects against it as they're read back.
+//!
+//! Since no key material is needed, the log can also be used to scan
+//! the whole backend for damaged or truncated objects with [`verify`].
+use crate::list::{Objects, Storage};
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+    Digest, Hasher,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Record {
+    id: ObjectId,
+    checksum: Digest,
+}
+
+struct ChecksumLog {
+    known: HashMap<ObjectId, Digest>,
+    log: fs::File,
+}
+
+impl ChecksumLog {
+    fn read(path: &Path) -> io::Result<HashMap<ObjectId, Digest>> {
+        let mut known = HashMap::new();
+
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(known),
+            Err(e) => return Err(e),
+        };
+
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str::<Record>(&line?) {
+                Ok(record) => {
+                    known.insert(record.id, record.checksum);
+                }
+                Err(error) => warn!(%error, "invalid entry in checksum log"),
+            }
+        }
+
+        Ok(known)
+    }
+
+    fn open(path: &Path) -> io::Result<Self> {
+        let known = Self::read(path)?;
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self { known, log })
+    }
+
+    fn record(&mut self, id: ObjectId, checksum: Digest) {
+        if self.known.insert(id, checksum) == Some(checksum) {
+            return;
+        }
+
+        let line = serde_json::to_string(&Record { id, checksum }).unwrap();
+        if let Err(error) = writeln!(self.log, "{line}") {
+            warn!(%error, "failed to update checksum log");
+        }
+    }
+}
+
+pub(crate) fn checksum(data: &[u8]) -> Digest {
+    *Hasher::new().update(data).finalize().as_bytes()
+}
+
+fn check(id: &ObjectId, expected: &Digest, data: &[u8]) -> io::Result<()> {
+    if checksum(data) == *expected {
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("object {id} does not match its checksum"),
+    ))
+}
+
+pub struct Checksummed {
+    upstream: Arc<dyn Storage>,
+    log: Mutex<ChecksumLog>,
+}
+
+impl Checksummed {
+    pub fn new(
+        upstream: Arc<dyn Storage>,
+        checksum_log: impl AsRef<Path>,
+    ) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            upstream,
+            log: Mutex::new(ChecksumLog::open(checksum_log.as_ref())?),
+        }))
+    }
+}
+
+impl Backend for Checksummed {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let sum = checksum(object.as_inner());
+        self.upstream.write_object(object)?;
+        self.log.lock().unwrap().record(*object.id(), sum);
+
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let object = self.upstream.read_object(id)?;
+
+        let expected = self.log.lock().unwrap().known.get(id).copied();
+        if let Some(expected) = expected {
+            check(id, &expected, object.as_inner())?;
+        }
+
+        Ok(object)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()?;
+        self.log.lock().unwrap().log.sync_data().map_err(Into::into)
+    }
+}
+
+impl Storage for Checksummed {
+    fn list(&self) -> io::Result<Objects> {
+        self.upstream.list()
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+/// Result of checking a backend against a checksum log
+#[derive(Default, Debug)]
+pub struct Report {
+    pub checked: usize,
+    pub failed: Vec<(ObjectId, String)>,
+}
+
+/// Read every object recorded in `checksum_log` from `backend`, and
+/// compare it to its checksum. No key is needed to do this.
+pub fn verify(backend: &dyn Backend, checksum_log: impl AsRef<Path>) -> io::Result<Report> {
+    let mut report = Report::default();
+
+    for (id, expected) in ChecksumLog::read(checksum_log.as_ref())? {
+        report.checked += 1;
+
+        let result = match backend.read_object(&id) {
+            Ok(object) => check(&id, &expected, object.as_inner()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(error) = result {
+            report.failed.push((id, error));
+        }
+    }
+
+    Ok(report)
+}
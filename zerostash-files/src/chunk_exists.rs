@@ -0,0 +1,321 @@
+//! Ask a remote stash which chunks it has before uploading them
+//!
+//! This is the protocol spoken by `0s serve`, so thin clients on other
+//! machines can deduplicate against a shared stash. A client sends the
+//! digests of the chunks it's about to upload, and the server answers
+//! with a bitmap of the ones the stash already has, so the answer costs
+//! one bit per chunk.
+//!
+//! Messages are MessagePack, and every message is prefixed with its
+//! length as a little endian `u32`. The server opens a connection with
+//! a [`Challenge`], then answers any number of [`Query`] and [`Answer`]
+//! pairs, until the client closes it.
+//!
+//! Queries are authenticated with a token shared by the server and its
+//! clients. The token itself is never sent: a query carries a MAC of
+//! its contents, keyed by a key derived from the token. The MAC also
+//! covers the nonce of the challenge and the number of the query on the
+//! connection, so a recorded query can't be replayed, and a query
+//! without the token can't be used to test guesses of it.
+use crate::chunk_index::ChunkIndex;
+use infinitree::{Digest, Hasher};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/// Version of the protocol spoken by this build
+pub const VERSION: u32 = 2;
+
+/// Most digests a single query may ask about
+pub const MAX_DIGESTS: usize = 1 << 16;
+
+// digests may be encoded as arrays, taking up to 2 bytes for each
+// byte, plus room for the rest of the query
+const MAX_MESSAGE: usize = MAX_DIGESTS * 66 + 1024;
+
+const KEY_CONTEXT: &str = "zerostash chunk_exists v2 query authentication";
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to encode message: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("failed to decode message: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+    #[error("message of {0} bytes is too large")]
+    TooLarge(usize),
+    #[error("the server speaks version {0} of the protocol")]
+    Unsupported(u32),
+}
+
+/// The key queries are authenticated with
+#[derive(Clone)]
+pub struct Token([u8; 32]);
+
+impl Token {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut hasher = Hasher::new_derive_key(KEY_CONTEXT);
+        hasher.update(secret);
+        Self(*hasher.finalize().as_bytes())
+    }
+
+    fn mac(&self, nonce: &[u8; 32], seq: u64, version: u32, digests: &[Digest]) -> Digest {
+        let mut hasher = Hasher::new_keyed(&self.0);
+        hasher.update(nonce);
+        hasher.update(&seq.to_le_bytes());
+        hasher.update(&version.to_le_bytes());
+        hasher.update(&(digests.len() as u64).to_le_bytes());
+        for digest in digests {
+            hasher.update(digest);
+        }
+        *hasher.finalize().as_bytes()
+    }
+}
+
+/// The first message of the server on every connection
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub version: u32,
+    pub nonce: [u8; 32],
+}
+
+/// Which of `digests` does the stash have?
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Query {
+    pub version: u32,
+    pub digests: Vec<Digest>,
+    pub mac: Digest,
+}
+
+impl Query {
+    /// The `seq`th query on a connection that started with `challenge`
+    pub fn new(token: &Token, challenge: &Challenge, seq: u64, digests: Vec<Digest>) -> Self {
+        Self {
+            version: VERSION,
+            mac: token.mac(&challenge.nonce, seq, VERSION, &digests),
+            digests,
+        }
+    }
+
+    fn is_authentic(&self, token: &Token, challenge: &Challenge, seq: u64) -> bool {
+        let expected = token.mac(&challenge.nonce, seq, self.version, &self.digests);
+
+        // don't leak how much of the MAC was right
+        expected
+            .iter()
+            .zip(self.mac.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Answer {
+    /// Bit `i` is set if the stash has `digests[i]` of the query,
+    /// starting from the lowest bit of the first byte
+    Known(Vec<u8>),
+    /// The server only speaks `version`
+    Unsupported { version: u32 },
+    /// The query asked about more than `max` digests
+    TooMany { max: usize },
+    /// The query was not made with the token of the server
+    Denied,
+}
+
+impl Answer {
+    /// Whether the stash has the `i`th digest of the query. `None` if
+    /// the query was not answered.
+    pub fn is_known(&self, i: usize) -> Option<bool> {
+        let Self::Known(bitmap) = self else {
+            return None;
+        };
+        Some(bitmap.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0))
+    }
+}
+
+/// Answer the `seq`th query on a connection that started with
+/// `challenge` from the chunks of a stash.
+pub fn answer(
+    chunks: &ChunkIndex,
+    token: &Token,
+    challenge: &Challenge,
+    seq: u64,
+    query: &Query,
+) -> Answer {
+    if query.version != VERSION {
+        return Answer::Unsupported { version: VERSION };
+    }
+    if query.digests.len() > MAX_DIGESTS {
+        return Answer::TooMany { max: MAX_DIGESTS };
+    }
+    if !query.is_authentic(token, challenge, seq) {
+        return Answer::Denied;
+    }
+
+    let mut bitmap = vec![0; query.digests.len().div_ceil(8)];
+    for (i, digest) in query.digests.iter().enumerate() {
+        if chunks.contains(digest) {
+            bitmap[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    Answer::Known(bitmap)
+}
+
+/// Answer queries on `conn` until the client closes it.
+///
+/// Returns the number of queries answered.
+pub fn serve(
+    chunks: &ChunkIndex,
+    token: &Token,
+    mut conn: impl Read + Write,
+) -> Result<usize, ProtocolError> {
+    let challenge = Challenge {
+        version: VERSION,
+        nonce: rand::random(),
+    };
+    write_message(&mut conn, &challenge)?;
+
+    let mut answered = 0;
+    while let Some(query) = read_message::<Query>(&mut conn)? {
+        let answer = answer(chunks, token, &challenge, answered as u64, &query);
+        write_message(&mut conn, &answer)?;
+        answered += 1;
+    }
+
+    Ok(answered)
+}
+
+/// The client side of a connection
+pub struct Client<C> {
+    conn: C,
+    token: Token,
+    challenge: Challenge,
+    seq: u64,
+}
+
+impl<C: Read + Write> Client<C> {
+    /// Wait for the challenge of the server on `conn`.
+    pub fn connect(mut conn: C, token: Token) -> Result<Self, ProtocolError> {
+        let challenge: Challenge = read_message(&mut conn)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if challenge.version != VERSION {
+            return Err(ProtocolError::Unsupported(challenge.version));
+        }
+
+        Ok(Self {
+            conn,
+            token,
+            challenge,
+            seq: 0,
+        })
+    }
+
+    /// Ask which of `digests` the server has, and wait for the answer.
+    pub fn ask(&mut self, digests: Vec<Digest>) -> Result<Answer, ProtocolError> {
+        let query = Query::new(&self.token, &self.challenge, self.seq, digests);
+        self.seq += 1;
+
+        write_message(&mut self.conn, &query)?;
+        read_message(&mut self.conn)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+}
+
+/// Write a length prefixed message.
+pub fn write_message<T: Serialize>(mut w: impl Write, msg: &T) -> Result<(), ProtocolError> {
+    let encoded = rmp_serde::to_vec(msg)?;
+    if encoded.len() > MAX_MESSAGE {
+        return Err(ProtocolError::TooLarge(encoded.len()));
+    }
+
+    w.write_all(&(encoded.len() as u32).to_le_bytes())?;
+    w.write_all(&encoded)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Read a length prefixed message, or `None` if the connection was
+/// closed before it started.
+pub fn read_message<T: DeserializeOwned>(mut r: impl Read) -> Result<Option<T>, ProtocolError> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(ProtocolError::TooLarge(len));
+    }
+
+    let mut encoded = vec![0; len];
+    r.read_exact(&mut encoded)?;
+    Ok(Some(rmp_serde::from_slice(&encoded)?))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn query_known_chunks() {
+        use super::{answer, read_message, write_message, Answer, Challenge, Client, Query, Token};
+        use crate::chunk_index::ChunkIndex;
+        use std::{
+            net::{TcpListener, TcpStream},
+            thread,
+        };
+
+        let chunks = ChunkIndex::default();
+        let known = rand::random();
+        chunks.insert_with(known, Default::default);
+        let token = Token::new(b"token");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = thread::scope(|s| {
+            let server = s.spawn(|| {
+                let (conn, _) = listener.accept().unwrap();
+                super::serve(&chunks, &token, conn).unwrap()
+            });
+
+            let conn = TcpStream::connect(addr).unwrap();
+            let mut client = Client::connect(conn, token.clone()).unwrap();
+            let answer = client.ask(vec![rand::random(), known]).unwrap();
+            assert_eq!(answer.is_known(0), Some(false));
+            assert_eq!(answer.is_known(1), Some(true));
+            drop(client);
+
+            server.join().unwrap()
+        });
+        assert_eq!(served, 1);
+
+        // without the token, or replayed with a different nonce or
+        // sequence number, queries are denied
+        let challenge = Challenge {
+            version: super::VERSION,
+            nonce: rand::random(),
+        };
+        let query = Query::new(&token, &challenge, 0, vec![known]);
+        let guess = Query::new(&Token::new(b"guess"), &challenge, 0, vec![known]);
+        let denied = |challenge, seq, query| {
+            answer(&chunks, &token, challenge, seq, query) == Answer::Denied
+        };
+        assert!(!denied(&challenge, 0, &query));
+        assert!(denied(&challenge, 0, &guess));
+        assert!(denied(&challenge, 1, &query));
+
+        let other = Challenge {
+            nonce: rand::random(),
+            ..challenge
+        };
+        assert!(denied(&other, 0, &query));
+
+        // messages are length prefixed
+        let mut buf = vec![];
+        write_message(&mut buf, &query).unwrap();
+        let read: Query = read_message(&buf[..]).unwrap().unwrap();
+        assert_eq!(read, query);
+    }
+}
@@ -0,0 +1,422 @@
+//! Import and export chunk pointers to seed deduplication
+//!
+//! Data that was written to the backend by an external tool (or a
+//! migration script) can be registered in the chunk index, so
+//! subsequent commits will reference it instead of uploading the same
+//! content again.
+use crate::{crypto_error::ChunkError, Files};
+use infinitree::{
+    fields::{Collection, Store, VersionedMap},
+    object::Reader,
+    ChunkPointer, Digest, Hasher, BLOCK_SIZE,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+
+/// Number of independent maps in the chunk index
+const SHARDS: usize = 16;
+
+/// Bits of the filter per expected chunk. With 4 bits set per digest,
+/// this results in a false positive rate of about 0.25%.
+const FILTER_BITS_PER_CHUNK: usize = 16;
+const FILTER_HASHES: usize = 4;
+/// Room for new chunks when the filter is built
+const FILTER_MIN_CHUNKS: usize = 1 << 20;
+
+type Shard = VersionedMap<Digest, ChunkPointer>;
+
+/// Index of all chunks in the stash, sharded by digest prefix
+///
+/// Each shard is a separate map, so workers inserting chunks from
+/// many threads rarely contend for the same one. All shards are
+/// written to the same stream on commit, which keeps the serialized
+/// format identical to a single `VersionedMap`.
+///
+/// Optionally, a bloom filter in front of the shards answers lookups
+/// of chunks that are definitely not in the index without touching the
+/// shared maps. See [`ChunkIndex::enable_filter`].
+#[derive(Clone, Default)]
+pub struct ChunkIndex {
+    shards: [Shard; SHARDS],
+    filter: Arc<OnceLock<DigestFilter>>,
+}
+
+impl ChunkIndex {
+    fn shard(&self, digest: &Digest) -> &Shard {
+        &self.shards[shard_of(digest)]
+    }
+
+    /// Build a bloom filter of the chunks in the index, and keep it up
+    /// to date with new ones.
+    ///
+    /// The filter is sized for twice the current number of chunks, so
+    /// it should be enabled after the index is loaded. If it fills up,
+    /// lookups are still correct, but fewer of them are answered by the
+    /// filter alone.
+    pub fn enable_filter(&self) {
+        self.filter.get_or_init(|| {
+            let filter = DigestFilter::with_capacity((self.len() * 2).max(FILTER_MIN_CHUNKS));
+            self.for_each(|digest, _| filter.insert(digest));
+            filter
+        });
+    }
+
+    /// False if `digest` is definitely not in the index
+    fn may_contain(&self, digest: &Digest) -> bool {
+        self.filter.get().map_or(true, |f| f.may_contain(digest))
+    }
+
+    fn remember(&self, digest: &Digest) {
+        if let Some(filter) = self.filter.get() {
+            filter.insert(digest);
+        }
+    }
+
+    /// Return the pointer for `digest`, or insert the result of `new`
+    /// if the chunk is not in the index yet.
+    pub fn insert_with(
+        &self,
+        digest: Digest,
+        new: impl FnOnce() -> ChunkPointer,
+    ) -> Arc<ChunkPointer> {
+        // another worker may be inserting the same chunk after a miss
+        // of its own, so the shard is checked again before `new` writes
+        // the chunk a second time
+        self.remember(&digest);
+        self.shard(&digest).insert_with(digest, new)
+    }
+
+    /// Point `digest` to a new copy of the chunk.
+    pub fn update(&self, digest: Digest, pointer: ChunkPointer) -> Option<Arc<ChunkPointer>> {
+        self.remember(&digest);
+        self.shard(&digest).update_with(digest, |_| pointer)
+    }
+
+    pub fn remove(&self, digest: Digest) {
+        self.shard(&digest).remove(digest);
+    }
+
+    pub fn get(&self, digest: &Digest) -> Option<Arc<ChunkPointer>> {
+        if !self.may_contain(digest) {
+            return None;
+        }
+        self.shard(digest).get(digest)
+    }
+
+    pub fn contains(&self, digest: &Digest) -> bool {
+        self.may_contain(digest) && self.shard(digest).contains(digest)
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&Digest, &ChunkPointer)) {
+        for shard in self.shards.iter() {
+            shard.for_each(&mut f);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Shard::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Shard::is_empty)
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.clear();
+        }
+
+        if let Some(filter) = self.filter.get() {
+            filter.clear();
+        }
+    }
+}
+
+fn shard_of(digest: &Digest) -> usize {
+    digest[0] as usize % SHARDS
+}
+
+/// A bloom filter of digests
+///
+/// Digests are uniformly distributed already, so the bits to set are
+/// taken straight from the digest instead of hashing it again.
+struct DigestFilter {
+    words: Box<[AtomicU64]>,
+    mask: u64,
+}
+
+impl DigestFilter {
+    fn with_capacity(chunks: usize) -> Self {
+        let bits = (chunks * FILTER_BITS_PER_CHUNK).next_power_of_two().max(64);
+        Self {
+            words: (0..bits / 64).map(|_| AtomicU64::new(0)).collect(),
+            mask: bits as u64 - 1,
+        }
+    }
+
+    fn bits(&self, digest: &Digest) -> impl Iterator<Item = (usize, u64)> + '_ {
+        digest.chunks_exact(8).take(FILTER_HASHES).map(|bytes| {
+            let bit = u64::from_le_bytes(bytes.try_into().unwrap()) & self.mask;
+            ((bit / 64) as usize, 1 << (bit % 64))
+        })
+    }
+
+    fn insert(&self, digest: &Digest) {
+        for (word, bit) in self.bits(digest) {
+            self.words[word].fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+
+    fn may_contain(&self, digest: &Digest) -> bool {
+        self.bits(digest)
+            .all(|(word, bit)| self.words[word].load(Ordering::Relaxed) & bit != 0)
+    }
+
+    fn clear(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Collection for ChunkIndex {
+    type Depth = infinitree::fields::depth::Incremental;
+
+    type Key = <Shard as Collection>::Key;
+
+    type Serialized = <Shard as Collection>::Serialized;
+
+    type Item = <Shard as Collection>::Item;
+
+    fn key(from: &Self::Serialized) -> &Self::Key {
+        <Shard as Collection>::key(from)
+    }
+
+    fn load(from: Self::Serialized, object: &mut dyn Reader) -> Self::Item {
+        <Shard as Collection>::load(from, object)
+    }
+
+    fn insert(&mut self, record: Self::Item) {
+        self.remember(&record.0);
+        let shard = shard_of(&record.0);
+        <Shard as Collection>::insert(&mut self.shards[shard], record)
+    }
+}
+
+impl Store for ChunkIndex {
+    fn store(
+        &mut self,
+        transaction: &mut dyn infinitree::index::Transaction,
+        object: &mut dyn infinitree::object::Writer,
+    ) {
+        for shard in self.shards.iter_mut() {
+            <Shard as Store>::store(shard, transaction, object)
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChunkImportError {
+    #[error("Invalid digest: {0}")]
+    InvalidDigest(String),
+    #[error("Chunk {digest} can not be read: {source}")]
+    Unreadable { digest: String, source: ChunkError },
+    #[error("Contents of chunk {0} do not match its digest")]
+    DigestMismatch(String),
+}
+
+/// A single entry of the chunk index
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkRecord {
+    #[serde(serialize_with = "ser_digest", deserialize_with = "deser_digest")]
+    pub digest: Digest,
+    pub pointer: ChunkPointer,
+}
+
+impl Files {
+    /// Call `f` with every chunk that's currently loaded in the index.
+    pub fn export_chunks(&self, mut f: impl FnMut(ChunkRecord)) {
+        self.chunks.for_each(|digest, pointer| {
+            f(ChunkRecord {
+                digest: *digest,
+                pointer: pointer.clone(),
+            })
+        });
+    }
+
+    /// Register an existing chunk in the index.
+    ///
+    /// Existing entries are never overwritten. Returns `true` if the
+    /// chunk was not known before.
+    pub fn import_chunk(&self, record: ChunkRecord) -> bool {
+        let mut inserted = false;
+        self.chunks.insert_with(record.digest, || {
+            inserted = true;
+            record.pointer
+        });
+
+        inserted
+    }
+}
+
+/// Read the chunk from the storage, and make sure its contents hash
+/// to the digest in the record.
+pub fn verify_chunk(
+    reader: &mut impl Reader,
+    dictionaries: &crate::dictionary::Dictionaries,
+    hasher: &mut Hasher,
+    record: &ChunkRecord,
+    buf: &mut Vec<u8>,
+) -> Result<(), ChunkImportError> {
+    buf.resize(BLOCK_SIZE, 0);
+
+    let data = crate::crypto_error::read_chunk(reader, dictionaries, &record.pointer, buf)
+        .map_err(|e| ChunkImportError::Unreadable {
+            digest: digest_to_hex(&record.digest),
+            source: e.source,
+        })?;
+
+    hasher.reset();
+    hasher.update(data);
+
+    if hasher.finalize().as_bytes() != &record.digest {
+        return Err(ChunkImportError::DigestMismatch(digest_to_hex(
+            &record.digest,
+        )));
+    }
+
+    Ok(())
+}
+
+pub fn digest_to_hex(digest: &Digest) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn digest_from_hex(s: &str) -> Result<Digest, ChunkImportError> {
+    let invalid = || ChunkImportError::InvalidDigest(s.to_string());
+
+    if s.len() != 64 || !s.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut digest = Digest::default();
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(digest)
+}
+
+fn ser_digest<S>(val: &Digest, ser: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    ser.serialize_str(&digest_to_hex(val))
+}
+
+fn deser_digest<'de, D>(deser: D) -> Result<Digest, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deser)?;
+    digest_from_hex(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn digest_hex_roundtrip() {
+        use super::{digest_from_hex, digest_to_hex};
+
+        let digest: infinitree::Digest = rand::random();
+        let hex = digest_to_hex(&digest);
+
+        assert_eq!(hex.len(), 64);
+        assert_eq!(digest_from_hex(&hex).unwrap(), digest);
+        assert!(digest_from_hex("abcd").is_err());
+        assert!(digest_from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn import_does_not_overwrite() {
+        use super::ChunkRecord;
+        use crate::Files;
+
+        let files = Files::default();
+        let record = ChunkRecord {
+            digest: rand::random(),
+            pointer: Default::default(),
+        };
+
+        assert!(files.import_chunk(record.clone()));
+        assert!(!files.import_chunk(record));
+    }
+    #[test]
+    fn sharded_index_finds_all_chunks() {
+        use super::ChunkIndex;
+
+        let index = ChunkIndex::default();
+        let digests = (0..100).map(|_| rand::random()).collect::<Vec<_>>();
+
+        for digest in digests.iter() {
+            index.insert_with(*digest, Default::default);
+        }
+
+        assert_eq!(index.len(), digests.len());
+        assert!(digests.iter().all(|d| index.contains(d)));
+
+        let mut seen = 0;
+        index.for_each(|_, _| seen += 1);
+        assert_eq!(seen, digests.len());
+    }
+
+    #[test]
+    fn filter_has_no_false_negatives() {
+        use super::ChunkIndex;
+
+        let index = ChunkIndex::default();
+        let loaded = (0..100).map(|_| rand::random()).collect::<Vec<_>>();
+        for digest in loaded.iter() {
+            index.insert_with(*digest, Default::default);
+        }
+
+        index.enable_filter();
+        let new = (0..100).map(|_| rand::random()).collect::<Vec<_>>();
+        assert!(new.iter().all(|d| !index.contains(d)));
+
+        for digest in new.iter() {
+            index.insert_with(*digest, Default::default);
+        }
+        assert_eq!(index.len(), 200);
+        assert!(loaded.iter().chain(new.iter()).all(|d| index.contains(d)));
+    }
+    #[test]
+    fn concurrent_misses_write_once() {
+        use super::ChunkIndex;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let index = ChunkIndex::default();
+        index.enable_filter();
+        let digests = (0..100).map(|_| rand::random()).collect::<Vec<_>>();
+        let written = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for digest in digests.iter() {
+                        index.insert_with(*digest, || {
+                            written.fetch_add(1, Ordering::Relaxed);
+                            Default::default()
+                        });
+                    }
+                });
+            }
+        });
+
+        assert_eq!(written.load(Ordering::Relaxed), digests.len());
+        assert_eq!(index.len(), digests.len());
+    }
+}
@@ -0,0 +1,102 @@
+//! Guess the content type of a file from its first few bytes
+//!
+//! This only recognizes common formats by their magic numbers, and
+//! is not meant to be exhaustive. Anything that's not recognized is
+//! reported as either `text/plain` or `application/octet-stream`.
+
+/// Number of bytes needed from the start of the file
+pub const SNIFF_LEN: usize = 512;
+
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+    (b"BM", "image/bmp"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\x7fELF", "application/x-elf"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/x-matroska"),
+    (b"<?xml", "application/xml"),
+];
+
+/// Return the content type of `data`, which should be the first
+/// [`SNIFF_LEN`] bytes of a file.
+pub fn sniff(data: &[u8]) -> &'static str {
+    let data = &data[..data.len().min(SNIFF_LEN)];
+
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return mime;
+    }
+
+    if data.len() >= 12 && &data[0..4] == b"RIFF" {
+        match &data[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            b"AVI " => return "video/x-msvideo",
+            _ => {}
+        }
+    }
+
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return match &data[8..12] {
+            b"heic" | b"heix" | b"mif1" => "image/heic",
+            b"avif" => "image/avif",
+            b"qt  " => "video/quicktime",
+            b"M4A " => "audio/mp4",
+            _ => "video/mp4",
+        };
+    }
+
+    let Some(text) = as_text(data) else {
+        return "application/octet-stream";
+    };
+
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html"
+    } else if start.starts_with("<svg") {
+        "image/svg+xml"
+    } else {
+        "text/plain"
+    }
+}
+
+fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+
+    match std::str::from_utf8(data) {
+        Ok(text) => Some(text),
+        // the buffer may end in the middle of a multi-byte character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sniff;
+
+    #[test]
+    fn sniff_common_types() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\x00\x00"), "image/png");
+        assert_eq!(sniff(b"\x00\x00\x00\x18ftypheic\x00\x00"), "image/heic");
+        assert_eq!(sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), "text/html");
+        assert_eq!(sniff("hello wörld".as_bytes()), "text/plain");
+        assert_eq!(sniff(&"ö".as_bytes()[..1]), "text/plain");
+        assert_eq!(sniff(b"\x00\x01\x02\x03"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,82 @@
+//! Runtime CPU feature detection
+//!
+//! Fast paths in the chunking code are compiled for multiple
+//! instruction sets, and the right one is selected when the process
+//! starts. This module also allows the binary to fail early with a
+//! useful message if it was built with target features the current
+//! CPU does not support, instead of crashing with `SIGILL`.
+use std::sync::OnceLock;
+
+#[derive(thiserror::Error, Debug)]
+#[error("This binary requires CPU features that are not available: {}", .missing.join(", "))]
+pub struct UnsupportedCpu {
+    pub missing: Vec<&'static str>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuFeatures {
+    pub sse2: bool,
+    pub sse41: bool,
+    pub avx2: bool,
+    pub avx512f: bool,
+}
+
+static FEATURES: OnceLock<CpuFeatures> = OnceLock::new();
+
+/// Return the features of the CPU we're currently running on.
+///
+/// Detection only runs once per process.
+pub fn features() -> &'static CpuFeatures {
+    FEATURES.get_or_init(detect)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect() -> CpuFeatures {
+    CpuFeatures {
+        sse2: is_x86_feature_detected!("sse2"),
+        sse41: is_x86_feature_detected!("sse4.1"),
+        avx2: is_x86_feature_detected!("avx2"),
+        avx512f: is_x86_feature_detected!("avx512f"),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn detect() -> CpuFeatures {
+    CpuFeatures::default()
+}
+
+/// Check that all target features enabled at compile time are
+/// supported by the CPU.
+///
+/// This should be called as early as possible on startup.
+pub fn check_compiled_features() -> Result<(), UnsupportedCpu> {
+    let detected = features();
+    let mut missing = vec![];
+
+    macro_rules! require {
+        ($feature:tt, $field:ident) => {
+            if cfg!(target_feature = $feature) && !detected.$field {
+                missing.push($feature);
+            }
+        };
+    }
+
+    require!("sse2", sse2);
+    require!("sse4.1", sse41);
+    require!("avx2", avx2);
+    require!("avx512f", avx512f);
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(UnsupportedCpu { missing })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn compiled_features_are_available() {
+        super::check_compiled_features().unwrap();
+    }
+}
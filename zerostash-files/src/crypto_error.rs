@@ -0,0 +1,57 @@
+//! Errors for chunks that can't be read back
+//!
+//! Decrypting a chunk fails if its object is damaged, or was not
+//! written with the key of the stash. The error names the object and
+//! the chunk, so `0s verify --object` can check the rest of it.
+use crate::{chunk_index::digest_to_hex, dictionary::Dictionaries};
+use infinitree::{
+    object::{ObjectError, ObjectId, Reader},
+    ChunkPointer,
+};
+
+#[derive(thiserror::Error, Debug)]
+#[error("Chunk {digest} in object {object} can not be read: {source}")]
+pub struct CryptoError {
+    pub object: ObjectId,
+    pub digest: String,
+    pub source: ChunkError,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ChunkError {
+    #[error(transparent)]
+    Object(#[from] ObjectError),
+    #[error("compression dictionary {0:08x} is not loaded")]
+    NoDictionary(u32),
+    #[error("decompression failed: {0}")]
+    Decompress(std::io::Error),
+}
+
+impl CryptoError {
+    pub fn new(pointer: &ChunkPointer, source: impl Into<ChunkError>) -> Self {
+        Self {
+            object: *pointer.object_id(),
+            digest: digest_to_hex(pointer.hash()),
+            source: source.into(),
+        }
+    }
+}
+
+/// Read the chunk at `pointer` into `buf`, and return the contents.
+///
+/// Chunks that were compressed with a [`dictionary`](crate::dictionary)
+/// are decompressed, if it's one of the loaded `dictionaries`.
+pub fn read_chunk<'buf>(
+    reader: &mut (impl Reader + ?Sized),
+    dictionaries: &Dictionaries,
+    pointer: &ChunkPointer,
+    buf: &'buf mut [u8],
+) -> Result<&'buf [u8], CryptoError> {
+    if crate::dictionary::compressed_with(pointer).is_some() {
+        return crate::dictionary::read_compressed(reader, dictionaries, pointer, buf);
+    }
+
+    reader
+        .read_chunk(pointer, buf)
+        .map_err(|source| CryptoError::new(pointer, source))
+}
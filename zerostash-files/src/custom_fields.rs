@@ -0,0 +1,296 @@
+//! Custom metadata that downstream crates attach to the index
+//!
+//! A custom field is a named map in the [`Files`](crate::Files) index
+//! with keys and values of its own types:
+//!
+//! ```ignore
+//! struct Tags;
+//!
+//! impl CustomField for Tags {
+//!     const NAME: &'static str = "tags";
+//!     type Key = String;
+//!     type Value = Vec<String>;
+//! }
+//!
+//! let tags = stash.index().custom_fields.get::<Tags>();
+//! tags.insert(&"home/photo.jpg".into(), &vec!["holiday".into()])?;
+//! ```
+//!
+//! Keys and values are serialized with MessagePack, and every field is
+//! kept in a versioned map of its own. They're loaded and committed
+//! with the rest of the index, and every commit stores the changes
+//! since the last one, like for other fields.
+//!
+//! Records of fields that the running binary doesn't know about are
+//! kept as they are, so builds with and without a field can use the
+//! same stash.
+use infinitree::{
+    fields::{Collection, Store, VersionedMap},
+    object::Reader,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+};
+
+/// A custom field in the index
+pub trait CustomField {
+    /// Unique name of the field in the index
+    const NAME: &'static str;
+    type Key: Serialize + DeserializeOwned;
+    type Value: Serialize + DeserializeOwned;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CustomFieldError {
+    #[error("Failed to encode record: {0}")]
+    Encode(#[from] rmp_serde::encode::Error),
+    #[error("Failed to decode record: {0}")]
+    Decode(#[from] rmp_serde::decode::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CustomFieldError>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordKey {
+    pub field: String,
+    pub key: Vec<u8>,
+}
+
+type Records = VersionedMap<RecordKey, Vec<u8>>;
+
+/// The records of all custom fields, in a map for each field
+#[derive(Clone, Default)]
+pub struct CustomFields(Arc<RwLock<BTreeMap<String, Records>>>);
+
+impl CustomFields {
+    /// Access the records of field `F`.
+    pub fn get<F: CustomField>(&self) -> FieldMap<F> {
+        FieldMap {
+            records: self.records(F::NAME),
+            _field: PhantomData,
+        }
+    }
+
+    /// Drop all loaded records, eg. before loading a different range
+    /// of commits.
+    pub fn clear(&self) {
+        // the maps stay, so the ones handed out see what's loaded next
+        for records in self.0.read().unwrap().values() {
+            records.clear();
+        }
+    }
+
+    /// The records of the field `name`, as they're stored
+    pub(crate) fn records(&self, name: &str) -> Records {
+        if let Some(records) = self.0.read().unwrap().get(name) {
+            return records.clone();
+        }
+
+        self.0
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Names of all fields that have records in the index
+    pub fn names(&self) -> BTreeSet<String> {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, records)| has_records(records))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns true if no field has records loaded.
+    pub fn is_empty(&self) -> bool {
+        !self.0.read().unwrap().values().any(has_records)
+    }
+}
+
+fn has_records(records: &Records) -> bool {
+    let mut found = false;
+    records.for_each(|_, _| found = true);
+    found
+}
+
+/// The records of a single field
+pub struct FieldMap<F> {
+    records: Records,
+    _field: PhantomData<fn() -> F>,
+}
+
+impl<F: CustomField> FieldMap<F> {
+    fn record_key(key: &F::Key) -> Result<RecordKey> {
+        Ok(RecordKey {
+            field: F::NAME.to_string(),
+            key: rmp_serde::to_vec(key)?,
+        })
+    }
+
+    /// Set the value of `key`, replacing any previous one.
+    pub fn insert(&self, key: &F::Key, value: &F::Value) -> Result<()> {
+        let key = Self::record_key(key)?;
+        let value = rmp_serde::to_vec(value)?;
+
+        if self.records.contains(&key) {
+            self.records.update_with(key, |_| value);
+        } else {
+            self.records.insert(key, value);
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &F::Key) -> Result<Option<F::Value>> {
+        match self.records.get(&Self::record_key(key)?) {
+            Some(value) => Ok(Some(rmp_serde::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(&self, key: &F::Key) -> Result<()> {
+        let key = Self::record_key(key)?;
+        if self.records.contains(&key) {
+            self.records.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Call `f` with every record of the field.
+    pub fn for_each(&self, mut f: impl FnMut(F::Key, F::Value)) -> Result<()> {
+        let mut result = Ok(());
+        self.records.for_each(|k, v| {
+            if result.is_err() {
+                return;
+            }
+            result = (|| -> Result<()> {
+                f(rmp_serde::from_slice(&k.key)?, rmp_serde::from_slice(v)?);
+                Ok(())
+            })();
+        });
+        result
+    }
+}
+
+impl Collection for CustomFields {
+    type Depth = infinitree::fields::depth::Incremental;
+
+    type Key = <Records as Collection>::Key;
+
+    type Serialized = <Records as Collection>::Serialized;
+
+    type Item = <Records as Collection>::Item;
+
+    fn key(from: &Self::Serialized) -> &Self::Key {
+        <Records as Collection>::key(from)
+    }
+
+    fn load(from: Self::Serialized, object: &mut dyn Reader) -> Self::Item {
+        <Records as Collection>::load(from, object)
+    }
+
+    fn insert(&mut self, record: Self::Item) {
+        let mut fields = self.0.write().unwrap();
+        let records = fields.entry(record.0.field.clone()).or_default();
+        <Records as Collection>::insert(records, record)
+    }
+}
+
+impl Store for CustomFields {
+    fn store(
+        &mut self,
+        transaction: &mut dyn infinitree::index::Transaction,
+        object: &mut dyn infinitree::object::Writer,
+    ) {
+        for records in self.0.write().unwrap().values_mut() {
+            <Records as Store>::store(records, transaction, object)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CustomField;
+
+    struct Tags;
+    impl CustomField for Tags {
+        const NAME: &'static str = "tags";
+        type Key = String;
+        type Value = Vec<String>;
+    }
+
+    struct Ratings;
+    impl CustomField for Ratings {
+        const NAME: &'static str = "ratings";
+        type Key = String;
+        type Value = u8;
+    }
+
+    #[test]
+    fn fields_are_separate() {
+        use super::CustomFields;
+
+        let fields = CustomFields::default();
+        let tags = fields.get::<Tags>();
+        let path = "home/photo.jpg".to_string();
+        assert!(fields.is_empty());
+
+        tags.insert(&path, &vec!["holiday".into()]).unwrap();
+        tags.insert(&path, &vec!["holiday".into(), "beach".into()])
+            .unwrap();
+        fields.get::<Ratings>().insert(&path, &5).unwrap();
+
+        assert_eq!(tags.get(&path).unwrap().unwrap().len(), 2);
+        assert_eq!(fields.get::<Ratings>().get(&path).unwrap(), Some(5));
+        assert_eq!(fields.names().len(), 2);
+
+        let mut count = 0;
+        fields.records(Tags::NAME).for_each(|_, _| count += 1);
+        assert_eq!(count, 1);
+
+        tags.remove(&path).unwrap();
+        assert_eq!(tags.get(&path).unwrap(), None);
+        assert_eq!(fields.names().len(), 1);
+    }
+
+    #[test]
+    fn fields_are_loaded_into_their_maps() {
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("fields".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let path = "home/photo.jpg".to_string();
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        let fields = &stash.index().custom_fields;
+        fields
+            .get::<Tags>()
+            .insert(&path, &vec!["holiday".into()])
+            .unwrap();
+        fields.get::<Ratings>().insert(&path, &5).unwrap();
+        stash.commit(None).unwrap();
+
+        let stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        let fields = &stash.index().custom_fields;
+        let tags = fields.get::<Tags>();
+        assert!(fields.is_empty());
+
+        stash.load(stash.index().custom_fields()).unwrap();
+        assert_eq!(tags.get(&path).unwrap(), Some(vec!["holiday".to_string()]));
+        assert_eq!(fields.get::<Ratings>().get(&path).unwrap(), Some(5));
+
+        let mut count = 0;
+        fields.records(Ratings::NAME).for_each(|_, _| count += 1);
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,381 @@
+//! Compress the chunks of small files with a shared zstd dictionary
+//!
+//! The storage writer compresses every chunk on its own, so a stash of
+//! many small, similar files, like source code, compresses poorly. A
+//! stash can [`train`] a zstd dictionary over a sample of its small
+//! files instead. The dictionary is stored as a chunk, which an
+//! [`CustomField`] of the index points to.
+//!
+//! Once a dictionary is [`load`]ed into the [`Dictionaries`] of the
+//! index, chunks of small files are compressed with it before they're
+//! written. They're stored under a digest that's derived from the
+//! digest of their contents, and starts with a marker, so
+//! [`read_chunk`](crate::crypto_error::read_chunk) knows to decompress
+//! them. Chunks that don't get smaller are stored as usual.
+//!
+//! Training a dictionary records chunk format [`FORMAT`] in the stash,
+//! so builds that can't decompress chunks refuse to open it.
+use crate::{
+    custom_fields::{CustomField, CustomFieldError},
+    Files,
+};
+use infinitree::{ChunkPointer, Digest, Hasher, Infinitree};
+use serde::{Deserialize, Serialize};
+
+/// Files up to this size are compressed with the dictionary
+pub const SMALL_FILE: u64 = 64 * 1024;
+
+/// The chunk format of stashes with compressed chunks
+pub const FORMAT: u64 = 2;
+
+/// The start of the digest of chunks compressed with a dictionary,
+/// followed by the id of the dictionary
+const MARKER: &[u8; 8] = b"0SZDICT1";
+
+const DICTIONARY: &str = "dictionary";
+
+struct Compression;
+
+impl CustomField for Compression {
+    const NAME: &'static str = "compression";
+    type Key = String;
+    type Value = Stored;
+}
+
+/// The dictionaries loaded for a stash, by id
+///
+/// Readers only see the pointer of a chunk, so they look up the
+/// dictionary it was compressed with here.
+#[derive(Clone, Default)]
+pub struct Dictionaries {
+    #[cfg(feature = "std-runtime")]
+    loaded: std::sync::Arc<zstd_dictionary::Loaded>,
+}
+
+/// Where the dictionary of a stash is stored
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stored {
+    pub id: u32,
+    pub pointer: ChunkPointer,
+}
+
+/// The dictionary recorded in the stash, if it has one
+pub fn get(stash: &Infinitree<Files>) -> Result<Option<Stored>, CustomFieldError> {
+    stash
+        .index()
+        .custom_fields
+        .get::<Compression>()
+        .get(&DICTIONARY.to_string())
+}
+
+/// The id of the dictionary the chunk at `pointer` was compressed with
+pub fn compressed_with(pointer: &ChunkPointer) -> Option<u32> {
+    let hash = pointer.hash();
+    if &hash[..MARKER.len()] != MARKER {
+        return None;
+    }
+
+    let id = hash[MARKER.len()..MARKER.len() + 4].try_into().unwrap();
+    Some(u32::from_le_bytes(id))
+}
+
+/// The digest the chunk at `pointer` is stored under, if its contents
+/// hash to `digest`
+pub fn stored_digest(pointer: &ChunkPointer, digest: &Digest) -> Digest {
+    match compressed_with(pointer) {
+        Some(id) => compressed_digest(id, digest),
+        None => *digest,
+    }
+}
+
+fn compressed_digest(id: u32, digest: &Digest) -> Digest {
+    let mut hasher = Hasher::new();
+    hasher.update(MARKER);
+    hasher.update(&id.to_le_bytes());
+    hasher.update(digest);
+
+    let mut stored = *hasher.finalize().as_bytes();
+    stored[..MARKER.len()].copy_from_slice(MARKER);
+    stored[MARKER.len()..MARKER.len() + 4].copy_from_slice(&id.to_le_bytes());
+    stored
+}
+
+#[cfg(feature = "std-runtime")]
+pub use zstd_dictionary::*;
+
+/// Builds without zstd can't decompress chunks, even if they opened a
+/// stash that has them.
+#[cfg(not(feature = "std-runtime"))]
+pub(crate) fn read_compressed<'buf>(
+    _reader: &mut (impl infinitree::object::Reader + ?Sized),
+    _dictionaries: &Dictionaries,
+    pointer: &ChunkPointer,
+    _buf: &'buf mut [u8],
+) -> Result<&'buf [u8], crate::crypto_error::CryptoError> {
+    use crate::crypto_error::{ChunkError, CryptoError};
+
+    let id = compressed_with(pointer).expect("the chunk is compressed");
+    Err(CryptoError::new(pointer, ChunkError::NoDictionary(id)))
+}
+
+#[cfg(feature = "std-runtime")]
+mod zstd_dictionary {
+    use super::{
+        compressed_digest, compressed_with, get, Compression, Dictionaries, Stored, DICTIONARY,
+        FORMAT, SMALL_FILE,
+    };
+    use crate::{
+        crypto_error::{read_chunk, ChunkError, CryptoError},
+        Files,
+    };
+    use infinitree::{
+        object::{Reader, Writer},
+        ChunkPointer, Digest, Hasher, Infinitree, BLOCK_SIZE,
+    };
+    use std::{
+        borrow::Cow,
+        collections::HashMap,
+        sync::{Arc, RwLock},
+    };
+    use tracing::debug;
+    use zstd::{
+        bulk::{Compressor, Decompressor},
+        dict::{DecoderDictionary, EncoderDictionary},
+    };
+
+    const LEVEL: i32 = 3;
+    const MAX_SIZE: usize = 112 * 1024;
+    const SAMPLE_BYTES: usize = 8 * 1024 * 1024;
+    /// zstd needs a reasonable number of samples to find anything
+    const MIN_SAMPLES: usize = 64;
+
+    pub(super) type Loaded = RwLock<HashMap<u32, Arc<Dictionary>>>;
+
+    pub struct Dictionary {
+        id: u32,
+        encoder: EncoderDictionary<'static>,
+        decoder: DecoderDictionary<'static>,
+    }
+
+    impl Dictionary {
+        fn new(id: u32, bytes: &[u8]) -> Arc<Self> {
+            Arc::new(Self {
+                id,
+                encoder: EncoderDictionary::copy(bytes, LEVEL),
+                decoder: DecoderDictionary::copy(bytes),
+            })
+        }
+
+        /// The digest and contents to store a chunk with `digest` and
+        /// `data` under. Only chunks that get smaller are compressed.
+        pub fn encode<'a>(&self, digest: Digest, data: &'a [u8]) -> (Digest, Cow<'a, [u8]>) {
+            let compressed = Compressor::with_prepared_dictionary(&self.encoder)
+                .and_then(|mut c| c.compress(data));
+
+            match compressed {
+                Ok(compressed) if compressed.len() < data.len() => {
+                    (compressed_digest(self.id, &digest), Cow::Owned(compressed))
+                }
+                _ => (digest, Cow::Borrowed(data)),
+            }
+        }
+    }
+
+    impl Dictionaries {
+        /// The loaded dictionary with `id`
+        pub fn get(&self, id: u32) -> Option<Arc<Dictionary>> {
+            self.loaded.read().unwrap().get(&id).cloned()
+        }
+
+        fn insert(&self, dictionary: Arc<Dictionary>) {
+            self.loaded
+                .write()
+                .unwrap()
+                .insert(dictionary.id, dictionary);
+        }
+    }
+
+    /// Load the dictionary recorded in the stash, if it has one, into
+    /// the [`Dictionaries`] of the index, so chunks compressed with it
+    /// can be read.
+    pub fn load(stash: &Infinitree<Files>) -> anyhow::Result<Option<Arc<Dictionary>>> {
+        let Some(stored) = get(stash)? else {
+            return Ok(None);
+        };
+        let dictionaries = &stash.index().dictionaries;
+        if let Some(loaded) = dictionaries.get(stored.id) {
+            return Ok(Some(loaded));
+        }
+
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut reader = stash.storage_reader()?;
+        let bytes = read_chunk(&mut reader, dictionaries, &stored.pointer, &mut buf)?;
+        debug!(
+            id = stored.id,
+            size = bytes.len(),
+            "loaded compression dictionary"
+        );
+
+        let dictionary = Dictionary::new(stored.id, bytes);
+        dictionaries.insert(dictionary.clone());
+        Ok(Some(dictionary))
+    }
+
+    /// Train a dictionary over the small files in the loaded tree of
+    /// `stash`, store it, and record it in the index.
+    ///
+    /// Returns `None` if there are too few small files to train on. The
+    /// caller is responsible for committing the changes.
+    pub fn train(stash: &Infinitree<Files>) -> anyhow::Result<Option<Arc<Dictionary>>> {
+        let dictionaries = &stash.index().dictionaries;
+        let mut reader = stash.storage_reader()?;
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut samples = vec![];
+        let mut total = 0;
+
+        for (_, entry) in stash.index().tree.iter_files() {
+            if entry.size == 0 || entry.size > SMALL_FILE || total >= SAMPLE_BYTES {
+                continue;
+            }
+
+            for pointer in entry.chunks.values() {
+                let data = read_chunk(&mut reader, dictionaries, pointer, &mut buf)?;
+                total += data.len();
+                samples.push(data.to_vec());
+            }
+        }
+
+        if samples.len() < MIN_SAMPLES {
+            return Ok(None);
+        }
+
+        let bytes = zstd::dict::from_samples(&samples, MAX_SIZE)?;
+        let id = rand::random();
+
+        let mut writer = stash.storage_writer()?;
+        let digest = *Hasher::new().update(&bytes).finalize().as_bytes();
+        let pointer = writer.write_chunk(&digest, &bytes)?;
+        writer.flush()?;
+
+        stash
+            .index()
+            .custom_fields
+            .get::<Compression>()
+            .insert(&DICTIONARY.to_string(), &Stored { id, pointer })?;
+        crate::object_size::require_format(stash, FORMAT)?;
+        debug!(
+            id,
+            samples = samples.len(),
+            size = bytes.len(),
+            "trained compression dictionary"
+        );
+
+        let dictionary = Dictionary::new(id, &bytes);
+        dictionaries.insert(dictionary.clone());
+        Ok(Some(dictionary))
+    }
+
+    /// Read a chunk that was compressed with a dictionary into `buf`.
+    pub(crate) fn read_compressed<'buf>(
+        reader: &mut (impl Reader + ?Sized),
+        dictionaries: &Dictionaries,
+        pointer: &ChunkPointer,
+        buf: &'buf mut [u8],
+    ) -> Result<&'buf [u8], CryptoError> {
+        let id = compressed_with(pointer).expect("the chunk is compressed");
+        let dictionary = dictionaries
+            .get(id)
+            .ok_or_else(|| CryptoError::new(pointer, ChunkError::NoDictionary(id)))?;
+
+        let mut compressed = vec![0; BLOCK_SIZE];
+        let compressed = reader
+            .read_chunk(pointer, &mut compressed)
+            .map_err(|source| CryptoError::new(pointer, source))?;
+
+        let len = Decompressor::with_prepared_dictionary(&dictionary.decoder)
+            .and_then(|mut d| d.decompress_to_buffer(compressed, buf))
+            .map_err(|e| CryptoError::new(pointer, ChunkError::Decompress(e)))?;
+        Ok(&buf[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std-runtime")]
+    #[test]
+    fn small_files_share_a_dictionary() {
+        use super::{compressed_with, load, stored_digest, train, FORMAT};
+        use crate::{crypto_error::read_chunk, Entry, Files};
+        use infinitree::{crypto::UsernamePassword, object::Writer, Infinitree, BLOCK_SIZE};
+        use std::sync::Arc;
+
+        let key = || {
+            UsernamePassword::with_credentials("dictionary".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+
+        let source = |n: usize| {
+            format!(
+                "fn function_{n}(input: &str) -> Option<usize> {{\n    input.find(\"{n}\")\n}}\n"
+            )
+            .repeat(4)
+        };
+        let mut writer = stash.storage_writer().unwrap();
+        for n in 0..200 {
+            let data = source(n);
+            let digest = *stash
+                .hasher()
+                .unwrap()
+                .update(data.as_bytes())
+                .finalize()
+                .as_bytes();
+            let mut entry = Entry {
+                size: data.len() as u64,
+                ..Default::default()
+            };
+            let pointer = writer.write_chunk(&digest, data.as_bytes()).unwrap();
+            entry.chunks.insert(0, Arc::new(pointer));
+            stash
+                .index()
+                .tree
+                .insert_file(&format!("src/{n}.rs"), entry)
+                .unwrap();
+        }
+        writer.flush().unwrap();
+
+        let dictionary = train(&stash).unwrap().unwrap();
+        assert_eq!(crate::object_size::format(&stash).unwrap(), FORMAT);
+        stash.commit(None).unwrap();
+
+        let data = source(1000);
+        let digest = *stash
+            .hasher()
+            .unwrap()
+            .update(data.as_bytes())
+            .finalize()
+            .as_bytes();
+        let (stored, encoded) = dictionary.encode(digest, data.as_bytes());
+        assert!(encoded.len() < data.len());
+
+        let pointer = writer.write_chunk(&stored, &encoded).unwrap();
+        writer.flush().unwrap();
+        assert!(compressed_with(&pointer).is_some());
+        assert_eq!(stored_digest(&pointer, &digest), stored);
+
+        // a stash that was opened again has to load the dictionary
+        let stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        stash.load_all().unwrap();
+        let dictionaries = &stash.index().dictionaries;
+        let mut buf = vec![0; BLOCK_SIZE];
+        let mut reader = stash.storage_reader().unwrap();
+        assert!(read_chunk(&mut reader, dictionaries, &pointer, &mut buf).is_err());
+
+        assert!(load(&stash).unwrap().is_some());
+        assert_eq!(
+            read_chunk(&mut reader, dictionaries, &pointer, &mut buf).unwrap(),
+            data.as_bytes()
+        );
+    }
+}
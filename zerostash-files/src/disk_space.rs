@@ -0,0 +1,135 @@
+//! Check that there's room on a file system before filling it up
+//!
+//! Paths that don't exist yet are looked up through their closest
+//! existing parent, so the space can be checked before anything is
+//! created. Only unix file systems can be asked for their free space,
+//! elsewhere every check passes.
+use std::{
+    collections::HashMap,
+    env, io,
+    path::{Path, PathBuf},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// A file system that doesn't have room for what's written to it
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "{} needs {} MiB, but only {} MiB is free",
+    path.display(),
+    needed.div_ceil(MIB),
+    available / MIB
+)]
+pub struct NotEnoughSpace {
+    pub path: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+/// Free space on the file system that `path` is on, or would be
+/// created on. `None` if it can't be told on this platform.
+pub fn available(path: &Path) -> io::Result<Option<u64>> {
+    available_on(&existing_ancestor(path)?)
+}
+
+/// Bytes that will be written, by the file system they go to
+#[derive(Default)]
+pub struct Needed {
+    devices: HashMap<u64, (PathBuf, u64)>,
+    parents: HashMap<PathBuf, u64>,
+}
+
+impl Needed {
+    /// Count `bytes` towards the file system of `path`.
+    pub fn add(&mut self, path: &Path, bytes: u64) -> io::Result<()> {
+        // most files share their directory with others
+        let parent = path.parent().unwrap_or(path);
+        let device = match self.parents.get(parent) {
+            Some(device) => *device,
+            None => {
+                let existing = existing_ancestor(parent)?;
+                let device = device_of(&existing)?;
+                self.devices.entry(device).or_insert((existing, 0));
+                self.parents.insert(parent.to_path_buf(), device);
+                device
+            }
+        };
+
+        self.devices.get_mut(&device).unwrap().1 += bytes;
+        Ok(())
+    }
+
+    /// The file systems that don't have room for what's counted
+    pub fn check(&self) -> io::Result<Vec<NotEnoughSpace>> {
+        let mut short = vec![];
+        for (path, needed) in self.devices.values() {
+            match available_on(path)? {
+                Some(available) if available < *needed => short.push(NotEnoughSpace {
+                    path: path.clone(),
+                    needed: *needed,
+                    available,
+                }),
+                _ => {}
+            }
+        }
+
+        Ok(short)
+    }
+}
+
+fn existing_ancestor(path: &Path) -> io::Result<PathBuf> {
+    let path = env::current_dir()?.join(path);
+    path.ancestors()
+        .find(|p| p.exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no parent directory exists"))
+}
+
+#[cfg(unix)]
+// the widths of the fields differ between platforms
+#[allow(clippy::unnecessary_cast)]
+fn available_on(path: &Path) -> io::Result<Option<u64>> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(Some(
+        stat.blocks_available() as u64 * stat.fragment_size() as u64,
+    ))
+}
+
+#[cfg(unix)]
+fn device_of(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.dev())
+}
+
+#[cfg(not(unix))]
+fn available_on(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+fn device_of(_path: &Path) -> io::Result<u64> {
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn more_than_free_space_is_short() {
+        use super::{available, Needed};
+
+        let dir = std::env::temp_dir();
+        let missing = dir.join("zerostash-disk-space/not/created");
+        let free = available(&missing).unwrap().unwrap();
+
+        let mut needed = Needed::default();
+        needed.add(&missing.join("a"), free / 2).unwrap();
+        needed.add(&dir.join("b"), free / 4).unwrap();
+        assert!(needed.check().unwrap().is_empty());
+
+        needed.add(&missing.join("c"), free).unwrap();
+        let short = needed.check().unwrap();
+        assert_eq!(short.len(), 1);
+        assert!(short[0].needed > free);
+    }
+}
@@ -1,10 +1,12 @@
 use chrono::{DateTime, TimeZone, Utc};
 use infinitree::ChunkPointer;
-#[cfg(not(target_os = "windows"))]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(unix)]
 use std::time::UNIX_EPOCH;
 use std::{
     collections::BTreeMap,
     fs, io,
+    ops::Range,
     path::{Component, Path, PathBuf},
     sync::Arc,
     time::SystemTimeError,
@@ -39,7 +41,13 @@ pub enum EntryError {
         source: io::Error,
     },
 
-    #[cfg(not(target_os = "windows"))]
+    #[error("Metadata encoding error: {source}")]
+    Encode {
+        #[from]
+        source: rmp_serde::encode::Error,
+    },
+
+    #[cfg(unix)]
     #[error("Errno: {source}")]
     Errno {
         #[from]
@@ -80,12 +88,17 @@ pub struct PreserveMetadata {
     #[clap(short = 'p', long = "preserve-permissions", default_value = "true")]
     pub permissions: bool,
 
-    /// Preserve owner/gid information. Requires root to restore.
+    /// Preserve owner/gid information. Requires root to restore, or root of a user
+    /// namespace for the ids it maps.
     #[clap(short = 'o', long = "preserve-ownership", default_value = "true")]
     pub ownership: bool,
     /// Preserve modification and creation times.
     #[clap(short = 't', long = "preserve-times", default_value = "true")]
     pub times: bool,
+    /// Preserve resource forks, Finder info and other `com.apple.*` extended
+    /// attributes, creation dates and file flags on macOS.
+    #[clap(long = "preserve-macos-metadata", default_value = "true")]
+    pub macos: bool,
 }
 
 pub(crate) fn normalize_filename(path: &impl AsRef<Path>) -> Result<String, EntryError> {
@@ -117,6 +130,23 @@ pub struct Entry {
     pub name: String,
 
     pub chunks: BTreeMap<u64, Arc<ChunkPointer>>,
+
+    /// Content type sniffed from the first few bytes, if enabled
+    #[serde(default)]
+    pub content_type: Option<String>,
+
+    /// The algorithm that split the file into chunks
+    #[serde(default)]
+    pub chunker: Option<crate::splitter::Chunker>,
+
+    /// Metadata that's not part of the structure, keyed by name.
+    ///
+    /// New kinds of metadata should go here instead of into new fields,
+    /// so the index stays readable for versions that don't know them.
+    /// Use [`Entry::extension`] and [`Entry::set_extension`] to access
+    /// them.
+    #[serde(default)]
+    pub extensions: BTreeMap<String, Vec<u8>>,
 }
 
 impl From<&Entry> for PathBuf {
@@ -147,6 +177,37 @@ impl PartialEq for Entry {
 }
 
 impl Entry {
+    /// Returns true if everything stored about the file is the same,
+    /// including the chunks and extensions that `==` ignores.
+    pub fn is_identical(&self, other: &Self) -> bool {
+        self == other
+            && self.chunks == other.chunks
+            && self.content_type == other.content_type
+            && self.chunker == other.chunker
+            && self.extensions == other.extensions
+    }
+
+    /// Decode the extension `name`, if it's present.
+    pub fn extension<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Option<Result<T, rmp_serde::decode::Error>> {
+        self.extensions
+            .get(name)
+            .map(|bytes| rmp_serde::from_slice(bytes))
+    }
+
+    /// Store `value` as the extension `name`, replacing any previous value.
+    pub fn set_extension<T: Serialize>(
+        &mut self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), rmp_serde::encode::Error> {
+        self.extensions
+            .insert(name.to_string(), rmp_serde::to_vec_named(value)?);
+        Ok(())
+    }
+
     #[cfg(windows)]
     pub fn from_metadata(
         metadata: fs::Metadata,
@@ -167,7 +228,7 @@ impl Entry {
             .ok_or(EntryError::NonUtf8Path)?
             .to_string();
 
-        Ok(Entry {
+        let entry = Entry {
             unix_secs,
             unix_nanos,
             unix_perm: None,
@@ -187,7 +248,12 @@ impl Entry {
             name,
 
             chunks: Vec::new(),
-        })
+            content_type: None,
+            chunker: None,
+            extensions: BTreeMap::new(),
+        };
+
+        entry.with_link_target()
     }
 
     #[cfg(unix)]
@@ -213,7 +279,7 @@ impl Entry {
             .ok_or(EntryError::NonUtf8Path)?
             .to_string();
 
-        Ok(Entry {
+        let mut entry = Entry {
             unix_secs,
             unix_nanos,
 
@@ -233,9 +299,166 @@ impl Entry {
             name,
 
             chunks: Default::default(),
+            content_type: None,
+            chunker: None,
+            extensions: BTreeMap::new(),
+        };
+
+        if entry.file_type.is_file() {
+            // a pair of integers always encodes
+            entry
+                .set_extension(INODE, &(metadata.dev(), metadata.ino()))
+                .unwrap();
+        }
+
+        entry
+            .with_macos_metadata(path.as_ref(), &metadata, preserve)?
+            .with_link_target()
+    }
+
+    #[cfg(target_os = "macos")]
+    fn with_macos_metadata(
+        mut self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        preserve: &PreserveMetadata,
+    ) -> Result<Entry, EntryError> {
+        if !preserve.macos {
+            return Ok(self);
+        }
+
+        // a file that can't be read is skipped, but its metadata is extra
+        match crate::macos::read(path, metadata) {
+            Ok(macos) if !macos.is_empty() => {
+                self.set_extension(crate::macos::EXTENSION, &macos)?;
+            }
+            Ok(_) => {}
+            Err(error) => {
+                tracing::warn!(%error, ?path, "failed to read macOS metadata, skipping it");
+            }
+        }
+
+        Ok(self)
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn with_macos_metadata(
+        self,
+        _path: &Path,
+        _metadata: &fs::Metadata,
+        _preserve: &PreserveMetadata,
+    ) -> Result<Entry, EntryError> {
+        Ok(self)
+    }
+
+    /// Remember if a symlink points to an absolute path. Paths from a
+    /// different platform can't tell on their own.
+    fn with_link_target(mut self) -> Result<Entry, EntryError> {
+        if let FileType::Symlink(ref target) = self.file_type {
+            let absolute = target.is_absolute();
+            self.set_extension(LINK_TARGET, &LinkTarget { absolute })?;
+        }
+
+        Ok(self)
+    }
+
+    /// Whether the target of a symlink is an absolute path, or `None`
+    /// for anything else.
+    pub fn link_is_absolute(&self) -> Option<bool> {
+        let FileType::Symlink(ref target) = self.file_type else {
+            return None;
+        };
+
+        // entries stored before this was recorded
+        Some(match self.extension::<LinkTarget>(LINK_TARGET) {
+            Some(Ok(link)) => link.absolute,
+            _ => target.is_absolute(),
         })
     }
 
+    /// The chunk that holds the byte at `offset`, and where it starts
+    pub fn chunk_at(&self, offset: u64) -> Option<(u64, &Arc<ChunkPointer>)> {
+        if offset >= self.size {
+            return None;
+        }
+
+        self.chunks
+            .range(..=offset)
+            .next_back()
+            .map(|(start, pointer)| (*start, pointer))
+    }
+
+    /// The chunks that hold the bytes in `range` in order, with where
+    /// each of them starts, and its length.
+    pub fn chunks_in(
+        &self,
+        range: Range<u64>,
+    ) -> impl Iterator<Item = (u64, u64, &Arc<ChunkPointer>)> + '_ {
+        let first = self
+            .chunk_at(range.start)
+            .map_or(range.start, |(start, _)| start);
+        let mut chunks = self.chunks.range(first..).peekable();
+
+        std::iter::from_fn(move || {
+            let (start, pointer) = chunks.next().filter(|(start, _)| **start < range.end)?;
+            let end = chunks.peek().map_or(self.size, |(next, _)| **next);
+            Some((*start, end - start, pointer))
+        })
+    }
+
+    /// The path this version of the file was renamed from, if the
+    /// commit that stored it detected a rename.
+    pub fn renamed_from(&self) -> Option<String> {
+        self.extension(RENAMED_FROM)?.ok()
+    }
+
+    pub fn set_renamed_from(&mut self, path: &str) {
+        // a string always encodes
+        self.set_extension(RENAMED_FROM, &path).unwrap();
+    }
+
+    /// Annotations added by a file hook, eg. `scanned=clean`.
+    pub fn annotations(&self) -> BTreeMap<String, String> {
+        self.extension(ANNOTATIONS)
+            .and_then(Result::ok)
+            .unwrap_or_default()
+    }
+
+    /// Replace the annotations of the file. An empty map removes them.
+    pub fn set_annotations(&mut self, annotations: &BTreeMap<String, String>) {
+        if annotations.is_empty() {
+            self.extensions.remove(ANNOTATIONS);
+        } else {
+            // a map of strings always encodes
+            self.set_extension(ANNOTATIONS, annotations).unwrap();
+        }
+    }
+
+    /// The device and inode number of the file when it was committed,
+    /// if the platform has them.
+    pub fn inode(&self) -> Option<(u64, u64)> {
+        self.extension(INODE)?.ok()
+    }
+
+    /// Metadata of the file that only macOS knows about, if it was
+    /// committed on macOS.
+    pub fn macos(&self) -> Option<crate::macos::Metadata> {
+        self.extension(crate::macos::EXTENSION)?.ok()
+    }
+
+    #[cfg(target_os = "macos")]
+    fn restore_macos_metadata(
+        &self,
+        path: &Path,
+        preserve: &PreserveMetadata,
+    ) -> Result<(), EntryError> {
+        if let (true, Some(macos)) = (preserve.macos, self.macos()) {
+            crate::macos::restore(path, &macos)?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(windows)]
     pub fn restore_to(
         &self,
@@ -254,7 +477,10 @@ impl Entry {
                 file.set_len(self.size)?;
                 file
             }
-            Symlink(ref pointed_to) => open_symlink(path, pointed_to)?,
+            Symlink(ref pointed_to) => {
+                create_symlink(path, pointed_to)?;
+                return Ok(None);
+            }
         };
 
         file.set_len(self.size)?;
@@ -297,7 +523,11 @@ impl Entry {
                 file.set_len(self.size)?;
                 file
             }
-            Symlink(ref pointed_to) => open_symlink(path, pointed_to)?,
+            Symlink(ref pointed_to) => {
+                create_symlink(path, pointed_to)?;
+                self.restore_link_metadata(path.as_ref(), preserve)?;
+                return Ok(None);
+            }
         };
 
         if preserve.permissions {
@@ -320,34 +550,85 @@ impl Entry {
             )?;
         }
 
+        #[cfg(target_os = "macos")]
+        self.restore_macos_metadata(path.as_ref(), preserve)?;
+
         Ok(if self.file_type.is_file() {
             Some(file)
         } else {
             None
         })
     }
+
+    /// Opening a symlink fails on some platforms if the target doesn't
+    /// exist, so the metadata is set through the path instead.
+    ///
+    /// Permissions of symlinks are never checked, and can't be changed
+    /// on Linux, so they're skipped.
+    #[cfg(unix)]
+    fn restore_link_metadata(
+        &self,
+        path: &Path,
+        preserve: &PreserveMetadata,
+    ) -> Result<(), EntryError> {
+        use nix::sys::stat::{utimensat, UtimensatFlags};
+        use std::time::{Duration, SystemTime};
+
+        if preserve.times {
+            let atime = SystemTime::now().duration_since(UNIX_EPOCH)?.into();
+            let mtime = Duration::new(self.unix_secs as u64, self.unix_nanos).into();
+            utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+        }
+
+        if preserve.ownership {
+            std::os::unix::fs::lchown(path, self.unix_uid, self.unix_gid)?;
+        }
+
+        #[cfg(target_os = "macos")]
+        self.restore_macos_metadata(path, preserve)?;
+
+        Ok(())
+    }
+}
+
+/// Extension that records how a symlink was stored
+const LINK_TARGET: &str = "link_target";
+/// Extension with the previous path of a renamed file
+const RENAMED_FROM: &str = "renamed_from";
+/// Extension with the device and inode number of a file
+const INODE: &str = "inode";
+/// Extension with the annotations of a file hook
+const ANNOTATIONS: &str = "annotations";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LinkTarget {
+    absolute: bool,
 }
 
 #[cfg(windows)]
-fn open_symlink(
+fn create_symlink(
     path: impl AsRef<Path> + Copy,
     pointed_to: impl AsRef<Path> + Copy,
-) -> Result<fs::File, io::Error> {
+) -> Result<(), io::Error> {
     use std::os::windows::fs::{symlink_dir, symlink_file};
 
-    let pointed_md = std::fs::metadata(pointed_to)?;
-    let symlink = if pointed_md.is_dir() {
-        symlink_dir
-    } else {
-        symlink_file
+    // relative targets are resolved from the directory of the link. If
+    // the target doesn't exist (yet), it's linked as a file.
+    let target = match path.as_ref().parent() {
+        Some(parent) => parent.join(pointed_to),
+        None => pointed_to.as_ref().to_path_buf(),
+    };
+    let symlink = match fs::metadata(target) {
+        Ok(md) if md.is_dir() => symlink_dir,
+        _ => symlink_file,
     };
 
     match symlink(pointed_to, path) {
-        Ok(()) => Ok(fs::OpenOptions::new().read(true).open(path)?),
+        Ok(()) => Ok(()),
         Err(err) if err.kind() == io::ErrorKind::NotFound => {
             if let Some(parent) = path.as_ref().parent() {
                 fs::create_dir_all(parent)?;
-                open_symlink(path, pointed_to)
+                create_symlink(path, pointed_to)
             } else {
                 Err(err)
             }
@@ -357,34 +638,27 @@ fn open_symlink(
 }
 
 #[cfg(unix)]
-fn open_symlink(
+fn create_symlink(
     path: impl AsRef<Path> + Copy,
     pointed_to: impl AsRef<Path> + Copy,
-) -> Result<fs::File, io::Error> {
-    use std::os::{
-        raw::c_int,
-        unix::fs::{symlink, OpenOptionsExt},
-    };
-
-    #[cfg(target_os = "linux")]
-    const NO_SYMLINK: c_int = libc::O_PATH | libc::O_NOFOLLOW;
-
-    #[cfg(not(target_os = "linux"))]
-    const NO_SYMLINK: c_int = libc::O_SYMLINK;
+) -> Result<(), io::Error> {
+    use std::os::unix::fs::symlink;
 
     match symlink(pointed_to, path) {
-        Ok(()) => {
-            let file = fs::OpenOptions::new()
-                .read(true)
-                .custom_flags(NO_SYMLINK)
-                .open(path)?;
-
-            Ok(file)
-        }
+        Ok(()) => Ok(()),
         Err(err) if err.kind() == io::ErrorKind::NotFound => {
             if let Some(parent) = path.as_ref().parent() {
                 fs::create_dir_all(parent)?;
-                open_symlink(path, pointed_to)
+                create_symlink(path, pointed_to)
+            } else {
+                Err(err)
+            }
+        }
+        // replace links like files are overwritten, but nothing else
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            if fs::symlink_metadata(path)?.is_symlink() {
+                fs::remove_file(path)?;
+                symlink(pointed_to, path)
             } else {
                 Err(err)
             }
@@ -393,6 +667,7 @@ fn open_symlink(
     }
 }
 
+#[cfg(any(unix, windows))]
 fn open_file(path: impl AsRef<Path> + Copy) -> Result<fs::File, io::Error> {
     match fs::OpenOptions::new()
         .create(true)
@@ -414,6 +689,7 @@ fn open_file(path: impl AsRef<Path> + Copy) -> Result<fs::File, io::Error> {
     }
 }
 
+#[cfg(any(unix, windows))]
 #[inline(always)]
 fn to_unix_mtime(m: &fs::Metadata) -> Result<(i64, u32), EntryError> {
     let mtime: chrono::DateTime<chrono::Utc> = m.modified()?.into();
@@ -440,4 +716,107 @@ mod tests {
         assert_eq!(Path::new("home/a/b"), get_path("/home/a/b").as_path());
         assert_eq!(Path::new("./a/b"), get_path("./a/b").as_path());
     }
+
+    #[test]
+    fn identical_entries_have_the_same_chunks() {
+        use super::*;
+
+        let entry = Entry::default();
+        let mut rewritten = entry.clone();
+        rewritten
+            .chunks
+            .insert(0, Arc::new(ChunkPointer::default()));
+
+        assert_eq!(entry, rewritten);
+        assert!(entry.is_identical(&entry.clone()));
+        assert!(!entry.is_identical(&rewritten));
+    }
+
+    #[test]
+    fn extensions_roundtrip() {
+        use super::*;
+
+        let mut entry = Entry::default();
+        entry.set_extension("xattrs", &vec![("user.a", 1)]).unwrap();
+
+        let xattrs: Vec<(String, u32)> = entry.extension("xattrs").unwrap().unwrap();
+        assert_eq!(xattrs, vec![("user.a".to_string(), 1)]);
+        assert!(entry.extension::<u32>("missing").is_none());
+    }
+
+    #[test]
+    fn annotations_are_extensions() {
+        use super::*;
+
+        let mut entry = Entry::default();
+        assert!(entry.annotations().is_empty());
+
+        let annotations = BTreeMap::from([("scanned".to_string(), "clean".to_string())]);
+        entry.set_annotations(&annotations);
+        assert!(entry.extensions.contains_key(ANNOTATIONS));
+        assert_eq!(entry.annotations(), annotations);
+
+        entry.set_annotations(&BTreeMap::new());
+        assert!(entry.extensions.is_empty());
+    }
+
+    #[test]
+    fn chunk_ranges() {
+        use super::*;
+
+        let entry = Entry {
+            size: 30,
+            chunks: [0, 10, 20]
+                .into_iter()
+                .map(|start| (start, Arc::new(ChunkPointer::default())))
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(entry.chunk_at(9).map(|(start, _)| start), Some(0));
+        assert_eq!(entry.chunk_at(20).map(|(start, _)| start), Some(20));
+        assert!(entry.chunk_at(30).is_none());
+
+        let chunks = entry
+            .chunks_in(15..21)
+            .map(|(start, len, _)| (start, len))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![(10, 10), (20, 10)]);
+        assert_eq!(entry.chunks_in(30..40).count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_dangling_symlink() {
+        use super::*;
+
+        let dir = std::env::temp_dir().join(format!("zerostash-link-{}", std::process::id()));
+        let path = dir.join("a/link");
+
+        let entry = Entry {
+            file_type: FileType::Symlink("../missing".into()),
+            unix_secs: 1_000_000,
+            ..Default::default()
+        }
+        .with_link_target()
+        .unwrap();
+        assert_eq!(entry.link_is_absolute(), Some(false));
+
+        let preserve = PreserveMetadata {
+            permissions: true,
+            ownership: false,
+            times: true,
+            macos: true,
+        };
+        assert!(entry.restore_to(&path, &preserve).unwrap().is_none());
+        // restoring again replaces the link
+        assert!(entry.restore_to(&path, &preserve).unwrap().is_none());
+
+        let md = fs::symlink_metadata(&path).unwrap();
+        assert!(md.is_symlink());
+        assert_eq!(to_unix_mtime(&md).unwrap().0, 1_000_000);
+        assert_eq!(fs::read_link(&path).unwrap(), Path::new("../missing"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
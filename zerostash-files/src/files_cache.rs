@@ -0,0 +1,203 @@
+//! Remember the files of the last commit on the local machine
+//!
+//! To find out if a file changed, a commit looks up its path in the
+//! tree, and compares the metadata. The files cache keeps the size,
+//! modification and change time of every file stored by the last
+//! commit, so unchanged files can be skipped right in the directory
+//! walk.
+//!
+//! The cache is tied to the commit it was written after, and it's
+//! ignored if the stash has moved on since, eg. because another machine
+//! committed to it.
+//!
+//! The cache is written in [`frame`]s, the first one holding the commit.
+//! A damaged frame only drops the files in it, which are then checked
+//! against the tree as usual.
+use crate::{frame, Tree};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, io, path::Path, time::UNIX_EPOCH};
+use tracing::debug;
+
+/// Files in a frame of the cache
+const FRAME_FILES: usize = 4096;
+
+/// What a file looked like when it was stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stamp {
+    size: u64,
+    mtime: (i64, u32),
+    /// Changes with permissions and ownership, unlike `mtime`
+    ctime: (i64, i64),
+}
+
+impl Stamp {
+    pub fn new(metadata: &fs::Metadata) -> Option<Self> {
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        #[cfg(unix)]
+        let ctime = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.ctime(), metadata.ctime_nsec())
+        };
+        #[cfg(not(unix))]
+        let ctime = (0, 0);
+
+        Some(Self {
+            size: metadata.len(),
+            mtime: (mtime.as_secs() as i64, mtime.subsec_nanos()),
+            ctime,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FilesCache {
+    commit: String,
+    files: HashMap<String, Stamp>,
+}
+
+impl FilesCache {
+    /// Read the cache at `path` if it was written after `commit`.
+    ///
+    /// A missing or unreadable cache is empty.
+    pub fn load(path: impl AsRef<Path>, commit: &str) -> Self {
+        let cache = fs::read(path)
+            .ok()
+            .and_then(|bytes| Self::decode(&bytes))
+            .unwrap_or_default();
+
+        if cache.commit == commit {
+            cache
+        } else {
+            Self::default()
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        // caches written before frames were one record
+        if !frame::is_framed(bytes) {
+            return rmp_serde::from_slice(bytes).ok();
+        }
+
+        let mut frames = frame::Frames::new(bytes);
+        let commit = frames.next_records::<String>()?.ok()?.pop()?;
+        let (files, damaged) = frames.records::<(String, Stamp)>();
+        if damaged > 0 {
+            debug!(damaged, "skipped damaged frames of the files cache");
+        }
+
+        Some(Self {
+            commit,
+            files: files.into_iter().collect(),
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut bytes = vec![];
+        frame::write(&mut bytes, [&self.commit], 1)?;
+        frame::write(&mut bytes, self.files.iter(), FRAME_FILES)?;
+
+        // don't leave a truncated cache behind if we're interrupted
+        let tmp = path.with_extension(format!("tmp.{}", std::process::id()));
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Is the file at `path` the same as in the last commit
+    pub fn is_unchanged(&self, path: &str, stamp: &Stamp) -> bool {
+        self.files.get(path) == Some(stamp)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Build the cache for `commit` from the files seen during the
+    /// directory walk.
+    ///
+    /// Only files that were stored as they were seen are kept, so
+    /// files that failed to read or were skipped are checked again
+    /// next time.
+    pub fn from_walk(seen: HashMap<String, Stamp>, tree: &Tree, commit: String) -> Self {
+        let files = seen
+            .into_iter()
+            .filter(|(path, stamp)| match tree.file(path) {
+                Ok(Some(entry)) => {
+                    entry.size == stamp.size && (entry.unix_secs, entry.unix_nanos) == stamp.mtime
+                }
+                _ => false,
+            })
+            .collect();
+
+        Self { commit, files }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn only_stored_files_are_cached() {
+        use super::{FilesCache, Stamp};
+        use crate::{Entry, Tree};
+        use std::collections::HashMap;
+
+        let stamp = Stamp {
+            size: 10,
+            mtime: (100, 5),
+            ctime: (100, 5),
+        };
+
+        let tree = Tree::default();
+        let stored = Entry {
+            size: 10,
+            unix_secs: 100,
+            unix_nanos: 5,
+            ..Default::default()
+        };
+        tree.insert_file("stored", stored.clone()).unwrap();
+        tree.insert_file("stale", Entry { size: 9, ..stored })
+            .unwrap();
+
+        let seen = HashMap::from([
+            ("stored".to_string(), stamp),
+            ("stale".to_string(), stamp),
+            ("skipped".to_string(), stamp),
+        ]);
+
+        let cache = FilesCache::from_walk(seen, &tree, "commit".into());
+        assert!(cache.is_unchanged("stored", &stamp));
+        assert!(!cache.is_unchanged("stale", &stamp));
+        assert!(!cache.is_unchanged("skipped", &stamp));
+    }
+
+    #[test]
+    fn damaged_frames_only_drop_their_files() {
+        use super::{FilesCache, Stamp};
+        use std::{collections::HashMap, fs};
+
+        let stamp = Stamp {
+            size: 10,
+            mtime: (100, 5),
+            ctime: (100, 5),
+        };
+        let cache = FilesCache {
+            commit: "commit".into(),
+            files: HashMap::from([("stored".to_string(), stamp)]),
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-files-cache-{}", rand::random::<u64>()));
+        cache.save(&path).unwrap();
+        assert!(FilesCache::load(&path, "commit").is_unchanged("stored", &stamp));
+
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        let damaged = FilesCache::load(&path, "commit");
+        assert_eq!(damaged.commit, "commit");
+        assert!(damaged.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
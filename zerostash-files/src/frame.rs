@@ -0,0 +1,181 @@
+//! Checksummed frames of records, for files that should survive damage
+//!
+//! The index streams of a stash are framed by the storage library. The
+//! records zerostash keeps on its own are written in frames instead,
+//! so a damaged byte only loses the records around it:
+//!
+//! ```text
+//! MAGIC | length | checksum | payload
+//! ```
+//!
+//! The length and checksum cover the payload, which is a batch of
+//! records. A frame that fails its checksum is skipped, and reading
+//! carries on at the next `MAGIC` after it. A frame can be read on its
+//! own from the offset [`write`] returns for it.
+use crate::checksum::checksum;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Write};
+
+const MAGIC: &[u8; 4] = b"0SFR";
+const HEADER_LEN: usize = MAGIC.len() + 4 + 32;
+
+/// Write `records` to `out` in frames of up to `batch` records.
+///
+/// Returns the offset of every frame, relative to where `out` was.
+pub fn write<T: Serialize>(
+    mut out: impl Write,
+    records: impl IntoIterator<Item = T>,
+    batch: usize,
+) -> io::Result<Vec<u64>> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    let mut records = records.into_iter().peekable();
+
+    while records.peek().is_some() {
+        let frame = records.by_ref().take(batch.max(1)).collect::<Vec<_>>();
+        let payload = rmp_serde::to_vec(&frame).map_err(io::Error::other)?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame is too large"))?;
+
+        out.write_all(MAGIC)?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&checksum(&payload))?;
+        out.write_all(&payload)?;
+
+        offsets.push(offset);
+        offset += (HEADER_LEN + payload.len()) as u64;
+    }
+
+    Ok(offsets)
+}
+
+/// Whether `data` starts with a frame
+pub fn is_framed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// The records of every intact frame in `data`, and the number of
+/// damaged frames that were skipped.
+pub fn read<T: DeserializeOwned>(data: &[u8]) -> (Vec<T>, usize) {
+    Frames::new(data).records()
+}
+
+/// The records of the frame at `offset` in `data`
+pub fn read_at<T: DeserializeOwned>(data: &[u8], offset: u64) -> Result<Vec<T>, Damaged> {
+    let start = usize::try_from(offset).map_err(|_| Damaged { offset })?;
+    let (payload, _) = parse(data, start)?;
+    rmp_serde::from_slice(payload).map_err(|_| Damaged { offset })
+}
+
+/// A frame that's truncated, or doesn't match its checksum
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("the frame at offset {offset} is damaged")]
+pub struct Damaged {
+    pub offset: u64,
+}
+
+/// The frames in a buffer, in order
+pub struct Frames<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Frames<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// The records of the next frame, or `None` at the end
+    pub fn next_records<T: DeserializeOwned>(&mut self) -> Option<Result<Vec<T>, Damaged>> {
+        let offset = self.pos as u64;
+        let payload = self.next()?;
+        Some(payload.and_then(|p| rmp_serde::from_slice(p).map_err(|_| Damaged { offset })))
+    }
+
+    /// The records of the remaining intact frames, and the number of
+    /// damaged frames that were skipped.
+    pub fn records<T: DeserializeOwned>(mut self) -> (Vec<T>, usize) {
+        let mut records = vec![];
+        let mut damaged = 0;
+        while let Some(frame) = self.next_records() {
+            match frame {
+                Ok(batch) => records.extend(batch),
+                Err(_) => damaged += 1,
+            }
+        }
+
+        (records, damaged)
+    }
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<&'a [u8], Damaged>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        match parse(self.data, self.pos) {
+            Ok((payload, end)) => {
+                self.pos = end;
+                Some(Ok(payload))
+            }
+            Err(damaged) => {
+                self.pos = resync(self.data, self.pos + 1);
+                Some(Err(damaged))
+            }
+        }
+    }
+}
+
+/// The payload of the frame at `start`, and where the frame ends
+fn parse(data: &[u8], start: usize) -> Result<(&[u8], usize), Damaged> {
+    let damaged = Damaged {
+        offset: start as u64,
+    };
+    let header = data
+        .get(start..start + HEADER_LEN)
+        .filter(|h| h.starts_with(MAGIC))
+        .ok_or(damaged)?;
+
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let end = start + HEADER_LEN + len;
+    let payload = data.get(start + HEADER_LEN..end).ok_or(damaged)?;
+
+    if checksum(payload)[..] != header[8..] {
+        return Err(damaged);
+    }
+    Ok((payload, end))
+}
+
+/// The position of the next frame at or after `from`
+fn resync(data: &[u8], from: usize) -> usize {
+    data.get(from..)
+        .and_then(|rest| rest.windows(MAGIC.len()).position(|w| w == MAGIC))
+        .map_or(data.len(), |n| from + n)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn damaged_frames_are_skipped() {
+        use super::{read, read_at, write};
+
+        let mut out = vec![];
+        let offsets = write(&mut out, 0..10u32, 3).unwrap();
+        assert_eq!(offsets.len(), 4);
+        assert_eq!(read::<u32>(&out), ((0..10).collect(), 0));
+        assert_eq!(read_at::<u32>(&out, offsets[2]).unwrap(), [6, 7, 8]);
+
+        // a flipped bit in the second frame loses only its records
+        let last = out.len() - 1;
+        out[offsets[2] as usize - 1] ^= 1;
+        assert_eq!(read::<u32>(&out), (vec![0, 1, 2, 6, 7, 8, 9], 1));
+
+        // so does a truncated frame at the end
+        out.truncate(last);
+        assert_eq!(read::<u32>(&out), (vec![0, 1, 2, 6, 7, 8], 2));
+        assert!(read_at::<u32>(&out, offsets[1]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Search the contents of files without restoring them
+//!
+//! Files are read one chunk at a time, and split into lines as the
+//! chunks are decrypted, so memory use is bounded by the size of a
+//! chunk and [`MAX_LINE`], whatever the size of the file.
+//!
+//! Gaps in sparse files have no chunks, and are skipped.
+use crate::{
+    crypto_error::{read_chunk, CryptoError},
+    dictionary::Dictionaries,
+    Entry,
+};
+use infinitree::object::Reader;
+use std::ops::ControlFlow;
+
+/// Lines longer than this are cut, and only the start is matched
+pub const MAX_LINE: usize = 64 * 1024;
+
+/// A line of a file, without the newline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Line<'a> {
+    /// Line number, starting at 1
+    pub number: u64,
+    pub text: &'a [u8],
+}
+
+/// Call `f` with every line of `entry`, in order.
+///
+/// Reading stops early when `f` returns [`ControlFlow::Break`].
+pub fn lines(
+    reader: &mut (impl Reader + ?Sized),
+    dictionaries: &Dictionaries,
+    entry: &Entry,
+    mut f: impl FnMut(Line<'_>) -> ControlFlow<()>,
+) -> Result<(), CryptoError> {
+    let mut buf = vec![];
+    let mut partial = vec![];
+    let mut number = 1;
+
+    let mut chunks = entry.chunks.iter().peekable();
+    while let Some((start, pointer)) = chunks.next() {
+        let end = chunks.peek().map_or(entry.size, |(next, _)| **next);
+        buf.resize(end.saturating_sub(*start) as usize, 0);
+        let mut data = read_chunk(reader, dictionaries, pointer, &mut buf)?;
+
+        while let Some(pos) = data.iter().position(|b| *b == b'\n') {
+            let text = if partial.is_empty() {
+                &data[..pos.min(MAX_LINE)]
+            } else {
+                append(&mut partial, &data[..pos]);
+                &partial[..]
+            };
+
+            if f(Line { number, text }).is_break() {
+                return Ok(());
+            }
+
+            partial.clear();
+            number += 1;
+            data = &data[pos + 1..];
+        }
+
+        append(&mut partial, data);
+    }
+
+    if !partial.is_empty() {
+        _ = f(Line {
+            number,
+            text: &partial,
+        });
+    }
+
+    Ok(())
+}
+
+fn append(partial: &mut Vec<u8>, data: &[u8]) {
+    let room = MAX_LINE.saturating_sub(partial.len());
+    partial.extend_from_slice(&data[..data.len().min(room)]);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn lines_across_chunks() {
+        use super::lines;
+        use crate::{Entry, Files};
+        use infinitree::{crypto::UsernamePassword, object::Writer, Infinitree};
+        use std::{ops::ControlFlow, sync::Arc};
+
+        let key =
+            UsernamePassword::with_credentials("grep".to_string(), "password".to_string()).unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+
+        let mut entry = Entry::default();
+        let mut writer = stash.storage_writer().unwrap();
+        for data in [&b"one\ntw"[..], b"o\n", b"three"] {
+            let pointer = writer.write_chunk(&rand::random(), data).unwrap();
+            entry.chunks.insert(entry.size, Arc::new(pointer));
+            entry.size += data.len() as u64;
+        }
+        writer.flush().unwrap();
+
+        let mut found = vec![];
+        let mut reader = stash.storage_reader().unwrap();
+        lines(&mut reader, &stash.index().dictionaries, &entry, |line| {
+            found.push((line.number, line.text.to_vec()));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            found,
+            vec![
+                (1, b"one".to_vec()),
+                (2, b"two".to_vec()),
+                (3, b"three".to_vec())
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! Tell whoever watches a long operation that it's still working
+//!
+//! A [`Heartbeat`] is shared between the operation and a watcher, such
+//! as a service manager's watchdog. Operations beat every time they
+//! take the next file, so a hung operation stops beating, while one
+//! that's idle for a while between files doesn't have to.
+use std::{fmt, sync::Arc};
+
+#[derive(Clone, Default)]
+pub struct Heartbeat(Option<Arc<dyn Fn() + Send + Sync>>);
+
+impl Heartbeat {
+    /// Call `f` on every beat.
+    pub fn new(f: impl Fn() + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(f)))
+    }
+
+    pub fn beat(&self) {
+        if let Some(f) = &self.0 {
+            f()
+        }
+    }
+}
+
+impl fmt::Debug for Heartbeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Heartbeat").field(&self.0.is_some()).finish()
+    }
+}
@@ -0,0 +1,72 @@
+//! Inspect files with an external command before they're stored
+//!
+//! The hook is called with the path of every new or changed file as
+//! its only argument, and the size of the file in
+//! `ZEROSTASH_FILE_SIZE`. Depending on its exit status, the file is:
+//!
+//!  * `0`: stored, with every `key=value` line the hook printed on its
+//!    standard output as an annotation of the entry
+//!  * `1`: skipped, eg. because a virus scanner found something
+//!
+//! Any other exit status, or failing to run the hook, also skips the
+//! file, so a broken scanner doesn't let files through unchecked. A
+//! skipped file keeps its previous version in the index, if any.
+use crate::Entry;
+use std::{collections::BTreeMap, path::Path, process::Command};
+use tracing::{trace, warn};
+
+/// What to do with a file after the hook inspected it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Store(BTreeMap<String, String>),
+    Skip,
+}
+
+/// Run `hook` on the file at `path`.
+pub fn inspect(hook: &Path, path: &Path, entry: &Entry) -> Verdict {
+    let output = match Command::new(hook)
+        .arg(path)
+        .env("ZEROSTASH_FILE_SIZE", entry.size.to_string())
+        .output()
+    {
+        Ok(output) => output,
+        Err(error) => {
+            warn!(%error, ?hook, "failed to run file hook");
+            return Verdict::Skip;
+        }
+    };
+
+    match output.status.code() {
+        Some(0) => Verdict::Store(parse_annotations(&String::from_utf8_lossy(&output.stdout))),
+        Some(1) => {
+            trace!(?path, "file hook skipped file");
+            Verdict::Skip
+        }
+        _ => {
+            warn!(status = %output.status, ?path, "file hook failed; skipping");
+            Verdict::Skip
+        }
+    }
+}
+
+fn parse_annotations(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn annotations_from_output() {
+        use super::parse_annotations;
+
+        let annotations = parse_annotations("scanned=clean\nengine = clamav 1.2\nnoise\n=x\n");
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations["scanned"], "clean");
+        assert_eq!(annotations["engine"], "clamav 1.2");
+    }
+}
@@ -0,0 +1,125 @@
+//! Translate user and group ids during restore
+//!
+//! Stashes store numeric ids, which rarely line up when restoring to
+//! a different host. A mapping file has one `SOURCE TARGET` pair per
+//! line, where `SOURCE` is the numeric id in the stash, and `TARGET`
+//! is either a numeric id or a user/group name on the local host.
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! Ids that are not in the mapping fall back to the invoking user.
+use std::{collections::HashMap, io};
+
+#[derive(thiserror::Error, Debug)]
+pub enum IdMapError {
+    #[error("IO error: {source}")]
+    IO {
+        #[from]
+        source: io::Error,
+    },
+    #[error("line {line}: expected `SOURCE TARGET`")]
+    Syntax { line: usize },
+    #[error("line {line}: invalid id `{id}`")]
+    InvalidId { line: usize, id: String },
+    #[error("line {line}: no such user or group `{name}`")]
+    UnknownName { line: usize, name: String },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IdMap {
+    ids: HashMap<u32, u32>,
+    fallback: u32,
+}
+
+impl IdMap {
+    /// Parse a mapping, resolving target names with `resolve`.
+    pub fn parse(
+        input: &str,
+        resolve: impl Fn(&str) -> Option<u32>,
+        fallback: u32,
+    ) -> Result<Self, IdMapError> {
+        let mut ids = HashMap::new();
+
+        for (i, line) in input.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (Some(source), Some(target), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(IdMapError::Syntax { line: line_no });
+            };
+
+            let source = source.parse().map_err(|_| IdMapError::InvalidId {
+                line: line_no,
+                id: source.to_string(),
+            })?;
+
+            let target = match target.parse() {
+                Ok(id) => id,
+                Err(_) => resolve(target).ok_or_else(|| IdMapError::UnknownName {
+                    line: line_no,
+                    name: target.to_string(),
+                })?,
+            };
+
+            ids.insert(source, target);
+        }
+
+        Ok(Self { ids, fallback })
+    }
+
+    /// Read a uid mapping file, resolving user names on this host.
+    #[cfg(unix)]
+    pub fn uid_map_file(path: impl AsRef<std::path::Path>) -> Result<Self, IdMapError> {
+        use nix::unistd::{getuid, User};
+
+        Self::parse(
+            &std::fs::read_to_string(path)?,
+            |name| Some(User::from_name(name).ok()??.uid.as_raw()),
+            getuid().as_raw(),
+        )
+    }
+
+    /// Read a gid mapping file, resolving group names on this host.
+    #[cfg(unix)]
+    pub fn gid_map_file(path: impl AsRef<std::path::Path>) -> Result<Self, IdMapError> {
+        use nix::unistd::{getgid, Group};
+
+        Self::parse(
+            &std::fs::read_to_string(path)?,
+            |name| Some(Group::from_name(name).ok()??.gid.as_raw()),
+            getgid().as_raw(),
+        )
+    }
+
+    pub fn map(&self, id: u32) -> u32 {
+        self.ids.get(&id).copied().unwrap_or(self.fallback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdMap, IdMapError};
+
+    #[test]
+    fn parse_mapping() {
+        let resolve = |name: &str| (name == "alice").then_some(1001);
+        let map = IdMap::parse("# comment\n1000 2000\n\n1002 alice\n", resolve, 42).unwrap();
+
+        assert_eq!(map.map(1000), 2000);
+        assert_eq!(map.map(1002), 1001);
+        assert_eq!(map.map(0), 42);
+
+        assert!(matches!(
+            IdMap::parse("1000 bob", resolve, 0),
+            Err(IdMapError::UnknownName { line: 1, .. })
+        ));
+        assert!(matches!(
+            IdMap::parse("1000", resolve, 0),
+            Err(IdMapError::Syntax { line: 1 })
+        ));
+    }
+}
@@ -0,0 +1,29 @@
+//! Stop long operations early, without leaving anything half done
+//!
+//! An [`Interrupt`] is shared between a signal handler and the
+//! operation. Operations only check it before taking the next file, so
+//! files that are in progress are always finished, and their objects
+//! are sealed as usual.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    /// Ask the operation to stop.
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The operation stopped early because it was interrupted
+#[derive(Debug, thiserror::Error)]
+#[error("interrupted")]
+pub struct Interrupted;
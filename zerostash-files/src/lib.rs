@@ -1,21 +1,89 @@
-use infinitree::{fields, ChunkPointer, Digest};
+use infinitree::fields;
+pub mod browse;
+#[cfg(feature = "std-runtime")]
+pub mod bundle;
+#[cfg(feature = "std-runtime")]
+pub mod cache;
+pub mod checksum;
+pub mod chunk_exists;
+pub mod chunk_index;
+pub mod content_type;
+pub mod cpu;
+pub mod crypto_error;
+pub mod dictionary;
+pub mod disk_space;
+pub mod custom_fields;
 pub mod tree;
 pub use tree::*;
 mod files;
 pub use files::*;
+pub mod files_cache;
+pub mod frame;
+#[cfg(feature = "std-runtime")]
+pub mod grep;
+pub mod heartbeat;
+pub mod hook;
+pub mod id_map;
+pub mod interrupt;
+pub mod list;
+pub mod macos;
+pub mod migrate;
+pub mod mirror;
 mod zfs_snapshots;
 pub use zfs_snapshots::*;
+pub mod pool;
+#[cfg(feature = "std-runtime")]
+pub mod prefetch;
+pub mod probe;
 pub mod rollsum;
+pub mod roots;
+pub mod route;
+#[cfg(feature = "std-runtime")]
+pub mod scrub;
+pub mod snapshot;
 pub mod splitter;
+#[cfg(feature = "std-runtime")]
+pub mod spool;
 mod stash;
+#[cfg(feature = "std-runtime")]
+pub mod upload;
+#[cfg(feature = "std-runtime")]
+pub mod unmapped;
+pub mod userns;
+#[cfg(feature = "std-runtime")]
+pub mod volumes;
+#[cfg(feature = "std-runtime")]
+pub mod write_balancer;
 
+pub use stash::commit_annotations;
+#[cfg(feature = "std-runtime")]
+pub use stash::compact;
+#[cfg(feature = "std-runtime")]
+pub use stash::copy;
+pub use stash::diff;
+pub use stash::digest_key;
 pub use stash::list_snapshots::ZfsSnapshotList;
+pub use stash::named_snapshot;
+pub use stash::object_size;
+pub use stash::pin;
+pub use stash::prune;
+#[cfg(feature = "std-runtime")]
 pub use stash::restore;
+pub use stash::rollback;
+pub use stash::salvage;
+#[cfg(feature = "std-runtime")]
+pub use stash::skip;
+#[cfg(feature = "std-runtime")]
 pub use stash::store;
+pub use stash::timings;
+
+use chunk_index::ChunkIndex;
+use custom_fields::CustomFields;
 
-type ChunkIndex = fields::VersionedMap<Digest, ChunkPointer>;
 type FileIndex = fields::VersionedMap<String, Entry>;
 type ZfsIndex = fields::VersionedMap<String, ZfsSnapshot>;
+type TombstoneIndex = fields::VersionedMap<infinitree::object::ObjectId, prune::Tombstone>;
+type PinIndex = fields::VersionedMap<String, pin::Pin>;
 
 #[derive(Clone, Default, infinitree::Index)]
 pub struct Files {
@@ -23,4 +91,9 @@ pub struct Files {
     pub files: FileIndex,
     pub zfs_snapshots: ZfsIndex,
     pub tree: Tree,
+    pub tombstones: TombstoneIndex,
+    pub pins: PinIndex,
+    pub custom_fields: CustomFields,
+    #[infinitree(skip)]
+    pub dictionaries: dictionary::Dictionaries,
 }
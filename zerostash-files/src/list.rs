@@ -0,0 +1,247 @@
+//! Enumerate the objects stored in a backend
+//!
+//! The `Backend` trait can only access objects by id, so backends that
+//! can list their objects implement [`Storage`] on top of it. Backends
+//! that wrap others list the objects of what they wrap, so an object
+//! that's stored in more than one place is listed once for each copy.
+use crate::chunk_index::digest_from_hex;
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Lazily listed object ids
+pub type Objects = Box<dyn Iterator<Item = io::Result<ObjectId>> + Send>;
+
+/// A backend that can enumerate the objects it stores
+pub trait Storage: Backend {
+    fn list(&self) -> io::Result<Objects>;
+
+    /// Use the storage where a `Backend` is needed.
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend>;
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing objects is not supported by this backend",
+    )
+}
+
+/// Iterate over the objects in a local directory backend
+///
+/// Every object is stored in a file named after its hex id, anything
+/// else in the directory is skipped. Entries are read lazily, so
+/// listing a large directory doesn't need to hold all ids in memory.
+pub struct Directory {
+    entries: fs::ReadDir,
+}
+
+impl Directory {
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            entries: fs::read_dir(path)?,
+        })
+    }
+}
+
+impl Iterator for Directory {
+    type Item = io::Result<ObjectId>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match entry.file_type() {
+                Ok(t) if t.is_file() => {}
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+
+            let name = entry.file_name();
+            if let Some(digest) = name.to_str().and_then(|n| digest_from_hex(n).ok()) {
+                return Some(Ok(ObjectId::from_bytes(digest)));
+            }
+        }
+    }
+}
+
+/// The objects in the local directory backend at `path`
+#[cfg(feature = "std-runtime")]
+pub struct Local {
+    path: PathBuf,
+    inner: Arc<infinitree::backends::Directory>,
+}
+
+#[cfg(feature = "std-runtime")]
+impl Local {
+    pub fn new(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Arc::new(Self {
+            inner: infinitree::backends::Directory::new(&path)?,
+            path,
+        }))
+    }
+}
+
+#[cfg(feature = "std-runtime")]
+impl Backend for Local {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.inner.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(feature = "std-runtime")]
+impl Storage for Local {
+    fn list(&self) -> io::Result<Objects> {
+        Ok(Box::new(Directory::new(&self.path)?))
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self.inner.clone()
+    }
+}
+
+/// A backend that can't list its objects, such as S3
+pub struct Unlisted(Arc<dyn Backend>);
+
+impl Unlisted {
+    pub fn new(backend: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self(backend))
+    }
+}
+
+impl Backend for Unlisted {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.0.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.0.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.0.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.0.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.0.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.0.sync()
+    }
+}
+
+impl Storage for Unlisted {
+    fn list(&self) -> io::Result<Objects> {
+        Err(unsupported())
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+impl Storage for infinitree::backends::test::InMemoryBackend {
+    fn list(&self) -> io::Result<Objects> {
+        Err(unsupported())
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn directory_lists_only_objects() {
+        use super::Directory;
+        use crate::chunk_index::digest_to_hex;
+        use infinitree::object::ObjectId;
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("zerostash-list-{}", rand::random::<u64>()));
+        fs::create_dir(&path).unwrap();
+
+        let digest: infinitree::Digest = rand::random();
+        fs::write(path.join(digest_to_hex(&digest)), b"object").unwrap();
+        fs::write(path.join("placement.log"), b"not an object").unwrap();
+        fs::create_dir(path.join(digest_to_hex(&rand::random()))).unwrap();
+
+        let objects = Directory::new(&path)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(objects, vec![ObjectId::from_bytes(digest)]);
+    }
+
+    #[cfg(feature = "std-runtime")]
+    #[test]
+    fn wrapped_backends_list_what_they_wrap() {
+        use super::{Local, Storage, Unlisted};
+        use crate::{migrate::object_from_bytes, mirror::Mirror};
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("zerostash-list-{}", rand::random::<u64>()));
+        fs::create_dir(&path).unwrap();
+
+        let id = ObjectId::from_bytes(rand::random());
+        let local = Local::new(&path).unwrap();
+        local
+            .write_object(&object_from_bytes(id, b"object"))
+            .unwrap();
+
+        let objects = Mirror::new(local.clone(), local.clone())
+            .list()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(objects, vec![id, id]);
+
+        let mirror = Mirror::new(local, Unlisted::new(InMemoryBackend::shared()));
+        assert!(mirror.list().is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
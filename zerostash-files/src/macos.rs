@@ -0,0 +1,234 @@
+//! Metadata of macOS files that has no place in a portable [`Entry`]
+//!
+//! Extended attributes in the `com.apple.` namespace hold resource
+//! forks, Finder info, quarantine marks and the like. Together with the
+//! creation date and the user flags of a file, they're stored in an
+//! extension of the entry, so the index stays readable everywhere, but
+//! they're only read from and restored to files on macOS.
+//!
+//! [`Entry`]: crate::Entry
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Name of the entry extension
+pub(crate) const EXTENSION: &str = "macos";
+
+/// Only extended attributes in this namespace are stored
+pub const XATTR_PREFIX: &str = "com.apple.";
+
+/// Attributes that are managed by the system, and can't be set back
+const SYSTEM_XATTRS: &[&str] = &[
+    "com.apple.system.",
+    "com.apple.decmpfs",
+    "com.apple.rootless",
+];
+
+/// User flags that are safe to set before the contents are written
+const RESTORED_FLAGS: u32 = 0x0000_0001 /* UF_NODUMP */ | 0x0000_8000 /* UF_HIDDEN */;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// `com.apple.*` extended attributes, eg. resource forks and Finder info
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Creation date, as seconds and nanoseconds since the epoch
+    pub created: Option<(i64, u32)>,
+    /// BSD flags of the file, eg. `UF_HIDDEN`
+    pub flags: u32,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.xattrs.is_empty() && self.created.is_none() && self.flags == 0
+    }
+}
+
+/// Whether the extended attribute `name` is stored
+pub fn is_stored_xattr(name: &str) -> bool {
+    name.starts_with(XATTR_PREFIX) && !SYSTEM_XATTRS.iter().any(|s| name.starts_with(s))
+}
+
+#[cfg(target_os = "macos")]
+pub use sys::{read, restore};
+
+#[cfg(target_os = "macos")]
+mod sys {
+    use super::{is_stored_xattr, Metadata, RESTORED_FLAGS};
+    use nix::libc;
+    use std::{
+        ffi::{CStr, CString},
+        fs, io,
+        os::{macos::fs::MetadataExt, unix::ffi::OsStrExt},
+        path::Path,
+        ptr,
+        time::UNIX_EPOCH,
+    };
+
+    /// Read the macOS metadata of the file at `path`, without following
+    /// symlinks.
+    pub fn read(path: &Path, metadata: &fs::Metadata) -> io::Result<Metadata> {
+        let created = metadata
+            .created()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| (since.as_secs() as i64, since.subsec_nanos()));
+
+        let path = c_path(path)?;
+        let mut xattrs = std::collections::BTreeMap::new();
+        for name in list_xattrs(&path)? {
+            let name_str = name.to_string_lossy();
+            if !is_stored_xattr(&name_str) {
+                continue;
+            }
+
+            match get_xattr(&path, &name) {
+                Ok(value) => {
+                    xattrs.insert(name_str.into_owned(), value);
+                }
+                // removed since it was listed
+                Err(e) if e.raw_os_error() == Some(libc::ENOATTR) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Metadata {
+            xattrs,
+            created,
+            flags: metadata.st_flags(),
+        })
+    }
+
+    /// Set the stored macOS metadata on the file at `path`, without
+    /// following symlinks.
+    pub fn restore(path: &Path, metadata: &Metadata) -> io::Result<()> {
+        let is_symlink = fs::symlink_metadata(path)?.is_symlink();
+        let path = c_path(path)?;
+
+        for (name, value) in metadata.xattrs.iter() {
+            let name = CString::new(name.as_str())?;
+            check(unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                    libc::XATTR_NOFOLLOW,
+                )
+            })?;
+        }
+
+        if let Some((secs, nanos)) = metadata.created {
+            let mut attrs: libc::attrlist = unsafe { std::mem::zeroed() };
+            attrs.bitmapcount = libc::ATTR_BIT_MAP_COUNT;
+            attrs.commonattr = libc::ATTR_CMN_CRTIME;
+            let mut created = libc::timespec {
+                tv_sec: secs,
+                tv_nsec: nanos as libc::c_long,
+            };
+
+            check(unsafe {
+                libc::setattrlist(
+                    path.as_ptr(),
+                    ptr::addr_of_mut!(attrs).cast(),
+                    ptr::addr_of_mut!(created).cast(),
+                    std::mem::size_of::<libc::timespec>(),
+                    libc::FSOPT_NOFOLLOW,
+                )
+            })?;
+        }
+
+        // flags of a symlink would be set on its target
+        let flags = metadata.flags & RESTORED_FLAGS;
+        if flags != 0 && !is_symlink {
+            check(unsafe { libc::chflags(path.as_ptr(), flags as libc::c_uint) })?;
+        }
+
+        Ok(())
+    }
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    fn list_xattrs(path: &CStr) -> io::Result<Vec<CString>> {
+        let size = check_size(unsafe {
+            libc::listxattr(path.as_ptr(), ptr::null_mut(), 0, libc::XATTR_NOFOLLOW)
+        })?;
+        if size == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![0u8; size];
+        let size = check_size(unsafe {
+            libc::listxattr(
+                path.as_ptr(),
+                names.as_mut_ptr().cast(),
+                names.len(),
+                libc::XATTR_NOFOLLOW,
+            )
+        })?;
+        names.truncate(size);
+
+        Ok(names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| CString::new(name).ok())
+            .collect())
+    }
+
+    fn get_xattr(path: &CStr, name: &CStr) -> io::Result<Vec<u8>> {
+        let size = check_size(unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                ptr::null_mut(),
+                0,
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        })?;
+
+        let mut value = vec![0u8; size];
+        let size = check_size(unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+                0,
+                libc::XATTR_NOFOLLOW,
+            )
+        })?;
+        value.truncate(size);
+
+        Ok(value)
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_size(result: libc::ssize_t) -> io::Result<usize> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            size => Ok(size as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn system_xattrs_are_not_stored() {
+        use super::is_stored_xattr;
+
+        assert!(is_stored_xattr("com.apple.ResourceFork"));
+        assert!(is_stored_xattr("com.apple.FinderInfo"));
+        assert!(!is_stored_xattr("com.apple.decmpfs"));
+        assert!(!is_stored_xattr("com.apple.system.Security"));
+        assert!(!is_stored_xattr("user.comment"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Copy the objects of a stash to a different backend
+//!
+//! Objects are copied as they are stored, without decrypting them, so
+//! no key is needed. Every object is read back from the destination
+//! after the copy, and compared to the original.
+use crate::checksum::checksum;
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+    Digest,
+};
+use std::{collections::HashMap, thread};
+use tracing::debug;
+
+/// Result of copying objects between backends
+#[derive(Default, Debug)]
+pub struct Report {
+    pub copied: usize,
+    pub failed: Vec<(ObjectId, String)>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.copied += other.copied;
+        self.failed.extend(other.failed);
+    }
+}
+
+/// Copy `objects` from `src` to `dst` on `threads` threads, and check
+/// that the destination returns the same contents.
+pub fn copy_objects(
+    src: &dyn Backend,
+    dst: &dyn Backend,
+    objects: &[ObjectId],
+    threads: usize,
+) -> Result<Report> {
+    let batch_size = objects.len().div_ceil(threads.max(1)).max(1);
+
+    let (mut report, written) = thread::scope(|s| {
+        let workers = objects
+            .chunks(batch_size)
+            .map(|batch| s.spawn(move || copy_batch(src, dst, batch)))
+            .collect::<Vec<_>>();
+
+        let mut report = Report::default();
+        let mut written = HashMap::new();
+        for worker in workers {
+            let (r, w) = worker.join().unwrap();
+            report.merge(r);
+            written.extend(w);
+        }
+
+        (report, written)
+    });
+
+    dst.sync()?;
+
+    for (id, expected) in written {
+        let result = match dst.read_object(&id) {
+            Ok(object) if checksum(object.as_inner()) == expected => {
+                report.copied += 1;
+                continue;
+            }
+            Ok(_) => "contents differ after copy".to_string(),
+            Err(error) => error.to_string(),
+        };
+
+        report.failed.push((id, result));
+    }
+
+    Ok(report)
+}
+
+fn copy_batch(
+    src: &dyn Backend,
+    dst: &dyn Backend,
+    objects: &[ObjectId],
+) -> (Report, HashMap<ObjectId, Digest>) {
+    let mut report = Report::default();
+    let mut written = HashMap::new();
+
+    for id in objects {
+        let result = src.read_object(id).and_then(|object| {
+            dst.write_object(&to_write_object(&object))?;
+            Ok(checksum(object.as_inner()))
+        });
+
+        match result {
+            Ok(sum) => {
+                debug!(%id, "copied object");
+                written.insert(*id, sum);
+            }
+            Err(error) => report.failed.push((*id, error.to_string())),
+        }
+    }
+
+    (report, written)
+}
+
+pub(crate) fn to_write_object(object: &ReadObject) -> WriteObject {
+    object_from_bytes(*object.id(), object.as_inner())
+}
+
+/// Create an object with the stored contents `data`.
+pub(crate) fn object_from_bytes(id: ObjectId, data: &[u8]) -> WriteObject {
+    let mut object = WriteObject::default();
+
+    object.as_inner_mut()[..data.len()].copy_from_slice(data);
+    object.set_id(id);
+    object
+}
@@ -0,0 +1,67 @@
+//! Keep reading from the old backend while migrating to a new one
+//!
+//! [`Mirror`] writes to the primary backend only, and falls back to
+//! reading from a read-only secondary backend when an object can't be
+//! read from the primary. This allows using a stash right after its
+//! objects were copied to a new backend, while the old copy is still
+//! around as a safety net.
+use crate::list::{Objects, Storage};
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{io, sync::Arc};
+use tracing::warn;
+
+pub struct Mirror {
+    primary: Arc<dyn Storage>,
+    fallback: Arc<dyn Storage>,
+}
+
+impl Mirror {
+    pub fn new(primary: Arc<dyn Storage>, fallback: Arc<dyn Storage>) -> Arc<Self> {
+        Arc::new(Self { primary, fallback })
+    }
+}
+
+impl Backend for Mirror {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.primary.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.primary.read_object(id).or_else(|error| {
+            warn!(%id, %error, "object missing from primary backend, reading the mirror");
+            self.fallback.read_object(id)
+        })
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.primary.preload(objects)
+    }
+
+    /// The fallback is read-only, objects are only deleted from the
+    /// primary backend.
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.primary.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.primary.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.primary.sync()
+    }
+}
+
+impl Storage for Mirror {
+    /// Objects that were already copied are listed twice.
+    fn list(&self) -> io::Result<Objects> {
+        Ok(Box::new(self.primary.list()?.chain(self.fallback.list()?)))
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
@@ -0,0 +1,206 @@
+//! Share chunks between stashes through a common data pool
+//!
+//! Stashes that are members of the same pool store their data in a
+//! shared backend with the same data key, for example the same
+//! `split_key` key pair with separate credentials for every member.
+//! Each member still has its own index.
+//!
+//! After a commit, a member publishes its chunk index to the pool's
+//! catalog, and before the next commit it imports the chunks published
+//! by every other member. Content that's already in the pool is
+//! therefore never uploaded twice.
+//!
+//! The catalog is a directory with one file of chunk records per
+//! member. An object in the pool is referenced by a member if any of
+//! its published chunks point into it. Since members publish every
+//! chunk they know about, including the ones imported from others,
+//! this errs on the side of keeping objects. An object may only be
+//! deleted from the pool once no member references it, see
+//! [`Pool::unreferenced`].
+use crate::{chunk_index::ChunkRecord, Files};
+use infinitree::object::ObjectId;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+const EXTENSION: &str = "chunks";
+
+pub struct Pool {
+    catalog: PathBuf,
+}
+
+impl Pool {
+    /// Open the pool catalog at `catalog`, creating it if needed.
+    pub fn open(catalog: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(catalog.as_ref())?;
+        Ok(Self {
+            catalog: catalog.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Open the pool catalog at `catalog` without creating it. A
+    /// catalog that doesn't exist has no members.
+    pub fn open_read_only(catalog: impl AsRef<Path>) -> Self {
+        Self {
+            catalog: catalog.as_ref().to_path_buf(),
+        }
+    }
+
+    fn member_file(&self, member: &str) -> PathBuf {
+        self.catalog.join(format!("{member}.{EXTENSION}"))
+    }
+
+    /// Names of all members that have published chunks, sorted
+    pub fn members(&self) -> io::Result<Vec<String>> {
+        let mut members = vec![];
+
+        let entries = match fs::read_dir(&self.catalog) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(members),
+            entries => entries?,
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(EXTENSION)) {
+                continue;
+            }
+
+            if let Some(name) = path.file_stem().and_then(OsStr::to_str) {
+                members.push(name.to_string());
+            }
+        }
+
+        members.sort();
+        Ok(members)
+    }
+
+    fn read_member(&self, member: &str, mut f: impl FnMut(ChunkRecord)) -> io::Result<()> {
+        let file = fs::File::open(self.member_file(member))?;
+
+        for line in BufReader::new(file).lines() {
+            match serde_json::from_str(&line?) {
+                Ok(record) => f(record),
+                Err(error) => warn!(%error, member, "invalid entry in pool catalog"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the published chunks of `member` with the chunks
+    /// currently loaded in `index`. Returns the number of chunks.
+    pub fn publish(&self, member: &str, index: &Files) -> io::Result<usize> {
+        let path = self.member_file(member);
+        let tmp = path.with_extension("tmp");
+        let mut output = BufWriter::new(fs::File::create(&tmp)?);
+
+        let mut published = 0;
+        let mut result = Ok(());
+        index.export_chunks(|record| {
+            if result.is_ok() {
+                result = serde_json::to_writer(&mut output, &record)
+                    .map_err(io::Error::from)
+                    .and_then(|_| writeln!(output));
+                published += 1;
+            }
+        });
+        result?;
+
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        // other members may be reading the catalog, so never leave a
+        // partially written file in place
+        fs::rename(tmp, path)?;
+
+        Ok(published)
+    }
+
+    /// Import the chunks published by all other members into `index`.
+    /// Returns the number of chunks that were not known before.
+    pub fn seed(&self, member: &str, index: &Files) -> io::Result<usize> {
+        let mut imported = 0;
+
+        for other in self.members()?.iter().filter(|m| *m != member) {
+            self.read_member(other, |record| {
+                if index.import_chunk(record) {
+                    imported += 1;
+                }
+            })?;
+        }
+
+        Ok(imported)
+    }
+
+    /// Number of members that reference each object in the pool
+    pub fn references(&self) -> io::Result<HashMap<ObjectId, usize>> {
+        let mut references = HashMap::new();
+
+        for member in self.members()? {
+            let mut objects = HashSet::new();
+            self.read_member(&member, |record| {
+                objects.insert(*record.pointer.object_id());
+            })?;
+
+            for id in objects {
+                *references.entry(id).or_default() += 1;
+            }
+        }
+
+        Ok(references)
+    }
+
+    /// Keep only the `objects` that no member of the pool references.
+    ///
+    /// Anything that removes objects from a pooled backend must only
+    /// delete these, and only after publishing its own chunk index.
+    pub fn unreferenced(
+        &self,
+        objects: impl IntoIterator<Item = ObjectId>,
+    ) -> io::Result<Vec<ObjectId>> {
+        let references = self.references()?;
+
+        Ok(objects
+            .into_iter()
+            .filter(|id| !references.contains_key(id))
+            .collect())
+    }
+
+    /// Keep only the `objects` that no member of the pool would
+    /// reference after `member` published `index`.
+    ///
+    /// Unlike publishing first, this doesn't change the catalog.
+    pub fn unreferenced_after_publish(
+        &self,
+        member: &str,
+        index: &Files,
+        objects: impl IntoIterator<Item = ObjectId>,
+    ) -> io::Result<Vec<ObjectId>> {
+        let mut references = HashSet::new();
+        for other in self.members()?.iter().filter(|m| *m != member) {
+            self.read_member(other, |record| {
+                references.insert(*record.pointer.object_id());
+            })?;
+        }
+        index.export_chunks(|record| {
+            references.insert(*record.pointer.object_id());
+        });
+
+        Ok(objects
+            .into_iter()
+            .filter(|id| !references.contains(id))
+            .collect())
+    }
+
+    /// Remove `member` from the pool. Objects that only this member
+    /// referenced become unreferenced.
+    pub fn leave(&self, member: &str) -> io::Result<()> {
+        fs::remove_file(self.member_file(member))
+    }
+}
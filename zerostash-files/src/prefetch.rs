@@ -0,0 +1,152 @@
+//! Background prefetching for remote backends
+//!
+//! [`Prefetch`] wraps a backend, and implements
+//! [`Backend::preload`] by fetching the requested objects on a set of
+//! background threads. Prefetched objects are handed out on the next
+//! `read_object` call, so when callers announce the objects they're
+//! about to read, the latency of the remote storage is hidden.
+use crate::list::{Objects, Storage};
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+    thread,
+};
+use tracing::debug;
+
+#[derive(Default)]
+struct Prefetched {
+    objects: HashMap<ObjectId, Arc<ReadObject>>,
+    order: VecDeque<ObjectId>,
+    pending: HashSet<ObjectId>,
+}
+
+struct Shared {
+    upstream: Arc<dyn Storage>,
+    capacity: usize,
+    state: Mutex<Prefetched>,
+}
+
+pub struct Prefetch {
+    shared: Arc<Shared>,
+    queue: flume::Sender<ObjectId>,
+}
+
+impl Prefetch {
+    /// Wrap `upstream`, fetching objects on `threads` background
+    /// threads, and keeping at most `capacity` prefetched objects in
+    /// memory.
+    pub fn new(upstream: Arc<dyn Storage>, threads: usize, capacity: usize) -> Arc<Self> {
+        let (queue, receiver) = flume::unbounded::<ObjectId>();
+        let shared = Arc::new(Shared {
+            upstream,
+            capacity,
+            state: Default::default(),
+        });
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            let shared = Arc::clone(&shared);
+
+            thread::spawn(move || {
+                while let Ok(id) = receiver.recv() {
+                    shared.fetch(id);
+                }
+            });
+        }
+
+        Arc::new(Self { shared, queue })
+    }
+}
+
+impl Shared {
+    fn fetch(&self, id: ObjectId) {
+        let object = match self.upstream.read_object(&id) {
+            Ok(object) => object,
+            Err(error) => {
+                debug!(%error, ?id, "prefetch failed");
+                self.state.lock().unwrap().pending.remove(&id);
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !state.pending.remove(&id) {
+            // the object was read in the meantime
+            return;
+        }
+
+        while state.order.len() >= self.capacity {
+            let Some(evicted) = state.order.pop_front() else {
+                break;
+            };
+            state.objects.remove(&evicted);
+        }
+
+        state.order.push_back(id);
+        state.objects.insert(id, object);
+    }
+}
+
+impl Backend for Prefetch {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.shared.upstream.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.pending.remove(id);
+
+            if let Some(object) = state.objects.remove(id) {
+                state.order.retain(|o| o != id);
+                return Ok(object);
+            }
+        }
+
+        self.shared.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        // `read_object` on a caching upstream also populates its
+        // cache, so there's no need to forward the hint
+        let mut state = self.shared.state.lock().unwrap();
+
+        for id in objects {
+            if state.objects.contains_key(id) || !state.pending.insert(*id) {
+                continue;
+            }
+
+            // workers only exit when `self` is dropped
+            _ = self.queue.send(*id);
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.shared.upstream.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.shared.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.shared.upstream.sync()
+    }
+}
+
+impl Storage for Prefetch {
+    fn list(&self) -> io::Result<Objects> {
+        self.shared.upstream.list()
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
@@ -0,0 +1,119 @@
+//! Check that a backend is usable before relying on it
+//!
+//! A probe writes a random object, reads it back, then deletes it, and
+//! times each step. This exercises the credentials and permissions a
+//! commit will need, so a misconfigured backend fails in seconds
+//! instead of after the first objects are uploaded.
+use crate::migrate::object_from_bytes;
+use infinitree::{
+    backends::{Backend, BackendError},
+    object::ObjectId,
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+const PROBE_LEN: usize = 4096;
+
+/// The step of the probe that failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Write,
+    Read,
+    Delete,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Write => "write",
+            Self::Read => "read",
+            Self::Delete => "delete",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("failed to {step} probe object {id}: {source}")]
+    Backend {
+        step: Step,
+        id: ObjectId,
+        #[source]
+        source: BackendError,
+    },
+    #[error("probe object {0} was read back with different contents")]
+    Mismatch(ObjectId),
+}
+
+impl ProbeError {
+    pub fn step(&self) -> Step {
+        match self {
+            Self::Backend { step, .. } => *step,
+            Self::Mismatch(_) => Step::Read,
+        }
+    }
+}
+
+/// Time taken by each step of a successful probe
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub write: Duration,
+    pub read: Duration,
+    pub delete: Duration,
+}
+
+/// Write, read back and delete a random object in `backend`.
+///
+/// The write is synced before reading, so backends that upload in the
+/// background are timed for the full round trip.
+pub fn probe(backend: &dyn Backend) -> Result<Latency, ProbeError> {
+    let id = ObjectId::from_bytes(rand::random());
+    let data: Vec<u8> = (0..PROBE_LEN).map(|_| rand::random()).collect();
+    let fail = |step| move |source| ProbeError::Backend { step, id, source };
+
+    let start = Instant::now();
+    backend
+        .write_object(&object_from_bytes(id, &data))
+        .and_then(|_| backend.sync())
+        .map_err(fail(Step::Write))?;
+    let write = start.elapsed();
+
+    let start = Instant::now();
+    let object = backend.read_object(&id).map_err(fail(Step::Read))?;
+    let read = start.elapsed();
+
+    let matches = object.as_inner().get(..PROBE_LEN) == Some(&data[..]);
+
+    // clean up even if the contents were wrong
+    let start = Instant::now();
+    backend
+        .delete(&[id])
+        .and_then(|_| backend.sync())
+        .map_err(fail(Step::Delete))?;
+    let delete = start.elapsed();
+
+    if !matches {
+        return Err(ProbeError::Mismatch(id));
+    }
+
+    Ok(Latency {
+        write,
+        read,
+        delete,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn probe_in_memory() {
+        use super::probe;
+        use infinitree::backends::test::InMemoryBackend;
+
+        let backend = InMemoryBackend::shared();
+        probe(backend.as_ref()).unwrap();
+    }
+}
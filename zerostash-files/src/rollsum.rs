@@ -21,8 +21,26 @@ impl Rollsum for SeaSplit {
         Self::default()
     }
 
-    #[inline(always)]
     fn find_offset(&mut self, buf: &[u8]) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        if crate::cpu::features().avx2 {
+            // SAFETY: AVX2 support was detected at runtime
+            return unsafe { self.find_offset_avx2(buf) };
+        }
+
+        self.find_offset_generic(buf)
+    }
+}
+
+impl SeaSplit {
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_offset_avx2(&mut self, buf: &[u8]) -> usize {
+        self.find_offset_generic(buf)
+    }
+
+    #[inline(always)]
+    fn find_offset_generic(&mut self, buf: &[u8]) -> usize {
         self.0 = SeaHasher::default();
         let mut last = 0;
 
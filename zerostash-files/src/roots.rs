@@ -0,0 +1,286 @@
+//! Where the files of a commit came from
+//!
+//! Paths are stored without their root, drive or leading `..`, so
+//! `/home/user/docs` and `docs` in `/home/user` are stored as
+//! `home/user/docs` and `docs`, unless a [`Source`] gives them a
+//! prefix of their own. A [`RootSpec`] records how a committed path
+//! was given, so a restore can put the files back where they came
+//! from, or somewhere else entirely, instead of under the stored path.
+use crate::files::{normalize_filename, EntryError};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootKind {
+    Absolute,
+    /// Relative to the working directory of the commit
+    Relative,
+    /// Absolute, on a Windows drive
+    Drive,
+}
+
+impl fmt::Display for RootKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Absolute => "absolute",
+            Self::Relative => "relative",
+            Self::Drive => "drive",
+        })
+    }
+}
+
+/// A path to commit, and where to store it in the stash
+///
+/// Given as `SOURCE:PREFIX`, eg. `/var/www:web` stores
+/// `/var/www/index.html` as `web/index.html`. Colons and `%` in the
+/// prefix are written as `%3A` and `%25`. Without a prefix, the path is
+/// stored as it's given, without its root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub path: PathBuf,
+    pub prefix: Option<String>,
+}
+
+impl Source {
+    /// Split a path given on the command line. Paths that exist are
+    /// never split, so files with a `:` in their name still work.
+    pub fn parse(given: &Path) -> Self {
+        let unchanged = || Self {
+            path: given.to_path_buf(),
+            prefix: None,
+        };
+
+        let Some((path, prefix)) = given.to_str().and_then(|s| s.rsplit_once(':')) else {
+            return unchanged();
+        };
+
+        // `C:\Users` is a drive, and not an alias
+        let drive = cfg!(windows) && path.len() == 1;
+        if path.is_empty() || drive || given.exists() {
+            return unchanged();
+        }
+
+        match normalize_filename(&unescape_prefix(prefix)) {
+            Ok(prefix) if !prefix.is_empty() => Self {
+                path: path.into(),
+                prefix: Some(prefix),
+            },
+            _ => unchanged(),
+        }
+    }
+
+    /// The stored path of `path`, which was found under this source.
+    pub fn stored(&self, path: &Path) -> Result<String, EntryError> {
+        match (&self.prefix, path.strip_prefix(&self.path)) {
+            (Some(prefix), Ok(rest)) => match normalize_filename(&rest)?.as_str() {
+                "" => Ok(prefix.clone()),
+                rest => Ok(format!("{prefix}/{rest}")),
+            },
+            _ => normalize_filename(&path),
+        }
+    }
+
+    /// The stored path of the source itself
+    pub fn root(&self) -> Result<String, EntryError> {
+        self.stored(&self.path)
+    }
+
+    /// Commit only `path` under this source, with the same prefix.
+    pub fn narrow(&self, path: &Path) -> Result<PathBuf, EntryError> {
+        Ok(match self.prefix {
+            Some(_) => format!("{}:{}", path.display(), escape_prefix(&self.stored(path)?)).into(),
+            None => path.to_path_buf(),
+        })
+    }
+}
+
+/// The last `:` of a source separates the prefix, so it can't have any
+fn escape_prefix(prefix: &str) -> String {
+    prefix.replace('%', "%25").replace(':', "%3A")
+}
+
+fn unescape_prefix(prefix: &str) -> String {
+    prefix.replace("%3A", ":").replace("%25", "%")
+}
+
+/// A path that was committed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootSpec {
+    pub kind: RootKind,
+    /// The path in the stash
+    pub stored: String,
+    /// The absolute path on the machine that made the commit
+    pub original: PathBuf,
+}
+
+impl RootSpec {
+    /// Describe `source` as it's given on the command line.
+    pub fn new(source: &Source) -> io::Result<Self> {
+        let path = &source.path;
+        let kind = match path.components().next() {
+            Some(Component::Prefix(_)) => RootKind::Drive,
+            Some(Component::RootDir) => RootKind::Absolute,
+            _ => RootKind::Relative,
+        };
+        let original = match kind {
+            RootKind::Relative => std::env::current_dir()?.join(path),
+            _ => path.to_path_buf(),
+        };
+
+        Ok(Self {
+            kind,
+            stored: source
+                .root()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            original,
+        })
+    }
+
+    /// The part of the stored `path` under this root, if it's inside.
+    pub fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.stored.is_empty() {
+            return Some(path);
+        }
+
+        match path.strip_prefix(self.stored.as_str())? {
+            "" => Some(""),
+            rest => rest.strip_prefix('/'),
+        }
+    }
+}
+
+/// Roots are stored in commit messages as a single line of JSON.
+impl fmt::Display for RootSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl FromStr for RootSpec {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Where to put restored files
+#[derive(Clone, Debug, Default)]
+pub enum Destination {
+    /// At their stored path, relative to the working directory
+    #[default]
+    Stored,
+    /// Where they were committed from
+    Original(Vec<RootSpec>),
+    /// At their path inside their root, relative to a directory
+    RelativeTo(PathBuf, Vec<RootSpec>),
+}
+
+impl Destination {
+    /// The path to restore the file stored at `path` to.
+    ///
+    /// Files that are not under any of the roots stay at their stored
+    /// path. If roots are nested, the innermost one is used.
+    pub fn path(&self, path: &str) -> PathBuf {
+        let (roots, base) = match self {
+            Self::Stored => return path.into(),
+            Self::Original(roots) => (roots, None),
+            Self::RelativeTo(dir, roots) => (roots, Some(dir)),
+        };
+
+        let Some((root, rest)) = roots
+            .iter()
+            .filter_map(|root| Some((root, root.strip(path)?)))
+            .max_by_key(|(root, _)| root.stored.len())
+        else {
+            return path.into();
+        };
+
+        match (base, rest) {
+            (Some(dir), rest) => dir.join(rest),
+            (None, "") => root.original.clone(),
+            (None, rest) => root.original.join(rest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn destination_of_stored_paths() {
+        use super::{Destination, RootKind, RootSpec};
+        use std::path::{Path, PathBuf};
+
+        let root = RootSpec {
+            kind: RootKind::Relative,
+            stored: "docs".into(),
+            original: "/home/user/docs".into(),
+        };
+        assert_eq!(root.to_string().parse::<RootSpec>().unwrap(), root);
+
+        let original = Destination::Original(vec![root.clone()]);
+        assert_eq!(
+            original.path("docs/a/b.txt"),
+            Path::new("/home/user/docs/a/b.txt")
+        );
+        assert_eq!(original.path("docs"), Path::new("/home/user/docs"));
+        assert_eq!(original.path("docsx/c"), Path::new("docsx/c"));
+
+        let relative = Destination::RelativeTo("/restore".into(), vec![root]);
+        assert_eq!(relative.path("docs/a"), PathBuf::from("/restore/a"));
+        assert_eq!(Destination::Stored.path("docs/a"), Path::new("docs/a"));
+    }
+
+    #[test]
+    fn prefixed_sources() {
+        use super::Source;
+        use std::path::Path;
+
+        let source = Source::parse(Path::new("/nonexistent/www:/web"));
+        assert_eq!(source.path, Path::new("/nonexistent/www"));
+        assert_eq!(source.prefix.as_deref(), Some("web"));
+        assert_eq!(source.root().unwrap(), "web");
+        assert_eq!(
+            source
+                .stored(Path::new("/nonexistent/www/a/index.html"))
+                .unwrap(),
+            "web/a/index.html"
+        );
+        assert_eq!(
+            source.narrow(Path::new("/nonexistent/www/a")).unwrap(),
+            Path::new("/nonexistent/www/a:web/a")
+        );
+
+        let plain = Source::parse(Path::new("/nonexistent/www"));
+        assert_eq!(plain.prefix, None);
+        assert_eq!(plain.root().unwrap(), "nonexistent/www");
+        assert_eq!(Source::parse(Path::new("/nonexistent:")).prefix, None);
+    }
+
+    #[test]
+    fn narrowed_sources_roundtrip() {
+        use super::Source;
+        use std::path::Path;
+
+        let source = Source::parse(Path::new("/nonexistent/a:b:we%3Ab%25"));
+        assert_eq!(source.path, Path::new("/nonexistent/a:b"));
+        assert_eq!(source.prefix.as_deref(), Some("we:b%"));
+
+        for path in [
+            "/nonexistent/a:b/c:d",
+            "/nonexistent/a:b/%3A:",
+            "/nonexistent/a:b",
+        ] {
+            let path = Path::new(path);
+            let narrowed = Source::parse(&source.narrow(path).unwrap());
+            assert_eq!(narrowed.path, path);
+            assert_eq!(narrowed.prefix, Some(source.stored(path).unwrap()));
+            assert_eq!(narrowed.root().unwrap(), source.stored(path).unwrap());
+        }
+    }
+}
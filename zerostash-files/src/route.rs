@@ -0,0 +1,292 @@
+//! Route objects to different backends based on what they contain
+//!
+//! Objects that hold file data are written inside a [`data`] scope, so
+//! they can be sent to cheaper bulk storage, while the index and
+//! everything else goes to the default backend, which is typically
+//! fast local storage. The size of an object can't be used for this,
+//! because every object is padded to the same size before it's
+//! written.
+//!
+//! Where each object was written is recorded in a local placement log,
+//! so reads go straight to the right backend. Objects missing from the
+//! log are looked up in every backend in order.
+use crate::list::{Objects, Storage};
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// What the objects sent to a route contain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// The index, and anything that's not file data
+    Index,
+    /// Chunks of stored files
+    Data,
+}
+
+thread_local! {
+    static WRITING: Cell<Kind> = Cell::new(Kind::Index);
+}
+
+struct Restore(Kind);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        WRITING.with(|kind| kind.set(self.0));
+    }
+}
+
+/// Mark the objects that `f` writes on the current thread as file data.
+pub fn data<T>(f: impl FnOnce() -> T) -> T {
+    let _restore = Restore(WRITING.with(|kind| kind.replace(Kind::Data)));
+    f()
+}
+
+pub struct Route {
+    /// Objects of this kind are written to `backend`
+    pub kind: Kind,
+    pub backend: Arc<dyn Storage>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Placed {
+    id: ObjectId,
+    backend: usize,
+}
+
+struct Placement {
+    known: HashMap<ObjectId, usize>,
+    log: fs::File,
+}
+
+impl Placement {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut known = HashMap::new();
+
+        if let Ok(file) = fs::File::open(path) {
+            for line in BufReader::new(file).lines() {
+                match serde_json::from_str::<Placed>(&line?) {
+                    Ok(placed) => {
+                        known.insert(placed.id, placed.backend);
+                    }
+                    Err(error) => warn!(%error, "invalid entry in placement log"),
+                }
+            }
+        }
+
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self { known, log })
+    }
+
+    fn record(&mut self, id: ObjectId, backend: usize) {
+        if self.known.insert(id, backend) == Some(backend) {
+            return;
+        }
+
+        let line = serde_json::to_string(&Placed { id, backend }).unwrap();
+        if let Err(error) = writeln!(self.log, "{line}") {
+            warn!(%error, "failed to update placement log");
+        }
+    }
+}
+
+pub struct Router {
+    /// The default backend is always at index 0
+    backends: Vec<Arc<dyn Storage>>,
+    kinds: Vec<Kind>,
+    placement: Mutex<Placement>,
+}
+
+impl Router {
+    pub fn new(
+        default: Arc<dyn Storage>,
+        routes: Vec<Route>,
+        placement_log: impl AsRef<Path>,
+    ) -> io::Result<Arc<Self>> {
+        let mut backends = vec![default];
+        let mut kinds = vec![Kind::Index];
+
+        for route in routes {
+            backends.push(route.backend);
+            kinds.push(route.kind);
+        }
+
+        Ok(Arc::new(Self {
+            backends,
+            kinds,
+            placement: Mutex::new(Placement::open(placement_log.as_ref())?),
+        }))
+    }
+
+    /// Pick the first route for `kind`, or the default backend.
+    fn route_for(&self, kind: Kind) -> usize {
+        self.kinds
+            .iter()
+            .skip(1)
+            .position(|k| *k == kind)
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    }
+
+    fn placement_of(&self, id: &ObjectId) -> Option<usize> {
+        self.placement.lock().unwrap().known.get(id).copied()
+    }
+
+    fn for_each_placement(
+        &self,
+        objects: &[ObjectId],
+        f: impl Fn(&dyn Storage, &[ObjectId]) -> Result<()>,
+    ) -> Result<()> {
+        let mut grouped = vec![vec![]; self.backends.len()];
+        let mut unknown = vec![];
+
+        for id in objects {
+            match self.placement_of(id) {
+                Some(i) => grouped[i].push(*id),
+                None => unknown.push(*id),
+            }
+        }
+
+        for (backend, ids) in self.backends.iter().zip(grouped) {
+            if !ids.is_empty() {
+                f(backend.as_ref(), &ids)?;
+            }
+            if !unknown.is_empty() {
+                f(backend.as_ref(), &unknown)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for Router {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let route = self.route_for(WRITING.with(Cell::get));
+        self.backends[route].write_object(object)?;
+        self.placement.lock().unwrap().record(*object.id(), route);
+
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        if let Some(route) = self.placement_of(id) {
+            return self.backends[route].read_object(id);
+        }
+
+        let mut last_error = None;
+        for (route, backend) in self.backends.iter().enumerate() {
+            match backend.read_object(id) {
+                Ok(object) => {
+                    self.placement.lock().unwrap().record(*id, route);
+                    return Ok(object);
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error.expect("there's always a default backend"))
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.for_each_placement(objects, |b, ids| b.preload(ids))
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.for_each_placement(objects, |b, ids| b.delete(ids))
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.for_each_placement(objects, |b, ids| b.keep_warm(ids))
+    }
+
+    fn sync(&self) -> Result<()> {
+        for backend in self.backends.iter() {
+            backend.sync()?;
+        }
+
+        self.placement
+            .lock()
+            .unwrap()
+            .log
+            .sync_data()
+            .map_err(Into::into)
+    }
+}
+
+impl Storage for Router {
+    fn list(&self) -> io::Result<Objects> {
+        let mut objects: Objects = Box::new(std::iter::empty());
+        for backend in self.backends.iter() {
+            objects = Box::new(objects.chain(backend.list()?));
+        }
+
+        Ok(objects)
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn data_is_routed_by_kind() {
+        use super::{data, Kind, Route, Router};
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+
+        let local = InMemoryBackend::shared();
+        let bulk = InMemoryBackend::shared();
+        let log = std::env::temp_dir().join(format!("zerostash-route-{}", rand::random::<u64>()));
+        let router = Router::new(
+            local.clone(),
+            vec![Route {
+                kind: Kind::Data,
+                backend: bulk.clone(),
+            }],
+            &log,
+        )
+        .unwrap();
+
+        let index = ObjectId::from_bytes(rand::random());
+        let chunks = ObjectId::from_bytes(rand::random());
+        router
+            .write_object(&object_from_bytes(index, b"index"))
+            .unwrap();
+        data(|| router.write_object(&object_from_bytes(chunks, b"chunks"))).unwrap();
+
+        assert!(local.read_object(&index).is_ok());
+        assert!(local.read_object(&chunks).is_err());
+        assert!(bulk.read_object(&chunks).is_ok());
+        assert!(bulk.read_object(&index).is_err());
+
+        // outside of the scope, writes go to the default backend again
+        let other = ObjectId::from_bytes(rand::random());
+        router
+            .write_object(&object_from_bytes(other, b"other"))
+            .unwrap();
+        assert!(local.read_object(&other).is_ok());
+
+        std::fs::remove_file(log).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Verify a stash a few objects at a time, eg. in the background
+//!
+//! Scrubbing goes through the objects of the chunk index in the order
+//! of their ids, and verifies every chunk in them. [`Progress`] records
+//! the last object that was checked, so scrubbing picks up after it,
+//! even after a restart. Once every object was checked, the next pass
+//! starts from the beginning.
+use crate::{
+    chunk_index::{verify_chunk, ChunkImportError, ChunkRecord},
+    dictionary::Dictionaries,
+    Files,
+};
+use infinitree::{object::Reader, Hasher, Infinitree};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, ops::Bound, path::Path};
+
+/// Where scrubbing a stash is at
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /// The last object that was checked
+    pub last: Option<String>,
+    /// Objects checked in the current pass
+    pub checked: u64,
+    /// Objects with chunks that failed verification in the current pass
+    pub failed: u64,
+    /// Passes over every object of the stash that were completed
+    pub passes: u64,
+}
+
+impl Progress {
+    /// Read the progress saved at `path`, or start from the beginning.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_vec(self)?)?;
+        fs::rename(temp, path)
+    }
+
+    fn record(&mut self, object: &str, wrapped: bool, ok: bool) {
+        if wrapped {
+            self.passes += 1;
+            self.checked = 0;
+            self.failed = 0;
+        }
+
+        self.last = Some(object.to_string());
+        self.checked += 1;
+        if !ok {
+            self.failed += 1;
+        }
+    }
+}
+
+/// The chunks of every object in the chunk index
+#[derive(Default)]
+pub struct Objects(BTreeMap<String, Vec<ChunkRecord>>);
+
+impl Objects {
+    /// Group the chunks that are loaded in the index of `stash`.
+    pub fn of(stash: &Infinitree<Files>) -> Self {
+        let mut objects = Self::default();
+        stash.index().export_chunks(|record| objects.insert(record));
+        objects
+    }
+
+    fn insert(&mut self, record: ChunkRecord) {
+        self.0
+            .entry(record.pointer.object_id().to_string())
+            .or_default()
+            .push(record);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The object after the last one in `progress`, and whether a new
+    /// pass starts with it
+    fn next(&self, progress: &Progress) -> Option<(&String, &[ChunkRecord], bool)> {
+        let after = match progress.last {
+            Some(ref last) => self
+                .0
+                .range::<str, _>((Bound::Excluded(last.as_str()), Bound::Unbounded))
+                .next(),
+            None => None,
+        };
+
+        match after {
+            Some((id, chunks)) => Some((id, chunks, false)),
+            None => {
+                let (id, chunks) = self.0.iter().next()?;
+                Some((id, chunks, progress.last.is_some()))
+            }
+        }
+    }
+}
+
+/// A scrubbed object, and the chunks in it that failed verification
+pub struct Scrubbed {
+    pub object: String,
+    pub errors: Vec<ChunkImportError>,
+}
+
+/// Verify the object after the last one in `progress`, and move
+/// `progress` past it. Returns `None` if there are no objects.
+pub fn step(
+    objects: &Objects,
+    progress: &mut Progress,
+    reader: &mut impl Reader,
+    dictionaries: &Dictionaries,
+    hasher: &mut Hasher,
+) -> Option<Scrubbed> {
+    let (object, chunks, wrapped) = objects.next(progress)?;
+
+    let mut buf = vec![];
+    let errors = chunks
+        .iter()
+        .filter_map(|record| verify_chunk(reader, dictionaries, hasher, record, &mut buf).err())
+        .collect::<Vec<_>>();
+
+    progress.record(object, wrapped, errors.is_empty());
+    Some(Scrubbed {
+        object: object.clone(),
+        errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn objects_are_scrubbed_round_robin() {
+        use super::{Objects, Progress};
+
+        let mut objects = Objects::default();
+        for id in ["b", "a"] {
+            objects.0.insert(id.to_string(), vec![]);
+        }
+
+        let mut progress = Progress::default();
+        let mut seen = vec![];
+        for _ in 0..3 {
+            let (id, _, wrapped) = objects.next(&progress).unwrap();
+            seen.push(id.clone());
+            progress.record(id, wrapped, true);
+        }
+
+        assert_eq!(seen, ["a", "b", "a"]);
+        assert_eq!(progress.passes, 1);
+        assert_eq!(progress.checked, 1);
+    }
+}
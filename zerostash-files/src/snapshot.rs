@@ -0,0 +1,170 @@
+//! Save the objects of a backend to a single file
+//!
+//! [`Snapshot`] records the ids of all objects written through it, and
+//! can dump them to one file, which can be loaded into another backend
+//! later. This makes it cheap to keep fixture stashes for tests and
+//! examples in an in-memory backend, without creating thousands of
+//! files.
+//!
+//! Objects are saved as they are stored, so no key is needed.
+use crate::{
+    chunk_index::digest_from_hex, migrate::object_from_bytes, object_size::MAX_OBJECT_SIZE,
+};
+use anyhow::{bail, Context};
+use infinitree::{
+    backends::{Backend, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+const MAGIC: &[u8] = b"zerostash-snapshot-v1\n";
+/// Objects ids are stored in hex
+const ID_LEN: usize = 64;
+
+pub struct Snapshot {
+    upstream: Arc<dyn Backend>,
+    objects: Mutex<BTreeSet<ObjectId>>,
+}
+
+impl Snapshot {
+    pub fn new(upstream: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self {
+            upstream,
+            objects: Mutex::default(),
+        })
+    }
+
+    /// Write all objects in the snapshot file at `path` to `upstream`.
+    pub fn load(upstream: Arc<dyn Backend>, path: impl AsRef<Path>) -> anyhow::Result<Arc<Self>> {
+        let path = path.as_ref();
+        let mut file = BufReader::new(
+            fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        );
+
+        let mut magic = [0; MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            bail!("{} is not a snapshot", path.display());
+        }
+
+        let snapshot = Self::new(upstream);
+        let mut data = vec![];
+        loop {
+            let mut id = [0; ID_LEN];
+            match file.read_exact(&mut id) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let id = std::str::from_utf8(&id)
+                .ok()
+                .and_then(|id| digest_from_hex(id).ok())
+                .map(ObjectId::from_bytes)
+                .context("snapshot is damaged")?;
+
+            let mut len = [0; 8];
+            file.read_exact(&mut len)?;
+            let len = u64::from_le_bytes(len);
+            if len > MAX_OBJECT_SIZE {
+                bail!("snapshot is damaged");
+            }
+
+            data.resize(len as usize, 0);
+            file.read_exact(&mut data)?;
+            snapshot.write_object(&object_from_bytes(id, &data))?;
+        }
+
+        snapshot.sync()?;
+        Ok(snapshot)
+    }
+
+    /// Save all objects written through this backend to `path`.
+    pub fn dump(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.sync()?;
+
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        file.write_all(MAGIC)?;
+
+        for id in self.objects.lock().unwrap().iter() {
+            let object = self.upstream.read_object(id)?;
+            let data = object.as_inner();
+
+            file.write_all(id.to_string().as_bytes())?;
+            file.write_all(&(data.len() as u64).to_le_bytes())?;
+            file.write_all(data)?;
+        }
+
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Backend for Snapshot {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.upstream.write_object(object)?;
+        self.objects.lock().unwrap().insert(*object.id());
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.delete(objects)?;
+
+        let mut recorded = self.objects.lock().unwrap();
+        for id in objects {
+            recorded.remove(id);
+        }
+
+        Ok(())
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.upstream.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn dump_and_load() {
+        use super::Snapshot;
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-snapshot-{}", rand::random::<u64>()));
+
+        let snapshot = Snapshot::new(InMemoryBackend::shared());
+        let id = ObjectId::from_bytes(rand::random::<[u8; 32]>());
+        snapshot
+            .write_object(&object_from_bytes(id, b"object contents"))
+            .unwrap();
+        snapshot.dump(&path).unwrap();
+
+        let loaded = Snapshot::load(InMemoryBackend::shared(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let object = loaded.read_object(&id).unwrap();
+        assert!(object.as_inner().starts_with(b"object contents"));
+    }
+}
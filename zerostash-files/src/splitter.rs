@@ -1,7 +1,69 @@
-use crate::rollsum::Rollsum;
+use crate::rollsum::{BupSplit, Rollsum, SeaSplit};
 use infinitree::{Digest, Hasher};
+use serde::{Deserialize, Serialize};
 
-use std::marker::PhantomData;
+use std::{marker::PhantomData, str::FromStr};
+
+/// Files smaller than this are split with [`Chunker::Sea`] by default,
+/// larger ones with [`Chunker::Bup`]
+const SEA_SPLIT_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+pub type Chunks<'file> = Box<dyn Iterator<Item = (u64, Digest, &'file [u8])> + Send + 'file>;
+
+/// Algorithm to find chunk boundaries in a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum Chunker {
+    /// Rolling SeaHash checksum
+    Sea,
+    /// bup's rolling checksum
+    Bup,
+}
+
+impl Chunker {
+    /// The chunker for a file of `size` bytes, if none is configured.
+    ///
+    /// This needs to stay the same for a given file size, otherwise
+    /// chunks won't deduplicate with previous commits.
+    pub fn for_size(size: usize) -> Self {
+        if size < SEA_SPLIT_MAX_SIZE {
+            Chunker::Sea
+        } else {
+            Chunker::Bup
+        }
+    }
+
+    pub fn split(self, data: &[u8], hasher: Hasher) -> Chunks<'_> {
+        match self {
+            Chunker::Sea => Box::new(FileSplitter::<SeaSplit>::new(data, hasher)),
+            Chunker::Bup => Box::new(FileSplitter::<BupSplit>::new(data, hasher)),
+        }
+    }
+}
+
+/// Use a chunker for files with a path that matches a glob
+#[derive(Clone, Debug)]
+pub struct ChunkerRule {
+    pub pattern: glob::Pattern,
+    pub chunker: Chunker,
+}
+
+impl FromStr for ChunkerRule {
+    type Err = String;
+
+    /// Parse `GLOB=CHUNKER`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use clap::ValueEnum;
+
+        let (glob, chunker) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("invalid rule `{s}`: expected GLOB=CHUNKER"))?;
+
+        Ok(Self {
+            pattern: glob::Pattern::new(glob).map_err(|e| e.to_string())?,
+            chunker: Chunker::from_str(chunker, true)?,
+        })
+    }
+}
 
 pub struct FileSplitter<'file, RS> {
     hasher: Hasher,
@@ -52,6 +114,7 @@ mod tests {
     const PATH: &str = "../tests/data/10k_random_blob";
 
     #[test]
+    #[cfg(feature = "std-runtime")]
     fn check_chunk_iterator_sum() {
         use super::FileSplitter;
         use crate::rollsum::SeaSplit;
@@ -68,4 +131,16 @@ mod tests {
             .sum();
         assert_eq!(size as u64, metadata.len());
     }
+
+    #[test]
+    fn parse_chunker_rule() {
+        use super::{Chunker, ChunkerRule};
+
+        let rule: ChunkerRule = "*.qcow2=bup".parse().unwrap();
+        assert_eq!(rule.chunker, Chunker::Bup);
+        assert!(rule.pattern.matches("vm/disk.qcow2"));
+
+        assert!("*.qcow2".parse::<ChunkerRule>().is_err());
+        assert!("*.qcow2=fastcdc".parse::<ChunkerRule>().is_err());
+    }
 }
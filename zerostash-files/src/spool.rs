@@ -0,0 +1,309 @@
+//! Write objects to a local directory first, and upload them later
+//!
+//! On a slow uplink, a commit that writes straight to a remote backend
+//! takes as long as the upload. [`Spool`] writes new objects to a local
+//! directory instead, so the commit finishes at disk speed. Object ids
+//! don't depend on where the object is stored, so the index that's
+//! committed is final right away.
+//!
+//! [`drain`] uploads the spooled objects to the upstream backend, with
+//! retries, and removes them from the spool once the upstream backend
+//! has synced them. Until then, reads find them in the spool.
+use crate::{checksum::checksum, list, migrate::object_from_bytes};
+use infinitree::{
+    backends::{Backend, Directory, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+    Digest,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, warn};
+
+/// Objects changed more recently than this may still be written, and
+/// are left for the next drain.
+pub const SETTLE_TIME: Duration = Duration::from_secs(60);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub struct Spool {
+    path: PathBuf,
+    local: Arc<Directory>,
+    upstream: Arc<dyn list::Storage>,
+}
+
+impl Spool {
+    /// Spool objects for `upstream` in the directory at `path`.
+    pub fn new(
+        path: impl AsRef<Path>,
+        upstream: Arc<dyn list::Storage>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        Ok(Arc::new(Self {
+            local: Directory::new(&path)?,
+            path,
+            upstream,
+        }))
+    }
+
+    fn is_spooled(&self, id: &ObjectId) -> bool {
+        self.path.join(id.to_string()).exists()
+    }
+}
+
+impl Backend for Spool {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.local.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        if self.is_spooled(id) {
+            if let Ok(object) = self.local.read_object(id) {
+                return Ok(object);
+            }
+        }
+
+        // the object may have been drained since
+        self.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        let remote = objects
+            .iter()
+            .filter(|id| !self.is_spooled(id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        self.upstream.preload(&remote)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        let (spooled, remote): (Vec<_>, Vec<_>) =
+            objects.iter().copied().partition(|id| self.is_spooled(id));
+
+        self.local.delete(&spooled)?;
+        self.upstream.delete(&remote)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.local.sync()
+    }
+}
+
+impl list::Storage for Spool {
+    fn list(&self) -> io::Result<list::Objects> {
+        let spooled = list::Directory::new(&self.path)?;
+        Ok(Box::new(spooled.chain(self.upstream.list()?)))
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+/// The result of draining a spool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Drained {
+    /// Objects that were uploaded and removed from the spool
+    pub uploaded: usize,
+    /// Objects that failed to upload after all retries
+    pub failed: usize,
+    /// Objects that changed too recently to upload
+    pub settling: usize,
+}
+
+/// Upload the objects in the spool at `path` to `upstream`.
+///
+/// Objects are uploaded in the order they were written. Every commit
+/// writes the root of the index last, so the root only reaches
+/// `upstream` after the objects it points to. Uploading stops at the
+/// first object that fails after all retries, waiting twice as long
+/// after each try, so nothing written after it goes up without it.
+///
+/// Objects are only removed from the spool after `upstream` is synced,
+/// and only if they weren't written again since they were uploaded, so
+/// an interrupted drain or a concurrent commit never loses an object.
+pub fn drain(
+    path: impl AsRef<Path>,
+    upstream: &dyn Backend,
+    settle: Duration,
+    retries: u32,
+) -> anyhow::Result<Drained> {
+    let path = path.as_ref();
+    let now = SystemTime::now();
+
+    recover(path)?;
+
+    let mut spooled = vec![];
+    for id in list::Directory::new(path)? {
+        let id = id?;
+        let modified = fs::metadata(path.join(id.to_string()))?.modified()?;
+        spooled.push((modified, id));
+    }
+    spooled.sort_by_key(|(modified, _)| *modified);
+
+    let mut drained = Drained::default();
+    let mut uploaded = vec![];
+    for (n, (modified, id)) in spooled.iter().enumerate() {
+        // everything after it was written later
+        if now.duration_since(*modified).unwrap_or_default() < settle {
+            drained.settling = spooled.len() - n;
+            break;
+        }
+
+        let data = fs::read(path.join(id.to_string()))?;
+        match retry(retries, || {
+            upstream.write_object(&object_from_bytes(*id, &data))
+        }) {
+            Ok(()) => {
+                debug!(%id, "uploaded spooled object");
+                uploaded.push((*id, checksum(&data)));
+            }
+            Err(error) => {
+                warn!(%id, %error, "failed to upload spooled object");
+                drained.failed = spooled.len() - n;
+                break;
+            }
+        }
+    }
+
+    retry(retries, || upstream.sync())?;
+    for (id, sum) in uploaded.iter() {
+        unspool(path, id, sum)?;
+    }
+    drained.uploaded = uploaded.len();
+
+    Ok(drained)
+}
+
+/// Put back objects that an interrupted drain was removing. They are
+/// uploaded again.
+fn recover(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let moved = entry?.path();
+        if moved.extension().is_some_and(|e| e == "drained") {
+            let file = moved.with_extension("");
+            match file.exists() {
+                true => fs::remove_file(moved)?,
+                false => fs::rename(moved, file)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove the spooled object `id` if it's still the version that was
+/// uploaded. A version written since stays for the next drain.
+fn unspool(path: &Path, id: &ObjectId, uploaded: &Digest) -> io::Result<()> {
+    let file = path.join(id.to_string());
+    let moved = path.join(format!("{id}.drained"));
+
+    // a commit writing the object again from here on creates a new file
+    match fs::rename(&file, &moved) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        result => result?,
+    }
+
+    if checksum(&fs::read(&moved)?) == *uploaded || file.exists() {
+        return fs::remove_file(moved);
+    }
+
+    debug!(%id, "object was written again since it was uploaded");
+    fs::rename(moved, file)
+}
+
+fn retry<T>(retries: u32, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+
+    loop {
+        match f() {
+            Err(error) if attempt < retries => {
+                warn!(%error, attempt, "retrying upload");
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn spooled_objects_are_drained() {
+        use super::{drain, Spool};
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+        use std::{fs, time::Duration};
+
+        let path = std::env::temp_dir().join(format!("zerostash-spool-{}", rand::random::<u64>()));
+        let upstream = InMemoryBackend::shared();
+        let spool = Spool::new(&path, upstream.clone()).unwrap();
+
+        let id = ObjectId::from_bytes(rand::random());
+        spool
+            .write_object(&object_from_bytes(id, b"spooled"))
+            .unwrap();
+        spool.sync().unwrap();
+        assert!(upstream.read_object(&id).is_err());
+        assert!(spool.read_object(&id).is_ok());
+
+        let drained = drain(&path, upstream.as_ref(), Duration::ZERO, 0).unwrap();
+        assert_eq!(drained.uploaded, 1);
+        assert!(!spool.is_spooled(&id));
+        assert_eq!(
+            spool.read_object(&id).unwrap().as_inner()[..7],
+            b"spooled"[..]
+        );
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rewritten_objects_stay_spooled() {
+        use super::{unspool, Spool};
+        use crate::{checksum::checksum, migrate::object_from_bytes};
+        use infinitree::{
+            backends::{test::InMemoryBackend, Backend},
+            object::ObjectId,
+        };
+        use std::fs;
+
+        let path = std::env::temp_dir().join(format!("zerostash-spool-{}", rand::random::<u64>()));
+        let spool = Spool::new(&path, InMemoryBackend::shared()).unwrap();
+
+        let id = ObjectId::from_bytes(rand::random());
+        spool.write_object(&object_from_bytes(id, b"old")).unwrap();
+        let uploaded = checksum(&fs::read(path.join(id.to_string())).unwrap());
+
+        // a commit writes the object again before the drain removes it
+        spool.write_object(&object_from_bytes(id, b"new")).unwrap();
+        unspool(&path, &id, &uploaded).unwrap();
+        assert!(spool.is_spooled(&id));
+
+        unspool(
+            &path,
+            &id,
+            &checksum(&fs::read(path.join(id.to_string())).unwrap()),
+        )
+        .unwrap();
+        assert!(!spool.is_spooled(&id));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,3 +1,21 @@
+pub mod commit_annotations;
+#[cfg(feature = "std-runtime")]
+pub mod compact;
+#[cfg(feature = "std-runtime")]
+pub mod copy;
+pub mod diff;
+pub mod digest_key;
 pub mod list_snapshots;
+pub mod named_snapshot;
+pub mod object_size;
+pub mod pin;
+pub mod prune;
+#[cfg(feature = "std-runtime")]
 pub mod restore;
+pub mod rollback;
+pub mod salvage;
+#[cfg(feature = "std-runtime")]
+pub mod skip;
+#[cfg(feature = "std-runtime")]
 pub mod store;
+pub mod timings;
@@ -0,0 +1,86 @@
+//! Structured `key=value` annotations of commits
+//!
+//! The commit metadata only has room for a message, so annotations are
+//! kept in a [`CustomField`] of the index instead. A commit's id isn't
+//! known until it's written, so its annotations are keyed by the commit
+//! before it, which is the latest commit when they're recorded, and
+//! stored together with the rest of the commit's changes.
+//!
+//! Recording annotations changes the index. The caller is responsible
+//! for committing the changes.
+use crate::{
+    custom_fields::{CustomField, Result},
+    Files,
+};
+use infinitree::{
+    tree::{CommitId, CommitMetadata},
+    Infinitree,
+};
+use std::collections::BTreeMap;
+
+pub type Annotations = BTreeMap<String, String>;
+
+struct CommitAnnotations;
+
+impl CustomField for CommitAnnotations {
+    const NAME: &'static str = "commit_annotations";
+    type Key = Option<CommitId>;
+    type Value = Annotations;
+}
+
+/// Record `annotations` for the next commit of `stash`.
+pub fn record(stash: &Infinitree<Files>, annotations: &Annotations) -> Result<()> {
+    let latest = stash.commit_list().last().map(|c| c.id);
+    stash
+        .index()
+        .custom_fields
+        .get::<CommitAnnotations>()
+        .insert(&latest, annotations)
+}
+
+/// The annotations of the commit with `metadata`, if the records of
+/// the commit are loaded.
+pub fn get(stash: &Infinitree<Files>, metadata: &CommitMetadata) -> Result<Option<Annotations>> {
+    stash
+        .index()
+        .custom_fields
+        .get::<CommitAnnotations>()
+        .get(&metadata.previous)
+}
+
+/// Returns true if `name` is the field of commit annotations.
+pub(crate) fn is_field(name: &str) -> bool {
+    name == CommitAnnotations::NAME
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn annotations_follow_their_commit() {
+        use super::{get, record, Annotations};
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("annotations".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let annotations = |job: &str| Annotations::from([("job".to_string(), job.to_string())]);
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        record(&stash, &annotations("first")).unwrap();
+        stash.commit(None).unwrap();
+        record(&stash, &annotations("second")).unwrap();
+        stash.commit(None).unwrap();
+
+        let stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        stash.load(stash.index().custom_fields()).unwrap();
+        let jobs = stash
+            .commit_list()
+            .iter()
+            .map(|c| get(&stash, &c.metadata).unwrap().unwrap()["job"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(jobs, vec!["first", "second"]);
+    }
+}
@@ -0,0 +1,459 @@
+//! Rewrite the history of a stash without what old commits removed
+//!
+//! Every field of the index stores the changes of each commit, and a
+//! file or chunk that's removed leaves a marker that hides its earlier
+//! versions. Loading the index reads all of them, so the markers of a
+//! stash with a lot of churn pile up, and slow down every open.
+//!
+//! Compacting keeps the latest few commits, and replays them into a
+//! new index over the same backend. The first one kept is stored as a
+//! full state without any markers, and the ones after it record their
+//! changes as usual. Files keep pointing to the same chunks, so no data
+//! is copied. Named snapshots and pins follow their commits to the new
+//! commit ids. The commits before the first one kept are dropped.
+//!
+//! A removal marker can only go together with the commit that recorded
+//! it, since the index stores each commit's changes as one unit. So
+//! the markers older than the oldest commit kept are dropped with the
+//! commits before it, and the markers of the kept commits stay.
+//!
+//! The new index is written to a local [`Spool`](crate::spool::Spool),
+//! and only [`publish`]ed once it's complete. Its objects are written
+//! to the backend in the order they were staged, which puts the root of
+//! the index last. An interrupted compaction leaves the old history in
+//! place. The objects of the old index are deleted once the new root is
+//! written. A [`ReadLog`] finds them while the old index is loaded.
+use crate::{
+    chunk_index::ChunkIndex,
+    commit_annotations, list,
+    migrate::to_write_object,
+    named_snapshot,
+    pin::{self, Pin, PinTarget},
+    Files, Tree,
+};
+use infinitree::{
+    backends::{Backend, Directory, Result as BackendResult},
+    fields::{Key, Value, VersionedMap},
+    object::{ObjectId, ReadObject, WriteObject},
+    tree::{CommitFilter, CommitId},
+    Infinitree,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::{debug, warn};
+
+/// Named snapshots and commit pins in the loaded index of `stash` that
+/// point to a commit that isn't `kept`
+pub fn dangling(stash: &Infinitree<Files>, kept: &[CommitId]) -> anyhow::Result<Vec<String>> {
+    let mut found = vec![];
+    for snapshot in named_snapshot::list(stash)? {
+        if !kept.contains(&snapshot.commit) {
+            found.push(format!("snapshot {}", snapshot.name));
+        }
+    }
+
+    for pin in pin::list(stash) {
+        if let PinTarget::Commit(ref id) = pin.target {
+            if !kept.contains(id) {
+                found.push(format!("pin {}", pin.target));
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// A backend that remembers the objects that were read through it.
+///
+/// Opening and loading a stash reads every object of its index, and
+/// none of the objects that hold file contents.
+pub struct ReadLog {
+    inner: Arc<dyn Backend>,
+    read: Mutex<HashSet<ObjectId>>,
+}
+
+impl ReadLog {
+    pub fn new(inner: Arc<dyn Backend>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            read: Mutex::default(),
+        })
+    }
+
+    /// The objects read so far
+    pub fn objects(&self) -> HashSet<ObjectId> {
+        self.read.lock().unwrap().clone()
+    }
+}
+
+impl Backend for ReadLog {
+    fn write_object(&self, object: &WriteObject) -> BackendResult<()> {
+        self.inner.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> BackendResult<Arc<ReadObject>> {
+        self.read.lock().unwrap().insert(*id);
+        self.inner.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> BackendResult<()> {
+        self.inner.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> BackendResult<()> {
+        self.inner.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> BackendResult<()> {
+        self.inner.keep_warm(objects)
+    }
+
+    fn sync(&self) -> BackendResult<()> {
+        self.inner.sync()
+    }
+}
+
+/// Replays commits of one stash into the index of another
+#[derive(Default)]
+pub struct Replay {
+    /// The new ids of the commits replayed so far
+    ids: HashMap<CommitId, CommitId>,
+    /// Objects that the replayed commits store data in
+    data: HashSet<ObjectId>,
+}
+
+impl Replay {
+    /// Load the index of `src` up to `commit`, and make the index of
+    /// `dst` the same.
+    ///
+    /// `load` is called to load the index. The changes must be
+    /// committed by the caller, followed by [`Replay::committed`].
+    pub fn apply(
+        &mut self,
+        src: &mut Infinitree<Files>,
+        dst: &Infinitree<Files>,
+        commit: CommitId,
+        load: impl FnOnce(&mut Infinitree<Files>),
+    ) {
+        src.filter_commits(CommitFilter::UpTo(commit));
+
+        let index = src.index();
+        index.tree.clear().unwrap();
+        index.files.clear();
+        index.zfs_snapshots.clear();
+        index.chunks.clear();
+        index.tombstones.clear();
+        index.pins.clear();
+        index.custom_fields.clear();
+        load(src);
+
+        let (src, dst) = (src.index(), dst.index());
+        src.chunks.for_each(|_, pointer| {
+            self.data.insert(*pointer.object_id());
+        });
+        src.tombstones.for_each(|id, _| {
+            self.data.insert(*id);
+        });
+        for (_, entry) in src.tree.iter_files() {
+            self.data
+                .extend(entry.chunks.values().map(|c| *c.object_id()));
+        }
+
+        mirror_tree(&src.tree, &dst.tree);
+        mirror_chunks(&src.chunks, &dst.chunks);
+        mirror(&src.files, &dst.files, |path, entry| {
+            (path.clone(), entry.clone())
+        });
+        mirror(&src.zfs_snapshots, &dst.zfs_snapshots, |name, snapshot| {
+            (name.clone(), snapshot.clone())
+        });
+        mirror(&src.tombstones, &dst.tombstones, |id, tombstone| {
+            (*id, tombstone.clone())
+        });
+        mirror(&src.pins, &dst.pins, |_, pin| self.pin(pin));
+        let mut fields = src.custom_fields.names();
+        fields.extend(dst.custom_fields.names());
+        for name in fields {
+            // annotations are keyed by commit, so the caller records
+            // them again for the replayed commit
+            if commit_annotations::is_field(&name) {
+                continue;
+            }
+
+            mirror(
+                &src.custom_fields.records(&name),
+                &dst.custom_fields.records(&name),
+                |key, value| {
+                    (
+                        key.clone(),
+                        named_snapshot::remap_record(key, value, &self.ids),
+                    )
+                },
+            );
+        }
+        debug!(?commit, "replayed commit");
+    }
+
+    /// Record the latest commit of `dst` as the replay of `commit`.
+    pub fn committed(&mut self, commit: CommitId, dst: &Infinitree<Files>) {
+        if let Some(new) = dst.commit_list().last() {
+            self.ids.insert(commit, new.id);
+        }
+    }
+
+    /// The objects in `read` that none of the replayed commits store
+    /// data in, so only the old index uses them.
+    pub fn index_objects(&self, read: impl IntoIterator<Item = ObjectId>) -> Vec<ObjectId> {
+        read.into_iter()
+            .filter(|id| !self.data.contains(id))
+            .collect()
+    }
+
+    /// A pin is keyed by its target, so a commit pin gets a new key.
+    fn pin(&self, pin: &Pin) -> (String, Pin) {
+        let mut pin = pin.clone();
+        if let PinTarget::Commit(ref mut id) = pin.target {
+            *id = self.ids.get(id).copied().unwrap_or(*id);
+        }
+        // a dropped commit means the kept ones are scanned again
+        pin.scanned = pin.scanned.and_then(|id| self.ids.get(&id).copied());
+
+        (pin.target.to_string(), pin)
+    }
+}
+
+fn mirror_tree(src: &Tree, dst: &Tree) {
+    // directories are visited before their contents
+    let mut paths = HashSet::new();
+    src.retain(|path, node| {
+        paths.insert(path.to_string());
+        let current = dst.node_by_path(path).ok().flatten();
+
+        match node.as_file() {
+            Some(entry) => match current {
+                Some(current) if current.as_file().is_some_and(|c| same(&c, &entry)) => {}
+                Some(current) if current.is_file() => {
+                    dst.update_file(path, entry.as_ref().clone()).unwrap();
+                }
+                Some(_) => {
+                    dst.remove(path).unwrap();
+                    dst.insert_file(path, entry.as_ref().clone()).unwrap();
+                }
+                None => dst.insert_file(path, entry.as_ref().clone()).unwrap(),
+            },
+            None => {
+                match current {
+                    Some(ref current) if current.is_dir() => {}
+                    Some(_) => {
+                        dst.remove(path).unwrap();
+                        dst.insert_directory(path).unwrap();
+                    }
+                    None if path.is_empty() => {}
+                    None => dst.insert_directory(path).unwrap(),
+                }
+
+                let metadata = node.metadata();
+                let current = current.and_then(|c| c.metadata());
+                if let Some(metadata) = metadata.filter(|m| Some(m) != current.as_ref()) {
+                    dst.set_directory_metadata(path, metadata.as_ref().clone())
+                        .unwrap();
+                }
+            }
+        }
+        true
+    });
+
+    dst.retain(|p, _| p.is_empty() || paths.contains(p));
+}
+
+fn mirror_chunks(src: &ChunkIndex, dst: &ChunkIndex) {
+    let mut digests = HashSet::new();
+    src.for_each(|digest, pointer| {
+        digests.insert(*digest);
+        match dst.get(digest) {
+            Some(current) if same(current.as_ref(), pointer) => {}
+            Some(_) => {
+                dst.update(*digest, pointer.clone());
+            }
+            None => {
+                dst.insert_with(*digest, || pointer.clone());
+            }
+        }
+    });
+
+    let mut removed = vec![];
+    dst.for_each(|digest, _| {
+        if !digests.contains(digest) {
+            removed.push(*digest);
+        }
+    });
+    for digest in removed {
+        dst.remove(digest);
+    }
+}
+
+/// Make `dst` hold what `src` does, with keys and values mapped by `f`.
+/// Only what differs is changed, so `dst` records a minimal changeset.
+fn mirror<K, V>(src: &VersionedMap<K, V>, dst: &VersionedMap<K, V>, f: impl Fn(&K, &V) -> (K, V))
+where
+    K: Key + Clone,
+    V: Value + Serialize,
+{
+    let mut keys = HashSet::new();
+    src.for_each(|key, value| {
+        let (key, value) = f(key, value);
+        match dst.get(&key) {
+            Some(current) if same(current.as_ref(), &value) => {}
+            Some(_) => {
+                dst.update_with(key.clone(), |_| value);
+            }
+            None => {
+                dst.insert(key.clone(), value);
+            }
+        }
+        keys.insert(key);
+    });
+
+    dst.retain(|key, _| keys.contains(key));
+}
+
+fn same<T: Serialize>(a: &T, b: &T) -> bool {
+    matches!(
+        (rmp_serde::to_vec(a), rmp_serde::to_vec(b)),
+        (Ok(a), Ok(b)) if a == b
+    )
+}
+
+/// What publishing a staged index did to the backend
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Published {
+    /// Objects of the new index that were written
+    pub objects: usize,
+    /// Objects of the old index that were deleted
+    pub deleted: usize,
+}
+
+/// Write the objects staged in the spool at `path` to `upstream`, then
+/// delete the objects of the old index in `replaced`, and remove the
+/// spool.
+///
+/// Objects are written in the order they were staged. The last one is
+/// the root of the new index, which makes it reachable, so it's only
+/// written after the backend synced everything it points to.
+pub fn publish(
+    path: &Path,
+    upstream: &dyn Backend,
+    replaced: &[ObjectId],
+) -> anyhow::Result<Published> {
+    let local = Directory::new(path)?;
+
+    let mut staged = vec![];
+    for id in list::Directory::new(path)? {
+        let id = id?;
+        let modified = fs::metadata(path.join(id.to_string()))?.modified()?;
+        staged.push((modified, id));
+    }
+    staged.sort();
+
+    let Some((_, root)) = staged.pop() else {
+        anyhow::bail!("nothing is staged in {}", path.display());
+    };
+    for (_, id) in staged.iter() {
+        upstream.write_object(&to_write_object(&local.read_object(id)?))?;
+    }
+    upstream.sync()?;
+
+    debug!(%root, "writing the new root");
+    upstream.write_object(&to_write_object(&local.read_object(&root)?))?;
+    upstream.sync()?;
+
+    let written = staged
+        .iter()
+        .map(|(_, id)| *id)
+        .chain([root])
+        .collect::<HashSet<_>>();
+    let old = replaced
+        .iter()
+        .filter(|id| !written.contains(id))
+        .copied()
+        .collect::<Vec<_>>();
+    let deleted = match upstream.delete(&old) {
+        Ok(()) => old.len(),
+        Err(error) => {
+            warn!(%error, "failed to delete the objects of the old index");
+            0
+        }
+    };
+
+    fs::remove_dir_all(path)?;
+    Ok(Published {
+        objects: written.len(),
+        deleted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn compacted_history_drops_old_commits() {
+        use super::{publish, ReadLog, Replay};
+        use crate::{spool::Spool, Files};
+        use infinitree::{backends::test::InMemoryBackend, crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("compact".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = InMemoryBackend::shared();
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        let tree = &stash.index().tree;
+        tree.insert_directory("removed").unwrap();
+        stash.commit(None).unwrap();
+        tree.remove("removed").unwrap();
+        tree.insert_directory("kept").unwrap();
+        stash.commit(None).unwrap();
+        tree.insert_directory("added").unwrap();
+        stash.commit(None).unwrap();
+
+        let read = ReadLog::new(storage.clone());
+        let mut src = Infinitree::<Files>::open(read.clone(), key()).unwrap();
+        let commits = src.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-compact-{}", rand::random::<u64>()));
+        let spool = Spool::new(&path, storage.clone()).unwrap();
+        let dst = Infinitree::<Files>::empty(spool, key()).unwrap();
+
+        let mut replay = Replay::default();
+        for id in commits[1..].iter() {
+            replay.apply(&mut src, &dst, *id, |stash| stash.load_all().unwrap());
+            dst.commit(None).unwrap();
+            dst.backend().sync().unwrap();
+            replay.committed(*id, &dst);
+        }
+
+        // nothing changes until the new index is published
+        let stash = Infinitree::<Files>::open(storage.clone(), key()).unwrap();
+        assert_eq!(stash.commit_list().len(), 3);
+
+        let old = replay.index_objects(read.objects());
+        let published = publish(&path, storage.as_ref(), &old).unwrap();
+        assert!(!path.exists());
+        assert!(published.deleted > 0);
+
+        let stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        stash.load_all().unwrap();
+        assert_eq!(stash.commit_list().len(), 2);
+
+        let tree = &stash.index().tree;
+        assert!(tree.node_by_path("kept").unwrap().is_some());
+        assert!(tree.node_by_path("added").unwrap().is_some());
+        assert!(tree.node_by_path("removed").ok().flatten().is_none());
+    }
+}
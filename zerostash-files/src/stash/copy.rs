@@ -0,0 +1,172 @@
+//! Copy the contents of a stash into another one
+//!
+//! The destination may use a different key and a different backend.
+//! Every chunk is read and decrypted from the source, re-hashed with
+//! the destination's hasher, and written to the destination, so no
+//! key material from the source stash is carried over. Chunks that
+//! were compressed with a dictionary are copied decompressed.
+use crate::{
+    crypto_error::read_chunk, dictionary::Dictionaries, files::Entry,
+    write_balancer::WriteBalancer, Files,
+};
+use flume as mpsc;
+use futures::future::join_all;
+use infinitree::{
+    object::{Reader, Writer},
+    ChunkPointer, Infinitree,
+};
+use std::{collections::HashSet, num::NonZeroUsize, sync::Arc};
+use tokio::task;
+use tracing::{debug, debug_span, Instrument};
+
+type Sender = mpsc::Sender<(String, Arc<Entry>)>;
+type Receiver = mpsc::Receiver<(String, Arc<Entry>)>;
+
+/// Make the tree and ZFS snapshots in `dst` identical to the currently
+/// loaded state of `src`.
+///
+/// Files that are already present in the destination with the same
+/// metadata are not copied again, so calling this for every commit of
+/// the source in order only transfers the changes. The caller is
+/// responsible for committing the destination.
+pub async fn copy_stash(
+    src: &Infinitree<Files>,
+    dst: &Infinitree<Files>,
+    threads: usize,
+) -> anyhow::Result<()> {
+    let (sender, workers) = start_workers(src, dst, threads)?;
+    let mut current_paths = HashSet::new();
+
+    src.index().tree.retain(|path, node| {
+        if node.is_dir() {
+            current_paths.insert(path.to_string());
+        }
+        true
+    });
+
+    for path in current_paths.iter().filter(|p| !p.is_empty()) {
+        dst.index().tree.insert_directory(path)?;
+    }
+
+    for (path, entry) in src.index().tree.iter_files() {
+        current_paths.insert(path.clone());
+        sender.send_async((path, entry)).await?;
+    }
+
+    drop(sender);
+    for result in join_all(workers).await {
+        result??;
+    }
+
+    dst.index()
+        .tree
+        .retain(|p, _| p.is_empty() || current_paths.contains(p));
+
+    copy_zfs_snapshots(src, dst)
+}
+
+fn copy_zfs_snapshots(src: &Infinitree<Files>, dst: &Infinitree<Files>) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    let mut result = Ok(());
+
+    src.index().zfs_snapshots.for_each(|name, snapshot| {
+        names.insert(name.clone());
+        if result.is_err() || dst.index().zfs_snapshots.contains(name) {
+            return;
+        }
+
+        debug!(%name, "copying zfs snapshot");
+        result = (|| {
+            let copy = snapshot.copy(src.storage_reader()?, dst.storage_writer()?)?;
+            dst.index().zfs_snapshots.insert(name.clone(), copy);
+            anyhow::Ok(())
+        })();
+    });
+    result?;
+
+    dst.index()
+        .zfs_snapshots
+        .retain(|name, _| names.contains(name));
+
+    Ok(())
+}
+
+fn start_workers(
+    src: &Infinitree<Files>,
+    dst: &Infinitree<Files>,
+    threads: usize,
+) -> anyhow::Result<(Sender, Vec<task::JoinHandle<anyhow::Result<()>>>)> {
+    let (sender, receiver) = mpsc::bounded(threads * 2);
+    let balancer = WriteBalancer::new(NonZeroUsize::new(threads).unwrap(), dst.storage_writer()?);
+    let hasher = crate::digest_key::hasher(dst)?;
+    let object_size = crate::object_size::size(src)?;
+
+    let mut workers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        workers.push(task::spawn(copy_file_loop(
+            receiver.clone(),
+            src.storage_reader()?,
+            src.index().dictionaries.clone(),
+            object_size,
+            dst.index().clone(),
+            hasher.clone(),
+            balancer.clone(),
+        )));
+    }
+
+    Ok((sender, workers))
+}
+
+async fn copy_file_loop(
+    r: Receiver,
+    mut reader: impl Reader,
+    dictionaries: Dictionaries,
+    object_size: usize,
+    index: Files,
+    mut hasher: infinitree::Hasher,
+    writer: WriteBalancer<impl Writer + Clone + 'static>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; object_size];
+
+    while let Ok((path, entry)) = r.recv_async().await {
+        if let Ok(Some(existing)) = index.tree.file(&path) {
+            if existing == entry {
+                debug!(?path, "already copied, skipping");
+                continue;
+            }
+        }
+
+        async {
+            let mut copy = entry.as_ref().clone();
+
+            for (offset, pointer) in entry.chunks.iter() {
+                let data = read_chunk(&mut reader, &dictionaries, pointer, &mut buf)?;
+                let hash = *hasher.reset().update(data).finalize().as_bytes();
+
+                let mut writer = writer.clone();
+                let mut failed = None;
+                let store = || {
+                    writer.write_chunk(&hash, data).unwrap_or_else(|error| {
+                        failed = Some(error);
+                        ChunkPointer::default()
+                    })
+                };
+                let pointer = index.chunks.insert_with(hash, store);
+
+                // the chunk isn't stored anywhere, don't leave it in the index
+                if let Some(error) = failed {
+                    index.chunks.remove(hash);
+                    return Err(error.into());
+                }
+                copy.chunks.insert(*offset, pointer);
+            }
+
+            index.tree.insert_file(&path, copy)?;
+            anyhow::Ok(())
+        }
+        .instrument(debug_span!("copying", ?path))
+        .await?;
+    }
+
+    Ok(())
+}
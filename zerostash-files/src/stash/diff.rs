@@ -0,0 +1,282 @@
+//! Compare the files of two commits
+//!
+//! A [`State`] holds the files of a commit by path. [`diff`] yields the
+//! changes between two states in order of their path, so tools can show
+//! what changed without walking the trees themselves.
+//!
+//! A file that's missing from the new state, and a file that's only in
+//! the new state with the same non-empty contents, are reported as a
+//! single rename. Directories are only compared through their contents.
+use crate::{Entry, Files};
+use infinitree::{
+    tree::{CommitFilter, CommitId},
+    Infinitree,
+};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{btree_map, BTreeMap, HashMap, HashSet},
+    iter::Peekable,
+    sync::Arc,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Added,
+    Removed,
+    Modified,
+    Renamed,
+}
+
+/// A file that changed between two commits
+#[derive(Clone, Debug, Serialize)]
+pub struct DiffEntry {
+    /// Path of the file in the new state, or the old one if it was removed
+    pub path: String,
+    pub kind: Kind,
+    /// Path of the file in the old state, if it was renamed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub old: Option<Arc<Entry>>,
+    pub new: Option<Arc<Entry>>,
+}
+
+/// The files of a commit, by path
+#[derive(Clone, Debug, Default)]
+pub struct State(BTreeMap<String, Arc<Entry>>);
+
+impl State {
+    /// The files in the loaded tree of `stash`
+    pub fn of(stash: &Infinitree<Files>) -> Self {
+        Self(stash.index().tree.iter_files().collect())
+    }
+
+    /// The files of `stash` at `commit`.
+    ///
+    /// `load` is called to load the index up to `commit`. The stash is
+    /// left filtered to it.
+    pub fn at(
+        stash: &mut Infinitree<Files>,
+        commit: CommitId,
+        load: impl FnOnce(&mut Infinitree<Files>),
+    ) -> anyhow::Result<Self> {
+        stash.filter_commits(CommitFilter::UpTo(commit));
+
+        let index = stash.index();
+        index
+            .tree
+            .clear()
+            .map_err(|e| anyhow::anyhow!("failed to clear the tree: {e:?}"))?;
+        index.files.clear();
+        load(stash);
+
+        Ok(Self::of(stash))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The changes from `old` to `new`, in order of their path.
+///
+/// Renamed files are paired up front, by their contents. Everything
+/// else is compared as the changes are read.
+pub fn diff<'a>(old: &'a State, new: &'a State) -> Diff<'a> {
+    let removed = old.0.iter().filter(|(p, _)| !new.0.contains_key(*p));
+    let added = new.0.iter().filter(|(p, _)| !old.0.contains_key(*p));
+
+    // removed files that may have moved, by their contents
+    let mut moved: HashMap<Vec<u8>, Vec<(&String, &Arc<Entry>)>> = HashMap::new();
+    for (path, entry) in removed.rev() {
+        if let Some(key) = content_key(entry) {
+            moved.entry(key).or_default().push((path, entry));
+        }
+    }
+
+    let mut renames = HashMap::new();
+    for (path, entry) in added {
+        let source = content_key(entry)
+            .and_then(|key| moved.get_mut(&key))
+            .and_then(Vec::pop);
+
+        if let Some(source) = source {
+            renames.insert(path.as_str(), source);
+        }
+    }
+
+    Diff {
+        old: old.0.iter().peekable(),
+        new: new.0.iter().peekable(),
+        renamed: renames.values().map(|(path, _)| path.as_str()).collect(),
+        renames,
+    }
+}
+
+type Paths<'a> = Peekable<btree_map::Iter<'a, String, Arc<Entry>>>;
+
+/// The changes between two states, see [`diff`]
+pub struct Diff<'a> {
+    old: Paths<'a>,
+    new: Paths<'a>,
+    /// The old path and entry of renamed files, by their new path
+    renames: HashMap<&'a str, (&'a String, &'a Arc<Entry>)>,
+    /// The old paths of renamed files
+    renamed: HashSet<&'a str>,
+}
+
+impl Iterator for Diff<'_> {
+    type Item = DiffEntry;
+
+    fn next(&mut self) -> Option<DiffEntry> {
+        loop {
+            let next = match (self.old.peek(), self.new.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old, _)), Some((new, _))) => old.cmp(new),
+            };
+
+            match next {
+                Ordering::Less => {
+                    let (path, entry) = self.old.next()?;
+                    if !self.renamed.contains(path.as_str()) {
+                        return Some(DiffEntry {
+                            path: path.clone(),
+                            kind: Kind::Removed,
+                            old_path: None,
+                            old: Some(entry.clone()),
+                            new: None,
+                        });
+                    }
+                }
+                Ordering::Greater => {
+                    let (path, entry) = self.new.next()?;
+                    return Some(match self.renames.get(path.as_str()) {
+                        Some((old_path, old_entry)) => DiffEntry {
+                            path: path.clone(),
+                            kind: Kind::Renamed,
+                            old_path: Some(old_path.to_string()),
+                            old: Some(Arc::clone(old_entry)),
+                            new: Some(entry.clone()),
+                        },
+                        None => DiffEntry {
+                            path: path.clone(),
+                            kind: Kind::Added,
+                            old_path: None,
+                            old: None,
+                            new: Some(entry.clone()),
+                        },
+                    });
+                }
+                Ordering::Equal => {
+                    let (path, old) = self.old.next()?;
+                    let (_, new) = self.new.next()?;
+                    if !old.is_identical(new) {
+                        return Some(DiffEntry {
+                            path: path.clone(),
+                            kind: Kind::Modified,
+                            old_path: None,
+                            old: Some(old.clone()),
+                            new: Some(new.clone()),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Files with the same key have the same contents. Empty files have
+/// none, so they're never taken for each other.
+fn content_key(entry: &Entry) -> Option<Vec<u8>> {
+    if entry.size == 0 || entry.chunks.is_empty() {
+        return None;
+    }
+    rmp_serde::to_vec(&(&entry.file_type, entry.size, &entry.chunks)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn renames_are_paired_by_contents() {
+        use super::{diff, Kind, State};
+        use crate::Entry;
+        use infinitree::ChunkPointer;
+        use std::sync::Arc;
+
+        // pointers can't be told apart in a test, so chunk offsets stand
+        // in for the contents
+        let file = |size, chunk: u64| {
+            let mut entry = Entry {
+                size,
+                ..Default::default()
+            };
+            entry
+                .chunks
+                .insert(chunk, Arc::new(ChunkPointer::default()));
+            Arc::new(entry)
+        };
+
+        let old = State(
+            [
+                ("moved".to_string(), file(10, 1)),
+                ("removed".to_string(), file(10, 2)),
+                ("same".to_string(), file(10, 3)),
+                ("written".to_string(), file(10, 4)),
+            ]
+            .into(),
+        );
+        let new = State(
+            [
+                ("added".to_string(), file(20, 5)),
+                ("renamed".to_string(), file(10, 1)),
+                ("same".to_string(), file(10, 3)),
+                ("written".to_string(), file(10, 6)),
+            ]
+            .into(),
+        );
+
+        let changes = diff(&old, &new)
+            .map(|d| (d.path, d.kind, d.old_path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                ("added".to_string(), Kind::Added, None),
+                ("removed".to_string(), Kind::Removed, None),
+                (
+                    "renamed".to_string(),
+                    Kind::Renamed,
+                    Some("moved".to_string())
+                ),
+                ("written".to_string(), Kind::Modified, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn extensions_are_modifications() {
+        use super::{diff, Kind, State};
+        use crate::Entry;
+        use std::sync::Arc;
+
+        let entry = Entry::default();
+        let mut tagged = entry.clone();
+        tagged
+            .set_extension("xattrs", &vec![("user.a", 1)])
+            .unwrap();
+
+        let old = State([("file".to_string(), Arc::new(entry))].into());
+        let new = State([("file".to_string(), Arc::new(tagged))].into());
+
+        let changes = diff(&old, &new).map(|d| d.kind).collect::<Vec<_>>();
+        assert_eq!(changes, [Kind::Modified]);
+        assert_eq!(diff(&old, &old).count(), 0);
+    }
+}
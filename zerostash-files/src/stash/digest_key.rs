@@ -0,0 +1,133 @@
+//! Hash chunks with a key of their own
+//!
+//! Chunks are deduplicated by a keyed hash of their contents. The
+//! hasher of the storage library is keyed from the same master key as
+//! chunk encryption, so whoever learns that key can also confirm which
+//! contents are stored by hashing a guess.
+//!
+//! A new stash gets a random digest key in a [`CustomField`] of the
+//! index instead. The index is encrypted, and the digest key isn't
+//! derived from any other key, so learning one of them doesn't give
+//! away the other.
+//!
+//! Stashes without a digest key were created before it was kept, and
+//! keep hashing with the storage library, so their chunks still
+//! deduplicate against what's stored. Stashes that share a pool only
+//! deduplicate against each other if they share a digest key.
+use crate::{
+    custom_fields::{CustomField, CustomFieldError},
+    Files,
+};
+use infinitree::{Hasher, Infinitree};
+
+const CHUNK_DIGEST: &str = "chunk_digest";
+
+struct Keys;
+
+impl CustomField for Keys {
+    const NAME: &'static str = "keys";
+    type Key = String;
+    type Value = [u8; 32];
+}
+
+/// The digest key of the stash, if it has one
+pub fn get(stash: &Infinitree<Files>) -> Result<Option<[u8; 32]>, CustomFieldError> {
+    stash
+        .index()
+        .custom_fields
+        .get::<Keys>()
+        .get(&CHUNK_DIGEST.to_string())
+}
+
+/// Give a stash without any commits a digest key.
+///
+/// Stashes that already have chunks are left alone, since their chunks
+/// were hashed without one. The caller is responsible for committing
+/// the changes.
+pub fn record(stash: &Infinitree<Files>) -> Result<(), CustomFieldError> {
+    if !stash.commit_list().is_empty() || get(stash)?.is_some() {
+        return Ok(());
+    }
+
+    stash
+        .index()
+        .custom_fields
+        .get::<Keys>()
+        .insert(&CHUNK_DIGEST.to_string(), &rand::random())
+}
+
+/// The hasher that chunks of the stash are deduplicated by.
+///
+/// Loads the custom fields of the index if none of them are loaded yet.
+pub fn hasher(stash: &Infinitree<Files>) -> anyhow::Result<Hasher> {
+    let loaded = !stash.index().custom_fields.is_empty();
+
+    let key = match get(stash)? {
+        Some(key) => Some(key),
+        None if loaded || stash.commit_list().is_empty() => None,
+        None => {
+            stash.load(stash.index().custom_fields())?;
+            get(stash)?
+        }
+    };
+
+    match key {
+        Some(key) => Ok(Hasher::new_keyed(&key)),
+        None => Ok(stash.hasher()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn new_stashes_get_their_own_key() {
+        use super::{get, hasher, record};
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("digest".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        record(&stash).unwrap();
+        let digest_key = get(&stash).unwrap().unwrap();
+        stash.commit(None).unwrap();
+
+        let digest = |stash| {
+            *hasher(stash)
+                .unwrap()
+                .update(b"chunk")
+                .finalize()
+                .as_bytes()
+        };
+        let expected = digest(&stash);
+        assert_ne!(
+            expected,
+            *stash
+                .hasher()
+                .unwrap()
+                .update(b"chunk")
+                .finalize()
+                .as_bytes()
+        );
+
+        // the key is found in a stash that was just opened, and kept
+        let stash = Infinitree::<Files>::open(storage.clone(), key()).unwrap();
+        assert_eq!(digest(&stash), expected);
+        record(&stash).unwrap();
+        assert_eq!(get(&stash).unwrap(), Some(digest_key));
+
+        // stashes that have commits without a key don't get one
+        let old = Infinitree::<Files>::empty(
+            infinitree::backends::test::InMemoryBackend::shared(),
+            key(),
+        )
+        .unwrap();
+        old.commit(None).unwrap();
+        record(&old).unwrap();
+        assert_eq!(get(&old).unwrap(), None);
+    }
+}
@@ -0,0 +1,152 @@
+//! Give names to commits
+//!
+//! A named snapshot labels the state of the stash at a commit, so its
+//! files can be listed and restored by name instead of a commit id.
+//! Snapshots are kept in a [`CustomField`] of the index, so stashes
+//! without any work with older versions, too.
+//!
+//! Creating or deleting a snapshot changes the index. The caller is
+//! responsible for committing the changes.
+use crate::{
+    custom_fields::{CustomField, RecordKey, Result},
+    Files,
+};
+use infinitree::{tree::CommitId, Infinitree};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamedSnapshot {
+    pub name: String,
+    pub commit: CommitId,
+    /// Unix timestamp of when the snapshot was created
+    pub created_at: u64,
+}
+
+struct Snapshots;
+
+impl CustomField for Snapshots {
+    const NAME: &'static str = "named_snapshots";
+    type Key = String;
+    type Value = NamedSnapshot;
+}
+
+/// Name the state of the stash at `commit`. Returns false if a
+/// snapshot with the same name exists already.
+pub fn create(stash: &Infinitree<Files>, name: &str, commit: CommitId) -> Result<bool> {
+    let snapshots = stash.index().custom_fields.get::<Snapshots>();
+    if snapshots.get(&name.to_string())?.is_some() {
+        return Ok(false);
+    }
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    snapshots.insert(
+        &name.to_string(),
+        &NamedSnapshot {
+            name: name.to_string(),
+            commit,
+            created_at,
+        },
+    )?;
+    Ok(true)
+}
+
+pub fn get(stash: &Infinitree<Files>, name: &str) -> Result<Option<NamedSnapshot>> {
+    stash
+        .index()
+        .custom_fields
+        .get::<Snapshots>()
+        .get(&name.to_string())
+}
+
+/// All named snapshots, oldest first
+pub fn list(stash: &Infinitree<Files>) -> Result<Vec<NamedSnapshot>> {
+    let mut snapshots = vec![];
+    stash
+        .index()
+        .custom_fields
+        .get::<Snapshots>()
+        .for_each(|_, snapshot| snapshots.push(snapshot))?;
+
+    snapshots.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+    Ok(snapshots)
+}
+
+/// Remove the name of a snapshot. The commit itself is kept. Returns
+/// false if there's no such snapshot.
+pub fn delete(stash: &Infinitree<Files>, name: &str) -> Result<bool> {
+    let snapshots = stash.index().custom_fields.get::<Snapshots>();
+    if snapshots.get(&name.to_string())?.is_none() {
+        return Ok(false);
+    }
+
+    snapshots.remove(&name.to_string())?;
+    Ok(true)
+}
+
+/// Point a stored snapshot record to the commit that `ids` maps its
+/// commit to. Other records are returned as they are.
+pub(crate) fn remap_record(
+    key: &RecordKey,
+    value: &[u8],
+    ids: &HashMap<CommitId, CommitId>,
+) -> Vec<u8> {
+    if key.field != Snapshots::NAME {
+        return value.to_vec();
+    }
+
+    let Ok(mut snapshot) = rmp_serde::from_slice::<NamedSnapshot>(value) else {
+        return value.to_vec();
+    };
+    match ids.get(&snapshot.commit) {
+        Some(id) => {
+            snapshot.commit = *id;
+            rmp_serde::to_vec(&snapshot).unwrap_or_else(|_| value.to_vec())
+        }
+        None => value.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn create_list_delete() {
+        use super::{create, delete, get, list};
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = || {
+            UsernamePassword::with_credentials("snapshots".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+        stash.index().tree.insert_directory("data").unwrap();
+        stash.commit(None).unwrap();
+        let commit = stash.commit_list().last().unwrap().id;
+
+        assert!(create(&stash, "before-upgrade", commit).unwrap());
+        assert!(!create(&stash, "before-upgrade", commit).unwrap());
+        stash.commit(None).unwrap();
+
+        let stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        stash.load_all().unwrap();
+        assert_eq!(
+            get(&stash, "before-upgrade").unwrap().unwrap().commit,
+            commit
+        );
+        assert_eq!(list(&stash).unwrap().len(), 1);
+
+        assert!(delete(&stash, "before-upgrade").unwrap());
+        assert!(!delete(&stash, "before-upgrade").unwrap());
+        assert!(list(&stash).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! Record the size of objects a stash was created with
+//!
+//! A stash records the size of its objects in a [`CustomField`] of the
+//! index when it's created, either the size it was configured with, or
+//! the default [`BLOCK_SIZE`]. Opening a stash validates the recorded
+//! size, and buffers, chunk size checks, and download estimates are
+//! sized by it.
+//!
+//! The storage writer still fills objects of [`BLOCK_SIZE`], so only
+//! stashes of that size can be written by this build. Stashes of other
+//! sizes can be opened and read.
+//!
+//! Stashes without a record were created before it was kept, with the
+//! same object size as the default.
+//!
+//! The layout also records the chunk format a stash needs, once it uses
+//! a feature that changes how chunks are stored, like
+//! [`dictionary`](crate::dictionary) compression. Builds that only know
+//! older formats refuse to open it, instead of returning chunks they
+//! can't decode.
+use crate::{
+    custom_fields::{CustomField, CustomFieldError},
+    Files,
+};
+use infinitree::{Infinitree, BLOCK_SIZE};
+use thiserror::Error;
+
+/// The smallest object size a stash can use
+pub const MIN_OBJECT_SIZE: u64 = 1024 * 1024;
+/// The largest object size a stash can use
+pub const MAX_OBJECT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The newest chunk format this build can read
+pub const FORMAT_VERSION: u64 = 2;
+
+const OBJECT_SIZE: &str = "object_size";
+const FORMAT: &str = "format";
+
+struct Layout;
+
+impl CustomField for Layout {
+    const NAME: &'static str = "layout";
+    type Key = String;
+    type Value = u64;
+}
+
+#[derive(Debug, Error)]
+pub enum ObjectSizeError {
+    #[error("the object size must be a power of two between 1 MiB and 64 MiB, not {size} bytes")]
+    Invalid { size: u64 },
+    #[error(
+        "the stash uses {recorded} byte objects, but this build only writes {} byte objects",
+        BLOCK_SIZE
+    )]
+    Unsupported { recorded: u64 },
+    #[error("the stash was created with {recorded} byte objects, not {requested}")]
+    Mismatch { recorded: u64, requested: u64 },
+    #[error(
+        "the stash uses chunk format {recorded}, but this build only reads up to {}",
+        FORMAT_VERSION
+    )]
+    NewerFormat { recorded: u64 },
+    #[error(transparent)]
+    CustomField(#[from] CustomFieldError),
+}
+
+/// The object size recorded in the stash
+pub fn get(stash: &Infinitree<Files>) -> Result<Option<u64>, ObjectSizeError> {
+    Ok(stash
+        .index()
+        .custom_fields
+        .get::<Layout>()
+        .get(&OBJECT_SIZE.to_string())?)
+}
+
+/// The size of the objects of the stash
+pub fn size(stash: &Infinitree<Files>) -> Result<usize, ObjectSizeError> {
+    Ok(get(stash)?.map_or(BLOCK_SIZE, |size| size as usize))
+}
+
+/// Check that `size` can be used as an object size.
+pub fn validate(size: u64) -> Result<(), ObjectSizeError> {
+    if !size.is_power_of_two() || !(MIN_OBJECT_SIZE..=MAX_OBJECT_SIZE).contains(&size) {
+        return Err(ObjectSizeError::Invalid { size });
+    }
+
+    Ok(())
+}
+
+/// Record the object size in a stash that doesn't have it yet, and
+/// make sure the stash can be written to.
+///
+/// A new stash is created with `requested`, or the default size. The
+/// size of an existing stash can't be changed. The caller is
+/// responsible for committing the changes.
+pub fn record(stash: &Infinitree<Files>, requested: Option<u64>) -> Result<(), ObjectSizeError> {
+    let recorded = match get(stash)? {
+        Some(recorded) => recorded,
+        None if stash.commit_list().is_empty() => requested.unwrap_or(BLOCK_SIZE as u64),
+        None => BLOCK_SIZE as u64,
+    };
+
+    validate(recorded)?;
+    if let Some(requested) = requested.filter(|r| *r != recorded) {
+        return Err(ObjectSizeError::Mismatch {
+            recorded,
+            requested,
+        });
+    }
+    if recorded != BLOCK_SIZE as u64 {
+        return Err(ObjectSizeError::Unsupported { recorded });
+    }
+
+    if get(stash)?.is_none() {
+        stash
+            .index()
+            .custom_fields
+            .get::<Layout>()
+            .insert(&OBJECT_SIZE.to_string(), &recorded)?;
+    }
+
+    Ok(())
+}
+
+/// The chunk format the stash needs, `1` if it doesn't record one
+pub fn format(stash: &Infinitree<Files>) -> Result<u64, ObjectSizeError> {
+    Ok(stash
+        .index()
+        .custom_fields
+        .get::<Layout>()
+        .get(&FORMAT.to_string())?
+        .unwrap_or(1))
+}
+
+/// Record that reading the stash needs at least chunk format
+/// `version`.
+///
+/// The caller is responsible for committing the changes.
+pub fn require_format(stash: &Infinitree<Files>, version: u64) -> Result<(), ObjectSizeError> {
+    if format(stash)? < version {
+        stash
+            .index()
+            .custom_fields
+            .get::<Layout>()
+            .insert(&FORMAT.to_string(), &version)?;
+    }
+
+    Ok(())
+}
+
+/// Check that the objects of the stash can be read by this build.
+pub fn check(stash: &Infinitree<Files>) -> Result<(), ObjectSizeError> {
+    let recorded = format(stash)?;
+    if recorded > FORMAT_VERSION {
+        return Err(ObjectSizeError::NewerFormat { recorded });
+    }
+
+    match get(stash)? {
+        Some(recorded) => validate(recorded),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn record_and_check() {
+        use super::{check, get, record, size, Layout, OBJECT_SIZE};
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree, BLOCK_SIZE};
+
+        let key = UsernamePassword::with_credentials("layout".to_string(), "password".to_string())
+            .unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+
+        assert_eq!(get(&stash).unwrap(), None);
+        check(&stash).unwrap();
+
+        // sizes that aren't valid are refused before anything's recorded
+        assert!(record(&stash, Some(3 * 1024 * 1024)).is_err());
+        assert!(record(&stash, Some(1024)).is_err());
+        assert_eq!(get(&stash).unwrap(), None);
+
+        record(&stash, Some(BLOCK_SIZE as u64)).unwrap();
+        assert_eq!(get(&stash).unwrap(), Some(BLOCK_SIZE as u64));
+        assert!(record(&stash, Some(BLOCK_SIZE as u64 * 2)).is_err());
+        record(&stash, None).unwrap();
+
+        // a stash with larger objects can be read, but not written
+        stash
+            .index()
+            .custom_fields
+            .get::<Layout>()
+            .insert(&OBJECT_SIZE.to_string(), &(BLOCK_SIZE as u64 * 2))
+            .unwrap();
+        check(&stash).unwrap();
+        assert_eq!(size(&stash).unwrap(), BLOCK_SIZE * 2);
+        assert!(record(&stash, None).is_err());
+
+        stash
+            .index()
+            .custom_fields
+            .get::<Layout>()
+            .insert(&OBJECT_SIZE.to_string(), &12345)
+            .unwrap();
+        assert!(check(&stash).is_err());
+    }
+
+    #[test]
+    fn newer_formats_are_refused() {
+        use super::{check, format, require_format, FORMAT_VERSION};
+        use crate::Files;
+        use infinitree::{crypto::UsernamePassword, Infinitree};
+
+        let key = UsernamePassword::with_credentials("format".to_string(), "password".to_string())
+            .unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+
+        assert_eq!(format(&stash).unwrap(), 1);
+        require_format(&stash, FORMAT_VERSION).unwrap();
+        require_format(&stash, 1).unwrap();
+        assert_eq!(format(&stash).unwrap(), FORMAT_VERSION);
+        check(&stash).unwrap();
+
+        require_format(&stash, FORMAT_VERSION + 1).unwrap();
+        assert!(check(&stash).is_err());
+    }
+}
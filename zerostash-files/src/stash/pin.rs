@@ -0,0 +1,230 @@
+//! Keep the data of paths and commits from being pruned
+//!
+//! A pin records the objects that hold the files it protects, and
+//! prune never tombstones or deletes them.
+//!
+//! A commit pin covers the tree of a single commit. A path pin covers
+//! every version of the files under the path, in any commit. Before
+//! each prune, the commits added since the last scan are checked for
+//! new versions, so files that were replaced in the meantime stay
+//! protected too.
+use crate::{files::normalize_filename, Files};
+use infinitree::{
+    object::ObjectId,
+    tree::{CommitFilter, CommitId},
+    Infinitree,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PinTarget {
+    /// A path in the tree, and everything under it
+    Path(String),
+    Commit(CommitId),
+}
+
+impl PinTarget {
+    /// Pin `path`, as it would be stored by a commit.
+    pub fn path(path: &str) -> anyhow::Result<Self> {
+        Ok(Self::Path(normalize_filename(&path)?))
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        match self {
+            Self::Path(pinned) => {
+                pinned.is_empty()
+                    || path
+                        .strip_prefix(pinned.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+            Self::Commit(_) => true,
+        }
+    }
+}
+
+/// Pins are keyed by their target in this format in the index.
+impl fmt::Display for PinTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "path:/{path}"),
+            Self::Commit(id) => write!(f, "commit:{id:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pin {
+    pub target: PinTarget,
+    /// Unix timestamp of when the pin was added
+    pub created_at: u64,
+    /// The last commit that was checked for files of the target
+    pub scanned: Option<CommitId>,
+    /// Objects that hold the files of the target
+    pub objects: HashSet<ObjectId>,
+}
+
+impl Pin {
+    /// The commits of `commits` that have to be checked, in order.
+    fn pending<'a>(&self, commits: &'a [CommitId]) -> &'a [CommitId] {
+        let scanned = self
+            .scanned
+            .and_then(|id| commits.iter().position(|c| *c == id));
+
+        match (&self.target, scanned) {
+            (PinTarget::Commit(_), Some(_)) => &[],
+            (PinTarget::Commit(id), None) => match commits.iter().position(|c| c == id) {
+                Some(n) => &commits[n..=n],
+                None => &[],
+            },
+            (PinTarget::Path(_), Some(n)) => &commits[n + 1..],
+            (PinTarget::Path(_), None) => commits,
+        }
+    }
+}
+
+/// Add a pin for `target`. Returns false if it's pinned already.
+///
+/// The pin doesn't protect anything until [`update`] is called. The
+/// caller is responsible for committing the changes.
+pub fn add(stash: &Infinitree<Files>, target: PinTarget) -> bool {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut added = false;
+    stash.index().pins.insert_with(target.to_string(), || {
+        added = true;
+        Pin {
+            target,
+            created_at,
+            scanned: None,
+            objects: HashSet::new(),
+        }
+    });
+
+    added
+}
+
+/// Remove the pin with `key`. Returns false if there's no such pin.
+pub fn remove(stash: &Infinitree<Files>, key: &str) -> bool {
+    let pins = &stash.index().pins;
+    let found = pins.contains(&key.to_string());
+    if found {
+        pins.remove(key.to_string());
+    }
+
+    found
+}
+
+pub fn list(stash: &Infinitree<Files>) -> Vec<Pin> {
+    let mut pins = vec![];
+    stash.index().pins.for_each(|_, pin| pins.push(pin.clone()));
+    pins.sort_by_key(|pin| pin.target.to_string());
+    pins
+}
+
+/// All objects that are protected by a pin
+pub fn pinned_objects(stash: &Infinitree<Files>) -> HashSet<ObjectId> {
+    let mut objects = HashSet::new();
+    stash
+        .index()
+        .pins
+        .for_each(|_, pin| objects.extend(pin.objects.iter().copied()));
+    objects
+}
+
+/// Scan the commits that were added since the pins were last updated.
+///
+/// The tree of every commit that needs checking is loaded in turn
+/// using `load`, then the latest one is loaded again. Returns the
+/// number of commits that were scanned. The caller is responsible for
+/// committing the changes.
+pub fn update(
+    stash: &mut Infinitree<Files>,
+    mut load: impl FnMut(&mut Infinitree<Files>),
+) -> usize {
+    let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+    let mut pins = list(stash);
+
+    let pending = commits
+        .iter()
+        .filter(|commit| {
+            pins.iter()
+                .any(|pin| pin.pending(&commits).contains(commit))
+        })
+        .copied()
+        .collect::<Vec<_>>();
+
+    if pending.is_empty() {
+        return 0;
+    }
+
+    for commit in pending.iter() {
+        let due = pins
+            .iter()
+            .map(|pin| pin.pending(&commits).contains(commit))
+            .collect::<Vec<_>>();
+
+        reload(stash, CommitFilter::UpTo(*commit), &mut load);
+
+        for (path, entry) in stash.index().tree.iter_files() {
+            for (pin, _) in pins.iter_mut().zip(due.iter()).filter(|(_, due)| **due) {
+                if pin.target.covers(&path) {
+                    pin.objects
+                        .extend(entry.chunks.values().map(|c| *c.object_id()));
+                }
+            }
+        }
+
+        for (pin, _) in pins.iter_mut().zip(due.iter()).filter(|(_, due)| **due) {
+            pin.scanned = Some(*commit);
+        }
+        debug!(?commit, "scanned commit for pins");
+    }
+
+    reload(stash, CommitFilter::All, &mut load);
+
+    let index = stash.index();
+    for pin in pins {
+        index.pins.update_with(pin.target.to_string(), |_| pin);
+    }
+
+    pending.len()
+}
+
+fn reload(
+    stash: &mut Infinitree<Files>,
+    filter: CommitFilter,
+    load: &mut impl FnMut(&mut Infinitree<Files>),
+) {
+    stash.filter_commits(filter);
+
+    let index = stash.index();
+    index.tree.clear().unwrap();
+    index.files.clear();
+    index.zfs_snapshots.clear();
+    load(stash);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn path_pins_cover_subtrees() {
+        use super::PinTarget;
+
+        let pin = PinTarget::path("/legal/2019").unwrap();
+        assert_eq!(pin.to_string(), "path:/legal/2019");
+        assert!(pin.covers("legal/2019"));
+        assert!(pin.covers("legal/2019/contract.pdf"));
+        assert!(!pin.covers("legal/20190"));
+        assert!(!pin.covers("legal"));
+        assert!(PinTarget::path("/").unwrap().covers("anything"));
+    }
+}
@@ -0,0 +1,212 @@
+//! Remove objects that no file uses anymore
+//!
+//! Pruning happens in two passes, so a mistake can be caught before
+//! any data is lost. First, objects that hold no chunk used by a file
+//! are tombstoned: their chunks are dropped from the chunk index, so
+//! new commits don't deduplicate against them, and the object is
+//! recorded in the index along with the time it was pruned. The
+//! object itself stays in the backend, so older commits can still be
+//! checked out.
+//!
+//! A later pass expires the tombstones that are older than a grace
+//! period, and deletes their objects from the backend.
+//!
+//! Only objects that hold chunks in the chunk index are considered,
+//! so the index itself and ZFS snapshot streams are never pruned.
+//! Objects protected by a [pin](crate::pin) are kept, too.
+use crate::{pin::pinned_objects, Files};
+use infinitree::{
+    object::ObjectId,
+    tree::{CommitFilter, CommitId},
+    Digest, Infinitree,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::debug;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Unix timestamp of the prune that removed the object
+    pub deleted_at: u64,
+}
+
+#[derive(Default, Debug)]
+pub struct Report {
+    /// Objects that were tombstoned
+    pub objects: Vec<ObjectId>,
+    /// Chunks dropped from the chunk index
+    pub chunks: usize,
+}
+
+/// Space held by a single commit
+#[derive(Debug)]
+pub struct Attribution {
+    pub commit: CommitId,
+    /// Files in the tree of the commit
+    pub files: usize,
+    /// Chunks that no other commit uses
+    pub unique_chunks: usize,
+    /// Stored size of the unique chunks, which would be freed if the
+    /// commit was dropped
+    pub unique_bytes: u64,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Tombstone all objects that hold no chunk used by the currently
+/// loaded tree, and are not pinned.
+///
+/// The caller is responsible for committing the changes.
+pub fn prune(stash: &Infinitree<Files>, now: SystemTime) -> Report {
+    let index = stash.index();
+
+    let mut used = pinned_objects(stash);
+    for (_, entry) in index.tree.iter_files() {
+        used.extend(entry.chunks.values().map(|c| *c.object_id()));
+    }
+
+    let mut chunks: HashMap<ObjectId, Vec<Digest>> = HashMap::new();
+    index.chunks.for_each(|digest, pointer| {
+        chunks
+            .entry(*pointer.object_id())
+            .or_default()
+            .push(*digest);
+    });
+
+    let tombstone = Tombstone {
+        deleted_at: unix_secs(now),
+    };
+
+    let mut report = Report::default();
+    for (id, digests) in chunks {
+        if used.contains(&id) {
+            continue;
+        }
+
+        debug!(%id, chunks = digests.len(), "tombstoning object");
+        for digest in digests.iter() {
+            index.chunks.remove(*digest);
+        }
+        index.tombstones.insert(id, tombstone.clone());
+
+        report.chunks += digests.len();
+        report.objects.push(id);
+    }
+
+    report
+}
+
+/// Count how much space every commit holds on its own.
+///
+/// The tree of every commit is loaded in turn using `load`, after
+/// which the tree of the last commit stays loaded. This reads the whole
+/// index once per commit, so it's slow on long histories.
+pub fn attribute(
+    stash: &mut Infinitree<Files>,
+    mut load: impl FnMut(&mut Infinitree<Files>),
+) -> Vec<Attribution> {
+    let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+
+    let mut used: HashMap<Digest, (usize, u64)> = HashMap::new();
+    let mut trees = vec![];
+
+    for commit in commits.iter() {
+        stash.filter_commits(CommitFilter::UpTo(*commit));
+
+        let index = stash.index();
+        index.tree.clear().unwrap();
+        index.files.clear();
+        index.zfs_snapshots.clear();
+        load(stash);
+
+        let mut files = 0;
+        let mut chunks = HashSet::new();
+        for (_, entry) in stash.index().tree.iter_files() {
+            files += 1;
+            for (_, pointer) in entry.chunks.iter() {
+                if chunks.insert(*pointer.hash()) {
+                    used.entry(*pointer.hash())
+                        .or_insert((0, pointer.size() as u64))
+                        .0 += 1;
+                }
+            }
+        }
+
+        debug!(?commit, files, chunks = chunks.len(), "counted commit");
+        trees.push((files, chunks));
+    }
+
+    commits
+        .into_iter()
+        .zip(trees)
+        .map(|(commit, (files, chunks))| {
+            let unique = chunks
+                .iter()
+                .filter_map(|digest| used.get(digest).filter(|(refs, _)| *refs == 1))
+                .map(|(_, size)| size);
+
+            let (unique_chunks, unique_bytes) =
+                unique.fold((0, 0), |(n, bytes), size| (n + 1, bytes + size));
+
+            Attribution {
+                commit,
+                files,
+                unique_chunks,
+                unique_bytes,
+            }
+        })
+        .collect()
+}
+
+/// Objects that were tombstoned more than `grace` before `now`, and
+/// may be deleted.
+///
+/// If a chunk in the index points to a tombstoned object again, the
+/// tombstone is dropped instead. The same happens if the object was
+/// pinned since.
+pub fn expired(stash: &Infinitree<Files>, grace: Duration, now: SystemTime) -> Vec<ObjectId> {
+    let index = stash.index();
+    let deadline = unix_secs(now).saturating_sub(grace.as_secs());
+
+    let mut live = pinned_objects(stash);
+    index.chunks.for_each(|_, pointer| {
+        live.insert(*pointer.object_id());
+    });
+
+    let mut expired = vec![];
+    let mut resurrected = vec![];
+    index.tombstones.for_each(|id, tombstone| {
+        if live.contains(id) {
+            resurrected.push(*id);
+        } else if tombstone.deleted_at <= deadline {
+            expired.push(*id);
+        }
+    });
+
+    for id in resurrected {
+        debug!(%id, "object is used again, dropping tombstone");
+        index.tombstones.remove(id);
+    }
+
+    expired
+}
+
+/// Delete `objects` from the backend, and forget their tombstones.
+///
+/// The caller is responsible for committing the changes.
+pub fn expire(stash: &Infinitree<Files>, objects: &[ObjectId]) -> anyhow::Result<()> {
+    stash.backend().delete(objects)?;
+
+    for id in objects {
+        stash.index().tombstones.remove(*id);
+    }
+
+    Ok(())
+}
@@ -1,19 +1,74 @@
-use crate::{files, Files};
+use crate::{
+    chunk_index::digest_to_hex,
+    crypto_error::{read_chunk, CryptoError},
+    dictionary,
+    disk_space::Needed,
+    files,
+    heartbeat::Heartbeat,
+    id_map::IdMap,
+    interrupt::{Interrupt, Interrupted},
+    roots::Destination,
+    userns::Ownership,
+    Files,
+};
+use anyhow::Context;
 use flume as mpsc;
 use futures::future::join_all;
 use infinitree::{fields::QueryAction, object, Infinitree, *};
 use memmap2::MmapOptions;
-use std::{env, path::PathBuf, sync::Arc};
-use tokio::task;
-use tracing::{error, trace};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task,
+};
+use tracing::{error, trace, warn, Instrument, Span};
 
 type ThreadWork = (PathBuf, Arc<files::Entry>);
 
 type Sender = mpsc::Sender<ThreadWork>;
 type Receiver = mpsc::Receiver<ThreadWork>;
 
+/// Number of files to announce to the backend before they're restored
+const PRELOAD_AHEAD: usize = 16;
+
 pub type FileIterator<'a> = Box<(dyn Iterator<Item = (String, Arc<files::Entry>)> + Send + 'a)>;
 
+/// What a restore would need to read from the backend
+#[derive(Debug, Default)]
+pub struct Estimate {
+    /// Number of files to restore
+    pub files: usize,
+    /// Total size of the restored files
+    pub bytes: u64,
+    /// Number of distinct chunks to read
+    pub chunks: usize,
+    /// Total size of the distinct chunks
+    pub chunk_bytes: u64,
+    /// Number of objects that hold the chunks
+    pub objects: usize,
+    /// Objects that are already in the local cache
+    pub cached_objects: usize,
+    /// Size of the objects of the stash
+    pub object_size: usize,
+}
+
+impl Estimate {
+    /// Expected download size. Objects are always fetched whole.
+    pub fn transfer_bytes(&self) -> u64 {
+        ((self.objects - self.cached_objects) * self.object_size) as u64
+    }
+}
+
 #[derive(clap::Args, Debug, Clone, Default)]
 pub struct Options {
     /// List of globs to match in the database
@@ -43,6 +98,56 @@ pub struct Options {
     #[cfg(target_family = "unix")]
     #[clap(short = 'C', long = "chroot")]
     pub chroot: Option<PathBuf>,
+
+    /// Translate owner uids using a mapping file with `SOURCE TARGET` lines.
+    /// Unmapped uids are restored as the current user.
+    #[cfg(target_family = "unix")]
+    #[clap(long = "uid-map", value_name = "FILE")]
+    pub uid_map: Option<PathBuf>,
+
+    /// Translate group ids using a mapping file with `SOURCE TARGET` lines.
+    /// Unmapped gids are restored as the current group.
+    #[cfg(target_family = "unix")]
+    #[clap(long = "gid-map", value_name = "FILE")]
+    pub gid_map: Option<PathBuf>,
+
+    /// Write `UID GID PATH` lines to FILE for files whose owner can't be restored,
+    /// eg. when running without root, or in a rootless container.
+    #[cfg(target_family = "unix")]
+    #[clap(long = "ownership-record", value_name = "FILE")]
+    pub ownership_record: Option<PathBuf>,
+
+    /// Fetch up to this many chunks of a single file at the same time.
+    /// Speeds up restoring large files from remote backends.
+    #[clap(long = "file-concurrency", value_name = "N", default_value_t = 1)]
+    pub file_concurrency: usize,
+
+    /// Restore files in path order, and write at most N files to each file system at
+    /// the same time. Use 1 to avoid seek storms on spinning disks.
+    #[clap(long = "writers-per-device", value_name = "N")]
+    pub writers_per_device: Option<NonZeroUsize>,
+
+    /// Flush restored files to disk in batches of N files on each worker.
+    #[clap(long = "fsync-batch", value_name = "N")]
+    pub fsync_batch: Option<NonZeroUsize>,
+
+    /// Hash every chunk again after it's written. Files that don't match
+    /// the stash are reported, and the restore fails at the end.
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Where to put the restored files
+    #[clap(skip)]
+    pub destination: Destination,
+
+    /// Stop queuing files when triggered. Files that are in progress
+    /// are finished, then [`Interrupted`] is returned.
+    #[clap(skip)]
+    pub interrupt: Interrupt,
+
+    /// Beats for every file that's queued or restored
+    #[clap(skip)]
+    pub heartbeat: Heartbeat,
 }
 
 fn iter<V: AsRef<[T]>, T: AsRef<str>>(stash: &Infinitree<Files>, glob: V) -> FileIterator {
@@ -102,25 +207,171 @@ impl Options {
         })
     }
 
+    /// Estimate the size of a restore without writing anything.
+    ///
+    /// `cached` returns whether an object can be read without
+    /// downloading it.
+    pub fn estimate(
+        &self,
+        stash: &Infinitree<Files>,
+        cached: impl Fn(&object::ObjectId) -> bool,
+    ) -> Estimate {
+        let mut estimate = Estimate::default();
+        let mut chunks = HashSet::new();
+        let mut objects = HashSet::new();
+
+        for (_, entry) in self.list(stash) {
+            estimate.files += 1;
+            estimate.bytes += entry.size;
+
+            for cp in entry.chunks.values() {
+                if chunks.insert(*cp.hash()) {
+                    estimate.chunk_bytes += cp.size() as u64;
+                }
+                objects.insert(*cp.object_id());
+            }
+        }
+
+        estimate.chunks = chunks.len();
+        estimate.objects = objects.len();
+        estimate.cached_objects = objects.iter().filter(|id| cached(id)).count();
+        estimate.object_size = crate::object_size::size(stash).unwrap_or(BLOCK_SIZE);
+
+        estimate
+    }
+
     pub async fn from_iter(
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
     ) -> anyhow::Result<u64> {
+        // mapping files are relative to the original working directory
+        let id_maps = self.id_maps()?;
+        let ownership = Arc::new(self.ownership()?);
         self.setup_env()?;
-        let (sender, workers) = self.start_workers(stash, threads)?;
+        let mismatches = Arc::new(AtomicUsize::new(0));
+        let (sender, workers) = self.start_workers(
+            stash,
+            threads,
+            id_maps.clone(),
+            ownership.clone(),
+            mismatches.clone(),
+        )?;
+
+        // symlinks are created last, so nothing is restored through one
+        let (links, mut files): (Vec<_>, Vec<_>) = self
+            .list(stash)
+            .partition(|(_, md)| md.file_type.is_symlink());
+
+        self.check_space(&files)?;
+
+        // files of a directory are written one after the other
+        if self.writers_per_device.is_some() {
+            files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
 
-        for (path, md) in self.list(stash) {
+        preload(stash, files.iter().take(PRELOAD_AHEAD));
+        for (i, (path, md)) in files.iter().enumerate() {
+            if self.interrupt.is_triggered() {
+                break;
+            }
+            self.heartbeat.beat();
+            preload(stash, files.get(i + PRELOAD_AHEAD));
+
+            let path = self.destination.path(path);
             trace!(?path, "queued");
-            sender.send_async((path.into(), md)).await.unwrap();
+            if sender.send_async((path, Arc::clone(md))).await.is_err() {
+                // every worker stopped on an error
+                break;
+            }
         }
 
         drop(sender);
-        join_all(workers).await;
+        for result in join_all(workers).await {
+            result??;
+        }
+
+        if self.interrupt.is_triggered() {
+            return Err(Interrupted.into());
+        }
+
+        // parents of everything that was restored, before `links` is used up
+        let mut parents = HashSet::new();
+        for (path, _) in files.iter().chain(links.iter()) {
+            let mut path = path.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                if !parents.insert(parent.to_string()) {
+                    break;
+                }
+                path = parent;
+            }
+        }
+
+        for (path, md) in links {
+            let path = self.destination.path(&path);
+            let md = id_maps.apply(md);
+            let preserve = preserve_for(&self.preserve, &ownership, &path, &md)?;
+            if let Err(error) = md.restore_to(&path, &preserve) {
+                error!(%error, ?path, "failed to restore symlink");
+
+                if !self.force {
+                    return Err(error.into());
+                }
+            }
+        }
+
+        // writing files changes the times of their directory, so the
+        // metadata of directories is restored last, children first
+        let mut dirs = stash.index().tree.directories();
+        dirs.retain(|(path, _)| parents.contains(path));
+        dirs.sort_by(|(a, _), (b, _)| b.cmp(a));
+        for (path, md) in dirs {
+            let path = self.destination.path(&path);
+            let md = id_maps.apply(md);
+            let preserve = preserve_for(&self.preserve, &ownership, &path, &md)?;
+            if let Err(error) = md.restore_to(&path, &preserve) {
+                error!(%error, ?path, "failed to restore directory metadata");
+
+                if !self.force {
+                    return Err(error.into());
+                }
+            }
+        }
+
+        ownership.flush()?;
+
+        // the rest is restored even if some files don't match, so the
+        // files that do are usable
+        let mismatches = mismatches.load(Ordering::SeqCst);
+        if mismatches > 0 {
+            return Err(Mismatch(mismatches).into());
+        }
 
         Ok(0)
     }
 
+    /// Refuse to start if the files won't fit where they're restored
+    /// to, or only warn about it with `force`.
+    fn check_space(&self, files: &[(String, Arc<files::Entry>)]) -> anyhow::Result<()> {
+        let mut needed = Needed::default();
+        for (path, md) in files {
+            needed.add(&self.destination.path(path), md.size)?;
+        }
+
+        let mut short = needed.check()?;
+        if self.force {
+            for error in short {
+                warn!(%error, "restoring anyway");
+            }
+            return Ok(());
+        }
+
+        match short.pop() {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+
     #[cfg(unix)]
     fn setup_env(&self) -> anyhow::Result<()> {
         if let Some(ref path) = self.chroot {
@@ -134,6 +385,19 @@ impl Options {
         Ok(())
     }
 
+    #[cfg(unix)]
+    fn id_maps(&self) -> anyhow::Result<IdMaps> {
+        Ok(IdMaps {
+            uid: self.uid_map.as_ref().map(IdMap::uid_map_file).transpose()?,
+            gid: self.gid_map.as_ref().map(IdMap::gid_map_file).transpose()?,
+        })
+    }
+
+    #[cfg(windows)]
+    fn id_maps(&self) -> anyhow::Result<IdMaps> {
+        Ok(IdMaps::default())
+    }
+
     #[cfg(windows)]
     fn setup_env(&self) -> anyhow::Result<()> {
         if let Some(ref path) = self.chdir {
@@ -143,39 +407,194 @@ impl Options {
         Ok(())
     }
 
+    #[cfg(unix)]
+    fn ownership(&self) -> anyhow::Result<Ownership> {
+        Ok(Ownership::new(self.ownership_record.as_deref())?)
+    }
+
+    #[cfg(windows)]
+    fn ownership(&self) -> anyhow::Result<Ownership> {
+        Ok(Ownership::new(None)?)
+    }
+
     fn start_workers(
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
-    ) -> anyhow::Result<(Sender, Vec<task::JoinHandle<()>>)> {
-        let mut preserve = self.preserve.clone();
+        id_maps: IdMaps,
+        ownership: Arc<Ownership>,
+        mismatches: Arc<AtomicUsize>,
+    ) -> anyhow::Result<(Sender, Vec<task::JoinHandle<anyhow::Result<()>>>)> {
+        let (sender, receiver) = mpsc::bounded(threads);
+        let worker = Worker {
+            force: self.force,
+            preserve: self.preserve.clone(),
+            id_maps,
+            ownership,
+            scheduler: Arc::new(Scheduler {
+                writers_per_device: self.writers_per_device.map(NonZeroUsize::get),
+                devices: Default::default(),
+            }),
+            fsync_batch: self.fsync_batch.map(NonZeroUsize::get),
+            verify: match self.verify {
+                true => Some(Verifier {
+                    hasher: crate::digest_key::hasher(stash)?,
+                    mismatches,
+                }),
+                false => None,
+            },
+            heartbeat: self.heartbeat.clone(),
+            dictionaries: stash.index().dictionaries.clone(),
+        };
 
-        #[cfg(not(target_os = "windows"))]
-        if !nix::unistd::Uid::effective().is_root() {
-            preserve.ownership = false;
-        }
+        let mut workers = vec![];
+        for _ in 0..threads {
+            let readers = (0..self.file_concurrency.max(1))
+                .map(|_| stash.storage_reader())
+                .collect::<Result<Vec<_>, _>>()?;
 
-        let (sender, receiver) = mpsc::bounded(threads);
-        let workers = (0..threads)
-            .map(|_| {
-                task::spawn(process_packet_loop(
-                    self.force,
-                    preserve.clone(),
-                    receiver.clone(),
-                    stash.storage_reader().unwrap(),
-                ))
-            })
-            .collect::<Vec<_>>();
+            workers.push(task::spawn(
+                process_packet_loop(worker.clone(), receiver.clone(), readers).in_current_span(),
+            ));
+        }
         Ok((sender, workers))
     }
 }
 
-async fn process_packet_loop(
+#[derive(Clone, Debug, Default)]
+struct IdMaps {
+    uid: Option<IdMap>,
+    gid: Option<IdMap>,
+}
+
+impl IdMaps {
+    fn apply(&self, entry: Arc<files::Entry>) -> Arc<files::Entry> {
+        if self.uid.is_none() && self.gid.is_none() {
+            return entry;
+        }
+
+        let mut entry = entry.as_ref().clone();
+        if let Some(ref uids) = self.uid {
+            entry.unix_uid = entry.unix_uid.map(|id| uids.map(id));
+        }
+        if let Some(ref gids) = self.gid {
+            entry.unix_gid = entry.unix_gid.map(|id| gids.map(id));
+        }
+
+        Arc::new(entry)
+    }
+}
+
+/// What to restore of `entry`. The owner is only restored if the
+/// process is allowed to.
+fn preserve_for(
+    preserve: &files::PreserveMetadata,
+    ownership: &Ownership,
+    path: &Path,
+    entry: &files::Entry,
+) -> std::io::Result<files::PreserveMetadata> {
+    let mut preserve = preserve.clone();
+    if preserve.ownership {
+        preserve.ownership = ownership.allows(path, entry)?;
+    }
+
+    Ok(preserve)
+}
+
+/// Hint the backend about the objects we're going to read.
+fn preload<'a>(
+    stash: &Infinitree<Files>,
+    files: impl IntoIterator<Item = &'a (String, Arc<files::Entry>)>,
+) {
+    let mut objects = files
+        .into_iter()
+        .flat_map(|(_, entry)| entry.chunks.values().map(|cp| *cp.object_id()))
+        .collect::<Vec<_>>();
+    objects.dedup();
+
+    if !objects.is_empty() {
+        _ = stash.backend().preload(&objects);
+    }
+}
+
+/// Restored files whose contents don't match the stash
+#[derive(Debug, thiserror::Error)]
+#[error("{0} restored files don't match the stash")]
+pub struct Mismatch(pub usize);
+
+/// Settings shared by all workers
+#[derive(Clone)]
+struct Worker {
     force: bool,
     preserve: files::PreserveMetadata,
+    id_maps: IdMaps,
+    ownership: Arc<Ownership>,
+    scheduler: Arc<Scheduler>,
+    fsync_batch: Option<usize>,
+    verify: Option<Verifier>,
+    heartbeat: Heartbeat,
+    dictionaries: dictionary::Dictionaries,
+}
+
+/// Checks restored files against the chunks in the stash
+#[derive(Clone)]
+struct Verifier {
+    hasher: Hasher,
+    mismatches: Arc<AtomicUsize>,
+}
+
+impl Verifier {
+    /// Hash the chunks of `entry` in the restored contents `buf`.
+    ///
+    /// Returns what's wrong with the first chunk that doesn't match.
+    fn check(&mut self, entry: &files::Entry, buf: &[u8]) -> Result<(), String> {
+        let mut chunks = entry.chunks.iter().peekable();
+        if chunks.peek().is_some_and(|(start, _)| **start != 0) {
+            return Err("the start of the file is not in any chunk".into());
+        }
+
+        while let Some((start, cp)) = chunks.next() {
+            // chunks are stored compressed, so only the next offset
+            // tells where this one ends
+            let start = *start as usize;
+            let end = chunks.peek().map_or(buf.len(), |(next, _)| **next as usize);
+            let data = buf
+                .get(start..end)
+                .ok_or_else(|| format!("chunk at {start} is past the end of the file"))?;
+
+            self.hasher.reset();
+            self.hasher.update(data);
+            let digest = *self.hasher.finalize().as_bytes();
+            if &dictionary::stored_digest(cp, &digest) != cp.hash() {
+                return Err(format!(
+                    "chunk {} at {start} has different contents",
+                    digest_to_hex(cp.hash())
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn process_packet_loop(
+    worker: Worker,
     r: Receiver,
-    mut objreader: impl object::Reader + 'static,
-) {
+    mut readers: Vec<impl object::Reader + Send + 'static>,
+) -> anyhow::Result<()> {
+    let Worker {
+        force,
+        preserve,
+        id_maps,
+        ownership,
+        scheduler,
+        fsync_batch,
+        mut verify,
+        heartbeat,
+        dictionaries,
+    } = worker;
+    let mut unsynced = vec![];
+
     // Since resources here are all managed by RAII, and they all
     // implement Drop, we can simply go through the Arc<_>s,
     // mmap them, open the corresponding objects to extract details,
@@ -186,6 +605,12 @@ async fn process_packet_loop(
 
     // This loop is managing an mmap of a file that's written
     while let Ok((path, metadata)) = r.recv_async().await {
+        heartbeat.beat();
+        let metadata = id_maps.apply(metadata);
+        let preserve = preserve_for(&preserve, &ownership, &path, &metadata)
+            .context("Failed to write ownership record")?;
+
+        let _permit = scheduler.acquire(&path).await;
         match metadata.restore_to(&path, &preserve) {
             Ok(Some(fd)) => {
                 let mut mmap = unsafe {
@@ -195,12 +620,30 @@ async fn process_packet_loop(
                         .expect("mmap")
                 };
 
-                for (start, cp) in metadata.chunks.iter() {
-                    let start = *start as usize;
-                    objreader.read_chunk(cp, &mut mmap[start..]).unwrap();
+                if let Err(error) = read_chunks(&metadata, &mut mmap, &mut readers, &dictionaries) {
+                    error!(%error, ?path, "failed to restore file");
+
+                    if !force {
+                        return Err(error.into());
+                    }
+                    continue;
+                }
+
+                if let Some(ref mut verifier) = verify {
+                    if let Err(problem) = verifier.check(&metadata, &mmap) {
+                        error!(?path, %problem, "restored file doesn't match the stash");
+                        verifier.mismatches.fetch_add(1, Ordering::SeqCst);
+                    }
                 }
 
                 trace!(?path, "restored");
+
+                if let Some(batch) = fsync_batch {
+                    unsynced.push((path, fd));
+                    if unsynced.len() >= batch {
+                        sync_files(&mut unsynced);
+                    }
+                }
             }
             Ok(None) => {
                 trace!(?path, file_type = ?metadata.file_type, "no chunks restored for file");
@@ -209,9 +652,264 @@ async fn process_packet_loop(
                 error!(%error, ?path, "failed to restore file");
 
                 if !force {
-                    panic!("error while restoring file");
+                    return Err(error.into());
                 }
             }
         }
     }
+
+    sync_files(&mut unsynced);
+    Ok(())
+}
+
+fn sync_files(files: &mut Vec<(PathBuf, fs::File)>) {
+    for (path, file) in files.drain(..) {
+        if let Err(error) = file.sync_all() {
+            error!(%error, ?path, "failed to flush file to disk");
+        }
+    }
+}
+
+/// Limits how many files are written to each file system at once
+struct Scheduler {
+    writers_per_device: Option<usize>,
+    devices: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl Scheduler {
+    /// Wait until `path` can be written. Writing is allowed while the
+    /// permit is held.
+    async fn acquire(&self, path: &Path) -> Option<OwnedSemaphorePermit> {
+        let limit = self.writers_per_device?;
+        let semaphore = self
+            .devices
+            .lock()
+            .unwrap()
+            .entry(device_of(path))
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+/// The device of the closest existing directory above `path`
+#[cfg(unix)]
+fn device_of(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    path.ancestors()
+        .skip(1)
+        .find_map(|dir| fs::metadata(dir).ok())
+        .or_else(|| fs::metadata(".").ok())
+        .map_or(0, |md| md.dev())
+}
+
+#[cfg(windows)]
+fn device_of(_path: &Path) -> u64 {
+    0
+}
+
+/// Read the chunks of a file into `buf`.
+///
+/// With multiple readers, the chunks are split into consecutive
+/// batches, which are fetched on separate threads into disjoint parts
+/// of the buffer.
+fn read_chunks(
+    entry: &files::Entry,
+    buf: &mut [u8],
+    readers: &mut [impl object::Reader + Send],
+    dictionaries: &dictionary::Dictionaries,
+) -> Result<(), CryptoError> {
+    if let [reader] = readers {
+        for (start, cp) in entry.chunks.iter() {
+            let start = *start as usize;
+            read_chunk(reader, dictionaries, cp, &mut buf[start..])?;
+        }
+        return Ok(());
+    }
+
+    let chunks = entry.chunks.iter().collect::<Vec<_>>();
+    let batch_size = chunks.len().div_ceil(readers.len()).max(1);
+
+    thread::scope(|s| {
+        let mut rest = buf;
+        let mut rest_start = 0;
+        let mut batches = chunks.chunks(batch_size).peekable();
+        let mut workers = vec![];
+
+        for reader in readers.iter_mut() {
+            let Some(batch) = batches.next() else {
+                break;
+            };
+
+            // the batch covers everything up to the next batch's first chunk
+            let end = match batches.peek() {
+                Some(next) => *next[0].0 as usize,
+                None => rest_start + rest.len(),
+            };
+            let (region, tail) = std::mem::take(&mut rest).split_at_mut(end - rest_start);
+            let region_start = rest_start;
+            rest = tail;
+            rest_start = end;
+
+            let span = Span::current();
+            workers.push(s.spawn(move || {
+                let _entered = span.enter();
+                for (start, cp) in batch {
+                    let offset = **start as usize - region_start;
+                    read_chunk(reader, dictionaries, cp, &mut region[offset..])?;
+                }
+                Ok(())
+            }));
+        }
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn writers_are_limited_per_device() {
+        use super::Scheduler;
+        use futures::FutureExt;
+
+        let dir = std::env::temp_dir();
+        let unlimited = Scheduler {
+            writers_per_device: None,
+            devices: Default::default(),
+        };
+        assert!(unlimited.acquire(&dir.join("a")).await.is_none());
+
+        let scheduler = Scheduler {
+            writers_per_device: Some(1),
+            devices: Default::default(),
+        };
+        let first = scheduler.acquire(&dir.join("a")).await;
+        assert!(first.is_some());
+
+        // the second file on the same device waits for the first
+        let mut second = Box::pin(scheduler.acquire(&dir.join("b")));
+        assert!((&mut second).now_or_never().is_none());
+        drop(first);
+        assert!(second.await.is_some());
+    }
+
+    #[test]
+    fn chunks_are_read_in_parallel_batches() {
+        use super::read_chunks;
+        use crate::{Entry, Files};
+        use infinitree::{crypto::UsernamePassword, object::Writer, Infinitree};
+        use std::sync::Arc;
+
+        let key = UsernamePassword::with_credentials("restore".to_string(), "password".to_string())
+            .unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+
+        let parts: [&[u8]; 5] = [b"first", b"second", b"third", b"fourth", b"fifth"];
+        let mut writer = stash.storage_writer().unwrap();
+        let mut file = Entry::default();
+        for part in parts {
+            let pointer = writer.write_chunk(&rand::random(), part).unwrap();
+            file.chunks.insert(file.size, Arc::new(pointer));
+            file.size += part.len() as u64;
+        }
+        writer.flush().unwrap();
+
+        // more readers than chunks leaves some of them idle
+        for concurrency in [1, 2, 3, 8] {
+            let mut readers = (0..concurrency)
+                .map(|_| stash.storage_reader().unwrap())
+                .collect::<Vec<_>>();
+            let mut buf = vec![0; file.size as usize];
+
+            read_chunks(&file, &mut buf, &mut readers, &stash.index().dictionaries).unwrap();
+            assert_eq!(buf, parts.concat(), "{concurrency} readers");
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mismatches_finish_the_restore() {
+        use super::{Mismatch, Options};
+        use crate::{
+            files::{FileType, PreserveMetadata},
+            roots::{Destination, RootKind, RootSpec},
+            Entry, Files,
+        };
+        use infinitree::{crypto::UsernamePassword, object::Writer, Infinitree};
+        use std::{fs, os::unix::fs::PermissionsExt, path::Path, sync::Arc};
+
+        let key = UsernamePassword::with_credentials("restore".to_string(), "password".to_string())
+            .unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+        let tree = &stash.index().tree;
+
+        // the chunk is stored under a digest that doesn't match it
+        let data = b"contents";
+        let mut writer = stash.storage_writer().unwrap();
+        let pointer = writer.write_chunk(&rand::random(), data).unwrap();
+        writer.flush().unwrap();
+
+        let mut file = Entry {
+            name: "file".into(),
+            size: data.len() as u64,
+            ..Entry::default()
+        };
+        file.chunks.insert(0, Arc::new(pointer));
+        tree.insert_file("dir/file", file).unwrap();
+        let link = Entry {
+            name: "link".into(),
+            file_type: FileType::Symlink("file".into()),
+            ..Entry::default()
+        };
+        tree.insert_file("dir/link", link).unwrap();
+        let dir = Entry {
+            name: "dir".into(),
+            file_type: FileType::Directory,
+            unix_perm: Some(0o750),
+            ..Entry::default()
+        };
+        tree.set_directory_metadata("dir", dir).unwrap();
+        stash.commit(None).unwrap();
+
+        let target =
+            std::env::temp_dir().join(format!("zerostash-restore-{}", rand::random::<u64>()));
+        let root = RootSpec {
+            kind: RootKind::Relative,
+            stored: String::new(),
+            original: target.clone(),
+        };
+        let options = Options {
+            preserve: PreserveMetadata {
+                permissions: true,
+                ..Default::default()
+            },
+            verify: true,
+            destination: Destination::RelativeTo(target.clone(), vec![root]),
+            ..Default::default()
+        };
+
+        let error = options.from_iter(&stash, 1).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<Mismatch>().map(|m| m.0), Some(1));
+
+        // everything else is restored before the mismatch is reported
+        assert_eq!(fs::read(target.join("dir/file")).unwrap(), data);
+        assert_eq!(
+            fs::read_link(target.join("dir/link")).unwrap(),
+            Path::new("file")
+        );
+        let mode = fs::metadata(target.join("dir"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        fs::remove_dir_all(target).unwrap();
+    }
 }
@@ -0,0 +1,150 @@
+//! Make the files of an old commit the latest state of the stash
+//!
+//! Rolling back doesn't drop the commits after the target. The tree of
+//! the target commit is loaded, then applied to the latest tree, so the
+//! next commit has the same files as the target. The history stays
+//! intact, and a rollback can be undone the same way.
+//!
+//! The chunks of the files are registered in the chunk index again. If
+//! their objects were pruned since, but the tombstones haven't expired
+//! yet, this keeps them from being deleted.
+use crate::{Entry, Files};
+use infinitree::{
+    tree::{CommitFilter, CommitId},
+    Infinitree,
+};
+use std::{collections::HashMap, sync::Arc};
+use tracing::debug;
+
+/// The changes made to the latest tree
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rollback {
+    /// Files and directories that were added or changed
+    pub restored: usize,
+    /// Files and directories that were removed
+    pub removed: usize,
+}
+
+/// Apply the tree of `target` to the latest tree of the stash.
+///
+/// `load` is called to load the index, first up to `target`, then up to
+/// the latest commit. Changes must be committed by the caller.
+pub fn rollback(
+    stash: &mut Infinitree<Files>,
+    target: CommitId,
+    mut load: impl FnMut(&mut Infinitree<Files>),
+) -> Rollback {
+    stash.filter_commits(CommitFilter::UpTo(target));
+    load(stash);
+
+    // directories have no entry, and are visited before their contents
+    let mut old: Vec<(String, Option<Arc<Entry>>)> = vec![];
+    stash.index().tree.retain(|path, node| {
+        old.push((path.to_string(), node.as_file()));
+        true
+    });
+    debug!(?target, nodes = old.len(), "loaded old tree");
+
+    let index = stash.index();
+    index.tree.clear().unwrap();
+    index.files.clear();
+    index.zfs_snapshots.clear();
+    stash.filter_commits(CommitFilter::All);
+    load(stash);
+
+    let index = stash.index();
+    let tree = &index.tree;
+    let is_dir = old
+        .iter()
+        .map(|(path, entry)| (path.clone(), entry.is_none()))
+        .collect::<HashMap<_, _>>();
+
+    let mut report = Rollback::default();
+    tree.retain(|path, node| {
+        let keep = is_dir.get(path) == Some(&node.is_dir());
+        if !keep {
+            report.removed += 1;
+        }
+        keep
+    });
+
+    for (path, entry) in old {
+        let current = tree.node_by_path(&path).ok().flatten();
+
+        match entry {
+            None if current.is_none() => {
+                tree.insert_directory(&path).unwrap();
+                report.restored += 1;
+            }
+            None => {}
+            Some(entry) => {
+                for (_, pointer) in entry.chunks.iter() {
+                    index
+                        .chunks
+                        .insert_with(*pointer.hash(), || pointer.as_ref().clone());
+                }
+
+                let unchanged = current
+                    .and_then(|node| node.as_file())
+                    .is_some_and(|current| current.is_identical(&entry));
+                if !unchanged {
+                    tree.insert_file(&path, entry.as_ref().clone()).unwrap();
+                    report.restored += 1;
+                }
+            }
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn changed_contents_are_restored() {
+        use super::{rollback, Rollback};
+        use crate::{Entry, Files};
+        use infinitree::{crypto::UsernamePassword, object::Writer, Infinitree};
+        use std::sync::Arc;
+
+        let key = || {
+            UsernamePassword::with_credentials("rollback".to_string(), "password".to_string())
+                .unwrap()
+        };
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage.clone(), key()).unwrap();
+
+        // same metadata, different contents
+        let mut writer = stash.storage_writer().unwrap();
+        let mut commit = |data: &[u8]| {
+            let pointer = writer.write_chunk(&rand::random(), data).unwrap();
+            writer.flush().unwrap();
+
+            let mut file = Entry {
+                name: "file".into(),
+                size: 3,
+                ..Entry::default()
+            };
+            file.chunks.insert(0, Arc::new(pointer.clone()));
+            stash.index().tree.insert_file("file", file).unwrap();
+            stash.commit(None).unwrap();
+            pointer
+        };
+        let old = commit(b"old");
+        commit(b"new");
+
+        let mut stash = Infinitree::<Files>::open(storage, key()).unwrap();
+        let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+        let report = rollback(&mut stash, commits[0], |stash| stash.load_all().unwrap());
+        assert_eq!(
+            report,
+            Rollback {
+                restored: 1,
+                removed: 0
+            }
+        );
+
+        let restored = stash.index().tree.file("file").unwrap().unwrap();
+        assert_eq!(restored.chunks[&0].as_ref(), &old);
+    }
+}
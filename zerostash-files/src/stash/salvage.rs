@@ -0,0 +1,169 @@
+//! Recover what's readable from damaged objects
+//!
+//! Every chunk is encrypted on its own, so a corrupted or truncated
+//! object usually still holds chunks that decrypt fine. Salvaging
+//! checks every chunk in the index, copies the readable chunks out of
+//! damaged objects into new ones, and drops the unreadable chunks from
+//! the index and the files that reference them.
+//!
+//! The damaged objects themselves are left in place.
+use crate::{
+    chunk_index::{verify_chunk, ChunkRecord},
+    Files,
+};
+use infinitree::{
+    object::{Reader, Writer},
+    Digest, Infinitree,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tracing::{debug, warn};
+
+#[derive(Default, Debug)]
+pub struct Report {
+    /// Number of chunks in the index
+    pub chunks: usize,
+    /// Chunks that could not be read, and were dropped
+    pub lost_chunks: usize,
+    /// Readable chunks that were moved out of damaged objects
+    pub moved_chunks: usize,
+    /// Files that referenced lost chunks, and the number of bytes lost
+    pub damaged_files: Vec<(String, u64)>,
+}
+
+/// Salvage the currently loaded state of `stash`.
+///
+/// The caller is responsible for committing the changes.
+pub fn salvage(stash: &Infinitree<Files>) -> anyhow::Result<Report> {
+    let index = stash.index();
+    let mut reader = stash.storage_reader()?;
+    let mut hasher = crate::digest_key::hasher(stash)?;
+    let mut buf = vec![];
+
+    let mut records = vec![];
+    index.export_chunks(|record| records.push(record));
+
+    let mut lost = HashSet::new();
+    let mut damaged_objects = HashSet::new();
+    for record in records.iter() {
+        if let Err(error) = verify_chunk(
+            &mut reader,
+            &index.dictionaries,
+            &mut hasher,
+            record,
+            &mut buf,
+        ) {
+            warn!(%error, "lost chunk");
+            lost.insert(record.digest);
+            damaged_objects.insert(*record.pointer.object_id());
+        }
+    }
+
+    let mut writer = stash.storage_writer()?;
+    let mut moved = HashMap::new();
+    let to_move = records
+        .iter()
+        .filter(|r| !lost.contains(&r.digest))
+        .filter(|r| damaged_objects.contains(r.pointer.object_id()));
+
+    for ChunkRecord { digest, pointer } in to_move {
+        let data = reader.read_chunk(pointer, &mut buf)?;
+        let new_pointer = crate::route::data(|| writer.write_chunk(digest, data))?;
+
+        index.chunks.update(*digest, new_pointer.clone());
+        moved.insert(*digest, Arc::new(new_pointer));
+    }
+    crate::route::data(|| writer.flush())?;
+
+    for digest in lost.iter() {
+        index.chunks.remove(*digest);
+    }
+
+    let mut report = Report {
+        chunks: records.len(),
+        lost_chunks: lost.len(),
+        moved_chunks: moved.len(),
+        damaged_files: vec![],
+    };
+
+    for (path, entry) in index.tree.iter_files().collect::<Vec<_>>() {
+        let bytes_lost = repair_entry(&path, &entry, &lost, &moved, index)?;
+        if bytes_lost > 0 {
+            report.damaged_files.push((path, bytes_lost));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Point the chunks of a file to their new location, and drop the ones
+/// that were lost. Returns the number of bytes lost.
+fn repair_entry(
+    path: &str,
+    entry: &crate::Entry,
+    lost: &HashSet<Digest>,
+    moved: &HashMap<Digest, Arc<infinitree::ChunkPointer>>,
+    index: &Files,
+) -> anyhow::Result<u64> {
+    let offsets = entry.chunks.keys().copied().collect::<Vec<_>>();
+    let mut repaired = entry.clone();
+    let mut changed = false;
+    let mut bytes_lost = 0;
+
+    for (i, (offset, pointer)) in entry.chunks.iter().enumerate() {
+        if lost.contains(pointer.hash()) {
+            let end = offsets.get(i + 1).copied().unwrap_or(entry.size);
+            // a damaged entry may list chunks past its size
+            bytes_lost += end.saturating_sub(*offset);
+            repaired.chunks.remove(offset);
+            changed = true;
+        } else if let Some(new_pointer) = moved.get(pointer.hash()) {
+            repaired.chunks.insert(*offset, new_pointer.clone());
+            changed = true;
+        }
+    }
+
+    if changed {
+        debug!(path, bytes_lost, "repaired file");
+        index
+            .tree
+            .update_file(path, repaired)
+            .map_err(|e| anyhow::anyhow!("failed to repair {path}: {e:?}"))?;
+    }
+
+    Ok(bytes_lost)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn repair_reports_errors_instead_of_panicking() {
+        use super::repair_entry;
+        use crate::{Entry, Files};
+        use infinitree::ChunkPointer;
+        use std::{
+            collections::{HashMap, HashSet},
+            sync::Arc,
+        };
+
+        // a damaged entry with a chunk past its size
+        let mut entry = Entry {
+            size: 10,
+            ..Default::default()
+        };
+        entry.chunks.insert(0, Arc::new(ChunkPointer::default()));
+        entry.chunks.insert(20, Arc::new(ChunkPointer::default()));
+
+        let index = Files::default();
+        index.tree.insert_file("a", entry.clone()).unwrap();
+        let lost = HashSet::from([*ChunkPointer::default().hash()]);
+
+        let lost_bytes = repair_entry("a", &entry, &lost, &HashMap::new(), &index).unwrap();
+        assert_eq!(lost_bytes, 10);
+
+        index.tree.set_read_only(true);
+        assert!(repair_entry("a", &entry, &lost, &HashMap::new(), &index).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Explain why the directory walk of a commit skips a path
+//!
+//! The walker drops skipped paths silently. To tell which rule was
+//! responsible, the rules are evaluated again the same way the walker
+//! does, keeping the match.
+use super::store::Options;
+use crate::files::normalize_filename;
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder, Glob},
+    Match,
+};
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+/// The rule that excludes a path from a commit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The path is not under any of the committed paths
+    NotIncluded,
+    /// `path` is hidden, and hidden files are ignored
+    Hidden(PathBuf),
+    /// `pattern` in the ignore file `file` matches `path`. Global
+    /// rules may have no file.
+    IgnoreRule {
+        path: PathBuf,
+        file: Option<PathBuf>,
+        pattern: String,
+    },
+    /// The file is larger than `--max-size`
+    MaxSize { size: u64, limit: u64 },
+    /// `path` is on a different file system than the committed path
+    OtherFileSystem(PathBuf),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotIncluded => write!(f, "not under any of the committed paths"),
+            Self::Hidden(path) => write!(f, "{} is hidden", path.display()),
+            Self::IgnoreRule {
+                path,
+                file: Some(file),
+                pattern,
+            } => write!(
+                f,
+                "{} matches `{pattern}` in {}",
+                path.display(),
+                file.display()
+            ),
+            Self::IgnoreRule { path, pattern, .. } => {
+                write!(f, "{} matches the global rule `{pattern}`", path.display())
+            }
+            Self::MaxSize { size, limit } => {
+                write!(f, "{size} bytes is larger than the limit of {limit}")
+            }
+            Self::OtherFileSystem(path) => {
+                write!(f, "{} is on a different file system", path.display())
+            }
+        }
+    }
+}
+
+impl Options {
+    /// Find the rule that excludes `path` from a commit with these
+    /// options. Returns `None` if the path is included.
+    pub fn explain_skip(&self, path: &Path) -> io::Result<Option<SkipReason>> {
+        let path = absolute(path)?;
+        let mut root = None;
+        for source in self.sources() {
+            let p = absolute(&source.path)?;
+            if path.starts_with(&p) {
+                root = Some(p);
+                break;
+            }
+        }
+        let Some(root) = root else {
+            return Ok(Some(SkipReason::NotIncluded));
+        };
+
+        let global = self.git_global.then(|| Gitignore::global().0);
+        let root_device = device(&fs::metadata(&root)?);
+
+        // the walker never enters a skipped directory, so every path
+        // from the root down is checked
+        let mut current = root.clone();
+        for component in path.strip_prefix(&root).unwrap().components() {
+            current.push(component);
+
+            let metadata = if self.follow_links {
+                fs::metadata(&current)?
+            } else {
+                fs::symlink_metadata(&current)?
+            };
+
+            if self.hidden && is_hidden(&current) {
+                return Ok(Some(SkipReason::Hidden(current)));
+            }
+
+            if let Some(reason) =
+                self.match_rules(&root, &current, metadata.is_dir(), global.as_ref())
+            {
+                return Ok(Some(reason));
+            }
+
+            if self.same_fs && device(&metadata) != root_device {
+                return Ok(Some(SkipReason::OtherFileSystem(current)));
+            }
+
+            if let (Some(limit), true) = (self.max_size, metadata.is_file()) {
+                if metadata.len() > limit {
+                    return Ok(Some(SkipReason::MaxSize {
+                        size: metadata.len(),
+                        limit,
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn match_rules(
+        &self,
+        root: &Path,
+        path: &Path,
+        is_dir: bool,
+        global: Option<&Gitignore>,
+    ) -> Option<SkipReason> {
+        let in_repo = path.ancestors().any(|dir| dir.join(".git").exists());
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| self.parents || dir.starts_with(root));
+
+        // `.ignore` files take precedence over git rules, and within
+        // each kind the closest file with a matching rule decides
+        let sources = [
+            (self.ignore, ".ignore"),
+            (self.git_ignore && in_repo, ".gitignore"),
+            (self.git_exclude && in_repo, ".git/info/exclude"),
+        ];
+
+        for (enabled, name) in sources {
+            if !enabled {
+                continue;
+            }
+
+            for dir in dirs.clone() {
+                let file = dir.join(name);
+                if !file.is_file() {
+                    continue;
+                }
+
+                match self.matcher(dir, &file).matched(path, is_dir) {
+                    Match::None => continue,
+                    Match::Whitelist(_) => return None,
+                    Match::Ignore(glob) => return Some(rule(path, glob)),
+                }
+            }
+        }
+
+        match global?.matched(path, is_dir) {
+            Match::Ignore(glob) => Some(rule(path, glob)),
+            _ => None,
+        }
+    }
+
+    fn matcher(&self, dir: &Path, file: &Path) -> Gitignore {
+        let mut builder = GitignoreBuilder::new(dir);
+        _ = builder.case_insensitive(self.case_insensitive);
+        _ = builder.add(file);
+        builder.build().unwrap_or_else(|_| Gitignore::empty())
+    }
+
+    /// Log the reason for every child of `dirs` that the walk skipped.
+    pub(crate) fn log_skipped(&self, dirs: &[PathBuf], walked: &HashSet<String>) {
+        for dir in dirs {
+            let Ok(children) = fs::read_dir(dir) else {
+                continue;
+            };
+
+            for child in children.flatten() {
+                let path = child.path();
+                if normalize_filename(&path).is_ok_and(|name| walked.contains(&name)) {
+                    continue;
+                }
+
+                match self.explain_skip(&path) {
+                    Ok(Some(reason)) => debug!(?path, %reason, "skipped"),
+                    Ok(None) => debug!(?path, "skipped, but no rule matches"),
+                    Err(error) => debug!(?path, %error, "skipped"),
+                }
+            }
+        }
+    }
+}
+
+fn rule(path: &Path, glob: &Glob) -> SkipReason {
+    SkipReason::IgnoreRule {
+        path: path.to_path_buf(),
+        file: glob.from().map(Path::to_path_buf),
+        pattern: glob.original().to_string(),
+    }
+}
+
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(std::env::current_dir()?.join(path))
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'))
+}
+
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.dev()
+}
+
+/// Not available on other platforms, so never reported
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn explain_ignore_rule() {
+        use super::SkipReason;
+        use crate::store::Options;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("zerostash-skip-{}", std::process::id()));
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join(".ignore"), "build/\n").unwrap();
+        fs::write(dir.join("build/out"), "").unwrap();
+        fs::write(dir.join("big"), [0; 10]).unwrap();
+
+        let options = Options {
+            paths: vec![dir.clone()],
+            ignore: true,
+            max_size: Some(5),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            options.explain_skip(&dir.join("build/out")).unwrap(),
+            Some(SkipReason::IgnoreRule {
+                path: dir.join("build"),
+                file: Some(dir.join(".ignore")),
+                pattern: "build/".into(),
+            })
+        );
+        assert_eq!(
+            options.explain_skip(&dir.join("big")).unwrap(),
+            Some(SkipReason::MaxSize { size: 10, limit: 5 })
+        );
+        assert_eq!(options.explain_skip(&dir.join(".ignore")).unwrap(), None);
+        assert_eq!(
+            options.explain_skip(&std::env::temp_dir()).unwrap(),
+            Some(SkipReason::NotIncluded)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
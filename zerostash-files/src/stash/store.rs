@@ -1,30 +1,149 @@
 use crate::{
+    content_type,
+    dictionary::{Dictionary, SMALL_FILE},
     files::{self, normalize_filename},
-    rollsum::{BupSplit, SeaSplit},
-    splitter::FileSplitter,
-    Files,
+    files_cache::{FilesCache, Stamp},
+    heartbeat::Heartbeat,
+    hook::{self, Verdict},
+    interrupt::Interrupt,
+    roots::Source,
+    splitter::{Chunker, ChunkerRule},
+    timings::{Stage, StageTimes, Timings},
+    write_balancer::WriteBalancer,
+    Files, Tree,
 };
 use anyhow::Context;
 use flume as mpsc;
 use futures::future::join_all;
 use ignore::{DirEntry, WalkBuilder};
-use infinitree::{
-    object::{Pool, Writer},
-    Digest, Infinitree,
-};
+use infinitree::{object::Writer, ChunkPointer, Digest, Infinitree};
 use memmap2::{Mmap, MmapOptions};
-use std::{collections::BTreeMap, fs, io::Read, num::NonZeroUsize, path::PathBuf};
-use tokio::task;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    io::Read,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::Notify, task};
 use tracing::{debug, debug_span, error, trace, warn, Instrument};
 
-type Sender = mpsc::Sender<(PathBuf, files::Entry)>;
-type Receiver = mpsc::Receiver<(PathBuf, files::Entry)>;
+/// The position of a file in the walk, the file to store, its path in
+/// the stash, and its metadata
+type ThreadWork = (u64, PathBuf, String, files::Entry);
+
+type Sender = mpsc::Sender<ThreadWork>;
+type Receiver = mpsc::Receiver<ThreadWork>;
+
+/// Files smaller than this are read into a reusable buffer. Larger
+/// files are memory mapped, and chunks are written straight from the
+/// mapping without copying the file contents first.
+const MMAP_THRESHOLD: usize = 1024 * 1024;
+
+/// The amount of data a commit added to the stash
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Added {
+    /// Total size of the new and changed files
+    pub logical: u64,
+    /// Size of the chunks written to storage, after deduplication and
+    /// compression
+    pub physical: u64,
+    /// Number of files that were recorded as renamed
+    pub renamed: u64,
+    /// Chunks that were written to storage
+    pub new_chunks: u64,
+    /// Chunks that were stored already, and are only referenced again
+    pub reused_chunks: u64,
+    /// The same counters for each committed path, in the order they
+    /// were given. Renames are only counted in the total.
+    pub sources: Vec<(PathBuf, Added)>,
+    /// The walk was interrupted, so only some of the files were
+    /// added, and nothing was removed from the tree
+    pub interrupted: bool,
+}
+
+#[derive(Default)]
+struct Counters {
+    logical: AtomicU64,
+    physical: AtomicU64,
+    new_chunks: AtomicU64,
+    chunks: AtomicU64,
+}
+
+impl Counters {
+    fn get(&self) -> Added {
+        let new_chunks = self.new_chunks.load(Ordering::Relaxed);
+        let chunks = self.chunks.load(Ordering::Relaxed);
+
+        Added {
+            logical: self.logical.load(Ordering::Relaxed),
+            physical: self.physical.load(Ordering::Relaxed),
+            new_chunks,
+            reused_chunks: chunks.saturating_sub(new_chunks),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct AddedCounters {
+    total: Counters,
+    renamed: AtomicU64,
+    sources: Vec<(PathBuf, Counters)>,
+}
+
+impl AddedCounters {
+    fn new(paths: &[PathBuf]) -> Self {
+        Self {
+            sources: paths
+                .iter()
+                .map(|path| (path.clone(), Counters::default()))
+                .collect(),
+            ..Default::default()
+        }
+    }
 
-const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+    /// The counters of the innermost committed path that contains
+    /// `path`
+    fn source(&self, path: &Path) -> Option<&Counters> {
+        self.sources
+            .iter()
+            .filter(|(source, _)| path.starts_with(source))
+            .max_by_key(|(source, _)| source.components().count())
+            .map(|(_, counters)| counters)
+    }
+
+    /// Add `n` to a counter of the total, and of `source`.
+    fn add(&self, source: Option<&Counters>, n: u64, counter: fn(&Counters) -> &AtomicU64) {
+        for counters in std::iter::once(&self.total).chain(source) {
+            counter(counters).fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> Added {
+        Added {
+            renamed: self.renamed.load(Ordering::Relaxed),
+            sources: self
+                .sources
+                .iter()
+                .map(|(path, counters)| (path.clone(), counters.get()))
+                .collect(),
+            ..self.total.get()
+        }
+    }
+}
 
 #[derive(clap::Args, Debug, Default, Clone)]
 pub struct Options {
     /// The paths to include in the commit. All changes (addition/removal) will be committed.
+    ///
+    /// Use `PATH:PREFIX` to store the files of a path under a prefix, eg. `/var/www:web`.
     pub paths: Vec<PathBuf>,
 
     #[clap(flatten)]
@@ -34,6 +153,31 @@ pub struct Options {
     #[clap(short = 'f', long)]
     pub force: bool,
 
+    /// Only look at files modified within the given time, eg. `90m`, `24h` or `7d`.
+    ///
+    /// Older files are kept as they are in the stash without comparing them.
+    /// Directories are still listed to find the files in them, because
+    /// changing a file doesn't update its directory's modification time.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub changed_within: Option<Duration>,
+
+    /// Record metadata changes, like renames and permissions, without reading file contents.
+    ///
+    /// A file with the same size, modification time and inode as the entry at its
+    /// path, or as exactly one other entry in case of a rename, reuses the stored
+    /// chunks. Entries stored without an inode never match. This takes precedence
+    /// over `--force`. Files with no match are stored as usual.
+    #[clap(long = "metadata-only")]
+    pub metadata_only: bool,
+
+    /// Detect files that were renamed or moved, and reuse their stored contents.
+    ///
+    /// A new path with the same size, modification time and inode as exactly one
+    /// stored file is not read. If the stored file is gone, the new entry records
+    /// that it was renamed.
+    #[clap(long = "detect-renames")]
+    pub detect_renames: bool,
+
     /// Ignore files larger than the given value in bytes.
     #[clap(short = 'M', long = "max-size")]
     pub max_size: Option<u64>,
@@ -73,6 +217,52 @@ pub struct Options {
     /// Follow symbolic links.
     #[clap(short = 'l', long = "follow-links")]
     pub follow_links: bool,
+
+    /// Walk directories in file system order, and write chunks as soon as they're ready.
+    ///
+    /// By default directory entries are sorted, and the chunks of files are
+    /// written in the order of the walk, so committing identical trees writes
+    /// the chunks to objects in the same order. Files are still read and
+    /// chunked in parallel.
+    #[clap(long = "unordered")]
+    pub unordered: bool,
+
+    /// Detect the content type of files from their first few bytes, and store it in the index.
+    #[clap(long = "sniff-types")]
+    pub sniff_types: bool,
+
+    /// Look up new chunks in a bloom filter before the chunk index.
+    ///
+    /// Speeds up committing mostly new data, for about 2 bytes of memory per chunk in the stash.
+    #[clap(long = "chunk-filter")]
+    pub chunk_filter: bool,
+
+    /// Chunking algorithm for files that don't match a `--chunker-rule`.
+    /// By default, it depends on the size of the file.
+    #[clap(long, value_enum)]
+    pub chunker: Option<Chunker>,
+
+    /// Use a chunking algorithm for files that match a glob, eg. `*.qcow2=bup`.
+    /// The first matching rule is used. May be repeated.
+    #[clap(long = "chunker-rule", value_name = "GLOB=CHUNKER")]
+    pub chunker_rules: Vec<ChunkerRule>,
+
+    /// Run a command on every new or changed file before storing it, eg. a virus scanner.
+    ///
+    /// The command is called with the path of the file. If it exits with 0, the file is
+    /// stored, and `key=value` lines it prints are added to the file as annotations.
+    /// Otherwise the file is skipped.
+    #[clap(long = "file-hook", value_name = "COMMAND")]
+    pub file_hook: Option<PathBuf>,
+
+    /// Stop walking the paths when triggered. The result only has the
+    /// files that were done by then, see [`Added::interrupted`].
+    #[clap(skip)]
+    pub interrupt: Interrupt,
+
+    /// Beats for every file that's walked or stored
+    #[clap(skip)]
+    pub heartbeat: Heartbeat,
 }
 
 impl Options {
@@ -80,12 +270,78 @@ impl Options {
         &self,
         stash: &Infinitree<Files>,
         threads: usize,
-    ) -> anyhow::Result<()> {
-        let (sender, workers) = start_workers(stash, threads, self.force)?;
-        let dir_walk = self.dir_walk()?;
+    ) -> anyhow::Result<Added> {
+        self.add_recursive_timed(stash, threads, Default::default())
+            .await
+    }
+
+    /// Same as [`Options::add_recursive`], but records the time spent
+    /// in each stage in `timings`.
+    pub async fn add_recursive_timed(
+        &self,
+        stash: &Infinitree<Files>,
+        threads: usize,
+        timings: Arc<Timings>,
+    ) -> anyhow::Result<Added> {
+        let (added, _) = self.add(stash, threads, timings, None).await?;
+        Ok(added)
+    }
+
+    /// Same as [`Options::add_recursive_timed`], but skips files that
+    /// are unchanged according to `cache`.
+    ///
+    /// Returns the stamps of the files seen, to build the cache for the
+    /// next commit with [`FilesCache::from_walk`] once this one is done.
+    pub async fn add_recursive_cached(
+        &self,
+        stash: &Infinitree<Files>,
+        threads: usize,
+        timings: Arc<Timings>,
+        cache: &FilesCache,
+    ) -> anyhow::Result<(Added, HashMap<String, Stamp>)> {
+        self.add(stash, threads, timings, Some(cache)).await
+    }
+
+    async fn add(
+        &self,
+        stash: &Infinitree<Files>,
+        threads: usize,
+        timings: Arc<Timings>,
+        cache: Option<&FilesCache>,
+    ) -> anyhow::Result<(Added, HashMap<String, Stamp>)> {
+        if self.chunk_filter {
+            stash.index().chunks.enable_filter();
+        }
+
+        let sources = self.sources();
+        let added = Arc::new(AddedCounters::new(
+            &sources.iter().map(|s| s.path.clone()).collect::<Vec<_>>(),
+        ));
+        let moved = Arc::new(Mutex::new(vec![]));
+        let mut seen = HashMap::new();
+        let (sender, workers) = start_workers(stash, threads, self, &timings, &added, &moved)?;
+        let dir_walk = self.dir_walk(&sources)?;
         let mut current_file_list = std::collections::HashSet::new();
 
+        // skipped paths are only explained in debug logs, because it
+        // needs another pass over every directory
+        let explain_skips = tracing::enabled!(tracing::Level::DEBUG);
+        let mut walked_dirs = vec![];
+        let mut dir_metadata = vec![];
+
+        let changed_after = self
+            .changed_within
+            .and_then(|age| SystemTime::now().checked_sub(age));
+
+        let mut walked = Instant::now();
+        let mut queued = 0;
         for dir_entry in dir_walk {
+            if self.interrupt.is_triggered() {
+                debug!("interrupted, not walking any further");
+                break;
+            }
+            self.heartbeat.beat();
+
             let (metadata, path) = match dir_entry {
                 Ok(de) => (de.metadata(), de.path().to_owned()),
                 Err(error) => {
@@ -94,13 +350,25 @@ impl Options {
                 }
             };
 
-            current_file_list.insert(normalize_filename(&path)?);
+            let source = source_of(&sources, &path);
+            let stored = match source {
+                Some(source) => source.stored(&path)?,
+                None => normalize_filename(&path)?,
+            };
+            current_file_list.insert(stored.clone());
 
             let metadata = match metadata {
                 Ok(md) if md.is_file() || md.is_symlink() => md,
                 Ok(md) if md.is_dir() => {
-                    let path_str = path.to_str().unwrap();
-                    stash.index().tree.insert_directory(path_str).unwrap();
+                    stash.index().tree.insert_directory(&stored).unwrap();
+                    match files::Entry::from_metadata(md, &path, &self.preserve) {
+                        Ok(entry) => dir_metadata.push((stored, entry)),
+                        // the root of the file system has no name
+                        Err(error) => debug!(%error, ?path, "no directory metadata"),
+                    }
+                    if explain_skips {
+                        walked_dirs.push(path);
+                    }
                     continue;
                 }
                 Err(error) => {
@@ -110,7 +378,24 @@ impl Options {
                 _ => continue,
             };
 
-            let entry = match files::Entry::from_metadata(metadata, &path, &self.preserve) {
+            if let (Some(cache), Some(stamp)) = (cache, Stamp::new(&metadata)) {
+                let unchanged = !self.force && cache.is_unchanged(&stored, &stamp);
+                seen.insert(stored.clone(), stamp);
+
+                if unchanged {
+                    trace!(?path, "unchanged since the last commit");
+                    continue;
+                }
+            }
+
+            if let Some(after) = changed_after {
+                if !self.force && !changed_since(&metadata, after) {
+                    trace!(?path, "not changed recently");
+                    continue;
+                }
+            }
+
+            let mut entry = match files::Entry::from_metadata(metadata, &path, &self.preserve) {
                 Ok(e) => e,
                 Err(error) => {
                     error!(%error, ?path, "failed to ingest file; aborting");
@@ -118,22 +403,58 @@ impl Options {
                 }
             };
 
-            trace!(?path, "queued");
-            sender.send((path, entry)).unwrap();
+            // a file given with a prefix is stored under a new name
+            if source.is_some_and(|s| s.prefix.is_some()) {
+                if let Some(name) = stored.rsplit('/').next() {
+                    entry.name = name.to_string();
+                }
+            }
+
+            trace!(?path, %stored, "queued");
+            timings.walker().add(Stage::Walk, walked.elapsed());
+            sender.send((queued, path, stored, entry)).unwrap();
+            queued += 1;
+            walked = Instant::now();
         }
 
+        timings.walker().add(Stage::Walk, walked.elapsed());
         drop(sender);
+
+        if explain_skips {
+            self.log_skipped(&walked_dirs, &current_file_list);
+        }
+
         join_all(workers).await;
 
-        let source_paths = self
-            .paths
+        // directory nodes are replaced, so only once the workers are
+        // done adding files to them
+        for (stored, mut entry) in dir_metadata {
+            entry.size = 0;
+            if let Some(name) = stored.rsplit('/').next() {
+                entry.name = name.to_string();
+            }
+            stash
+                .index()
+                .tree
+                .set_directory_metadata(&stored, entry)
+                .unwrap();
+        }
+
+        // files that weren't walked may still exist
+        if self.interrupt.is_triggered() {
+            let mut added = added.get();
+            added.interrupted = true;
+            return Ok((added, seen));
+        }
+
+        let source_paths = sources
             .iter()
-            .map(normalize_filename)
+            .map(Source::root)
             .collect::<Result<Vec<_>, _>>()?;
 
         stash.index().tree.retain(|p, _| {
             for sp in source_paths.iter() {
-                if p.starts_with(sp) {
+                if is_under(p, sp) {
                     // if the current directory is part of the new commit, diff
                     return current_file_list.contains(p);
                 }
@@ -143,11 +464,24 @@ impl Options {
             true
         });
 
-        Ok(())
+        if self.detect_renames {
+            let moved = std::mem::take(&mut *moved.lock().unwrap());
+            record_renames(&stash.index().tree, moved, &added);
+        }
+
+        Ok((added.get(), seen))
+    }
+
+    /// The paths to commit, and their prefixes in the stash
+    pub fn sources(&self) -> Vec<Source> {
+        self.paths.iter().map(|p| Source::parse(p)).collect()
     }
 
-    fn dir_walk(&self) -> anyhow::Result<impl Iterator<Item = Result<DirEntry, ignore::Error>>> {
-        let mut paths = self.paths.iter();
+    fn dir_walk(
+        &self,
+        sources: &[Source],
+    ) -> anyhow::Result<impl Iterator<Item = Result<DirEntry, ignore::Error>>> {
+        let mut paths = sources.iter().map(|s| &s.path);
         let mut builder = WalkBuilder::new(paths.next().context("no path available")?);
 
         for path in paths {
@@ -166,138 +500,489 @@ impl Options {
         builder.ignore(self.ignore);
         builder.follow_links(self.follow_links);
 
+        if !self.unordered {
+            builder.sort_by_file_name(|a, b| a.cmp(b));
+        }
+
         Ok(builder.build())
     }
 }
 
+/// Parse a duration like `90s`, `30m`, `24h` or `7d`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) =
+        s.split_at(s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len());
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("`{s}` does not start with a number"))?;
+
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{unit}`, use s, m, h or d")),
+    };
+
+    number
+        .checked_mul(secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("`{s}` is too long"))
+}
+
+/// Was the file's content or metadata changed after `time`
+fn changed_since(metadata: &fs::Metadata, time: SystemTime) -> bool {
+    let modified = metadata.modified().map_or(true, |m| m > time);
+
+    #[cfg(unix)]
+    let changed = {
+        use std::os::unix::fs::MetadataExt;
+        let ctime = u64::try_from(metadata.ctime())
+            .map(|secs| Duration::new(secs, metadata.ctime_nsec() as u32));
+        match (ctime, time.duration_since(SystemTime::UNIX_EPOCH)) {
+            (Ok(ctime), Ok(time)) => ctime > time,
+            // changed before the epoch
+            (Err(_), Ok(_)) => false,
+            (_, Err(_)) => true,
+        }
+    };
+    #[cfg(not(unix))]
+    let changed = false;
+
+    modified || changed
+}
+
+/// The innermost source that contains `path`
+fn source_of<'a>(sources: &'a [Source], path: &Path) -> Option<&'a Source> {
+    sources
+        .iter()
+        .filter(|source| path.starts_with(&source.path))
+        .max_by_key(|source| source.path.components().count())
+}
+
+/// Is `path` the same as, or below `parent` in the tree
+fn is_under(path: &str, parent: &str) -> bool {
+    path.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || parent.ends_with('/'))
+}
+
+/// Mark the files in `moved` as renamed if their previous path is
+/// gone from the tree. The rest were copied.
+fn record_renames(tree: &Tree, moved: Vec<(String, String)>, added: &AddedCounters) {
+    for (path, from) in moved {
+        if !matches!(tree.file(&from), Ok(None)) {
+            continue;
+        }
+
+        if let Ok(Some(entry)) = tree.file(&path) {
+            let mut entry = entry.as_ref().clone();
+            entry.set_renamed_from(&from);
+            tree.insert_file(&path, entry).unwrap();
+
+            debug!(%path, %from, "renamed");
+            added.renamed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// State shared by the files processed on a worker task
+struct Worker<W> {
+    force: bool,
+    metadata_only: bool,
+    known: Option<Arc<KnownContents>>,
+    /// Files that reuse the contents of a different path, and the path
+    moved: Arc<Mutex<Vec<(String, String)>>>,
+    /// Orders the writes of files, unless `--unordered` is given
+    sequencer: Option<Arc<Sequencer>>,
+    sniff_types: bool,
+    chunker: Option<Chunker>,
+    chunker_rules: Arc<Vec<ChunkerRule>>,
+    file_hook: Option<PathBuf>,
+    heartbeat: Heartbeat,
+    index: crate::Files,
+    hasher: infinitree::Hasher,
+    dictionary: Option<Arc<Dictionary>>,
+    writer: WriteBalancer<W>,
+    times: Arc<StageTimes>,
+    added: Arc<AddedCounters>,
+}
+
 fn start_workers(
     stash: &Infinitree<Files>,
     threads: usize,
-    force: bool,
+    options: &Options,
+    timings: &Timings,
+    added: &Arc<AddedCounters>,
+    moved: &Arc<Mutex<Vec<(String, String)>>>,
 ) -> anyhow::Result<(Sender, Vec<task::JoinHandle<()>>)> {
     // make sure the input and output queues are generous
     let (sender, receiver) = mpsc::bounded(threads * 2);
-    let balancer = Pool::new(NonZeroUsize::new(threads).unwrap(), stash.storage_writer()?)?;
-    let hasher = stash.hasher()?;
+
+    // ordered writes happen one at a time, and more writers would
+    // spread the chunks between objects depending on scheduling
+    let sequencer = (!options.unordered).then(Arc::<Sequencer>::default);
+    let writers = if sequencer.is_some() { 1 } else { threads };
+    let balancer = WriteBalancer::new(NonZeroUsize::new(writers).unwrap(), stash.storage_writer()?);
+    let hasher = crate::digest_key::hasher(stash)?;
+    let dictionary = crate::dictionary::load(stash)?;
+    let chunker_rules = Arc::new(options.chunker_rules.clone());
+    let known = (options.metadata_only || options.detect_renames)
+        .then(|| Arc::new(KnownContents::new(&stash.index().tree)));
 
     let workers = (0..threads)
         .map(|_| {
-            task::spawn(process_file_loop(
-                force,
-                receiver.clone(),
-                stash.index().clone(),
-                hasher.clone(),
-                balancer.clone(),
-            ))
+            let worker = Worker {
+                force: options.force,
+                metadata_only: options.metadata_only,
+                known: known.clone(),
+                moved: Arc::clone(moved),
+                sequencer: sequencer.clone(),
+                sniff_types: options.sniff_types,
+                chunker: options.chunker,
+                chunker_rules: Arc::clone(&chunker_rules),
+                file_hook: options.file_hook.clone(),
+                heartbeat: options.heartbeat.clone(),
+                index: stash.index().clone(),
+                hasher: hasher.clone(),
+                dictionary: dictionary.clone(),
+                writer: balancer.clone(),
+                times: timings.worker(),
+                added: Arc::clone(added),
+            };
+
+            task::spawn(process_file_loop(worker, receiver.clone()).in_current_span())
         })
         .collect::<Vec<_>>();
 
     Ok((sender, workers))
 }
 
-async fn process_file_loop(
-    force: bool,
-    r: Receiver,
-    index: crate::Files,
-    hasher: infinitree::Hasher,
-    writer: Pool<impl Writer + Clone + 'static>,
-) {
-    let mut buf = Vec::with_capacity(MAX_FILE_SIZE);
+async fn process_file_loop(worker: Worker<impl Writer + Clone + 'static>, r: Receiver) {
+    let mut buf = Vec::with_capacity(MMAP_THRESHOLD);
 
-    while let Ok((path, entry)) = r.recv_async().await {
+    while let Ok((seq, path, path_str, entry)) = r.recv_async().await {
+        worker.heartbeat.beat();
         buf.clear();
-        let path_str = path.to_string_lossy();
-
-        if !force {
-            let tree = &index.tree;
-            if let Ok(Some(node)) = tree.node_by_path(&path_str) {
-                match node.as_ref() {
-                    crate::Node::File { refs: _, entry: e } if *e.as_ref() == entry => {
-                        debug!(?path, "already indexed, skipping");
-                        continue;
-                    }
-                    crate::Node::File { refs: _, entry: _ } => {
-                        debug!(?path, "adding new file");
-                    }
-                    crate::Node::Directory { .. } => {}
-                }
-            }
+
+        process_file(&worker, seq, path, path_str, entry, &mut buf).await;
+        if let Some(sequencer) = &worker.sequencer {
+            sequencer.finish(seq);
         }
+    }
+}
+
+async fn process_file(
+    worker: &Worker<impl Writer + Clone + 'static>,
+    seq: u64,
+    path: PathBuf,
+    path_str: String,
+    mut entry: files::Entry,
+    buf: &mut Vec<u8>,
+) {
+    let index = &worker.index;
+
+    if let Some(known) = &worker.known {
+        if let Some((from, stored)) =
+            known.find(&index.tree, &path_str, &entry, worker.metadata_only)
+        {
+            debug!(?path, ?from, "reusing stored contents");
+            if let Some(from) = from {
+                worker
+                    .moved
+                    .lock()
+                    .unwrap()
+                    .push((path_str.clone(), from.to_string()));
+            }
 
-        let size = entry.size;
-        if size == 0 || entry.file_type.is_symlink() {
+            entry.chunks = stored.chunks.clone();
+            entry.chunker = stored.chunker;
+            entry.content_type = stored.content_type.clone();
+            entry.set_annotations(&stored.annotations());
             index.tree.insert_file(&path_str, entry).unwrap();
-            continue;
+            return;
         }
+    }
 
-        let osfile = match fs::File::open(&path) {
-            Ok(f) => f,
-            Err(error) => {
-                warn!(%error, ?path, "failed to open file; skipping");
-                continue;
+    if !worker.force {
+        let tree = &index.tree;
+        if let Ok(Some(node)) = tree.node_by_path(&path_str) {
+            match node.as_ref() {
+                crate::Node::File { refs: _, entry: e } if *e.as_ref() == entry => {
+                    debug!(?path, "already indexed, skipping");
+                    return;
+                }
+                crate::Node::File { refs: _, entry: _ } => {
+                    debug!(?path, "adding new file");
+                }
+                crate::Node::Directory { .. } => {}
             }
-        };
+        }
+    }
+
+    if let (Some(hook), false) = (&worker.file_hook, entry.file_type.is_symlink()) {
+        match hook::inspect(hook, &path, &entry) {
+            Verdict::Store(annotations) => entry.set_annotations(&annotations),
+            Verdict::Skip => {
+                debug!(?path, "skipped by file hook");
+                return;
+            }
+        }
+    }
+
+    let size = entry.size;
+    if size == 0 || entry.file_type.is_symlink() {
+        index.tree.insert_file(&path_str, entry).unwrap();
+        return;
+    }
+
+    let osfile = match worker.times.measure(Stage::Read, || fs::File::open(&path)) {
+        Ok(f) => f,
+        Err(error) => {
+            warn!(%error, ?path, "failed to open file; skipping");
+            return;
+        }
+    };
 
-        index_file(
-            entry,
-            osfile,
-            &mut buf,
-            path.clone(),
-            &index,
-            hasher.clone(),
-            &writer,
-        )
+    index_file(worker, seq, entry, osfile, buf, path.clone(), &path_str)
         .instrument(debug_span!("indexing", ?path, size))
         .await;
-    }
 }
 
 async fn index_file(
+    worker: &Worker<impl Writer + Clone + 'static>,
+    seq: u64,
     mut entry: files::Entry,
     mut osfile: fs::File,
     buf: &mut Vec<u8>,
     path: PathBuf,
-    index: &crate::Files,
-    hasher: infinitree::Hasher,
-    writer: &Pool<impl Writer + Clone + 'static>,
+    stored: &str,
 ) {
+    let Worker {
+        sequencer,
+        sniff_types,
+        index,
+        hasher,
+        writer,
+        times,
+        added,
+        ..
+    } = worker;
+
     let size = entry.size as usize;
+    let source = added.source(&path);
+    added.add(source, entry.size, |c| &c.logical);
 
-    if size < MAX_FILE_SIZE {
+    let read_start = Instant::now();
+    let mut mmap;
+    let data = if size < MMAP_THRESHOLD {
         osfile.read_to_end(buf).unwrap();
+        &buf[0..size]
+    } else {
+        mmap = MmappedFile::new(size, osfile);
+        mmap.open()
+    };
+    times.add(Stage::Read, read_start.elapsed());
+
+    if *sniff_types {
+        entry.content_type = Some(content_type::sniff(data).to_string());
     }
 
-    let mut mmap = MmappedFile::new(size, osfile);
-    let (_, chunks) = async_scoped::TokioScope::scope_and_block(|s| {
-        let splitter: Box<dyn Iterator<Item = (u64, Digest, &[u8])>> = if size < MAX_FILE_SIZE {
-            Box::new(FileSplitter::<SeaSplit>::new(&buf[0..size], hasher))
-        } else {
-            Box::new(FileSplitter::<BupSplit>::new(mmap.open(), hasher))
-        };
+    let chunker = worker.chunker_for(&path, size);
+    entry.chunker = Some(chunker);
+    let dictionary = worker
+        .dictionary
+        .as_deref()
+        .filter(|_| entry.size <= SMALL_FILE);
+    let mut splitter = chunker.split(data, hasher.clone());
 
-        for (start, hash, data) in splitter {
-            let mut writer = writer.clone();
+    let chunks = if let Some(sequencer) = sequencer {
+        let mut split = vec![];
+        while let Some(chunk) = times.measure(Stage::Chunk, || splitter.next()) {
+            split.push(chunk);
+        }
 
-            s.spawn(async move {
-                let store = || writer.write_chunk(&hash, data).unwrap();
-                let ptr = index.chunks.insert_with(hash, store);
-                (start, ptr)
-            })
+        // keep the chunks of a file together, and in the order of the
+        // walk, so the resulting objects don't depend on task scheduling
+        sequencer.wait(seq).await;
+        let mut chunks = BTreeMap::new();
+        for (start, hash, data) in split {
+            let mut writer = writer.clone();
+            let store = || write_chunk(times, added, source, dictionary, &mut writer, &hash, data);
+            chunks.insert(start, index.chunks.insert_with(hash, store));
         }
-    });
+        chunks
+    } else {
+        let (_, chunks) = async_scoped::TokioScope::scope_and_block(|s| {
+            while let Some((start, hash, data)) = times.measure(Stage::Chunk, || splitter.next()) {
+                let mut writer = writer.clone();
+
+                s.spawn(
+                    async move {
+                        let store = || {
+                            write_chunk(times, added, source, dictionary, &mut writer, &hash, data)
+                        };
+                        let ptr = index.chunks.insert_with(hash, store);
+                        (start, ptr)
+                    }
+                    .in_current_span(),
+                )
+            }
+        });
 
-    _ = std::mem::replace(
-        &mut entry.chunks,
         chunks
             .into_iter()
             .collect::<Result<BTreeMap<_, _>, _>>()
-            .unwrap(),
-    );
+            .unwrap()
+    };
+
+    _ = std::mem::replace(&mut entry.chunks, chunks);
+    added.add(source, entry.chunks.len() as u64, |c| &c.chunks);
 
     debug!(?path, chunks = entry.chunks.len(), "indexed");
 
-    let path_str = path.to_str().unwrap();
-    index.tree.insert_file(path_str, entry).unwrap();
+    index.tree.insert_file(stored, entry).unwrap();
+}
+
+/// Lets workers write the chunks of files in the order they were
+/// walked in, while reading and chunking them in parallel.
+#[derive(Default)]
+struct Sequencer {
+    /// The next file to write, and the files after it that are done
+    state: Mutex<(u64, BTreeSet<u64>)>,
+    notify: Notify,
+}
+
+impl Sequencer {
+    /// Wait until every file before `seq` is done.
+    async fn wait(&self, seq: u64) {
+        loop {
+            // registered before checking, so no wakeup is missed
+            let notified = self.notify.notified();
+            if self.state.lock().unwrap().0 == seq {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Mark the file `seq` as done, whether it had anything to write or not.
+    fn finish(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        let (next, done) = &mut *state;
+        done.insert(seq);
+        while done.remove(next) {
+            *next += 1;
+        }
+        drop(state);
+
+        self.notify.notify_waiters();
+    }
+}
+
+/// The files in the stash before a `--metadata-only` or
+/// `--detect-renames` commit, keyed by their size, modification time
+/// and inode. Keys shared by several entries are ambiguous, and map to
+/// `None`.
+///
+/// Files stored without an inode can't be matched, as size and
+/// modification time alone are shared by unrelated files too often.
+struct KnownContents(HashMap<Stamp, Option<(String, Arc<files::Entry>)>>);
+
+type Stamp = (u64, i64, u32, (u64, u64));
+
+impl KnownContents {
+    fn new(tree: &Tree) -> Self {
+        let mut known = HashMap::new();
+        for (path, entry) in tree.iter_files() {
+            let Some(stamp) = stamp(&entry).filter(|_| entry.file_type.is_file()) else {
+                continue;
+            };
+
+            known
+                .entry(stamp)
+                .and_modify(|e| *e = None)
+                .or_insert(Some((path, entry)));
+        }
+
+        Self(known)
+    }
+
+    /// The stored entry with the contents of the file at `path`, and
+    /// its path if it's stored somewhere else.
+    ///
+    /// With `metadata_only`, the entry at the same path is used if it's
+    /// the same inode, otherwise only new paths are matched.
+    fn find(
+        &self,
+        tree: &Tree,
+        path: &str,
+        entry: &files::Entry,
+        metadata_only: bool,
+    ) -> Option<(Option<&str>, Arc<files::Entry>)> {
+        if !entry.file_type.is_file() {
+            return None;
+        }
+        let stamp = stamp(entry)?;
+
+        match tree.file(path) {
+            Ok(Some(stored)) if metadata_only && self::stamp(&stored) == Some(stamp) => {
+                Some((None, stored))
+            }
+            Ok(Some(_)) if !metadata_only => None,
+            _ => {
+                let (from, stored) = self.0.get(&stamp)?.as_ref()?;
+                (from != path).then(|| (Some(from.as_str()), stored.clone()))
+            }
+        }
+    }
+}
+
+fn stamp(entry: &files::Entry) -> Option<Stamp> {
+    Some((
+        entry.size,
+        entry.unix_secs,
+        entry.unix_nanos,
+        entry.inode()?,
+    ))
+}
+
+impl<W> Worker<W> {
+    /// The chunker of the first rule that matches `path`, or the default.
+    fn chunker_for(&self, path: &Path, size: usize) -> Chunker {
+        self.chunker_rules
+            .iter()
+            .find(|rule| rule.pattern.matches_path(path))
+            .map(|rule| rule.chunker)
+            .or(self.chunker)
+            .unwrap_or_else(|| Chunker::for_size(size))
+    }
+}
+
+/// Write a chunk that's not in the index yet.
+///
+/// The storage writer compresses every chunk on its own, before it's
+/// encrypted. Chunks of small files are compressed with the
+/// `dictionary` of the stash first, if it has one, and stored under a
+/// digest that tells readers to decompress them. The index still
+/// deduplicates by the digest of the contents.
+fn write_chunk(
+    times: &StageTimes,
+    added: &AddedCounters,
+    source: Option<&Counters>,
+    dictionary: Option<&Dictionary>,
+    writer: &mut impl Writer,
+    hash: &Digest,
+    data: &[u8],
+) -> ChunkPointer {
+    let (hash, data) = match dictionary {
+        Some(dictionary) => dictionary.encode(*hash, data),
+        None => (*hash, Cow::Borrowed(data)),
+    };
+    let pointer = times.measure(Stage::Write, || writer.write_chunk(&hash, &data).unwrap());
+    added.add(source, pointer.size() as u64, |c| &c.physical);
+    added.add(source, 1, |c| &c.new_chunks);
+
+    pointer
 }
 
 struct MmappedFile {
@@ -316,7 +1001,7 @@ impl MmappedFile {
     }
 
     fn open(&mut self) -> &[u8] {
-        self.mmap.get_or_insert(unsafe {
+        self.mmap.get_or_insert_with(|| unsafe {
             MmapOptions::new()
                 .len(self.len)
                 .populate()
@@ -325,3 +1010,143 @@ impl MmappedFile {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn durations() {
+        use super::parse_duration;
+        use std::time::Duration;
+
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("-5m").is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 60)).is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn changed_since_compares_to_now() {
+        use super::changed_since;
+        use std::time::{Duration, SystemTime};
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-changed-{}", rand::random::<u64>()));
+        std::fs::write(&path, b"changed").unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let now = SystemTime::now();
+        assert!(changed_since(&metadata, now - Duration::from_secs(60)));
+        assert!(!changed_since(&metadata, now + Duration::from_secs(60)));
+        assert!(changed_since(&metadata, SystemTime::UNIX_EPOCH));
+    }
+
+    #[test]
+    fn paths_under_a_parent() {
+        use super::is_under;
+
+        assert!(is_under("home/a", "home/a"));
+        assert!(is_under("home/a/b", "home/a"));
+        assert!(is_under("home/a/b", "home/a/"));
+        assert!(!is_under("home/ab", "home/a"));
+        assert!(!is_under("home", "home/a"));
+    }
+
+    #[tokio::test]
+    async fn sequenced_files_wait_for_the_ones_before() {
+        use super::Sequencer;
+        use futures::FutureExt;
+
+        let sequencer = Sequencer::default();
+        sequencer.wait(0).await;
+
+        // files may finish out of order
+        let mut second = Box::pin(sequencer.wait(2));
+        sequencer.finish(1);
+        assert!((&mut second).now_or_never().is_none());
+        sequencer.finish(0);
+        second.await;
+    }
+
+    #[test]
+    fn renames_need_the_old_path_to_be_gone() {
+        use super::{record_renames, AddedCounters};
+        use crate::{files::Entry, Tree};
+
+        let tree = Tree::default();
+        tree.insert_file("new/file", Entry::default()).unwrap();
+        tree.insert_file("copy", Entry::default()).unwrap();
+        tree.insert_file("original", Entry::default()).unwrap();
+        let added = AddedCounters::default();
+
+        record_renames(
+            &tree,
+            vec![
+                ("new/file".to_string(), "old/file".to_string()),
+                ("copy".to_string(), "original".to_string()),
+            ],
+            &added,
+        );
+
+        let renamed = |path| tree.file(path).unwrap().unwrap().renamed_from();
+        assert_eq!(renamed("new/file").as_deref(), Some("old/file"));
+        assert_eq!(renamed("copy"), None);
+        assert_eq!(added.get().renamed, 1);
+    }
+
+    #[test]
+    fn known_contents_match_path_and_inode() {
+        use super::KnownContents;
+        use crate::{files::Entry, Tree};
+
+        let file = |inode: Option<(u64, u64)>, secs| {
+            let mut entry = Entry {
+                size: 100,
+                unix_secs: secs,
+                ..Default::default()
+            };
+            if let Some(inode) = inode {
+                entry.set_extension("inode", &inode).unwrap();
+            }
+            entry
+        };
+
+        let tree = Tree::default();
+        tree.insert_file("a", file(Some((1, 10)), 5)).unwrap();
+        tree.insert_file("b", file(Some((1, 11)), 5)).unwrap();
+        tree.insert_file("old", file(None, 5)).unwrap();
+        let known = KnownContents::new(&tree);
+        let find = |path, entry: &Entry, metadata_only| {
+            known
+                .find(&tree, path, entry, metadata_only)
+                .map(|(from, stored)| (from.map(str::to_string), stored))
+        };
+
+        // the same size and time on another inode is a different file
+        let (from, stored) = find("c", &file(Some((1, 11)), 5), false).unwrap();
+        assert_eq!(from.as_deref(), Some("b"));
+        assert_eq!(stored.inode(), Some((1, 11)));
+        assert!(find("c", &file(Some((1, 12)), 5), false).is_none());
+        assert!(find("c", &file(Some((2, 10)), 5), false).is_none());
+        assert!(find("c", &file(None, 5), false).is_none());
+
+        // an existing path is only reused with `--metadata-only`
+        assert!(find("a", &file(Some((1, 10)), 5), false).is_none());
+        assert_eq!(find("a", &file(Some((1, 10)), 5), true).unwrap().0, None);
+
+        // a replaced file at the same path is not matched to itself
+        assert!(find("a", &file(Some((1, 10)), 6), true).is_none());
+        assert!(find("old", &file(None, 5), true).is_none());
+    }
+}
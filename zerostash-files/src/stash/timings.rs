@@ -0,0 +1,90 @@
+//! Break down the time spent in each stage of a commit
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Walking the directory tree and reading metadata
+    Walk,
+    /// Opening and reading or mapping files
+    Read,
+    /// Finding chunk boundaries and hashing chunks
+    Chunk,
+    /// Compressing, encrypting, and writing chunks to the backend
+    Write,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Walk, Stage::Read, Stage::Chunk, Stage::Write];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Walk => "walk",
+            Stage::Read => "read",
+            Stage::Chunk => "chunk & hash",
+            Stage::Write => "compress, encrypt & upload",
+        }
+    }
+}
+
+/// Time spent in each stage by a single thread
+#[derive(Default, Debug)]
+pub struct StageTimes([AtomicU64; Stage::ALL.len()]);
+
+impl StageTimes {
+    pub fn add(&self, stage: Stage, elapsed: Duration) {
+        self.0[stage as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Run `f`, and add the time it took to `stage`.
+    pub fn measure<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(stage, start.elapsed());
+        result
+    }
+
+    pub fn get(&self, stage: Stage) -> Duration {
+        Duration::from_nanos(self.0[stage as usize].load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Timings {
+    walker: StageTimes,
+    workers: Mutex<Vec<Arc<StageTimes>>>,
+}
+
+impl Timings {
+    /// Times of the thread walking the directory tree
+    pub fn walker(&self) -> &StageTimes {
+        &self.walker
+    }
+
+    /// Register a new worker thread.
+    pub fn worker(&self) -> Arc<StageTimes> {
+        let times = Arc::new(StageTimes::default());
+        self.workers.lock().unwrap().push(Arc::clone(&times));
+        times
+    }
+
+    /// Total time spent in `stage`, summed over all threads
+    pub fn total(&self, stage: Stage) -> Duration {
+        self.walker.get(stage) + self.per_worker(stage).into_iter().sum::<Duration>()
+    }
+
+    /// Time spent in `stage` by each worker thread
+    pub fn per_worker(&self, stage: Stage) -> Vec<Duration> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| w.get(stage))
+            .collect()
+    }
+}
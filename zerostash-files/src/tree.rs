@@ -1,14 +1,16 @@
 use std::{
+    collections::HashSet,
+    fmt,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     vec,
 };
 
 use infinitree::{
     fields::{Collection, Store, VersionedMap},
-    Digest,
+    ChunkPointer, Digest,
 };
 
 use crate::Entry;
@@ -18,17 +20,42 @@ pub enum FsError<'a> {
     InvalidPath(Vec<&'a str>),
     NoSuchFileOrDirectory,
     InvalidFilesystem,
+    ReadOnly,
 }
 pub type Result<'a, T> = std::result::Result<T, FsError<'a>>;
 
+/// A problem found by [`Tree::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// The directory entry at the path points to a missing node
+    Dangling(String),
+    /// The directory at the path is reachable through more than one
+    /// entry
+    Loop(String),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::Dangling(path) => write!(f, "dangling reference: {path}"),
+            Inconsistency::Loop(path) => write!(f, "directory loop: {path}"),
+        }
+    }
+}
+
 type InnerTree = VersionedMap<Digest, Node>;
 
-// InnerTree, is root initialized
-pub struct Tree(InnerTree, AtomicBool);
+// InnerTree, is root initialized, is read-only, chunks of loaded files
+pub struct Tree(InnerTree, AtomicBool, AtomicBool, ChunkInterner);
 
 impl Default for Tree {
     fn default() -> Tree {
-        let tree = Tree(InnerTree::default(), false.into());
+        let tree = Tree(
+            InnerTree::default(),
+            false.into(),
+            false.into(),
+            ChunkInterner::default(),
+        );
         tree.insert_root().unwrap();
         tree
     }
@@ -37,7 +64,46 @@ impl Default for Tree {
 // auto-derive will not work for resolving constraints properly
 impl Clone for Tree {
     fn clone(&self) -> Self {
-        Tree(self.0.clone(), self.1.load(Ordering::SeqCst).into())
+        Tree(
+            self.0.clone(),
+            self.1.load(Ordering::SeqCst).into(),
+            self.2.load(Ordering::SeqCst).into(),
+            self.3.clone(),
+        )
+    }
+}
+
+/// Share chunk pointers between the files loaded from the index
+///
+/// Every file is deserialized with its own copy of each chunk pointer,
+/// even if the same chunk is used by many files. Keeping a single copy
+/// of each saves around 100 bytes per duplicate reference, for about
+/// 10 bytes per distinct chunk. The serialized format is not affected.
+///
+/// Clones of the tree, eg. the ones held by commit workers, share the
+/// same set.
+#[derive(Clone, Default)]
+struct ChunkInterner(Arc<Mutex<HashSet<Arc<ChunkPointer>>>>);
+
+impl ChunkInterner {
+    fn intern(&self, mut node: Arc<Node>) -> Arc<Node> {
+        if !node.is_file() {
+            return node;
+        }
+
+        if let Node::File { entry, .. } = Arc::make_mut(&mut node) {
+            let mut shared = self.0.lock().unwrap();
+            for pointer in Arc::make_mut(entry).chunks.values_mut() {
+                match shared.get(pointer.as_ref()) {
+                    Some(interned) => *pointer = Arc::clone(interned),
+                    None => {
+                        shared.insert(Arc::clone(pointer));
+                    }
+                }
+            }
+        }
+
+        node
     }
 }
 
@@ -49,6 +115,10 @@ pub enum Node {
     },
     Directory {
         entries: scc::HashMap<String, Digest>,
+        /// Mode, owner and times of the directory, if it was committed
+        /// from a file system
+        #[serde(default)]
+        metadata: Option<Arc<Entry>>,
     },
 }
 
@@ -56,6 +126,7 @@ impl Node {
     fn directory() -> Node {
         Node::Directory {
             entries: scc::HashMap::with_capacity(0),
+            metadata: None,
         }
     }
 
@@ -73,6 +144,14 @@ impl Node {
         }
     }
 
+    /// The entry of a file, or the metadata of a directory
+    pub fn metadata(&self) -> Option<Arc<Entry>> {
+        match self {
+            Node::File { entry, .. } => Some(Arc::clone(entry)),
+            Node::Directory { metadata, .. } => metadata.clone(),
+        }
+    }
+
     pub fn is_file(&self) -> bool {
         matches!(self, Node::File { .. })
     }
@@ -91,8 +170,27 @@ impl Tree {
         Ok(())
     }
 
+    /// Make all changes to the tree fail with [`FsError::ReadOnly`]
+    ///
+    /// Loading the tree from the stash is not affected.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.2.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.2.load(Ordering::SeqCst)
+    }
+
+    fn check_writable<'a>(&self) -> Result<'a, ()> {
+        if self.is_read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
     /// Create a new directory at `path`, creating all entries in between
     pub fn insert_directory<'a>(&self, path: &'a str) -> Result<'a, ()> {
+        self.check_writable()?;
         let (noderef, _current, filename) = self.create_path_to_parent(path)?;
         self.add_empty_dir(&noderef, filename);
         Ok(())
@@ -100,6 +198,7 @@ impl Tree {
 
     /// Insert or overwrite an file at `path`, creating all entries in between
     pub fn insert_file<'a>(&self, path: &'a str, file: Entry) -> Result<'a, ()> {
+        self.check_writable()?;
         let (noderef, _current, filename) = self.create_path_to_parent(path)?;
         self.add_file(&noderef, filename, file);
         Ok(())
@@ -107,6 +206,7 @@ impl Tree {
 
     /// Updates an file at `path`
     pub fn update_file<'a>(&self, path: &'a str, file: Entry) -> Result<'a, ()> {
+        self.check_writable()?;
         let file_ref = self.get_ref(path)?.ok_or(FsError::NoSuchFileOrDirectory)?;
         self.0
             .update_with(file_ref, |_| Node::file(file))
@@ -114,13 +214,72 @@ impl Tree {
         Ok(())
     }
 
+    /// Record the mode, owner and times of the directory at `path`
+    ///
+    /// The directory node is replaced, so this must not run at the same
+    /// time as changes to its entries.
+    pub fn set_directory_metadata<'a>(&self, path: &'a str, metadata: Entry) -> Result<'a, ()> {
+        self.check_writable()?;
+        let dir_ref = self.get_ref(path)?.ok_or(FsError::NoSuchFileOrDirectory)?;
+        let Some(Node::Directory {
+            entries,
+            metadata: current,
+        }) = self.0.get(&dir_ref).as_deref().cloned()
+        else {
+            return Err(FsError::InvalidFilesystem);
+        };
+
+        // every update is stored in the next commit, even if it's the same
+        if current.as_deref() == Some(&metadata) {
+            return Ok(());
+        }
+
+        self.0
+            .update_with(dir_ref, |_| Node::Directory {
+                entries,
+                metadata: Some(metadata.into()),
+            })
+            .ok_or(FsError::InvalidFilesystem)?;
+        Ok(())
+    }
+
+    /// All directories with recorded metadata, and their paths
+    pub fn directories(&self) -> Vec<(String, Arc<Entry>)> {
+        let mut found = vec![];
+        let mut stack = vec![(String::new(), Digest::default())];
+
+        while let Some((path, noderef)) = stack.pop() {
+            let Some(node) = self.0.get(&noderef) else {
+                continue;
+            };
+            let Node::Directory { entries, metadata } = node.as_ref() else {
+                continue;
+            };
+
+            if let Some(metadata) = metadata {
+                found.push((path.clone(), Arc::clone(metadata)));
+            }
+            entries.scan(|name, childref| {
+                let child = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}/{name}")
+                };
+                stack.push((child, *childref));
+            });
+        }
+
+        found
+    }
+
     /// Recursively remove a subtree the `path`
     pub fn remove<'a>(&self, path: &'a str) -> Result<'a, ()> {
+        self.check_writable()?;
         let (parent_ref, parent, to_delete) = self.path_to_parent(path)?;
 
         let stack = scc::Stack::default();
 
-        if let Node::Directory { ref entries } = parent.as_ref() {
+        if let Node::Directory { ref entries, .. } = parent.as_ref() {
             let node_ref = entries
                 .read(to_delete, |_, v| *v)
                 .ok_or(FsError::NoSuchFileOrDirectory)?;
@@ -139,6 +298,7 @@ impl Tree {
 
                     Some(Node::Directory {
                         entries: ref inner_entries,
+                        ..
                     }) => {
                         if inner_entries.is_empty() {
                             // if it's empty, just delete it
@@ -204,11 +364,12 @@ impl Tree {
 
     /// Move the file from the old path to the new path in the tree
     pub fn move_node<'a>(&self, old_path: &'a str, new_path: &'a str) -> Result<'a, ()> {
+        self.check_writable()?;
         let (parent_ref, _, node_name) = self.path_to_parent(old_path)?;
         let noderef = {
             let mut noderef = None;
             self.0.update_with(parent_ref, |current| {
-                let Node::Directory { ref entries } = current.as_ref() else {
+                let Node::Directory { ref entries, .. } = current.as_ref() else {
                     unreachable!()
                 };
                 noderef = entries.remove(node_name);
@@ -224,7 +385,7 @@ impl Tree {
         // add to the new place, overwriting an existing file there
         let (new_ref, _, new_node_name) = self.path_to_parent(new_path)?;
         self.0.update_with(new_ref, |new| {
-            let Node::Directory { ref entries } = new.as_ref() else {
+            let Node::Directory { ref entries, .. } = new.as_ref() else {
                 unreachable!()
             };
             _ = entries.insert(new_node_name.into(), noderef);
@@ -240,7 +401,7 @@ impl Tree {
 
     fn remove_file(&self, parent_ref: &Digest, filename: &str) {
         self.0.update_with(*parent_ref, |new| {
-            if let Node::Directory { ref entries } = new.as_ref() {
+            if let Node::Directory { ref entries, .. } = new.as_ref() {
                 entries.remove(filename);
                 return new;
             }
@@ -251,7 +412,7 @@ impl Tree {
     /// Return the internal reference to the path
     fn get_ref<'a>(&self, path: &'a str) -> Result<'a, Option<Digest>> {
         let (_, current, filename) = self.path_to_parent(path)?;
-        let Node::Directory { ref entries } = current.as_ref() else {
+        let Node::Directory { ref entries, .. } = current.as_ref() else {
             unreachable!()
         };
 
@@ -262,7 +423,7 @@ impl Tree {
     pub fn is_file<'a>(&self, path: &'a str) -> Result<'a, bool> {
         let (_, current, _filename) = self.path_to_parent(path)?;
 
-        let Node::Directory { ref entries } = current.as_ref() else {
+        let Node::Directory { ref entries, .. } = current.as_ref() else {
             unreachable!()
         };
 
@@ -295,7 +456,7 @@ impl Tree {
         for part in parts.iter() {
             consumed.push(*part);
 
-            let Some(Node::Directory { entries }) = current.as_deref() else {
+            let Some(Node::Directory { entries, .. }) = current.as_deref() else {
                 return Err(FsError::InvalidPath(consumed));
             };
 
@@ -334,7 +495,7 @@ impl Tree {
         for part in parts.iter() {
             consumed.push(*part);
 
-            let Some(Node::Directory { entries }) = current.as_deref() else {
+            let Some(Node::Directory { entries, .. }) = current.as_deref() else {
                 return Err(FsError::InvalidPath(consumed));
             };
 
@@ -361,7 +522,7 @@ impl Tree {
     fn add_empty_dir(&self, parent: &Digest, name: &str) -> (Digest, Arc<Node>) {
         let noderef: Digest = rand::random();
         self.0.update_with(*parent, |parent| {
-            let Node::Directory { ref entries } = parent.as_ref() else {
+            let Node::Directory { ref entries, .. } = parent.as_ref() else {
                 panic!("invalid use of library");
             };
             _ = entries.insert(name.into(), noderef);
@@ -376,7 +537,7 @@ impl Tree {
         let mut noderef: Digest = rand::random();
         let mut update = false;
         self.0.update_with(*parent, |parent| {
-            let Node::Directory { ref entries } = parent.as_ref() else {
+            let Node::Directory { ref entries, .. } = parent.as_ref() else {
                 panic!("invalid use of library");
             };
             match entries.entry(name.into()) {
@@ -418,7 +579,7 @@ impl Tree {
                             to_remove.push(path);
                         }
                     }
-                    Node::Directory { entries } => {
+                    Node::Directory { entries, .. } => {
                         if !f(&path, &node) {
                             to_remove.push(path);
                         } else {
@@ -448,7 +609,43 @@ impl Tree {
         TreeIterator { stack, inner: self }
     }
 
+    /// Walk the whole tree and collect the entries that can't be
+    /// followed.
+    pub fn validate(&self) -> Vec<Inconsistency> {
+        let mut problems = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = vec![(String::new(), Digest::default())];
+
+        while let Some((path, noderef)) = stack.pop() {
+            let Some(node) = self.0.get(&noderef) else {
+                problems.push(Inconsistency::Dangling(path));
+                continue;
+            };
+
+            let Node::Directory { entries, .. } = node.as_ref() else {
+                continue;
+            };
+
+            if !seen.insert(noderef) {
+                problems.push(Inconsistency::Loop(path));
+                continue;
+            }
+
+            entries.scan(|name, childref| {
+                let child = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}/{name}")
+                };
+                stack.push((child, *childref));
+            });
+        }
+
+        problems
+    }
+
     pub fn clear(&self) -> Result<'_, ()> {
+        self.check_writable()?;
         self.0.clear();
         self.insert_root()
     }
@@ -467,17 +664,25 @@ impl<'a> Iterator for TreeIterator<'a> {
             let (prefix, node) = (next.0.to_string(), next.1.as_ref());
             match node {
                 Node::File { refs: _, entry } => return Some((prefix, entry.clone())),
-                Node::Directory { entries } => {
-                    entries.scan(|name, digest| {
+                Node::Directory { entries, .. } => {
+                    let mut children = Vec::with_capacity(entries.len());
+                    entries.scan(|name, digest| children.push((name.clone(), *digest)));
+
+                    // `scc::HashMap` has no stable iteration order, so
+                    // sort the entries to make the output reproducible.
+                    // They're pushed in reverse, so they pop in order.
+                    children.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+                    for (name, digest) in children {
                         let path = if prefix.is_empty() {
-                            name.to_string()
+                            name
                         } else {
                             format!("{prefix}/{name}")
                         };
-                        if let Some(curr) = self.inner.0.get(digest) {
+                        if let Some(curr) = self.inner.0.get(&digest) {
                             self.stack.push((path, curr));
                         }
-                    });
+                    }
                 }
             }
         }
@@ -510,6 +715,7 @@ impl Collection for Tree {
             self.0.update_with(key, |_| new_entry.unwrap());
             self.1.store(true, Ordering::Relaxed);
         } else if !self.0.contains(&key) {
+            let new_entry = new_entry.map(|node| self.3.intern(node));
             <InnerTree as Collection>::insert(&mut self.0, (key, new_entry))
         }
     }
@@ -532,7 +738,7 @@ mod test {
     use infinitree::{crypto::UsernamePassword, Digest, Infinitree};
     use scc::HashSet;
 
-    use crate::{Entry, Files, Node, Tree};
+    use crate::{Entry, Files, FsError, Node, Tree};
 
     #[test]
     fn test_create_path_to_parent() {
@@ -564,6 +770,48 @@ mod test {
         assert!(tree.file(file_path).unwrap().is_none());
     }
 
+    #[test]
+    fn test_directory_metadata() {
+        use std::sync::Arc;
+
+        let tree = Tree::default();
+        tree.insert_file("a/b/file", Entry::default()).unwrap();
+
+        let metadata = Entry {
+            name: "b".into(),
+            unix_perm: Some(0o750),
+            ..Entry::default()
+        };
+        tree.set_directory_metadata("a/b", metadata.clone())
+            .unwrap();
+
+        // entries of the directory are kept
+        assert!(tree.file("a/b/file").unwrap().is_some());
+        assert_eq!(
+            tree.directories(),
+            vec![("a/b".to_string(), metadata.clone().into())]
+        );
+
+        // unchanged metadata doesn't replace the node
+        let node = tree.node_by_path("a/b").unwrap().unwrap();
+        tree.set_directory_metadata("a/b", metadata.clone())
+            .unwrap();
+        assert!(Arc::ptr_eq(
+            &node,
+            &tree.node_by_path("a/b").unwrap().unwrap()
+        ));
+
+        let changed = Entry {
+            unix_perm: Some(0o700),
+            ..metadata
+        };
+        tree.set_directory_metadata("a/b", changed).unwrap();
+        assert!(!Arc::ptr_eq(
+            &node,
+            &tree.node_by_path("a/b").unwrap().unwrap()
+        ));
+    }
+
     #[test]
     fn test_move_node() {
         let key = || {
@@ -840,6 +1088,19 @@ mod test {
         assert_eq!(tree.index().tree.iter_files().count(), 3);
     }
 
+    #[test]
+    fn test_iter_files_is_sorted() {
+        let tree = Tree::default();
+        let files = ["b/2.rs", "a/z.rs", "b/1.rs", "a/b/c.rs", "c.rs"];
+
+        for file in files {
+            tree.insert_file(file, Entry::default()).unwrap();
+        }
+
+        let paths = tree.iter_files().map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(paths, ["a/b/c.rs", "a/z.rs", "b/1.rs", "b/2.rs", "c.rs"]);
+    }
+
     #[test]
     fn test_get_dir() {
         let tree = Tree::default();
@@ -863,7 +1124,7 @@ mod test {
         assert_eq!(root.is_dir(), root_node.is_dir());
         assert_eq!("home", test_name);
 
-        if let Node::Directory { entries } = root.as_ref().clone() {
+        if let Node::Directory { entries, .. } = root.as_ref().clone() {
             let (test_ref, test_node, to_name) = tree.path_to_parent("home/travel").unwrap();
 
             let t_ref = entries.read("home", |_, v| *v).unwrap();
@@ -887,4 +1148,68 @@ mod test {
 
         assert!(tree.node_by_path("home/travel").unwrap().is_none());
     }
+
+    #[test]
+    fn test_loaded_chunks_are_shared() {
+        use infinitree::{fields::Collection, ChunkPointer};
+        use std::sync::Arc;
+
+        let file = || {
+            let mut entry = Entry::default();
+            entry.chunks.insert(0, Arc::new(ChunkPointer::default()));
+            Arc::new(Node::file(entry))
+        };
+
+        let mut tree = Tree::default();
+        Collection::insert(&mut tree, ([1; 32], Some(file())));
+        Collection::insert(&mut tree, ([2; 32], Some(file())));
+
+        let a = tree.node_by_ref(&[1; 32]).unwrap().as_file().unwrap();
+        let b = tree.node_by_ref(&[2; 32]).unwrap().as_file().unwrap();
+        assert!(Arc::ptr_eq(&a.chunks[&0], &b.chunks[&0]));
+
+        // clones share the interned chunks instead of copying them
+        let mut clone = tree.clone();
+        Collection::insert(&mut clone, ([3; 32], Some(file())));
+        let c = clone.node_by_ref(&[3; 32]).unwrap().as_file().unwrap();
+        assert!(Arc::ptr_eq(&a.chunks[&0], &c.chunks[&0]));
+        assert!(Arc::ptr_eq(&tree.3 .0, &clone.3 .0));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let tree = Tree::default();
+        tree.insert_file("home/pic.png", Entry::default()).unwrap();
+        tree.set_read_only(true);
+
+        assert!(matches!(
+            tree.insert_file("home/new.png", Entry::default()),
+            Err(FsError::ReadOnly)
+        ));
+        assert!(matches!(
+            tree.remove("home/pic.png"),
+            Err(FsError::ReadOnly)
+        ));
+        assert!(tree.file("home/pic.png").unwrap().is_some());
+        assert!(tree.clone().is_read_only());
+    }
+
+    #[test]
+    fn test_validate() {
+        let tree = Tree::default();
+        tree.insert_file("home/travel/pic.png", Entry::default())
+            .unwrap();
+        assert!(tree.validate().is_empty());
+
+        let (_, parent, _) = tree.path_to_parent("home/travel/pic.png").unwrap();
+        let Node::Directory { entries, .. } = parent.as_ref() else {
+            unreachable!()
+        };
+        tree.0.remove(entries.read("pic.png", |_, v| *v).unwrap());
+
+        assert_eq!(
+            tree.validate(),
+            vec![crate::Inconsistency::Dangling("home/travel/pic.png".into())]
+        );
+    }
 }
@@ -0,0 +1,101 @@
+//! Read the objects of a directory into memory instead of mapping them
+//!
+//! The directory backend maps object files into memory when they're
+//! read, so the page cache holds them, and they're not copied to the
+//! heap. On a network file system, a file that's truncated or goes
+//! away while it's mapped faults the process instead of failing the
+//! read. [`Unmapped`] reads a copy of every object instead.
+use crate::{
+    list::{self, Objects, Storage},
+    migrate::object_from_bytes,
+};
+use infinitree::{
+    backends::{Backend, Directory, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+pub struct Unmapped {
+    path: PathBuf,
+    inner: Arc<Directory>,
+}
+
+impl Unmapped {
+    pub fn new(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Arc::new(Self {
+            inner: Directory::new(&path)?,
+            path,
+        }))
+    }
+}
+
+impl Backend for Unmapped {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.inner.write_object(object)
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let data = fs::read(self.path.join(id.to_string()))?;
+        Ok(Arc::new(object_from_bytes(*id, &data).into()))
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.inner.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+impl Storage for Unmapped {
+    fn list(&self) -> io::Result<Objects> {
+        Ok(Box::new(list::Directory::new(&self.path)?))
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn reads_what_the_directory_wrote() {
+        use super::Unmapped;
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{Backend, Directory},
+            object::ObjectId,
+        };
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-unmapped-{}", rand::random::<u64>()));
+        let id = ObjectId::from_bytes(rand::random());
+        let directory = Directory::new(&path).unwrap();
+        directory
+            .write_object(&object_from_bytes(id, &[7; 100]))
+            .unwrap();
+        directory.sync().unwrap();
+
+        let unmapped = Unmapped::new(&path).unwrap();
+        assert_eq!(
+            unmapped.read_object(&id).unwrap().as_inner(),
+            directory.read_object(&id).unwrap().as_inner()
+        );
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+//! Upload objects on a separate pool of IO threads
+//!
+//! Writing an object to a remote backend is mostly waiting on the
+//! network. [`Upload`] takes uploads off the worker that sealed the
+//! object, so CPU workers can keep chunking, compressing and
+//! encrypting while objects are in flight.
+//!
+//! IO threads are started on demand: whenever the upload queue is
+//! full, another thread is added up to the configured maximum. A
+//! backend with high latency will therefore get more concurrent
+//! uploads, while a fast one keeps using a single thread.
+//!
+//! Uploads can also be delayed by a random amount of time, so the
+//! storage provider can't tell as precisely when data was written.
+use crate::list::{Objects, Storage};
+use infinitree::{
+    backends::{Backend, BackendError, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use rand::Rng;
+use std::{
+    collections::HashSet,
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tracing::{debug, Span};
+
+#[derive(Default)]
+struct State {
+    in_flight: HashSet<ObjectId>,
+    error: Option<BackendError>,
+}
+
+struct Shared {
+    upstream: Arc<dyn Storage>,
+    state: Mutex<State>,
+    done: Condvar,
+    jitter: Duration,
+}
+
+pub struct Upload {
+    shared: Arc<Shared>,
+    /// Objects are queued with the span they were written in, so the
+    /// upload is logged as part of the same operation
+    queue: flume::Sender<(WriteObject, Span)>,
+    receiver: flume::Receiver<(WriteObject, Span)>,
+    threads: AtomicUsize,
+    max_threads: usize,
+}
+
+impl Upload {
+    /// Wrap `upstream`, uploading objects on up to `max_threads`
+    /// background threads.
+    pub fn new(upstream: Arc<dyn Storage>, max_threads: usize) -> Arc<Self> {
+        Self::with_jitter(upstream, max_threads, Duration::ZERO)
+    }
+
+    /// Same as [`Upload::new`], but wait a random time of up to
+    /// `jitter` before each upload.
+    pub fn with_jitter(
+        upstream: Arc<dyn Storage>,
+        max_threads: usize,
+        jitter: Duration,
+    ) -> Arc<Self> {
+        let max_threads = max_threads.max(1);
+        let (queue, receiver) = flume::bounded(max_threads);
+
+        let upload = Self {
+            shared: Arc::new(Shared {
+                upstream,
+                state: Default::default(),
+                done: Condvar::new(),
+                jitter,
+            }),
+            queue,
+            receiver,
+            threads: AtomicUsize::new(0),
+            max_threads,
+        };
+        upload.add_thread();
+
+        Arc::new(upload)
+    }
+
+    fn add_thread(&self) {
+        let added = self
+            .threads
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_threads).then_some(n + 1)
+            });
+
+        let Ok(current) = added else {
+            return;
+        };
+        debug!(threads = current + 1, "adding upload thread");
+
+        let receiver = self.receiver.clone();
+        let shared = Arc::clone(&self.shared);
+
+        thread::spawn(move || {
+            while let Ok((object, span)) = receiver.recv() {
+                let _entered = span.enter();
+                shared.upload(object);
+            }
+        });
+    }
+
+    /// Block until none of `objects` are being uploaded.
+    fn wait_for(&self, objects: &[ObjectId]) {
+        let mut state = self.shared.state.lock().unwrap();
+        while objects.iter().any(|id| state.in_flight.contains(id)) {
+            state = self.shared.done.wait(state).unwrap();
+        }
+    }
+
+    /// Return the first error from a background upload, if any.
+    fn take_error(&self) -> Result<()> {
+        match self.shared.state.lock().unwrap().error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    fn upload(&self, object: WriteObject) {
+        if !self.jitter.is_zero() {
+            thread::sleep(rand::thread_rng().gen_range(Duration::ZERO..self.jitter));
+        }
+
+        debug!(object = %object.id(), "uploading");
+        let result = self.upstream.write_object(&object);
+
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(object.id());
+        if let Err(error) = result {
+            state.error.get_or_insert(error);
+        }
+
+        self.done.notify_all();
+    }
+}
+
+impl Backend for Upload {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        self.take_error()?;
+        self.shared
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .insert(*object.id());
+
+        let queued = match self.queue.try_send((object.clone(), Span::current())) {
+            Ok(()) => return Ok(()),
+            Err(flume::TrySendError::Full(queued)) => {
+                self.add_thread();
+                queued
+            }
+            Err(flume::TrySendError::Disconnected(_)) => {
+                unreachable!("workers only exit when `self` is dropped")
+            }
+        };
+
+        // wait for a free slot, so memory use stays bounded
+        _ = self.queue.send(queued);
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        self.wait_for(std::slice::from_ref(id));
+        self.shared.upstream.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        self.shared.upstream.preload(objects)
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        self.wait_for(objects);
+        self.shared.upstream.delete(objects)
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        self.shared.upstream.keep_warm(objects)
+    }
+
+    fn sync(&self) -> Result<()> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            while !state.in_flight.is_empty() {
+                state = self.shared.done.wait(state).unwrap();
+            }
+        }
+
+        self.take_error()?;
+        self.shared.upstream.sync()
+    }
+}
+
+impl Storage for Upload {
+    fn list(&self) -> io::Result<Objects> {
+        // objects still in flight aren't listed until they're uploaded
+        self.shared.upstream.list()
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
@@ -0,0 +1,135 @@
+//! Restore as much ownership as possible without full root
+//!
+//! Changing the owner of a file needs root. Inside a rootless
+//! container, or under `unshare --map-root-user`, 0s runs as root of a
+//! user namespace, and can only change owners to the ids that are
+//! mapped in `/proc/self/uid_map` and `/proc/self/gid_map`.
+//!
+//! Files owned by ids that can't be restored get the owner of the
+//! process instead. Their original owner can be written to an
+//! ownership record, one `UID GID PATH` line per file, so it can be
+//! applied later by a privileged user.
+use crate::files::Entry;
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+use tracing::debug;
+
+/// Ranges of ids mapped into the user namespace
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdRanges(Vec<(u32, u32)>);
+
+impl IdRanges {
+    /// Parse an id map of the kernel, with `INSIDE OUTSIDE COUNT` lines.
+    pub fn parse(map: &str) -> Self {
+        Self(
+            map.lines()
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace().map(str::parse::<u32>);
+                    let inside = fields.next()?.ok()?;
+                    let _outside = fields.next()?.ok()?;
+                    let count = fields.next()?.ok()?;
+                    Some((inside, count))
+                })
+                .collect(),
+        )
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.0
+            .iter()
+            .any(|(start, count)| id >= *start && (id - start) < *count)
+    }
+}
+
+/// Decides whose files can be chowned, and records the rest
+pub struct Ownership {
+    can_chown: bool,
+    uids: Option<IdRanges>,
+    gids: Option<IdRanges>,
+    record: Option<Mutex<BufWriter<fs::File>>>,
+}
+
+impl Ownership {
+    /// Check the privileges of the process, and create the ownership
+    /// record at `record`, if given.
+    pub fn new(record: Option<&Path>) -> io::Result<Self> {
+        let record = match record {
+            Some(path) => Some(Mutex::new(BufWriter::new(fs::File::create(path)?))),
+            None => None,
+        };
+
+        #[cfg(unix)]
+        let can_chown = nix::unistd::Uid::effective().is_root();
+        #[cfg(not(unix))]
+        let can_chown = false;
+
+        // outside of a namespace, every id is mapped
+        let read_map = |path| fs::read_to_string(path).ok().map(|m| IdRanges::parse(&m));
+        let (uids, gids) = match cfg!(target_os = "linux") && can_chown {
+            true => (
+                read_map("/proc/self/uid_map"),
+                read_map("/proc/self/gid_map"),
+            ),
+            false => (None, None),
+        };
+        debug!(can_chown, ?uids, ?gids, "restoring ownership");
+
+        Ok(Self {
+            can_chown,
+            uids,
+            gids,
+            record,
+        })
+    }
+
+    /// Returns true if the owner of `entry` can be restored. Otherwise
+    /// it's written to the ownership record.
+    pub fn allows(&self, path: &Path, entry: &Entry) -> io::Result<bool> {
+        let mapped = |ranges: &Option<IdRanges>, id: Option<u32>| match (ranges, id) {
+            (Some(ranges), Some(id)) => ranges.contains(id),
+            _ => true,
+        };
+
+        if self.can_chown
+            && mapped(&self.uids, entry.unix_uid)
+            && mapped(&self.gids, entry.unix_gid)
+        {
+            return Ok(true);
+        }
+
+        if let (Some(record), Some(uid), Some(gid)) = (&self.record, entry.unix_uid, entry.unix_gid)
+        {
+            writeln!(record.lock().unwrap(), "{uid} {gid} {}", path.display())?;
+        }
+
+        Ok(false)
+    }
+
+    /// Write the rest of the ownership record to disk.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.record {
+            Some(record) => record.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn rootless_container_ranges() {
+        use super::IdRanges;
+
+        let ranges =
+            IdRanges::parse("         0       1000          1\n         1     100000      65536\n");
+        assert!(ranges.contains(0));
+        assert!(ranges.contains(1));
+        assert!(ranges.contains(65536));
+        assert!(!ranges.contains(65537));
+        assert!(IdRanges::parse("0 0 4294967295").contains(1000));
+    }
+}
@@ -0,0 +1,524 @@
+//! Split a directory backend into volumes, eg. for FAT and exFAT disks
+//!
+//! Objects are small enough for the file size limit of FAT, but a
+//! single directory of a large stash can run out of entries. Objects
+//! are written to volume subdirectories instead, each holding up to a
+//! fixed number of objects before the next one is started.
+//!
+//! The volumes of a stash are listed in a manifest next to them. When
+//! a disk fills up, the stash can continue on a new disk, with the old
+//! disks mounted as read-only locations: new volumes are only created
+//! on the current disk, but objects are read from any mounted volume.
+//! The manifest is copied to every new disk, so the newest one knows
+//! about all volumes.
+//!
+//! Some objects, like the root of the index, are written again with
+//! the same id by every commit. They're rewritten in place while their
+//! volume is on the current disk and it has room, and moved to the
+//! current volume otherwise.
+//!
+//! Objects that were written to the directory before it was split into
+//! volumes are still read from there.
+use crate::{disk_space, list};
+use infinitree::{
+    backends::{Backend, Directory, Result},
+    object::{ObjectId, ReadObject, WriteObject},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// File name of the manifest
+pub const MANIFEST: &str = "volumes.json";
+
+/// Where objects that are stored directly in the directory are located
+const UNSPLIT: &str = ".";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// All volumes of the stash, in the order they were created
+    pub volumes: Vec<Volume>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Volume {
+    /// Name of the volume subdirectory
+    pub name: String,
+    /// Number of objects in the volume when it was last written to
+    pub objects: usize,
+}
+
+impl Manifest {
+    /// Read the manifest that knows about the most volumes in `roots`.
+    pub fn find(roots: &[PathBuf]) -> io::Result<Self> {
+        let mut newest = Self::default();
+
+        for root in roots {
+            let manifest: Self = match fs::read(root.join(MANIFEST)) {
+                Ok(bytes) => serde_json::from_slice(&bytes)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            // volumes are only ever added
+            if manifest.volumes.len() > newest.volumes.len() {
+                newest = manifest;
+            }
+        }
+
+        Ok(newest)
+    }
+
+    fn write(&self, root: &Path) -> io::Result<()> {
+        let temp = root.join(format!("{MANIFEST}.tmp"));
+        fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(temp, root.join(MANIFEST))
+    }
+}
+
+/// The directories of the volumes in `roots` that are mounted, in the
+/// order they were created
+pub fn mounted(roots: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    Ok(Manifest::find(roots)?
+        .volumes
+        .iter()
+        .filter_map(|volume| {
+            roots
+                .iter()
+                .map(|root| root.join(&volume.name))
+                .find(|dir| dir.is_dir())
+        })
+        .collect())
+}
+
+struct Mounted {
+    path: PathBuf,
+    dir: Arc<Directory>,
+    /// Only volumes in the current directory are written to
+    writable: bool,
+}
+
+struct State {
+    manifest: Manifest,
+    mounted: HashMap<String, Mounted>,
+    located: HashMap<ObjectId, String>,
+    changed: bool,
+}
+
+impl State {
+    fn volume_of(&self, id: &ObjectId) -> Option<Arc<Directory>> {
+        let name = self.located.get(id)?;
+        Some(Arc::clone(&self.mounted.get(name)?.dir))
+    }
+
+    fn unmounted(&self) -> Vec<&str> {
+        self.manifest
+            .volumes
+            .iter()
+            .filter(|v| !self.mounted.contains_key(&v.name))
+            .map(|v| v.name.as_str())
+            .collect()
+    }
+
+    fn volume_mut(&mut self, name: &str) -> Option<&mut Volume> {
+        self.manifest.volumes.iter_mut().find(|v| v.name == name)
+    }
+}
+
+pub struct Volumes {
+    path: PathBuf,
+    max_objects: usize,
+    state: Mutex<State>,
+}
+
+impl Volumes {
+    /// Write volumes of up to `max_objects` objects in `path`.
+    ///
+    /// Volumes in `mounts` are read, and objects are deleted from
+    /// them, but no new objects are written to them.
+    pub fn new(
+        path: impl AsRef<Path>,
+        max_objects: usize,
+        mounts: &[impl AsRef<Path>],
+    ) -> anyhow::Result<Arc<Self>> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let roots = std::iter::once(path.clone())
+            .chain(mounts.iter().map(|p| p.as_ref().to_path_buf()))
+            .collect::<Vec<_>>();
+        let mut manifest = Manifest::find(&roots)?;
+
+        let mut mounted = HashMap::new();
+        let mut located = HashMap::new();
+        for id in list::Directory::new(&path)? {
+            located.insert(id?, UNSPLIT.to_string());
+        }
+        if !located.is_empty() {
+            mounted.insert(
+                UNSPLIT.to_string(),
+                Mounted {
+                    dir: Directory::new(&path)?,
+                    path: path.clone(),
+                    writable: false,
+                },
+            );
+        }
+
+        for volume in manifest.volumes.iter_mut() {
+            let Some(root) = roots.iter().find(|r| r.join(&volume.name).is_dir()) else {
+                continue;
+            };
+
+            let dir = root.join(&volume.name);
+            volume.objects = 0;
+            for id in list::Directory::new(&dir)? {
+                located.insert(id?, volume.name.clone());
+                volume.objects += 1;
+            }
+
+            mounted.insert(
+                volume.name.clone(),
+                Mounted {
+                    dir: Directory::new(&dir)?,
+                    path: dir,
+                    writable: root == &path,
+                },
+            );
+        }
+
+        Ok(Arc::new(Self {
+            path,
+            max_objects: max_objects.max(1),
+            state: Mutex::new(State {
+                manifest,
+                mounted,
+                located,
+                changed: false,
+            }),
+        }))
+    }
+
+    /// Find a volume with room for one more object, or start a new one.
+    fn volume_for_write(&self, state: &mut State) -> Result<String> {
+        if let Some(last) = state.manifest.volumes.last() {
+            let writable = state.mounted.get(&last.name).is_some_and(|m| m.writable);
+            if writable && last.objects < self.max_objects {
+                return Ok(last.name.clone());
+            }
+        }
+
+        let name = format!("vol-{:04}", state.manifest.volumes.len());
+        let dir = self.path.join(&name);
+        fs::create_dir_all(&dir)?;
+
+        state.mounted.insert(
+            name.clone(),
+            Mounted {
+                dir: Directory::new(&dir)?,
+                path: dir,
+                writable: true,
+            },
+        );
+        state.manifest.volumes.push(Volume {
+            name: name.clone(),
+            objects: 0,
+        });
+        state.changed = true;
+
+        Ok(name)
+    }
+
+    /// Whether `object` can be written again to the volume `name`,
+    /// where it's stored already
+    fn has_room(state: &State, name: &str, object: &WriteObject) -> bool {
+        let Some(mounted) = state.mounted.get(name).filter(|m| m.writable) else {
+            return false;
+        };
+
+        // the old copy may only be freed once the new one is written
+        match disk_space::available(&mounted.path) {
+            Ok(Some(available)) => available >= object.as_inner().len() as u64,
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn group(&self, objects: &[ObjectId]) -> Vec<(Arc<Directory>, Vec<ObjectId>)> {
+        let state = self.state.lock().unwrap();
+        let mut grouped: HashMap<&str, Vec<ObjectId>> = HashMap::new();
+
+        for id in objects {
+            match state.located.get(id) {
+                Some(name) if state.mounted.contains_key(name) => {
+                    grouped.entry(name.as_str()).or_default().push(*id)
+                }
+                _ => warn!(%id, "object is not on a mounted volume"),
+            }
+        }
+
+        grouped
+            .into_iter()
+            .map(|(name, ids)| (Arc::clone(&state.mounted[name].dir), ids))
+            .collect()
+    }
+}
+
+impl Backend for Volumes {
+    fn write_object(&self, object: &WriteObject) -> Result<()> {
+        let id = *object.id();
+        let (name, current, dir) = {
+            let mut state = self.state.lock().unwrap();
+            let current = state.located.get(&id).cloned();
+            let name = match current {
+                Some(ref name) if Self::has_room(&state, name, object) => name.clone(),
+                _ => self.volume_for_write(&mut state)?,
+            };
+
+            let dir = Arc::clone(&state.mounted[&name].dir);
+            (name, current, dir)
+        };
+
+        // the object is only recorded on its volume once it's there
+        dir.write_object(object)?;
+
+        let moved_from = {
+            let mut state = self.state.lock().unwrap();
+            match current {
+                Some(ref old) if *old == name => None,
+                current => {
+                    state.located.insert(id, name.clone());
+                    state.volume_mut(&name).unwrap().objects += 1;
+                    state.changed = true;
+
+                    current.and_then(|old| {
+                        if let Some(volume) = state.volume_mut(&old) {
+                            volume.objects = volume.objects.saturating_sub(1);
+                        }
+                        state.mounted.get(&old).map(|m| Arc::clone(&m.dir))
+                    })
+                }
+            }
+        };
+
+        // reads find the new copy, the old one is only taking up space
+        if let Some(old) = moved_from {
+            if let Err(error) = old.delete(&[id]) {
+                warn!(%id, %error, "failed to remove the old copy of a moved object");
+            }
+        }
+        Ok(())
+    }
+
+    fn read_object(&self, id: &ObjectId) -> Result<Arc<ReadObject>> {
+        let state = self.state.lock().unwrap();
+        let Some(dir) = state.volume_of(id) else {
+            let unmounted = state.unmounted();
+            let hint = match unmounted.is_empty() {
+                true => String::new(),
+                false => format!(", it may be on {}", unmounted.join(", ")),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("object {id} is not on a mounted volume{hint}"),
+            )
+            .into());
+        };
+        drop(state);
+
+        dir.read_object(id)
+    }
+
+    fn preload(&self, objects: &[ObjectId]) -> Result<()> {
+        for (dir, ids) in self.group(objects) {
+            dir.preload(&ids)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, objects: &[ObjectId]) -> Result<()> {
+        for (dir, ids) in self.group(objects) {
+            dir.delete(&ids)?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for id in objects {
+            if let Some(name) = state.located.remove(id) {
+                if let Some(volume) = state.volume_mut(&name) {
+                    volume.objects = volume.objects.saturating_sub(1);
+                }
+                state.changed = true;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn keep_warm(&self, objects: &[ObjectId]) -> Result<()> {
+        for (dir, ids) in self.group(objects) {
+            dir.keep_warm(&ids)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for mounted in state.mounted.values().filter(|m| m.writable) {
+            mounted.dir.sync()?;
+        }
+
+        if state.changed {
+            state.manifest.write(&self.path)?;
+            state.changed = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl list::Storage for Volumes {
+    /// Only objects on mounted volumes are listed.
+    fn list(&self) -> io::Result<list::Objects> {
+        let paths = {
+            let state = self.state.lock().unwrap();
+            state
+                .mounted
+                .values()
+                .map(|m| m.path.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut objects: list::Objects = Box::new(std::iter::empty());
+        for path in paths {
+            objects = Box::new(objects.chain(list::Directory::new(path)?));
+        }
+
+        Ok(objects)
+    }
+
+    fn into_backend(self: Arc<Self>) -> Arc<dyn Backend> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn volumes_fill_up_and_move_disks() {
+        use super::{mounted, Volumes};
+        use crate::migrate::object_from_bytes;
+        use infinitree::{backends::Backend, object::ObjectId};
+        use std::fs;
+
+        let base =
+            std::env::temp_dir().join(format!("zerostash-volumes-{}", rand::random::<u64>()));
+        let (first, second) = (base.join("first"), base.join("second"));
+
+        let ids = (0..3)
+            .map(|_| ObjectId::from_bytes(rand::random()))
+            .collect::<Vec<_>>();
+
+        let volumes = Volumes::new(&first, 2, &[] as &[&str]).unwrap();
+        for id in &ids[..2] {
+            volumes
+                .write_object(&object_from_bytes(*id, b"first"))
+                .unwrap();
+        }
+        volumes.sync().unwrap();
+
+        // the first disk is full, continue on the second one
+        let volumes = Volumes::new(&second, 2, &[&first]).unwrap();
+        volumes
+            .write_object(&object_from_bytes(ids[2], b"second"))
+            .unwrap();
+        volumes.sync().unwrap();
+        assert_eq!(
+            mounted(&[second.clone(), first.clone()]).unwrap(),
+            vec![first.join("vol-0000"), second.join("vol-0001")]
+        );
+
+        // objects rewritten with the same id move to the current disk
+        volumes
+            .write_object(&object_from_bytes(ids[1], b"rewritten"))
+            .unwrap();
+        volumes.sync().unwrap();
+
+        // without the first disk, only the new objects can be read
+        let volumes = Volumes::new(&second, 2, &[] as &[&str]).unwrap();
+        assert!(volumes.read_object(&ids[2]).is_ok());
+        assert_eq!(
+            volumes.read_object(&ids[1]).unwrap().as_inner()[..9],
+            b"rewritten"[..]
+        );
+        let error = volumes.read_object(&ids[0]).unwrap_err().to_string();
+        assert!(error.contains("vol-0000"));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_are_not_recorded() {
+        use super::Volumes;
+        use crate::migrate::object_from_bytes;
+        use infinitree::{backends::Backend, object::ObjectId};
+        use std::fs;
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-volumes-{}", rand::random::<u64>()));
+        let (stored, lost) = (
+            ObjectId::from_bytes(rand::random()),
+            ObjectId::from_bytes(rand::random()),
+        );
+
+        let volumes = Volumes::new(&path, 2, &[] as &[&str]).unwrap();
+        volumes
+            .write_object(&object_from_bytes(stored, b"stored"))
+            .unwrap();
+
+        // the volume can't be written to anymore
+        fs::remove_dir_all(path.join("vol-0000")).unwrap();
+        fs::write(path.join("vol-0000"), b"not a directory").unwrap();
+        assert!(volumes
+            .write_object(&object_from_bytes(lost, b"lost"))
+            .is_err());
+
+        {
+            let state = volumes.state.lock().unwrap();
+            assert!(state.located.contains_key(&stored));
+            assert!(!state.located.contains_key(&lost));
+            assert_eq!(state.manifest.volumes[0].objects, 1);
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn objects_from_before_the_split_are_read() {
+        use super::Volumes;
+        use crate::migrate::object_from_bytes;
+        use infinitree::{
+            backends::{Backend, Directory},
+            object::ObjectId,
+        };
+        use std::fs;
+
+        let path =
+            std::env::temp_dir().join(format!("zerostash-volumes-{}", rand::random::<u64>()));
+        let id = ObjectId::from_bytes(rand::random());
+        fs::create_dir_all(&path).unwrap();
+        Directory::new(&path)
+            .unwrap()
+            .write_object(&object_from_bytes(id, b"unsplit"))
+            .unwrap();
+
+        let volumes = Volumes::new(&path, 2, &[] as &[&str]).unwrap();
+        assert!(volumes.read_object(&id).is_ok());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Share a set of object writers between worker threads
+//!
+//! A [`Pool`](infinitree::object::Pool) hands out writers in a
+//! round-robin fashion, which means that with many workers and small
+//! files every writer ends up with a partially filled object, and
+//! they are all sealed half-empty at the end of the commit.
+//!
+//! The [`WriteBalancer`] keeps idle writers on a stack instead. The
+//! most recently used writer is always handed out first, so chunks are
+//! packed densely into as few objects as possible, and additional
+//! writers only get used when there's actual contention.
+//!
+//! Writers are leased with a guard that puts them back on the stack
+//! when it's dropped, so a worker that panics mid-write doesn't leave
+//! [`flush`](Writer::flush) waiting forever. Workers run as tokio
+//! tasks, so waiting for a writer moves the task off the runtime's
+//! worker thread first.
+use infinitree::{
+    object::{ObjectError, Writer},
+    ChunkPointer, Digest,
+};
+use std::{
+    num::NonZeroUsize,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+struct Shared<W> {
+    idle: Mutex<Vec<W>>,
+    available: Condvar,
+    total: usize,
+}
+
+pub struct WriteBalancer<W> {
+    inner: Arc<Shared<W>>,
+}
+
+impl<W> Clone for WriteBalancer<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// A writer taken off the idle stack, until it's dropped
+struct Lease<'a, W> {
+    shared: &'a Shared<W>,
+    writer: Option<W>,
+}
+
+impl<W> Deref for Lease<'_, W> {
+    type Target = W;
+
+    fn deref(&self) -> &W {
+        self.writer.as_ref().unwrap()
+    }
+}
+
+impl<W> DerefMut for Lease<'_, W> {
+    fn deref_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+}
+
+impl<W> Drop for Lease<'_, W> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            self.shared.idle().push(writer);
+            // `flush` may also be waiting on this, so wake everyone up
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+impl<W> Shared<W> {
+    /// The idle writers. Writers are only ever pushed and popped with
+    /// the lock held, so the stack is intact even if it's poisoned.
+    fn idle(&self) -> MutexGuard<'_, Vec<W>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait until `done` is true for the idle writers.
+    fn wait_until(&self, mut done: impl FnMut(&mut Vec<W>) -> bool) -> MutexGuard<'_, Vec<W>> {
+        let mut wait = || {
+            let mut idle = self.idle();
+            while !done(&mut idle) {
+                idle = self
+                    .available
+                    .wait(idle)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            idle
+        };
+
+        // don't block a worker thread that other tasks are queued on
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(wait)
+            }
+            _ => wait(),
+        }
+    }
+}
+
+impl<W: Writer + Clone> WriteBalancer<W> {
+    /// Create a balancer with `writers` number of clones of `writer`.
+    pub fn new(writers: NonZeroUsize, writer: W) -> Self {
+        let idle = (0..writers.get()).map(|_| writer.clone()).collect();
+
+        Self {
+            inner: Arc::new(Shared {
+                idle: Mutex::new(idle),
+                available: Condvar::new(),
+                total: writers.get(),
+            }),
+        }
+    }
+
+    fn take(&self) -> Lease<'_, W> {
+        let writer = self
+            .inner
+            .wait_until(|idle| !idle.is_empty())
+            .pop()
+            .unwrap();
+
+        Lease {
+            shared: &self.inner,
+            writer: Some(writer),
+        }
+    }
+}
+
+impl<W: Writer + Clone> Writer for WriteBalancer<W> {
+    fn write_chunk(&mut self, hash: &Digest, data: &[u8]) -> Result<ChunkPointer, ObjectError> {
+        let mut writer = self.take();
+        crate::route::data(|| writer.write_chunk(hash, data))
+    }
+
+    fn flush(&mut self) -> Result<(), ObjectError> {
+        // wait until all in-flight writes are finished, so we don't
+        // seal an object that's still being written to
+        let total = self.inner.total;
+        let mut idle = self.inner.wait_until(|idle| idle.len() == total);
+
+        for writer in idle.iter_mut() {
+            crate::route::data(|| writer.flush())?;
+        }
+
+        drop(idle);
+        self.inner.available.notify_all();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn concurrent_writes_are_flushed() {
+        use super::WriteBalancer;
+        use crate::Files;
+        use infinitree::{
+            crypto::UsernamePassword,
+            object::{Reader, Writer},
+            Infinitree, BLOCK_SIZE,
+        };
+        use std::{num::NonZeroUsize, thread};
+
+        let key =
+            UsernamePassword::with_credentials("balancer".to_string(), "password".to_string())
+                .unwrap();
+        let storage = infinitree::backends::test::InMemoryBackend::shared();
+        let stash = Infinitree::<Files>::empty(storage, key).unwrap();
+        let mut balancer = WriteBalancer::new(
+            NonZeroUsize::new(2).unwrap(),
+            stash.storage_writer().unwrap(),
+        );
+
+        let workers = (0..8u8)
+            .map(|worker| {
+                let mut balancer = balancer.clone();
+                thread::spawn(move || {
+                    (0..32u8)
+                        .map(|n| {
+                            let data = vec![worker; n as usize + 1];
+                            (balancer.write_chunk(&rand::random(), &data).unwrap(), data)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let written = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect::<Vec<_>>();
+        balancer.flush().unwrap();
+
+        let mut reader = stash.storage_reader().unwrap();
+        let mut buf = vec![0; BLOCK_SIZE];
+        for (pointer, data) in written {
+            assert_eq!(reader.read_chunk(&pointer, &mut buf).unwrap(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn writers_come_back_after_a_panic() {
+        use super::WriteBalancer;
+        use infinitree::{
+            object::{ObjectError, Writer},
+            ChunkPointer, Digest,
+        };
+        use std::{num::NonZeroUsize, thread};
+
+        #[derive(Clone)]
+        struct Failing;
+
+        impl Writer for Failing {
+            fn write_chunk(&mut self, _: &Digest, _: &[u8]) -> Result<ChunkPointer, ObjectError> {
+                panic!("the writer failed");
+            }
+
+            fn flush(&mut self) -> Result<(), ObjectError> {
+                Ok(())
+            }
+        }
+
+        let mut balancer = WriteBalancer::new(NonZeroUsize::new(1).unwrap(), Failing);
+        let mut worker = balancer.clone();
+        let panicked = thread::spawn(move || worker.write_chunk(&[0; 32], b"data")).join();
+        assert!(panicked.is_err());
+
+        // the only writer is idle again, so this doesn't wait forever
+        balancer.flush().unwrap();
+    }
+}
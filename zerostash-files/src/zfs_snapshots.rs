@@ -50,27 +50,30 @@ impl ZfsSnapshot {
         writer: AEADWriter,
         stdin: &mut ChildStdout,
     ) -> Result<ZfsSnapshot, SnapshotError> {
-        let mut sink = BufferedSink::with_chunk_size(writer, 4_100_000);
-        let mut buf = vec![0; 1_000_000];
-
-        loop {
-            let read_amount = stdin.read(&mut buf)?;
-            if read_amount == 0 {
-                break;
-            }
-            sink.write_all(&buf[..read_amount])?;
-        }
+        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let stream = write_stream(writer, stdin)?;
 
-        let stream = sink.finish()?;
+        Ok(Self {
+            stream,
+            creation_time_secs: since_epoch.as_secs(),
+            creation_time_nanos: since_epoch.as_nanos(),
+        })
+    }
 
-        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-        let creation_time_secs = since_epoch.as_secs();
-        let creation_time_nanos = since_epoch.as_nanos();
+    /// Copy the snapshot to a different stash, keeping the original
+    /// creation time.
+    pub fn copy(
+        &self,
+        reader: PoolRef<AEADReader>,
+        writer: AEADWriter,
+    ) -> Result<ZfsSnapshot, SnapshotError> {
+        let mut source = self.stream.open_reader(reader);
+        let stream = write_stream(writer, &mut source)?;
 
         Ok(Self {
             stream,
-            creation_time_secs,
-            creation_time_nanos,
+            creation_time_secs: self.creation_time_secs,
+            creation_time_nanos: self.creation_time_nanos,
         })
     }
 
@@ -93,3 +96,21 @@ impl ZfsSnapshot {
         Ok(())
     }
 }
+
+fn write_stream(
+    writer: AEADWriter,
+    input: &mut impl Read,
+) -> Result<infinitree::object::Stream, SnapshotError> {
+    let mut sink = BufferedSink::with_chunk_size(writer, 4_100_000);
+    let mut buf = vec![0; 1_000_000];
+
+    loop {
+        let read_amount = input.read(&mut buf)?;
+        if read_amount == 0 {
+            break;
+        }
+        sink.write_all(&buf[..read_amount])?;
+    }
+
+    Ok(sink.finish()?)
+}
@@ -7,7 +7,7 @@ use std::{
 use criterion::{criterion_group, criterion_main, Criterion};
 use fuse_mt::FuseMT;
 use infinitree::{backends, crypto::UsernamePassword, Infinitree};
-use zerostash_fuse::mount::ZerostashFs;
+use zerostash_fuse::mount::{Options, ZerostashFs};
 
 criterion_group!(benches, mount_starup);
 criterion_main! {benches}
@@ -23,7 +23,8 @@ async fn mount() -> anyhow::Result<()> {
     let backend = backends::Directory::new(PathBuf::from("../tests/data/Mounting/Stash/")).unwrap();
     let stash = Infinitree::open(backend, key).unwrap();
     let fuse_args = [OsStr::new("-o"), OsStr::new("fsname=zerostash")];
-    let filesystem = ZerostashFs::open(Arc::new(Mutex::new(stash)), 0, false).unwrap();
+    let filesystem =
+        ZerostashFs::open(Arc::new(Mutex::new(stash)), 0, &Options::default()).unwrap();
     let fs = FuseMT::new(filesystem, 1);
     let handle =
         fuse_mt::spawn_mount(fs, "../tests/data/Mounting/Target/", &fuse_args[..]).unwrap();
@@ -1,11 +1,49 @@
 use infinitree::{
     object::{AEADReader, PoolRef, Reader},
-    ChunkPointer,
+    ChunkPointer, Infinitree,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter::Peekable,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinSet;
+use zerostash_files::{
+    crypto_error::{read_chunk, CryptoError},
+    dictionary::Dictionaries,
+    Entry, Files,
 };
-use std::{iter::Peekable, sync::Arc};
 
 type Chunk = (u64, Arc<ChunkPointer>);
 
+/// The chunks of a file from an offset on, looked up one at a time
+/// instead of copying the chunk list of the file for every read
+struct EntryChunks {
+    entry: Arc<Entry>,
+    next: Option<u64>,
+}
+
+impl EntryChunks {
+    /// Start with the chunk that holds the byte at `offset`.
+    fn new(entry: Arc<Entry>, offset: u64) -> Self {
+        let start = entry.chunk_at(offset).map_or(offset, |(start, _)| start);
+        Self {
+            entry,
+            next: Some(start),
+        }
+    }
+}
+
+impl Iterator for EntryChunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        let (offset, pointer) = self.entry.chunks.range(self.next?..).next()?;
+        self.next = offset.checked_add(1);
+        Some((*offset, Arc::clone(pointer)))
+    }
+}
+
 struct ChunksIter {
     pub chunks: Peekable<Box<dyn Iterator<Item = Chunk> + Send + Sync>>,
 }
@@ -34,6 +72,9 @@ impl ChunksIter {
 #[derive(Debug)]
 pub enum ChunkDataError {
     NullChunkPointer,
+    /// The chunks of the entry don't fit in its size
+    InvalidOffset,
+    Crypto(CryptoError),
 }
 
 pub struct ChunkStackCache {
@@ -43,8 +84,8 @@ pub struct ChunkStackCache {
 }
 
 impl ChunkStackCache {
-    pub fn new(chunks: Vec<Chunk>) -> Self {
-        let chunks = ChunksIter::new(chunks.into_iter());
+    pub fn new(entry: Arc<Entry>) -> Self {
+        let chunks = ChunksIter::new(EntryChunks::new(entry, 0));
         Self {
             chunks,
             buf: Default::default(),
@@ -66,6 +107,7 @@ impl ChunkStackCache {
     pub fn read_next(
         &mut self,
         file_size: usize,
+        cache: &ChunkCache,
         objectreader: &mut PoolRef<AEADReader>,
     ) -> anyhow::Result<(), ChunkDataError> {
         let Some((c_offset, pointer)) = self.chunks.get_next() else {
@@ -74,12 +116,13 @@ impl ChunkStackCache {
 
         let next_c_offset = self.chunks.peek_next_offset(file_size);
 
-        let len = next_c_offset - c_offset;
-        self.buf.extend(std::iter::repeat(0).take(len));
-        let start = self.buf.len() - len;
-        objectreader
-            .read_chunk(&pointer, &mut self.buf[start..])
-            .unwrap();
+        let len = next_c_offset
+            .checked_sub(c_offset)
+            .ok_or(ChunkDataError::InvalidOffset)?;
+        let data = cache
+            .read(&pointer, len, objectreader)
+            .map_err(ChunkDataError::Crypto)?;
+        self.buf.extend_from_slice(&data);
 
         Ok(())
     }
@@ -93,12 +136,8 @@ pub struct ChunkStack {
 }
 
 impl ChunkStack {
-    pub fn new(chunks: Vec<Chunk>, offset: usize) -> Self {
-        let index = match chunks.binary_search_by(|a| a.0.cmp(&(offset as u64))) {
-            Ok(v) => v,
-            Err(v) => v - 1,
-        };
-        let chunks = ChunksIter::new(chunks.into_iter().skip(index));
+    pub fn new(entry: Arc<Entry>, offset: usize) -> Self {
+        let chunks = ChunksIter::new(EntryChunks::new(entry, offset as u64));
 
         Self {
             chunks,
@@ -113,6 +152,7 @@ impl ChunkStack {
         &mut self,
         file_size: usize,
         offset: usize,
+        cache: &ChunkCache,
         objectreader: &mut PoolRef<AEADReader>,
     ) -> anyhow::Result<(), ChunkDataError> {
         let Some((c_offset, pointer)) = self.chunks.get_next() else {
@@ -125,12 +165,13 @@ impl ChunkStack {
             self.start = Some(offset - c_offset);
         }
 
-        let len = next_c_offset - c_offset;
-        self.buf.extend(std::iter::repeat(0).take(len));
-        let start = self.buf.len() - len;
-        objectreader
-            .read_chunk(&pointer, &mut self.buf[start..])
-            .unwrap();
+        let len = next_c_offset
+            .checked_sub(c_offset)
+            .ok_or(ChunkDataError::InvalidOffset)?;
+        let data = cache
+            .read(&pointer, len, objectreader)
+            .map_err(ChunkDataError::Crypto)?;
+        self.buf.extend_from_slice(&data);
 
         Ok(())
     }
@@ -146,3 +187,171 @@ impl ChunkStack {
         false
     }
 }
+
+/// In-memory LRU cache of decrypted chunks
+///
+/// Without a local object cache every read from a remote stash is a
+/// blocking round trip to the backend. Keeping recently used chunks in
+/// memory, and fetching the chunks of a read concurrently, makes
+/// cache-less remote mounts usable for browsing.
+pub struct ChunkCache {
+    capacity: usize,
+    dictionaries: Dictionaries,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    chunks: HashMap<ChunkPointer, (u64, Arc<[u8]>)>,
+    order: BTreeMap<u64, ChunkPointer>,
+    size: usize,
+    clock: u64,
+}
+
+impl ChunkCache {
+    /// Create a cache that holds at most `capacity` bytes, and
+    /// decompresses chunks with the loaded `dictionaries`.
+    pub fn new(capacity: usize, dictionaries: Dictionaries) -> Self {
+        Self {
+            capacity,
+            dictionaries,
+            state: Default::default(),
+        }
+    }
+
+    pub fn contains(&self, pointer: &ChunkPointer) -> bool {
+        self.state.lock().unwrap().chunks.contains_key(pointer)
+    }
+
+    pub fn get(&self, pointer: &ChunkPointer) -> Option<Arc<[u8]>> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        state.clock += 1;
+        let (stamp, data) = state.chunks.get_mut(pointer)?;
+        let last_used = std::mem::replace(stamp, state.clock);
+
+        state.order.remove(&last_used);
+        state.order.insert(state.clock, pointer.clone());
+
+        Some(data.clone())
+    }
+
+    pub fn insert(&self, pointer: ChunkPointer, data: Arc<[u8]>) {
+        if data.len() > self.capacity {
+            return;
+        }
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+
+        if state.chunks.contains_key(&pointer) {
+            return;
+        }
+
+        while state.size + data.len() > self.capacity {
+            let Some((_, evicted)) = state.order.pop_first() else {
+                break;
+            };
+            if let Some((_, data)) = state.chunks.remove(&evicted) {
+                state.size -= data.len();
+            }
+        }
+
+        state.clock += 1;
+        state.size += data.len();
+        state.order.insert(state.clock, pointer.clone());
+        state.chunks.insert(pointer, (state.clock, data));
+    }
+
+    /// Drop every cached chunk
+    pub fn clear(&self) {
+        *self.state.lock().unwrap() = Default::default();
+    }
+
+    /// Return the contents of the chunk, reading it from the storage
+    /// if it's not in the cache.
+    pub fn read(
+        &self,
+        pointer: &ChunkPointer,
+        len: usize,
+        objectreader: &mut impl Reader,
+    ) -> Result<Arc<[u8]>, CryptoError> {
+        if let Some(data) = self.get(pointer) {
+            return Ok(data);
+        }
+
+        let mut buf = vec![0; len];
+        read_chunk(objectreader, &self.dictionaries, pointer, &mut buf)?;
+
+        let data: Arc<[u8]> = buf.into();
+        self.insert(pointer.clone(), data.clone());
+
+        Ok(data)
+    }
+
+    /// Concurrently fetch all `chunks` that are not in the cache yet.
+    ///
+    /// Errors are ignored here, and will be reported when the chunk is
+    /// actually read.
+    pub async fn prefetch(
+        self: &Arc<Self>,
+        stash: &Infinitree<Files>,
+        chunks: impl IntoIterator<Item = (Arc<ChunkPointer>, usize)>,
+    ) {
+        let mut tasks = JoinSet::new();
+
+        for (pointer, len) in chunks {
+            if self.contains(&pointer) {
+                continue;
+            }
+
+            let Ok(mut objectreader) = stash.storage_reader() else {
+                break;
+            };
+
+            let cache = Arc::clone(self);
+            tasks.spawn_blocking(move || {
+                _ = cache.read(&pointer, len, &mut objectreader);
+            });
+        }
+
+        while tasks.join_next().await.is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn chunks_past_the_end_are_an_error() {
+        use super::{ChunkCache, ChunkDataError, ChunkStack};
+        use infinitree::{backends::Directory, crypto::UsernamePassword, ChunkPointer, Infinitree};
+        use std::sync::Arc;
+        use zerostash_files::{Entry, Files};
+
+        let storage = tempfile::tempdir().unwrap();
+        let stash = Infinitree::<Files>::empty(
+            Directory::new(storage.path()).unwrap(),
+            UsernamePassword::with_credentials("chunks".to_string(), "password".to_string())
+                .unwrap(),
+        )
+        .unwrap();
+
+        // a damaged entry with a chunk past its size
+        let mut entry = Entry {
+            size: 10,
+            ..Default::default()
+        };
+        entry.chunks.insert(0, Arc::new(ChunkPointer::default()));
+        entry.chunks.insert(20, Arc::new(ChunkPointer::default()));
+
+        let mut chunks = ChunkStack::new(Arc::new(entry), 10);
+        let result = chunks.read_next(
+            10,
+            10,
+            &ChunkCache::new(1024, Default::default()),
+            &mut stash.storage_reader().unwrap(),
+        );
+        assert!(matches!(result, Err(ChunkDataError::InvalidOffset)));
+    }
+}
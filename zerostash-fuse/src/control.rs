@@ -0,0 +1,98 @@
+//! Virtual files in the `/.zerostash` directory of a mount
+//!
+//! The directory is not stored in the stash, and it hides anything
+//! with the same name at the root of the tree. Status files can be
+//! read to check on a live mount from scripts, and writing anything to
+//! a control file triggers an action, eg. `echo 1 > .zerostash/commit`.
+//! Files can't be added or removed.
+use nix::libc;
+use std::path::Path;
+
+/// Path of the control directory inside the mount
+pub const DIR: &str = "/.zerostash";
+
+/// Name of the control directory in the root of the mount
+pub const DIR_NAME: &str = ".zerostash";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlFile {
+    /// Whether the tree is consistent, and if the mount is writable
+    Status,
+    /// Unix timestamp of the last commit, or `never`
+    LastCommit,
+    /// Bytes written since the last commit
+    DirtyBytes,
+    /// `ok`, or the error of the last commit
+    Backend,
+    /// Commit the changes on write
+    Commit,
+    /// Drop the cached chunks on write
+    Flush,
+}
+
+impl ControlFile {
+    pub const ALL: &'static [ControlFile] = &[
+        ControlFile::Status,
+        ControlFile::LastCommit,
+        ControlFile::DirtyBytes,
+        ControlFile::Backend,
+        ControlFile::Commit,
+        ControlFile::Flush,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlFile::Status => "status",
+            ControlFile::LastCommit => "last_commit",
+            ControlFile::DirtyBytes => "dirty_bytes",
+            ControlFile::Backend => "backend",
+            ControlFile::Commit => "commit",
+            ControlFile::Flush => "flush",
+        }
+    }
+
+    /// Control files are written to, status files are only read
+    pub fn is_writable(self) -> bool {
+        matches!(self, ControlFile::Commit | ControlFile::Flush)
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.strip_prefix(DIR).ok()?.to_str()?;
+        Self::ALL.iter().copied().find(|file| file.name() == name)
+    }
+}
+
+/// Is `path` the control directory, or anything in it
+pub fn is_control_path(path: &Path) -> bool {
+    path.starts_with(DIR)
+}
+
+/// Refuse to change anything in the control directory
+pub fn reject_changes(path: &Path) -> Result<(), libc::c_int> {
+    if is_control_path(path) {
+        Err(libc::EPERM)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn control_paths() {
+        use super::{is_control_path, ControlFile};
+        use std::path::Path;
+
+        assert_eq!(
+            ControlFile::from_path(Path::new("/.zerostash/status")),
+            Some(ControlFile::Status)
+        );
+        assert_eq!(
+            ControlFile::from_path(Path::new("/.zerostash/commit")),
+            Some(ControlFile::Commit)
+        );
+        assert_eq!(ControlFile::from_path(Path::new("/.zerostash/nope")), None);
+        assert!(is_control_path(Path::new("/.zerostash")));
+        assert!(!is_control_path(Path::new("/.zerostash-not")));
+    }
+}
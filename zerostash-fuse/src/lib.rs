@@ -1,5 +1,6 @@
 #![deny(unused_crate_dependencies)]
 pub mod chunks;
+pub mod control;
 pub mod mount;
 
 #[cfg(test)]
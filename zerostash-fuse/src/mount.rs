@@ -1,92 +1,315 @@
 //! `mount` subcommand
 
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, HashSet, VecDeque},
     ffi::OsStr,
     io::Result,
     num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::{atomic::Ordering, Arc},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use fuse_mt::*;
 use infinitree::{
     object::{AEADReader, AEADWriter, Pool, PoolRef, Reader, Writer},
-    Infinitree, BLOCK_SIZE,
+    ChunkPointer, Infinitree, BLOCK_SIZE,
 };
 use nix::libc;
 use scc::ebr::{AtomicShared, Guard, Shared, Tag};
 use tokio::{runtime::Handle, task::JoinHandle};
-use tracing::debug;
-use zerostash_files::{Entry, FileType, Files, Node};
+use tracing::{debug, error, warn};
+use zerostash_files::{
+    crypto_error::{read_chunk, CryptoError},
+    dictionary::Dictionaries,
+    digest_key, route, Entry, FileType, Files, FsError, Inconsistency, Node,
+};
 
+use crate::chunks::ChunkCache;
+use crate::chunks::ChunkDataError;
 use crate::chunks::ChunkStack;
 use crate::chunks::ChunkStackCache;
+use crate::control::{self, reject_changes, ControlFile};
 
 const MAX_BUFFER_SIZE: usize = infinitree::BLOCK_SIZE;
+/// Default size of the in-memory chunk cache in bytes
+pub const DEFAULT_MEMORY_CACHE: usize = 256 * 1024 * 1024;
+/// Prefetch this many maximum sized chunks past the end of a read
+const READAHEAD_CHUNKS: usize = 4;
+/// Ask the backend to preload objects this many maximum sized chunks
+/// past the end of a read
+const PRELOAD_CHUNKS: usize = 16;
 use zerostash_files::rollsum::CHUNK_SIZE_LIMIT;
 
+/// How access to the mounted filesystem is checked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Permissions {
+    /// Don't check permissions, anyone who can reach the mount has full access
+    #[default]
+    Off,
+    /// Let the kernel check the permission bits against the reported owners
+    Kernel,
+    /// Only allow access for the owner of the mount and root
+    Owner,
+}
+
+/// Called after every commit of a read-write mount
+#[derive(Clone)]
+pub struct CommitHook(pub Arc<dyn Fn() + Send + Sync>);
+
+impl std::fmt::Debug for CommitHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CommitHook")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub read_write: bool,
+    /// Size of the in-memory chunk cache in bytes
+    pub memory_cache: usize,
+    /// Preload the objects of recently modified files up to this many bytes
+    pub preload: Option<usize>,
+    /// Report every file as owned by this user instead of the stored uid
+    pub uid: Option<u32>,
+    /// Report every file as owned by this group instead of the stored gid
+    pub gid: Option<u32>,
+    /// Allow users other than the one mounting to access the filesystem
+    pub allow_other: bool,
+    pub permissions: Permissions,
+    pub after_commit: Option<CommitHook>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            read_write: false,
+            memory_cache: DEFAULT_MEMORY_CACHE,
+            preload: None,
+            uid: None,
+            gid: None,
+            allow_other: false,
+            permissions: Permissions::default(),
+            after_commit: None,
+        }
+    }
+}
+
+impl Options {
+    fn mount_options(&self) -> Vec<&'static OsStr> {
+        let mut options = vec![
+            OsStr::new(if self.read_write { "rw" } else { "ro" }),
+            OsStr::new("nodev"),
+            OsStr::new("nosuid"),
+            OsStr::new("noatime"),
+            OsStr::new("fsname=zerostash"),
+        ];
+
+        if self.allow_other {
+            options.push(OsStr::new("allow_other"));
+        }
+
+        if self.permissions == Permissions::Kernel {
+            options.push(OsStr::new("default_permissions"));
+        }
+
+        // extended attributes are served from the stash, so Finder
+        // doesn't need `._` files to keep them
+        #[cfg(target_os = "macos")]
+        options.extend([OsStr::new("volname=zerostash"), OsStr::new("noappledouble")]);
+
+        options
+    }
+}
+
 pub async fn mount(
     stash: Infinitree<Files>,
     mountpoint: &str,
     threads: usize,
-    read_write: bool,
+    options: &Options,
 ) -> anyhow::Result<()> {
+    let mounted = spawn(stash, mountpoint, threads, options)?;
+
+    // Wait until we are done.
+    tokio::signal::ctrl_c().await?;
+
+    // Ensure the filesystem is unmounted.
+    mounted.unmount();
+
+    Ok(())
+}
+
+/// A stash mounted in the background
+pub struct Mounted {
+    stash: Arc<Infinitree<Files>>,
+    session: Box<dyn FnOnce()>,
+    auto_commit: Option<JoinHandle<()>>,
+}
+
+impl Mounted {
+    /// Unmount the filesystem, and return the stash so the changes
+    /// can be committed.
+    pub fn unmount(self) -> Arc<Infinitree<Files>> {
+        if let Some(task) = self.auto_commit {
+            task.abort();
+        }
+
+        (self.session)();
+        self.stash
+    }
+}
+
+/// Mount the stash at `mountpoint` without blocking. Needs to be
+/// called from a Tokio runtime.
+pub fn spawn(
+    stash: Infinitree<Files>,
+    mountpoint: &str,
+    threads: usize,
+    options: &Options,
+) -> anyhow::Result<Mounted> {
     let stash = Arc::new(stash);
+    let filesystem = ZerostashFs::open(Arc::clone(&stash), threads, options)?;
+
+    // an inconsistent tree is always mounted read-only
+    let options = &Options {
+        read_write: filesystem.writer.is_some(),
+        ..options.clone()
+    };
 
-    if read_write {
+    let auto_commit = if options.read_write {
         stash.load(stash.index().chunks()).unwrap();
         let stash_clone = Arc::clone(&stash);
-        tokio::spawn(async move {
-            auto_commit(stash_clone).await;
+        let hook = options.after_commit.clone();
+        let state = Arc::clone(&filesystem.commits);
+        Some(tokio::spawn(async move {
+            auto_commit(stash_clone, hook, state).await;
+        }))
+    } else {
+        None
+    };
+
+    let fs = fuse_mt::FuseMT::new(filesystem, threads);
+
+    // Mount the filesystem.
+    let handle = spawn_mount(fs, mountpoint, &options.mount_options())?;
+
+    if let Some(budget) = options.preload {
+        let stash = Arc::clone(&stash);
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = preload_recent_files(&stash, budget) {
+                debug!(%error, "preloading failed");
+            }
         });
     }
 
-    let mount_type = if read_write { "rw" } else { "ro" };
-
-    let filesystem = ZerostashFs::open(stash, threads, read_write).unwrap();
-    let fs = fuse_mt::FuseMT::new(filesystem, 1);
+    Ok(Mounted {
+        stash,
+        session: Box::new(move || handle.join()),
+        auto_commit,
+    })
+}
 
-    // Mount the filesystem.
-    let handle = spawn_mount(
-        fs,
-        mountpoint,
-        &[
-            OsStr::new(mount_type),
-            OsStr::new("nodev"),
-            OsStr::new("nosuid"),
-            OsStr::new("noatime"),
-            OsStr::new("fsname=zerostash"),
-        ],
-    )?;
+/// Ask the backend to preload the objects of the most recently modified
+/// files, up to `budget` bytes of file contents.
+fn preload_recent_files(stash: &Infinitree<Files>, budget: usize) -> anyhow::Result<()> {
+    let mut files = stash
+        .index()
+        .tree
+        .iter_files()
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>();
+    files.sort_unstable_by_key(|e| std::cmp::Reverse((e.unix_secs, e.unix_nanos)));
+
+    let mut seen = HashSet::new();
+    let mut objects = vec![];
+    let mut total = 0;
+
+    for entry in files {
+        total += entry.size as usize;
+        if total > budget {
+            break;
+        }
 
-    // Wait until we are done.
-    tokio::signal::ctrl_c().await?;
+        for pointer in entry.chunks.values() {
+            let id = *pointer.object_id();
+            if seen.insert(id) {
+                objects.push(id);
+            }
+        }
+    }
 
-    // Ensure the filesystem is unmounted.
-    handle.join();
+    debug!(objects = objects.len(), "preloading");
+    stash.backend().preload(&objects)?;
 
     Ok(())
 }
 
-async fn auto_commit(stash: Arc<Infinitree<Files>>) {
+/// Keeps track of commits for the control files
+#[derive(Default)]
+struct CommitState {
+    /// Bytes written since the last commit
+    dirty: AtomicU64,
+    /// Time of the last commit. Held while committing.
+    last: Mutex<Option<SystemTime>>,
+    /// The error of the last commit, if it failed
+    error: Mutex<Option<String>>,
+}
+
+async fn auto_commit(
+    stash: Arc<Infinitree<Files>>,
+    hook: Option<CommitHook>,
+    state: Arc<CommitState>,
+) {
     let mut interval = tokio::time::interval(Duration::from_secs(180));
 
     loop {
         interval.tick().await;
-
-        _ = stash.commit("Fuse commit");
-        _ = stash.backend().sync();
+        commit(&stash, hook.as_ref(), &state);
         debug!("Committed Changes!");
     }
 }
 
+fn commit(stash: &Infinitree<Files>, hook: Option<&CommitHook>, state: &CommitState) {
+    let mut last = state.last.lock().unwrap();
+    let dirty = state.dirty.swap(0, Ordering::SeqCst);
+
+    let result = stash
+        .commit("Fuse commit")
+        .map_err(|e| e.to_string())
+        .and_then(|_| stash.backend().sync().map_err(|e| e.to_string()));
+
+    match result {
+        Ok(()) => {
+            *last = Some(SystemTime::now());
+            *state.error.lock().unwrap() = None;
+        }
+        Err(error) => {
+            warn!(%error, "commit failed");
+            state.dirty.fetch_add(dirty, Ordering::SeqCst);
+            *state.error.lock().unwrap() = Some(error);
+        }
+    }
+
+    if let Some(CommitHook(hook)) = hook {
+        hook();
+    }
+}
+
 pub struct ZerostashFs {
     commit_timestamp: SystemTime,
     stash: Arc<Infinitree<Files>>,
     writer: Option<Pool<AEADWriter>>,
     chunks_cache: scc::HashMap<PathBuf, ChunkStackCache>,
+    memory_cache: Arc<ChunkCache>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    permissions: Permissions,
+    after_commit: Option<CommitHook>,
+    commits: Arc<CommitState>,
+    inconsistencies: Vec<Inconsistency>,
     open_handles: scc::HashMap<u64, OpenFileHandle>,
     runtime: Handle,
 }
@@ -95,7 +318,7 @@ struct OpenFileHandle {
     // temporary, need to rewrite read() impl
     #[allow(unused)]
     entry: AtomicShared<Entry>,
-    writer: Option<JoinHandle<AtomicShared<Entry>>>,
+    writer: Option<JoinHandle<std::result::Result<AtomicShared<Entry>, CryptoError>>>,
     write_queue: flume::Sender<WriteOp>,
 }
 
@@ -160,7 +383,8 @@ impl OpenFileHandle {
                         pool: pool.clone(),
                         entry: (*entry).clone(),
                         reader: parent.stash.storage_reader().unwrap(),
-                        hasher: parent.stash.hasher().unwrap(),
+                        dictionaries: parent.stash.index().dictionaries.clone(),
+                        hasher: digest_key::hasher(&parent.stash).unwrap(),
                     };
                     parent.runtime.spawn(committer.start())
                 };
@@ -168,8 +392,9 @@ impl OpenFileHandle {
                 Some({
                     let entry = shared_entry.clone(Ordering::Relaxed, &Guard::new());
                     parent.runtime.spawn(async move {
-                        _ = tokio::join!(merger_task, commit_task);
-                        entry
+                        let (_, committed) = tokio::join!(merger_task, commit_task);
+                        committed.expect("the commit task panicked")?;
+                        Ok(entry)
                     })
                 })
             }
@@ -186,6 +411,7 @@ impl OpenFileHandle {
 struct CommitChanges {
     commit_queue_r: flume::Receiver<WriteOp>,
     reader: PoolRef<AEADReader>,
+    dictionaries: Dictionaries,
     hasher: infinitree::Hasher,
     pool: Pool<AEADWriter>,
 
@@ -194,11 +420,18 @@ struct CommitChanges {
 }
 
 impl CommitChanges {
-    async fn start(mut self) {
-        let mut basebuf = Vec::with_capacity(BLOCK_SIZE);
+    /// Apply the writes to the entry until the file is closed.
+    ///
+    /// If a chunk that's partially overwritten can't be read, the rest
+    /// of the writes are dropped, and the error is returned when the
+    /// file is closed.
+    async fn start(mut self) -> std::result::Result<(), CryptoError> {
+        let mut basebuf = vec![0; BLOCK_SIZE];
+        let mut failed = None;
 
         loop {
             let (offset, mut buf) = match self.commit_queue_r.recv_async().await {
+                Ok(WriteOp::Write(_)) if failed.is_some() => continue,
                 Ok(WriteOp::Write(WriteData { offset, buf })) => (offset, buf),
                 Ok(WriteOp::Flush) => {
                     self.shared_entry.swap(
@@ -226,7 +459,15 @@ impl CommitChanges {
             let mut write_start = (offset - base_offset) as usize;
             let mut write_end = write_start + buf.len();
             loop {
-                let chunk_end = self.reader.read_chunk(&ptr, &mut basebuf).unwrap().len();
+                let chunk_end =
+                    match read_chunk(&mut self.reader, &self.dictionaries, &ptr, &mut basebuf) {
+                        Ok(data) => data.len(),
+                        Err(error) => {
+                            error!(%error, "failed to update chunk, dropping the writes");
+                            failed = Some(error);
+                            break;
+                        }
+                    };
 
                 if write_end <= chunk_end {
                     basebuf[write_start..write_end].copy_from_slice(buf.make_contiguous());
@@ -255,11 +496,13 @@ impl CommitChanges {
                 }
             }
         }
+
+        failed.map_or(Ok(()), Err)
     }
 
     fn write_new_chunk_for_offset(&mut self, slice: &[u8], offset: u64) {
         let digest = self.hasher.reset().update(slice).finalize();
-        let pointer = self.pool.write_chunk(digest.as_bytes(), slice).unwrap();
+        let pointer = route::data(|| self.pool.write_chunk(digest.as_bytes(), slice)).unwrap();
         self.entry.chunks.insert(offset, pointer.into());
     }
 
@@ -359,15 +602,22 @@ impl IngestChanges {
 }
 
 impl ZerostashFs {
-    pub fn open(stash: Arc<Infinitree<Files>>, threads: usize, read_write: bool) -> Result<Self> {
+    pub fn open(stash: Arc<Infinitree<Files>>, threads: usize, options: &Options) -> Result<Self> {
         stash.load(stash.index().tree()).unwrap();
 
-        let commit_timestamp = match stash.commit_list().last() {
-            Some(last) => last.metadata.time,
-            None => panic!("stash is empty"),
-        };
+        let last_commit = stash.commit_list().last().map(|last| last.metadata.time);
+        let commit_timestamp = last_commit.unwrap_or_else(SystemTime::now);
 
-        let writer = if read_write {
+        let inconsistencies = stash.index().tree.validate();
+        if !inconsistencies.is_empty() {
+            warn!(
+                problems = inconsistencies.len(),
+                "the tree is inconsistent, mounting read-only. See {}/status",
+                control::DIR
+            );
+        }
+
+        let writer = if options.read_write && inconsistencies.is_empty() {
             Some(
                 Pool::new(
                     NonZeroUsize::new(threads).unwrap(),
@@ -379,12 +629,29 @@ impl ZerostashFs {
             None
         };
 
+        // anything that gets past the checks in the filesystem still
+        // can't change the tree
+        stash.index().tree.set_read_only(writer.is_none());
+
         Ok(ZerostashFs {
             commit_timestamp,
             stash,
             writer,
             open_handles: scc::HashMap::new(),
             chunks_cache: scc::HashMap::new(),
+            memory_cache: Arc::new(ChunkCache::new(
+                options.memory_cache,
+                stash.index().dictionaries.clone(),
+            )),
+            uid: options.uid,
+            gid: options.gid,
+            permissions: options.permissions,
+            after_commit: options.after_commit.clone(),
+            commits: Arc::new(CommitState {
+                last: Mutex::new(last_commit),
+                ..Default::default()
+            }),
+            inconsistencies,
             runtime: Handle::current(),
         })
     }
@@ -400,6 +667,233 @@ impl ZerostashFs {
             .insert(val, OpenFileHandle::new(self, entry, flags));
         val
     }
+
+    fn owner_uid(&self) -> u32 {
+        self.uid.unwrap_or_else(|| nix::unistd::getuid().into())
+    }
+
+    fn owner_gid(&self) -> u32 {
+        self.gid.unwrap_or_else(|| nix::unistd::getgid().into())
+    }
+
+    fn file_attr(&self, file: &Entry, atime: SystemTime) -> FileAttr {
+        let mut attr = file_to_fuse(file, atime);
+        if let Some(uid) = self.uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            attr.gid = gid;
+        }
+        attr
+    }
+
+    fn dir_attr(&self) -> FileAttr {
+        FileAttr {
+            uid: self.owner_uid(),
+            gid: self.owner_gid(),
+            ..DIR_ATTR
+        }
+    }
+
+    /// Attributes of a directory that was committed with its metadata
+    fn stored_dir_attr(&self, dir: &Entry) -> FileAttr {
+        let mtime = UNIX_EPOCH + Duration::new(dir.unix_secs as u64, dir.unix_nanos);
+        let (crtime, flags) = macos_attr(dir);
+        FileAttr {
+            mtime,
+            ctime: mtime,
+            crtime,
+            flags,
+            perm: dir
+                .unix_perm
+                .map_or(DIR_ATTR.perm, |perm| (perm & 0o777) as u16),
+            uid: self
+                .uid
+                .or(dir.unix_uid)
+                .unwrap_or_else(|| self.owner_uid()),
+            gid: self
+                .gid
+                .or(dir.unix_gid)
+                .unwrap_or_else(|| self.owner_gid()),
+            ..DIR_ATTR
+        }
+    }
+
+    fn read_chunk(&self, pointer: &ChunkPointer) -> std::result::Result<Vec<u8>, CryptoError> {
+        let mut reader = self.stash.storage_reader().unwrap();
+        // i'm assuming we're not so good at compression that this
+        // isn't enough?
+        let mut buf: Vec<u8> = vec![0; pointer.size() * 16];
+        let len = read_chunk(
+            &mut reader,
+            &self.stash.index().dictionaries,
+            pointer,
+            &mut buf,
+        )?
+        .len();
+
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    fn store_chunk(&self, data: &[u8]) -> Arc<ChunkPointer> {
+        let hash = *digest_key::hasher(&self.stash)
+            .unwrap()
+            .update(data)
+            .finalize()
+            .as_bytes();
+
+        let mut writer = self.writer.as_ref().unwrap().clone();
+        self.stash.index().chunks.insert_with(hash, move || {
+            route::data(|| writer.write_chunk(&hash, data)).unwrap()
+        })
+    }
+
+    /// Zero the range `start..end` of the file. Chunks that are fully
+    /// covered are replaced by zeroed chunks of the same length, so
+    /// they deduplicate, and partially covered ones are rewritten.
+    fn punch_hole(
+        &self,
+        entry: &Entry,
+        start: u64,
+        end: u64,
+    ) -> std::result::Result<BTreeMap<u64, Arc<ChunkPointer>>, CryptoError> {
+        let mut chunks = entry.chunks.clone();
+        let offsets = entry.chunks.keys().copied().collect::<Vec<_>>();
+
+        for (i, (chunk_start, pointer)) in entry.chunks.iter().enumerate() {
+            let chunk_start = *chunk_start;
+            let chunk_end = offsets.get(i + 1).copied().unwrap_or(entry.size);
+            if chunk_end <= start || chunk_start >= end {
+                continue;
+            }
+
+            let data = if start <= chunk_start && chunk_end <= end {
+                vec![0; (chunk_end - chunk_start) as usize]
+            } else {
+                let mut data = self.read_chunk(pointer)?;
+                let zero_end = ((end - chunk_start) as usize).min(data.len());
+                let zero_start = (start.saturating_sub(chunk_start) as usize).min(zero_end);
+                data[zero_start..zero_end].fill(0);
+                data
+            };
+
+            chunks.insert(chunk_start, self.store_chunk(&data));
+        }
+
+        Ok(chunks)
+    }
+
+    fn control_contents(&self, file: ControlFile) -> Vec<u8> {
+        match file {
+            ControlFile::Status => {
+                let mut status = format!(
+                    "tree: {}\nmode: {}\n",
+                    if self.inconsistencies.is_empty() {
+                        "ok"
+                    } else {
+                        "inconsistent"
+                    },
+                    if self.writer.is_some() {
+                        "read-write"
+                    } else {
+                        "read-only"
+                    }
+                );
+
+                for problem in self.inconsistencies.iter() {
+                    status.push_str(&format!("{problem}\n"));
+                }
+
+                status.into_bytes()
+            }
+            ControlFile::LastCommit => match *self.commits.last.lock().unwrap() {
+                Some(time) => format!(
+                    "{}\n",
+                    time.duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                )
+                .into_bytes(),
+                None => b"never\n".to_vec(),
+            },
+            ControlFile::DirtyBytes => {
+                format!("{}\n", self.commits.dirty.load(Ordering::SeqCst)).into_bytes()
+            }
+            ControlFile::Backend => match self.commits.error.lock().unwrap().as_ref() {
+                Some(error) => format!("error: {error}\n").into_bytes(),
+                None => b"ok\n".to_vec(),
+            },
+            ControlFile::Commit | ControlFile::Flush => vec![],
+        }
+    }
+
+    fn control_write(&self, file: ControlFile) -> std::result::Result<(), libc::c_int> {
+        match file {
+            ControlFile::Commit if self.writer.is_none() => return Err(libc::EROFS),
+            ControlFile::Commit => self.runtime.block_on(async {
+                commit(&self.stash, self.after_commit.as_ref(), &self.commits);
+            }),
+            ControlFile::Flush => {
+                self.memory_cache.clear();
+                self.chunks_cache.clear();
+            }
+            _ => return Err(libc::EACCES),
+        }
+
+        Ok(())
+    }
+
+    fn control_attr(&self, path: &Path) -> Option<FileAttr> {
+        if path == Path::new(control::DIR) {
+            return Some(self.dir_attr());
+        }
+
+        let file = ControlFile::from_path(path)?;
+        Some(FileAttr {
+            size: self.control_contents(file).len() as u64,
+            kind: fuse_mt::FileType::RegularFile,
+            perm: if file.is_writable() { 0o200 } else { 0o444 },
+            mtime: self.commit_timestamp,
+            ..self.dir_attr()
+        })
+    }
+
+    /// The extended attributes that were committed on macOS
+    #[cfg(target_os = "macos")]
+    fn stored_xattrs(
+        &self,
+        path: &Path,
+    ) -> std::result::Result<BTreeMap<String, Vec<u8>>, libc::c_int> {
+        if control::is_control_path(path) {
+            return Ok(BTreeMap::new());
+        }
+
+        let node = self
+            .stash
+            .index()
+            .tree
+            .node_by_path(path.to_str().ok_or(libc::ENOENT)?);
+        let Ok(Some(node)) = node else {
+            return Err(libc::ENOENT);
+        };
+
+        let entry = match node.as_ref() {
+            Node::File { entry, .. } => Some(entry.as_ref()),
+            Node::Directory { metadata, .. } => metadata.as_ref(),
+        };
+        Ok(entry
+            .and_then(Entry::macos)
+            .map(|macos| macos.xattrs)
+            .unwrap_or_default())
+    }
+
+    fn check_access(&self, req: &RequestInfo) -> std::result::Result<(), libc::c_int> {
+        match self.permissions {
+            Permissions::Owner if req.uid != 0 && req.uid != self.owner_uid() => Err(libc::EACCES),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl FilesystemMT for ZerostashFs {
@@ -408,15 +902,22 @@ impl FilesystemMT for ZerostashFs {
 
         if self.writer.is_some() {
             self.runtime.block_on(async {
-                _ = self.stash.commit("Fuse commit");
-                _ = self.stash.backend().sync();
+                commit(&self.stash, self.after_commit.as_ref(), &self.commits);
             });
         }
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+    fn getattr(&self, req: RequestInfo, path: &Path, _fh: Option<u64>) -> ResultEntry {
+        self.check_access(&req)?;
         debug!("gettattr = {:?}", path);
 
+        if control::is_control_path(path) {
+            return self
+                .control_attr(path)
+                .map(|attr| (TTL, attr))
+                .ok_or(libc::ENOENT);
+        }
+
         let path_str = path.to_str().unwrap();
 
         let node = {
@@ -431,20 +932,83 @@ impl FilesystemMT for ZerostashFs {
 
         match node.as_ref() {
             Node::File { refs: _, entry } => {
-                Ok((TTL, file_to_fuse(entry.as_ref(), self.commit_timestamp)))
+                Ok((TTL, self.file_attr(entry.as_ref(), self.commit_timestamp)))
             }
-            Node::Directory { entries: _ } => Ok((TTL, DIR_ATTR)),
+            Node::Directory {
+                metadata: Some(metadata),
+                ..
+            } => Ok((TTL, self.stored_dir_attr(metadata))),
+            Node::Directory { .. } => Ok((TTL, self.dir_attr())),
         }
     }
 
-    fn opendir(&self, _req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+    fn statfs(&self, req: RequestInfo, _path: &Path) -> ResultStatfs {
+        self.check_access(&req)?;
+
+        // there's no limit to the size of a stash, but file managers
+        // refuse to copy to a volume without free space, so report 1 PiB
+        let free = match self.writer {
+            Some(_) => (1 << 50) / BLOCK_SIZE as u64,
+            None => 0,
+        };
+        Ok(Statfs {
+            blocks: free,
+            bfree: free,
+            bavail: free,
+            files: 0,
+            ffree: free,
+            bsize: BLOCK_SIZE as u32,
+            namelen: 255,
+            frsize: BLOCK_SIZE as u32,
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxattr(&self, req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
+        self.check_access(&req)?;
+
+        let xattrs = self.stored_xattrs(path)?;
+        let value = name
+            .to_str()
+            .and_then(|name| xattrs.get(name))
+            .ok_or(libc::ENOATTR)?;
+        xattr_reply(value.clone(), size)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn listxattr(&self, req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
+        self.check_access(&req)?;
+
+        let mut names = vec![];
+        for name in self.stored_xattrs(path)?.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        xattr_reply(names, size)
+    }
+
+    fn opendir(&self, req: RequestInfo, _path: &Path, flags: u32) -> ResultOpen {
+        self.check_access(&req)?;
         debug!("opendir");
         Ok((0, flags))
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        self.check_access(&req)?;
         debug!("open: {:?}", path);
 
+        if control::is_control_path(path) {
+            let Some(file) = ControlFile::from_path(path) else {
+                return Err(libc::EISDIR);
+            };
+
+            let writing = flags & (libc::O_RDWR | libc::O_WRONLY) as u32 > 0;
+            if writing != file.is_writable() {
+                return Err(libc::EACCES);
+            }
+            return Ok((0, flags));
+        }
+
         if self.writer.is_none() && flags & (libc::O_RDWR | libc::O_WRONLY) as u32 > 0 {
             return Err(libc::EROFS);
         }
@@ -474,6 +1038,10 @@ impl FilesystemMT for ZerostashFs {
     ) -> ResultEmpty {
         debug!("release {:?}", path);
 
+        if control::is_control_path(path) {
+            return Ok(());
+        }
+
         let Some((_, handle)) = self.open_handles.remove(&fh) else {
             return Err(libc::EINVAL);
         };
@@ -481,7 +1049,11 @@ impl FilesystemMT for ZerostashFs {
         if let Some(background) = handle.writer {
             handle.write_queue.send(WriteOp::Close).unwrap();
             let path_str = path.to_str().unwrap();
-            let new_entry = self.runtime.block_on(background).unwrap();
+            let new_entry = self
+                .runtime
+                .block_on(background)
+                .map_err(|_| libc::EIO)?
+                .map_err(crypto_errno)?;
             let guard = Guard::new();
             let new_entry_deref = new_entry.load(Ordering::Relaxed, &guard).as_ref().unwrap();
 
@@ -489,7 +1061,7 @@ impl FilesystemMT for ZerostashFs {
                 .index()
                 .tree
                 .update_file(path_str, new_entry_deref.clone())
-                .unwrap();
+                .map_err(|e| tree_errno(e, libc::EIO))?;
         }
 
         Ok(())
@@ -508,9 +1080,20 @@ impl FilesystemMT for ZerostashFs {
         Ok(())
     }
 
-    fn readdir(&self, _req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+    fn readdir(&self, req: RequestInfo, path: &Path, _fh: u64) -> ResultReaddir {
+        self.check_access(&req)?;
         debug!("readdir: {:?}", path);
 
+        if path == Path::new(control::DIR) {
+            return Ok(ControlFile::ALL
+                .iter()
+                .map(|file| DirectoryEntry {
+                    name: file.name().into(),
+                    kind: fuse_mt::FileType::RegularFile,
+                })
+                .collect());
+        }
+
         let path_str = path.to_str().unwrap();
         let node = {
             let index = self.stash.index();
@@ -522,7 +1105,7 @@ impl FilesystemMT for ZerostashFs {
             return Err(libc::ENOENT);
         };
 
-        let Node::Directory { entries } = node.as_ref() else {
+        let Node::Directory { entries, .. } = node.as_ref() else {
             return Err(libc::ENOENT);
         };
 
@@ -547,6 +1130,15 @@ impl FilesystemMT for ZerostashFs {
             current = entry.next();
         }
 
+        if path == Path::new("/") {
+            vec.retain(|entry| entry.name != control::DIR_NAME);
+            vec.push(DirectoryEntry {
+                name: control::DIR_NAME.into(),
+                kind: fuse_mt::FileType::Directory,
+            });
+        }
+
+        vec.sort_unstable_by(|a, b| a.name.cmp(&b.name));
         Ok(vec)
     }
 
@@ -561,8 +1153,18 @@ impl FilesystemMT for ZerostashFs {
     ) -> CallbackResult {
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
 
+        if let Some(file) = ControlFile::from_path(path) {
+            let contents = self.control_contents(file);
+            let start = (offset as usize).min(contents.len());
+            let end = (start + size as usize).min(contents.len());
+            return callback(Ok(&contents[start..end]));
+        }
+
         let real_path = strip_path(path);
-        let path_string = real_path.to_str().unwrap();
+        // the tree only has utf-8 paths
+        let Some(path_string) = real_path.to_str() else {
+            return callback(Err(libc::ENOENT));
+        };
 
         let entry = {
             let index = &self.stash.index();
@@ -579,25 +1181,52 @@ impl FilesystemMT for ZerostashFs {
         if offset > file_size {
             return callback(Err(libc::EINVAL));
         }
+        if offset == file_size {
+            return callback(Ok(&[]));
+        }
 
         let size = size as usize;
-        let sort_chunks = || entry.chunks.clone().into_iter().collect::<Vec<_>>();
-        let mut obj_reader = self.stash.storage_reader().unwrap();
+        let Ok(mut obj_reader) = self.stash.storage_reader() else {
+            return callback(Err(libc::EIO));
+        };
+
+        // fetch everything this read needs at once instead of one
+        // round trip per chunk
+        let readahead_end = (offset + size + READAHEAD_CHUNKS * CHUNK_SIZE_LIMIT) as u64;
+        let preload_end = (offset + size + PRELOAD_CHUNKS * CHUNK_SIZE_LIMIT) as u64;
+        let wanted = entry
+            .chunks_in(offset as u64..readahead_end)
+            .map(|(_, len, pointer)| (Arc::clone(pointer), len as usize))
+            .collect::<Vec<_>>();
+        let mut hints = entry
+            .chunks
+            .range(readahead_end..preload_end)
+            .map(|(_, pointer)| *pointer.object_id())
+            .collect::<Vec<_>>();
+
+        hints.dedup();
+        if !hints.is_empty() {
+            _ = self.stash.backend().preload(&hints);
+        }
 
         self.runtime.block_on(async {
+            self.memory_cache.prefetch(&self.stash, wanted).await;
+
             {
                 let mut chunks = self
                     .chunks_cache
                     .entry(real_path.to_path_buf())
-                    .or_insert_with(|| ChunkStackCache::new(sort_chunks()));
+                    .or_insert_with(|| ChunkStackCache::new(Arc::clone(&entry)));
                 let chunks = chunks.get_mut();
 
                 if chunks.last_read_offset == offset {
                     let end = size.min(file_size - offset);
                     if chunks.buf.len() < end {
                         loop {
-                            if chunks.read_next(file_size, &mut obj_reader).is_err() {
-                                return callback(Err(libc::EINVAL));
+                            if let Err(error) =
+                                chunks.read_next(file_size, &self.memory_cache, &mut obj_reader)
+                            {
+                                return callback(Err(chunk_errno(error)));
                             }
 
                             if chunks.buf.len() >= end {
@@ -611,14 +1240,13 @@ impl FilesystemMT for ZerostashFs {
                 }
             }
 
-            let mut chunks = ChunkStack::new(sort_chunks(), offset);
+            let mut chunks = ChunkStack::new(entry, offset);
 
             loop {
-                if chunks
-                    .read_next(file_size, offset, &mut obj_reader)
-                    .is_err()
+                if let Err(error) =
+                    chunks.read_next(file_size, offset, &self.memory_cache, &mut obj_reader)
                 {
-                    return callback(Err(libc::EINVAL));
+                    return callback(Err(chunk_errno(error)));
                 }
 
                 if chunks.is_full(size, file_size, offset) {
@@ -641,11 +1269,16 @@ impl FilesystemMT for ZerostashFs {
     ) -> ResultWrite {
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
 
-        let Some(handle) = self.open_handles.get(&fh) else {
+        let Ok(size) = data.len().try_into() else {
             return Err(libc::EINVAL);
         };
 
-        let Ok(size) = data.len().try_into() else {
+        if let Some(file) = ControlFile::from_path(path) {
+            self.control_write(file)?;
+            return Ok(size);
+        }
+
+        let Some(handle) = self.open_handles.get(&fh) else {
             return Err(libc::EINVAL);
         };
 
@@ -661,13 +1294,21 @@ impl FilesystemMT for ZerostashFs {
                 buf: data.into(),
             }))
             .unwrap();
+        self.commits.dirty.fetch_add(size as u64, Ordering::Relaxed);
 
         Ok(size)
     }
 
-    fn truncate(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+    fn truncate(&self, req: RequestInfo, path: &Path, _fh: Option<u64>, size: u64) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!("truncate {:?}: size {}", path, size);
 
+        // shells truncate control files before writing to them
+        if ControlFile::from_path(path).is_some_and(ControlFile::is_writable) {
+            return Ok(());
+        }
+        reject_changes(path)?;
+
         let real_path = strip_path(path);
         let path_string = real_path.to_str().unwrap();
 
@@ -694,7 +1335,7 @@ impl FilesystemMT for ZerostashFs {
                         ..entry.as_ref().clone()
                     },
                 )
-                .unwrap();
+                .map_err(|e| tree_errno(e, libc::EIO))?;
 
             return Ok(());
         }
@@ -714,57 +1355,102 @@ impl FilesystemMT for ZerostashFs {
             unreachable!();
         };
 
-        let truncated_chunk = {
-            let mut reader = self.stash.storage_reader().unwrap();
-            // i'm assuming we're not so good at compression that this
-            // isn't enough?
-            let mut buf: Vec<u8> = vec![0; last_chunk.size() * 16];
-            reader.read_chunk(last_chunk, &mut buf).unwrap();
+        let mut truncated_chunk = self.read_chunk(last_chunk).map_err(crypto_errno)?;
+        truncated_chunk.truncate((size - last_chunk_start) as usize);
+        chunks.insert(last_chunk_start, self.store_chunk(&truncated_chunk));
 
-            buf.truncate((size - last_chunk_start) as usize);
-            buf
+        let new_entry = Entry {
+            chunks,
+            size,
+            ..entry.as_ref().clone()
         };
 
-        let hash = *self
-            .stash
-            .hasher()
-            .unwrap()
-            .update(&truncated_chunk)
-            .finalize()
-            .as_bytes();
+        self.stash
+            .index()
+            .tree
+            .update_file(path_string, new_entry)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
-        let index = self.stash.index();
-        let mut writer = self.writer.as_ref().unwrap().clone();
-        chunks.insert(
-            last_chunk_start,
-            index.chunks.insert_with(hash, move || {
-                writer.write_chunk(&hash, &truncated_chunk).unwrap()
-            }),
+        Ok(())
+    }
+
+    fn fallocate(
+        &self,
+        req: RequestInfo,
+        path: &Path,
+        _fh: u64,
+        offset: u64,
+        length: u64,
+        mode: u32,
+    ) -> ResultEmpty {
+        self.check_access(&req)?;
+        debug!(
+            "fallocate {:?}: offset {} length {} mode {:#x}",
+            path, offset, length, mode
         );
+        reject_changes(path)?;
 
-        let new_entry = Entry {
-            chunks,
-            size,
-            ..entry.as_ref().clone()
+        if self.writer.is_none() {
+            return Err(libc::EROFS);
+        }
+
+        let mode = mode as i32;
+        if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE) != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+
+        let path_string = strip_path(path).to_str().unwrap();
+        let Ok(Some(entry)) = self.stash.index().tree.file(path_string) else {
+            return Err(libc::ENOENT);
         };
+        let end = offset.checked_add(length).ok_or(libc::EFBIG)?;
 
-        index.tree.update_file(path_string, new_entry).unwrap();
+        let new_entry = if mode & FALLOC_FL_PUNCH_HOLE != 0 {
+            if mode & FALLOC_FL_KEEP_SIZE == 0 {
+                return Err(libc::EINVAL);
+            }
+
+            Entry {
+                chunks: self
+                    .punch_hole(&entry, offset, end.min(entry.size))
+                    .map_err(crypto_errno)?,
+                ..entry.as_ref().clone()
+            }
+        } else if mode & FALLOC_FL_KEEP_SIZE == 0 && end > entry.size {
+            // there's nothing to reserve, so preallocation only
+            // needs to extend the file
+            Entry {
+                size: end,
+                ..entry.as_ref().clone()
+            }
+        } else {
+            return Ok(());
+        };
+
+        self.stash
+            .index()
+            .tree
+            .update_file(path_string, new_entry)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
         Ok(())
     }
 
     fn rename(
         &self,
-        _req: RequestInfo,
+        req: RequestInfo,
         parent: &Path,
         name: &OsStr,
         newparent: &Path,
         newname: &OsStr,
     ) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!(
             "rename: {:?}/{:?} -> {:?}/{:?}",
             parent, name, newparent, newname
         );
+        reject_changes(&parent.join(name))?;
+        reject_changes(&newparent.join(newname))?;
 
         let path = parent.join(name);
         let path_str = strip_path(&path).to_str().unwrap().to_string();
@@ -773,29 +1459,31 @@ impl FilesystemMT for ZerostashFs {
         let index = self.stash.index();
         let tree = &index.tree;
 
-        if tree.move_node(&path_str, &new_path_str).is_err() {
-            return Err(libc::EIO);
-        }
+        tree.move_node(&path_str, &new_path_str)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
         Ok(())
     }
 
-    fn mkdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+    fn mkdir(&self, req: RequestInfo, parent: &Path, name: &OsStr, _mode: u32) -> ResultEntry {
+        self.check_access(&req)?;
         debug!("mkdir: {:?}/{:?}", parent, name);
+        reject_changes(&parent.join(name))?;
 
         let path = parent.join(name);
         let index = self.stash.index();
         let tree = &index.tree;
 
-        if tree.insert_directory(path.to_str().unwrap()).is_err() {
-            return Err(libc::EIO);
-        }
+        tree.insert_directory(path.to_str().unwrap())
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
-        Ok((TTL, DIR_ATTR))
+        Ok((TTL, self.dir_attr()))
     }
 
-    fn rmdir(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+    fn rmdir(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!("rmdir: {:?}/{:?}", parent, name);
+        reject_changes(&parent.join(name))?;
 
         let path = parent.join(name);
         let path_str = strip_path(&path).to_str().unwrap().to_string();
@@ -803,15 +1491,16 @@ impl FilesystemMT for ZerostashFs {
         let index = self.stash.index();
         let tree = &index.tree;
 
-        if tree.remove(&path_str).is_err() {
-            return Err(libc::EIO);
-        }
+        tree.remove(&path_str)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
         Ok(())
     }
 
-    fn unlink(&self, _req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+    fn unlink(&self, req: RequestInfo, parent: &Path, name: &OsStr) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!("unlink: {:?}/{:?}", parent, name);
+        reject_changes(&parent.join(name))?;
 
         let path = parent.join(name);
         let path_str = strip_path(&path).to_str().unwrap().to_string();
@@ -819,13 +1508,47 @@ impl FilesystemMT for ZerostashFs {
         let index = self.stash.index();
         let tree = &index.tree;
 
-        if tree.remove(&path_str).is_err() {
-            return Err(libc::EIO);
-        }
+        tree.remove(&path_str)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
 
         Ok(())
     }
 
+    fn mknod(
+        &self,
+        req: RequestInfo,
+        parent: &Path,
+        name: &OsStr,
+        mode: u32,
+        _rdev: u32,
+    ) -> ResultEntry {
+        self.check_access(&req)?;
+        debug!("mknod {:?}/{:?} {:#o}", parent, name, mode);
+        reject_changes(&parent.join(name))?;
+
+        // only regular files can be stored in the tree
+        if mode & libc::S_IFMT as u32 != libc::S_IFREG as u32 {
+            return Err(libc::EPERM);
+        }
+
+        let real_path = parent.join(name);
+        let path_string = strip_path(&real_path).to_str().unwrap();
+
+        let index = self.stash.index();
+        let tree = &index.tree;
+        if let Ok(Some(_)) = tree.node_by_path(path_string) {
+            return Err(libc::EEXIST);
+        }
+
+        let entry = new_file_entry(&req, name, mode & !(libc::S_IFMT as u32));
+        let attr = self.file_attr(&entry, SystemTime::now());
+
+        tree.insert_file(path_string, entry)
+            .map_err(|e| tree_errno(e, libc::EIO))?;
+
+        Ok((TTL, attr))
+    }
+
     fn create(
         &self,
         req: RequestInfo,
@@ -834,38 +1557,44 @@ impl FilesystemMT for ZerostashFs {
         mode: u32,
         flags: u32,
     ) -> ResultCreate {
+        self.check_access(&req)?;
         debug!("create {:?}/{:?}", parent, name);
+        reject_changes(&parent.join(name))?;
         let real_path = parent.join(name);
         let path_string = strip_path(&real_path).to_str().unwrap();
 
-        let now = SystemTime::now();
-        let unix = now.duration_since(UNIX_EPOCH).unwrap();
-        let name = name.to_str().unwrap().to_string();
-
-        let entry = Arc::new(Entry {
-            unix_secs: unix.as_secs() as i64,
-            unix_nanos: unix.as_nanos() as u32,
-            unix_perm: Some(mode),
-            unix_uid: Some(req.uid),
-            unix_gid: Some(req.gid),
-            readonly: None,
-            file_type: FileType::File,
-            size: 0,
-            name,
-            chunks: Default::default(),
-        });
-
-        let attr = file_to_fuse(&entry, SystemTime::now());
-
         let index = self.stash.index();
         let tree = &index.tree;
-        if tree
-            .insert_file(path_string, entry.as_ref().clone())
-            .is_err()
-        {
-            return Err(libc::EIO);
-        }
+        let existing = tree.node_by_path(path_string).ok().flatten();
+
+        let entry = match create_action(existing.as_deref(), flags)? {
+            CreateAction::New => {
+                let entry = new_file_entry(&req, name, mode);
+                tree.insert_file(path_string, entry.clone())
+                    .map_err(|e| tree_errno(e, libc::EIO))?;
+                Arc::new(entry)
+            }
+            CreateAction::Open { truncate } => {
+                let Some(entry) = existing.and_then(|node| node.as_file()) else {
+                    return Err(libc::EIO);
+                };
 
+                if truncate {
+                    let entry = Entry {
+                        size: 0,
+                        chunks: Default::default(),
+                        ..entry.as_ref().clone()
+                    };
+                    tree.update_file(path_string, entry.clone())
+                        .map_err(|e| tree_errno(e, libc::EIO))?;
+                    Arc::new(entry)
+                } else {
+                    entry
+                }
+            }
+        };
+
+        let attr = self.file_attr(&entry, SystemTime::now());
         let fh = self.new_handle(entry, flags.into());
 
         Ok(CreatedEntry {
@@ -876,8 +1605,10 @@ impl FilesystemMT for ZerostashFs {
         })
     }
 
-    fn chmod(&self, _req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+    fn chmod(&self, req: RequestInfo, path: &Path, _fh: Option<u64>, mode: u32) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!("chmod: {:?} {:#o}", path, mode);
+        reject_changes(path)?;
         let path_string = strip_path(path).to_str().unwrap().to_string();
 
         let mut index = self.stash.index().clone();
@@ -895,22 +1626,23 @@ impl FilesystemMT for ZerostashFs {
             ..entry.as_ref().clone()
         };
 
-        if tree.update_file(&path_string, new_entry).is_err() {
-            return Err(libc::ENOENT);
-        }
+        tree.update_file(&path_string, new_entry)
+            .map_err(|e| tree_errno(e, libc::ENOENT))?;
 
         Ok(())
     }
 
     fn chown(
         &self,
-        _req: RequestInfo,
+        req: RequestInfo,
         path: &Path,
         _fh: Option<u64>,
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> ResultEmpty {
+        self.check_access(&req)?;
         debug!("chown {:?} to {:?}:{:?}", path, uid, gid);
+        reject_changes(path)?;
         let path_string = strip_path(path).to_str().unwrap().to_string();
 
         let index = self.stash.index();
@@ -936,9 +1668,8 @@ impl FilesystemMT for ZerostashFs {
             ..entry.as_ref().clone()
         };
 
-        if tree.update_file(&path_string, new_entry).is_err() {
-            return Err(libc::ENOENT);
-        }
+        tree.update_file(&path_string, new_entry)
+            .map_err(|e| tree_errno(e, libc::ENOENT))?;
 
         Ok(())
     }
@@ -946,6 +1677,10 @@ impl FilesystemMT for ZerostashFs {
 
 const TTL: Duration = Duration::from_secs(1);
 
+// from linux/falloc.h, which is not exported by libc on all platforms
+const FALLOC_FL_KEEP_SIZE: i32 = 0x01;
+const FALLOC_FL_PUNCH_HOLE: i32 = 0x02;
+
 const DIR_ATTR: FileAttr = FileAttr {
     size: 0,
     blocks: 0,
@@ -956,23 +1691,68 @@ const DIR_ATTR: FileAttr = FileAttr {
     kind: fuse_mt::FileType::Directory,
     perm: 0o777,
     nlink: 1,
-    uid: 1000,
-    gid: 1000,
+    uid: 0,
+    gid: 0,
     rdev: 0,
     flags: 0,
 };
 
+fn new_file_entry(req: &RequestInfo, name: &OsStr, mode: u32) -> Entry {
+    let unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    Entry {
+        unix_secs: unix.as_secs() as i64,
+        unix_nanos: unix.subsec_nanos(),
+        unix_perm: Some(mode),
+        unix_uid: Some(req.uid),
+        unix_gid: Some(req.gid),
+        readonly: None,
+        file_type: FileType::File,
+        size: 0,
+        name: name.to_str().unwrap().to_string(),
+        chunks: Default::default(),
+        content_type: None,
+        chunker: None,
+        extensions: Default::default(),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CreateAction {
+    New,
+    Open { truncate: bool },
+}
+
+/// Decide how to handle `create` based on the open flags and the
+/// node that's already at the path, if any.
+fn create_action(
+    existing: Option<&Node>,
+    flags: u32,
+) -> std::result::Result<CreateAction, libc::c_int> {
+    let flags = flags as i32;
+
+    match existing {
+        None => Ok(CreateAction::New),
+        Some(_) if flags & libc::O_EXCL != 0 => Err(libc::EEXIST),
+        Some(Node::Directory { .. }) => Err(libc::EISDIR),
+        Some(Node::File { .. }) => Ok(CreateAction::Open {
+            truncate: flags & libc::O_TRUNC != 0,
+        }),
+    }
+}
+
 fn file_to_fuse(file: &Entry, atime: SystemTime) -> FileAttr {
     let mtime = UNIX_EPOCH
         + Duration::from_secs(file.unix_secs as u64)
         + Duration::from_nanos(file.unix_nanos as u64);
+    let (crtime, flags) = macos_attr(file);
     FileAttr {
         size: file.size,
         blocks: 1,
         atime,
         mtime,
         ctime: mtime,
-        crtime: SystemTime::UNIX_EPOCH,
+        crtime,
         kind: match_filetype(file.file_type.clone()),
         perm: (file.unix_perm.unwrap() & 0o777) as u16,
         nlink: 1,
@@ -983,7 +1763,54 @@ fn file_to_fuse(file: &Entry, atime: SystemTime) -> FileAttr {
             .unix_uid
             .unwrap_or_else(|| nix::unistd::getuid().into()),
         rdev: 0,
-        flags: 0,
+        flags,
+    }
+}
+
+/// Creation time and flags of a file that was committed on macOS
+fn macos_attr(entry: &Entry) -> (SystemTime, u32) {
+    match entry.macos() {
+        Some(macos) => (
+            macos.created.map_or(UNIX_EPOCH, |(secs, nanos)| {
+                UNIX_EPOCH + Duration::new(secs as u64, nanos)
+            }),
+            macos.flags,
+        ),
+        None => (UNIX_EPOCH, 0),
+    }
+}
+
+/// Reply with the size of `data` if that's all that's asked for
+#[cfg(target_os = "macos")]
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    match size {
+        0 => Ok(Xattr::Size(data.len() as u32)),
+        size if data.len() > size as usize => Err(libc::ERANGE),
+        _ => Ok(Xattr::Data(data)),
+    }
+}
+
+/// Map errors of the tree to an errno, using `otherwise` unless
+/// there's a better match
+fn tree_errno(error: FsError<'_>, otherwise: libc::c_int) -> libc::c_int {
+    match error {
+        FsError::ReadOnly => libc::EROFS,
+        _ => otherwise,
+    }
+}
+
+/// Chunks that can't be decrypted are logged with the object they're
+/// in, and reported as I/O errors
+fn crypto_errno(error: CryptoError) -> libc::c_int {
+    error!(%error, "failed to read chunk");
+    libc::EIO
+}
+
+fn chunk_errno(error: ChunkDataError) -> libc::c_int {
+    match error {
+        ChunkDataError::NullChunkPointer => libc::EINVAL,
+        ChunkDataError::InvalidOffset => libc::EIO,
+        ChunkDataError::Crypto(error) => crypto_errno(error),
     }
 }
 
@@ -998,3 +1825,43 @@ fn match_filetype(file_type: FileType) -> fuse_mt::FileType {
         FileType::Directory => panic!("Must be a file!"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{create_action, CreateAction};
+    use nix::libc;
+    use std::sync::Arc;
+    use zerostash_files::{Entry, Node};
+
+    #[test]
+    fn create_flags() {
+        let file = Node::File {
+            refs: Default::default(),
+            entry: Arc::new(Entry::default()),
+        };
+        let dir = Node::Directory {
+            entries: Default::default(),
+            metadata: None,
+        };
+
+        let creat = libc::O_CREAT as u32;
+        let excl = (libc::O_CREAT | libc::O_EXCL) as u32;
+        let trunc = (libc::O_CREAT | libc::O_TRUNC) as u32;
+
+        // mkstemp & friends
+        assert_eq!(create_action(None, excl), Ok(CreateAction::New));
+        assert_eq!(create_action(Some(&file), excl), Err(libc::EEXIST));
+        assert_eq!(create_action(Some(&dir), excl), Err(libc::EEXIST));
+
+        // open(O_CREAT) and open(O_CREAT | O_TRUNC) on existing files
+        assert_eq!(
+            create_action(Some(&file), creat),
+            Ok(CreateAction::Open { truncate: false })
+        );
+        assert_eq!(
+            create_action(Some(&file), trunc),
+            Ok(CreateAction::Open { truncate: true })
+        );
+        assert_eq!(create_action(Some(&dir), creat), Err(libc::EISDIR));
+    }
+}
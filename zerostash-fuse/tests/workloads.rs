@@ -0,0 +1,142 @@
+//! Run real tools against a mounted stash
+//!
+//! The FUSE layer has to survive whatever `cp`, `rsync` and `git` throw
+//! at it. Each tool writes into a fresh read-write mount, then the
+//! stash is unmounted, committed, and the stored tree is checked
+//! against the source.
+//!
+//! These need FUSE, so they only run with `--features fuse-tests`.
+//! Tools that aren't installed are skipped.
+use infinitree::{backends::Directory, crypto::UsernamePassword, Infinitree};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use walkdir::WalkDir;
+use zerostash_files::{browse::Browser, Files};
+use zerostash_fuse::mount::{spawn, Options};
+
+const THREADS: usize = 4;
+
+fn key() -> UsernamePassword {
+    UsernamePassword::with_credentials("fuse-tests".to_string(), "password".to_string()).unwrap()
+}
+
+fn source() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/data/100_random_1k")
+}
+
+/// Run `command`, or return false if it's not installed
+fn run(command: &mut Command) -> bool {
+    let program = command.get_program().to_string_lossy().to_string();
+
+    match command.status() {
+        Ok(status) => {
+            assert!(status.success(), "{program} failed: {status}");
+            true
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("{program} is not installed, skipping");
+            false
+        }
+        Err(e) => panic!("{program}: {e}"),
+    }
+}
+
+/// Make a git repository with the contents of `source`
+fn init_repo(source: &Path, repo: &Path) -> bool {
+    let git = |args: &[&str]| run(Command::new("git").arg("-C").arg(repo).args(args));
+
+    git(&["init", "--quiet"])
+        && run(Command::new("cp").arg("-a").arg(source.join(".")).arg(repo))
+        && git(&["add", "."])
+        && git(&[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "test",
+        ])
+}
+
+/// Check that every file under `source` is stored under `prefix`
+fn assert_stored(browser: &Browser, source: &Path, prefix: &str) {
+    let files = WalkDir::new(source)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != ".git")
+        .map(Result::unwrap)
+        .filter(|e| e.file_type().is_file());
+
+    for file in files {
+        let relative = file.path().strip_prefix(source).unwrap();
+        let path = format!("{prefix}/{}", relative.display());
+        let expected = std::fs::read(file.path()).unwrap();
+
+        assert_eq!(browser.file(&path).unwrap().size, expected.len() as u64);
+        assert_eq!(
+            browser.read(&path, 0, expected.len()).unwrap(),
+            expected,
+            "{path}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn coreutils_workloads() {
+    let source = source();
+    let storage = tempfile::tempdir().unwrap();
+    let mountpoint = tempfile::tempdir().unwrap();
+    let repo = tempfile::tempdir().unwrap();
+    let mnt = mountpoint.path();
+
+    let backend = Directory::new(storage.path()).unwrap();
+    let stash = Infinitree::<Files>::empty(backend.clone(), key()).unwrap();
+    let options = Options {
+        read_write: true,
+        ..Default::default()
+    };
+    let mounted = spawn(stash, mnt.to_str().unwrap(), THREADS, &options).unwrap();
+
+    let copied = run(Command::new("cp")
+        .arg("-a")
+        .arg(&source)
+        .arg(mnt.join("cp")));
+    let synced = run(Command::new("rsync")
+        .arg("--archive")
+        .arg(source.join(""))
+        .arg(mnt.join("rsync")));
+    let cloned = init_repo(&source, repo.path())
+        && run(Command::new("git")
+            .args(["clone", "--quiet"])
+            .arg(repo.path())
+            .arg(mnt.join("git")))
+        && run(Command::new("git")
+            .arg("-C")
+            .arg(mnt.join("git"))
+            .args(["fsck", "--full"]));
+
+    let stash = mounted.unmount();
+    stash.commit("fuse tests").unwrap();
+    stash.backend().sync().unwrap();
+    drop(stash);
+
+    let stash = Infinitree::<Files>::open(backend.clone(), key()).unwrap();
+    stash.load(stash.index().tree()).unwrap();
+    let problems = stash.index().tree.validate();
+    assert!(problems.is_empty(), "{problems:?}");
+
+    let browser = Browser::new(Infinitree::<Files>::open(backend, key()).unwrap()).unwrap();
+    if copied {
+        assert_stored(&browser, &source, "cp");
+    }
+    if synced {
+        assert_stored(&browser, &source, "rsync");
+    }
+    if cloned {
+        assert_stored(&browser, repo.path(), "git");
+    }
+}
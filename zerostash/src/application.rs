@@ -1,14 +1,19 @@
 //! Zerostash Abscissa Application
 
-use crate::{commands::EntryPoint, prelude::*};
+use crate::{
+    commands::EntryPoint,
+    logging::{self, LogFormat},
+    prelude::*,
+};
 use abscissa_core::{
     application::{self, AppCell},
     config::{self, CfgCell},
-    trace, Application, FrameworkError, StandardPaths,
+    terminal::component::Terminal,
+    trace, Application, Component, Config, FrameworkError, FrameworkErrorKind, StandardPaths,
 };
 use abscissa_tokio::TokioComponent;
 use anyhow::Result;
-use std::num::NonZeroUsize;
+use std::{num::NonZeroUsize, path::Path};
 
 /// Application state
 pub static APP: AppCell<ZerostashApp> = AppCell::new();
@@ -61,13 +66,49 @@ impl Application for ZerostashApp {
     /// If you would like to add additional components to your application
     /// beyond the default ones provided by the framework, this is the place
     /// to do so.
+    ///
+    /// JSON logs and log files are not supported by the framework's
+    /// tracing component, so it's replaced in that case.
     fn register_components(&mut self, command: &Self::Cmd) -> Result<(), FrameworkError> {
-        let mut framework_components = self.framework_components(command)?;
+        let mut framework_components =
+            if command.log_format == LogFormat::Text && command.log_file.is_none() {
+                self.framework_components(command)?
+            } else {
+                logging::init(
+                    log_filter(command),
+                    command.log_format,
+                    command.log_file.as_deref(),
+                )
+                .map_err(|e| FrameworkErrorKind::ComponentError.context(e))?;
+
+                let terminal: Box<dyn Component<Self>> =
+                    Box::new(Terminal::new(self.term_colors(command)));
+                vec![terminal]
+            };
         framework_components.push(Box::new(TokioComponent::new()?));
         let mut app_components = self.state.components_mut();
         app_components.register(framework_components)
     }
 
+    /// Load the configuration file, decrypting it first if it was
+    /// encrypted with `0s config encrypt`.
+    fn load_config(&mut self, path: &Path) -> Result<Self::Cfg, FrameworkError> {
+        use crate::config::encrypted;
+
+        let contents = std::fs::read(path).map_err(|e| FrameworkErrorKind::IoError.context(e))?;
+        let contents = if encrypted::is_encrypted(&contents) {
+            encrypted::passphrase(false)
+                .and_then(|pw| encrypted::decrypt(&contents, &pw))
+                .map_err(|e| FrameworkErrorKind::ConfigError.context(e))?
+        } else {
+            contents
+        };
+
+        let toml =
+            String::from_utf8(contents).map_err(|e| FrameworkErrorKind::ConfigError.context(e))?;
+        Self::Cfg::load_toml(toml)
+    }
+
     /// Post-configuration lifecycle callback.
     ///
     /// Called regardless of whether config is loaded to indicate this is the
@@ -82,13 +123,15 @@ impl Application for ZerostashApp {
 
     /// Get tracing configuration from command-line options
     fn tracing_config(&self, command: &EntryPoint) -> trace::Config {
-        match command.verbose {
-            0 => "info",
-            1 => "debug",
-            _ => "trace",
-        }
-        .to_owned()
-        .into()
+        log_filter(command).to_owned().into()
+    }
+}
+
+fn log_filter(command: &EntryPoint) -> &'static str {
+    match command.verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
     }
 }
 
@@ -7,5 +7,12 @@ use zerostash::application::APP;
 
 /// Boot Zerostash
 fn main() {
+    // Fail with a readable message instead of SIGILL if the binary was
+    // built for a newer CPU than the one we're running on.
+    if let Err(err) = zerostash_files::cpu::check_compiled_features() {
+        eprintln!("0s: {err}");
+        std::process::exit(1);
+    }
+
     abscissa_core::boot(&APP);
 }
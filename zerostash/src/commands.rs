@@ -2,14 +2,70 @@
 
 mod keys;
 use keys::*;
+mod backend;
+use backend::*;
+mod bundle;
+use bundle::*;
+mod cache;
+use cache::*;
 mod checkout;
 use checkout::*;
+mod clone;
+use clone::*;
 mod commit;
 use commit::*;
+mod compact;
+use compact::*;
+mod config;
+use config::*;
+mod diff;
+use diff::*;
+mod exit_codes;
+use exit_codes::*;
+mod find;
+use find::*;
+mod grep;
+use grep::*;
+mod index;
+use index::*;
 mod log;
 use log::*;
 mod ls;
 use ls::*;
+mod manifest;
+use manifest::*;
+mod migrate_backend;
+use migrate_backend::*;
+mod pin;
+use pin::*;
+mod prune;
+use prune::*;
+mod receive;
+use receive::*;
+mod rollback;
+use rollback::*;
+mod salvage;
+use salvage::*;
+mod self_update;
+use self_update::*;
+mod serve;
+use serve::*;
+mod share;
+use share::*;
+mod snapshot;
+use snapshot::*;
+mod stats;
+use stats::*;
+mod thaw;
+use thaw::*;
+mod unbundle;
+use unbundle::*;
+mod upload;
+use upload::*;
+mod verify;
+use verify::*;
+mod watch;
+use watch::*;
 mod wipe;
 use wipe::*;
 mod zfs;
@@ -20,10 +76,11 @@ pub mod mount;
 pub use mount::*;
 
 use crate::{
-    config::{Key, SymmetricKey, YubikeyCRConfig, YubikeyCRKey},
+    config::{Key, Override, SymmetricKey, YubikeyCRConfig, YubikeyCRKey},
+    logging::LogFormat,
     prelude::*,
 };
-use abscissa_core::{Command, Configurable, Runnable};
+use abscissa_core::{Command, Configurable, FrameworkError, Runnable};
 use clap::{ArgGroup, Parser};
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -35,25 +92,116 @@ pub const CONFIG_FILE: &str = "zerostash.toml";
 /// Subcommands need to be listed in an enum.
 #[derive(Debug, Parser)]
 pub enum ZerostashCmd {
+    /// Check the storage backends of a stash
+    #[clap(subcommand)]
+    Backend(BackendCommand),
+
+    /// Write all objects of a stash into a single file, eg. for tape backups
+    Bundle(Bundle),
+
+    /// Manage the local caches of a stash
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+
     /// Check out files
     Checkout(Checkout),
 
+    /// Copy all commits of a stash to a new stash, optionally using a new key
+    Clone(CloneStash),
+
     /// Add files to a stash
     Commit(Commit),
 
+    /// Drop old commits, and rewrite the index without what they removed
+    Compact(Compact),
+
+    /// Manage the configuration file
+    #[clap(subcommand)]
+    Config(ConfigCmd),
+
+    /// Show the files that changed between two commits
+    Diff(Diff),
+
+    /// List the exit codes of 0s, and what they mean
+    ExitCodes(ExitCodes),
+
+    /// Find files by path and content type
+    Find(Find),
+
+    /// Search the contents of files in the stash, without restoring them
+    Grep(Grep),
+
+    /// Manage the chunk index of a stash
+    #[clap(subcommand)]
+    Index(Index),
+
     /// List commits in the stash
     Log(Log),
 
     /// List files in a stash
     Ls(Ls),
 
+    /// Write a JSON manifest of all files in a commit
+    Manifest(Manifest),
+
+    /// Copy all objects of a stash to a new backend
+    MigrateBackend(MigrateBackend),
+
     /// Mount the files in a stash
     #[cfg(feature = "fuse")]
     Mount(Mount),
 
     /// Key management & generation
+    #[clap(alias = "key")]
     Keys(Keys),
 
+    /// Keep the data of paths or commits from being pruned
+    #[clap(subcommand)]
+    Pin(PinCommand),
+
+    /// Mark objects no file uses as deleted, and delete them after a grace period
+    Prune(Prune),
+
+    /// Unpack files that were shared with you using `0s share`
+    Receive(Receive),
+
+    /// Make the files of an older commit the latest state of the stash
+    Rollback(Rollback),
+
+    /// Recover readable chunks from damaged objects, and drop the rest
+    Salvage(Salvage),
+
+    /// Update 0s to the latest signed release
+    SelfUpdate(SelfUpdate),
+
+    /// Answer which chunks the stash has, for clients on other machines
+    Serve(Serve),
+
+    /// Encrypt files of a stash for age recipients, without access to the stash
+    Share(Share),
+
+    /// Give names to commits, so they can be restored with `--snapshot`
+    #[clap(subcommand)]
+    Snapshot(SnapshotCommand),
+
+    /// Show statistics of a stash, or of its local caches
+    Stats(Stats),
+
+    /// Restore archived S3 objects of a stash, so they can be read
+    Thaw(Thaw),
+
+    /// Extract a bundle into a new stash directory
+    Unbundle(Unbundle),
+
+    /// Upload the objects in the spool of a stash to its backend
+    Upload(Upload),
+
+    /// Check the integrity of stored data
+    Verify(Verify),
+
+    /// Watch directories and continuously commit changes
+    Watch(Watch),
+
     /// Delete all data of a stash
     Wipe(Wipe),
 
@@ -75,6 +223,14 @@ pub struct EntryPoint {
     #[clap(short, long, default_value_t = 0)]
     pub verbose: usize,
 
+    /// Format of log messages
+    #[clap(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Append log messages to a file instead of printing them
+    #[clap(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
     /// Use config file. Command line args will take precedence!
     #[clap(short, long, value_name = "PATH")]
     pub config: Option<String>,
@@ -82,6 +238,11 @@ pub struct EntryPoint {
     /// Use the specified config file
     #[clap(long)]
     pub insecure_config: bool,
+
+    /// Override a field of a stash's configuration, eg. `mystash.backend=s3://...`.
+    /// May be repeated.
+    #[clap(long = "set", value_name = "STASH.FIELD=VALUE")]
+    pub overrides: Vec<Override>,
 }
 
 #[derive(clap::Args, Clone, Debug)]
@@ -112,6 +273,10 @@ pub struct StashArgs {
     /// Commit ID to load before doing any operations on the stash
     #[clap(long)]
     pub commit_id: Option<infinitree::tree::CommitId>,
+
+    /// Named snapshot to load before doing any operations on the stash
+    #[clap(long, conflicts_with = "commit_id")]
+    pub snapshot: Option<String>,
 }
 
 impl StashArgs {
@@ -135,19 +300,40 @@ impl StashArgs {
     }
 
     pub(crate) fn parse_stash(&self) -> crate::config::Stash {
-        crate::config::Stash::from_str(&self.stash).unwrap()
+        crate::config::Stash::from_str(&self.stash)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e))
     }
 
     pub(crate) fn open_with(&self, key: Option<Key>) -> Stash {
-        let stash = crate::config::Stash::from_str(&self.stash)
-            .unwrap()
+        let stash = self
+            .parse_stash()
             .open_or_new(key)
-            .unwrap();
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        stash
+            .load(stash.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        zerostash_files::object_size::check(&stash)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        zerostash_files::dictionary::load(&stash).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let snapshot = self.snapshot.as_ref().map(|name| {
+            zerostash_files::named_snapshot::get(&stash, name)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+                .unwrap_or_else(|| fail(ErrorKind::Config, format!("no snapshot {name}")))
+        });
+
+        // only the records up to the selected commit should stay loaded
+        stash.index().custom_fields.clear();
 
         if let Some(commit) = self.commit_id {
             stash.filter_commits(infinitree::tree::CommitFilter::UpTo(commit));
         }
 
+        if let Some(snapshot) = snapshot {
+            stash.filter_commits(infinitree::tree::CommitFilter::UpTo(snapshot.commit));
+        }
+
         stash
     }
 
@@ -161,11 +347,39 @@ impl Runnable for EntryPoint {
         use ZerostashCmd::*;
         abscissa_tokio::run(&APP, async move {
             match &*self.cmd {
+                Backend(cmd) => cmd.run().await,
+                Bundle(cmd) => cmd.run().await,
+                Cache(cmd) => cmd.run().await,
                 Checkout(cmd) => cmd.run().await,
+                Clone(cmd) => cmd.run().await,
                 Commit(cmd) => cmd.run().await,
+                Compact(cmd) => cmd.run().await,
+                Config(cmd) => cmd.run().await,
+                Diff(cmd) => cmd.run().await,
+                ExitCodes(cmd) => cmd.run().await,
+                Find(cmd) => cmd.run().await,
+                Grep(cmd) => cmd.run().await,
+                Index(cmd) => cmd.run().await,
                 Log(cmd) => cmd.run().await,
                 Ls(cmd) => cmd.run().await,
+                Manifest(cmd) => cmd.run().await,
+                MigrateBackend(cmd) => cmd.run().await,
                 Keys(cmd) => cmd.run().await,
+                Pin(cmd) => cmd.run().await,
+                Prune(cmd) => cmd.run().await,
+                Receive(cmd) => cmd.run().await,
+                Rollback(cmd) => cmd.run().await,
+                Salvage(cmd) => cmd.run().await,
+                SelfUpdate(cmd) => cmd.run().await,
+                Serve(cmd) => cmd.run().await,
+                Share(cmd) => cmd.run().await,
+                Snapshot(cmd) => cmd.run().await,
+                Stats(cmd) => cmd.run().await,
+                Thaw(cmd) => cmd.run().await,
+                Unbundle(cmd) => cmd.run().await,
+                Upload(cmd) => cmd.run().await,
+                Verify(cmd) => cmd.run().await,
+                Watch(cmd) => cmd.run().await,
                 Wipe(cmd) => cmd.run().await,
                 Zfs(cmd) => cmd.run().await,
                 #[cfg(feature = "fuse")]
@@ -204,4 +418,13 @@ impl Configurable<ZerostashConfig> for EntryPoint {
             None
         }
     }
+
+    /// Apply the overrides given on the command line
+    fn process_config(
+        &self,
+        mut config: ZerostashConfig,
+    ) -> Result<ZerostashConfig, FrameworkError> {
+        config.overrides = self.overrides.clone();
+        Ok(config)
+    }
 }
@@ -0,0 +1,66 @@
+//! `backend` subcommand
+
+use crate::prelude::*;
+
+#[derive(Command, Debug)]
+pub enum BackendCommand {
+    /// Check that every backend of a stash can be written, read and
+    /// deleted from, and show how long each step takes
+    Check(CheckBackend),
+}
+
+#[async_trait]
+impl AsyncRunnable for BackendCommand {
+    async fn run(&self) {
+        use BackendCommand::*;
+        match self {
+            Check(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct CheckBackend {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for CheckBackend {
+    async fn run(&self) {
+        let stash = self.stash.parse_stash();
+
+        let mut failed = 0;
+        for (backend, latency) in stash.backend.probe() {
+            match latency {
+                Ok(latency) => println!(
+                    "{backend}: ok (write {:?}, read {:?}, delete {:?})",
+                    latency.write, latency.read, latency.delete
+                ),
+                Err(e) => {
+                    println!("{backend}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            fail(
+                ErrorKind::Backend,
+                format!("{failed} backends of {} are not usable", stash.alias),
+            );
+        }
+    }
+}
+
+/// Probe the backends of `stash` before a long operation, so problems
+/// show up in seconds.
+pub(crate) fn check_before_start(stash: &crate::config::Stash) -> Result<(), crate::error::Error> {
+    for (backend, latency) in stash.backend.probe() {
+        if let Err(e) = latency {
+            return Err(ErrorKind::Backend.error(format!("{backend}: {e}")));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! `bundle` subcommand
+
+use crate::prelude::*;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, BufWriter, IsTerminal},
+    path::PathBuf,
+    str::FromStr,
+};
+use zerostash_files::bundle;
+
+#[derive(Command, Debug)]
+pub struct Bundle {
+    /// Stash path or alias
+    stash: String,
+
+    /// Write the bundle to a file instead of the standard output
+    #[clap(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Bundle {
+    /// Start the application.
+    async fn run(&self) {
+        let config = crate::config::Stash::from_str(&self.stash)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+
+        let mut objects = config
+            .backend
+            .list_objects()
+            .and_then(|list| list.collect::<Result<HashSet<_>, _>>())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+            .into_iter()
+            .collect::<Vec<_>>();
+        objects.sort_by_key(|id| id.to_string());
+
+        // the index is authenticated with the key of the stash
+        let stash = config
+            .try_open(None)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let hasher = stash.hasher().unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+        let backend = stash.backend();
+
+        let out: Box<dyn Write> = match &self.output {
+            Some(path) => {
+                Box::new(fs::File::create_new(path).unwrap_or_else(|e| fail(ErrorKind::Io, e)))
+            }
+            None if io::stdout().is_terminal() => fail(
+                ErrorKind::Config,
+                "refusing to write a bundle to a terminal, use `--output` or redirect it",
+            ),
+            None => Box::new(io::stdout().lock()),
+        };
+
+        let index = bundle::write(backend.as_ref(), objects, hasher, BufWriter::new(out))
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        eprintln!("Bundled {} objects", index.len());
+    }
+}
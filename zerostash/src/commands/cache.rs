@@ -0,0 +1,95 @@
+//! `cache` subcommand
+
+use crate::{migration::migration, prelude::*};
+use std::collections::HashSet;
+use zerostash_files::{cache, list};
+
+#[derive(Command, Debug)]
+pub enum CacheCommand {
+    /// Remove all objects, pins and statistics from the local caches of a stash
+    Clear(ClearCache),
+    /// Load the index of a stash into its local caches, and pin it there
+    Warm(WarmCache),
+}
+
+#[async_trait]
+impl AsyncRunnable for CacheCommand {
+    async fn run(&self) {
+        use CacheCommand::*;
+        match self {
+            Clear(c) => c.run().await,
+            Warm(w) => w.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ClearCache {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ClearCache {
+    async fn run(&self) {
+        let stash = self.stash.parse_stash();
+        let paths = stash.backend.cache_paths();
+        if paths.is_empty() {
+            println!("The stash has no local cache");
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        for path in paths {
+            let removed = cache::clear(path).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            println!("{path}: removed {removed} objects");
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct WarmCache {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for WarmCache {
+    async fn run(&self) {
+        let paths = self
+            .stash
+            .parse_stash()
+            .backend
+            .cache_paths()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            println!("The stash has no local cache");
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        // reading the index goes through the caches
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        let mut data = HashSet::new();
+        stash
+            .index()
+            .chunks
+            .for_each(|_, pointer| _ = data.insert(*pointer.object_id()));
+
+        for path in paths {
+            let index = list::Directory::new(&path)
+                .and_then(|list| list.collect::<Result<Vec<_>, _>>())
+                .unwrap_or_else(|e| fail(ErrorKind::Io, e))
+                .into_iter()
+                .filter(|id| !data.contains(id));
+
+            let pinned = cache::pin(&path, index).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            println!("{path}: {pinned} index objects pinned");
+        }
+    }
+}
@@ -1,7 +1,19 @@
 //! `checkout` subcommand
 
-use crate::prelude::*;
-use zerostash_files::restore;
+use crate::{
+    commit_message::stash_roots,
+    glacier, logging,
+    prelude::*,
+    systemd::Operation,
+    telemetry::{Outcome, Run},
+};
+use humansize::{format_size, BINARY};
+use std::path::PathBuf;
+use tracing::Instrument;
+use zerostash_files::{
+    crypto_error::CryptoError, disk_space::NotEnoughSpace, interrupt::Interrupted, restore,
+    roots::Destination,
+};
 
 #[derive(Command, Debug)]
 pub struct Checkout {
@@ -10,18 +22,138 @@ pub struct Checkout {
 
     #[clap(flatten)]
     options: restore::Options,
+
+    /// Print how much data needs to be downloaded, without restoring
+    /// anything
+    #[clap(long)]
+    estimate: bool,
+
+    /// Restore files to the absolute paths they were committed from
+    #[clap(long, conflicts_with = "relative_to")]
+    original_paths: bool,
+
+    /// Restore the contents of each committed path into DIR, instead
+    /// of under the full stored path
+    #[clap(long, value_name = "DIR")]
+    relative_to: Option<PathBuf>,
 }
 
 #[async_trait]
 impl AsyncRunnable for Checkout {
     /// Start the application.
     async fn run(&self) {
+        let service = Operation::start("checkout");
+        let run = Run::start(&self.stash.parse_stash(), "checkout");
+        service.status("loading the index");
         let stash = self.stash.open();
-        stash.load(stash.index().tree()).unwrap();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        if self.estimate {
+            self.estimate(&stash);
+            return;
+        }
 
-        self.options
+        let options = restore::Options {
+            destination: self.destination(&stash),
+            interrupt: interrupt_on_ctrl_c(),
+            heartbeat: service.heartbeat(),
+            ..self.options.clone()
+        };
+
+        let bytes = match run.is_enabled() {
+            true => options.estimate(&stash, |_| false).bytes,
+            false => 0,
+        };
+
+        service.status("restoring files");
+        let result = options
             .from_iter(&stash, APP.get_worker_threads())
-            .await
-            .expect("Error extracting data");
+            .instrument(logging::operation_span("checkout"))
+            .await;
+
+        let kind = result.as_ref().err().and_then(|e| {
+            if e.is::<CryptoError>() || e.is::<restore::Mismatch>() {
+                Some(ErrorKind::Verification)
+            } else if e.is::<Interrupted>() {
+                Some(ErrorKind::Interrupted)
+            } else if e.is::<NotEnoughSpace>() {
+                Some(ErrorKind::Io)
+            } else {
+                None
+            }
+        });
+        let outcome = match result {
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Failed(kind),
+        };
+        run.finish(bytes, 0, outcome).await;
+
+        match (result, kind) {
+            (Ok(_), _) => {}
+            (Err(_), Some(ErrorKind::Interrupted)) => {
+                fail(ErrorKind::Interrupted, "some files were not restored")
+            }
+            (Err(e), Some(kind)) => fail(kind, e),
+            (Err(e), None) if glacier::is_archived(&e) => fail(
+                ErrorKind::Backend,
+                format!("{e}\nSome objects are archived, run `0s thaw --wait` first"),
+            ),
+            (Err(e), None) => fail(ErrorKind::Io, e),
+        }
+    }
+}
+
+impl Checkout {
+    fn destination(&self, stash: &Stash) -> Destination {
+        if !self.original_paths && self.relative_to.is_none() {
+            return Destination::Stored;
+        }
+
+        stash
+            .load(stash.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let roots = stash_roots(stash).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        if roots.is_empty() {
+            fail(
+                ErrorKind::Config,
+                "the stash doesn't record where its files were committed from",
+            );
+        }
+
+        match self.relative_to {
+            Some(ref dir) => Destination::RelativeTo(dir.clone(), roots),
+            None => Destination::Original(roots),
+        }
+    }
+
+    fn estimate(&self, stash: &Stash) {
+        let cached = self
+            .stash
+            .parse_stash()
+            .backend
+            .cached_objects()
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        let estimate = self.options.estimate(stash, |id| cached.contains(id));
+
+        println!(
+            "Files:      {} ({})",
+            estimate.files,
+            format_size(estimate.bytes, BINARY)
+        );
+        println!(
+            "Chunks:     {} ({})",
+            estimate.chunks,
+            format_size(estimate.chunk_bytes, BINARY)
+        );
+        println!(
+            "Objects:    {} ({} cached)",
+            estimate.objects, estimate.cached_objects
+        );
+        println!(
+            "Download:   {}",
+            format_size(estimate.transfer_bytes(), BINARY)
+        );
     }
 }
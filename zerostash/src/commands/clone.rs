@@ -0,0 +1,107 @@
+//! `clone` subcommand
+
+use crate::{commit_message::CommitMessage, config::Key, migration::migration, prelude::*};
+use infinitree::tree::CommitFilter;
+use std::{num::NonZeroUsize, path::PathBuf, str::FromStr};
+use zerostash_files::copy::copy_stash;
+
+#[derive(Command, Debug)]
+pub struct CloneStash {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Destination stash path or alias. Must be empty.
+    destination: String,
+
+    /// Ask for new credentials for the destination instead of reusing the source key
+    #[clap(long)]
+    new_key: bool,
+
+    /// Use a keyfile for the destination
+    #[clap(long, value_name = "PATH", conflicts_with = "new_key")]
+    new_keyfile: Option<PathBuf>,
+
+    /// Only copy the latest N commits.
+    ///
+    /// Only data that is reachable from the copied commits is
+    /// transferred, so this can be used to produce a compact archive.
+    #[clap(long, value_name = "N")]
+    depth: Option<NonZeroUsize>,
+}
+
+#[async_trait]
+impl AsyncRunnable for CloneStash {
+    /// Start the application.
+    async fn run(&self) {
+        let mut src = self.stash.open();
+
+        let dst_key = if let Some(ref path) = self.new_keyfile {
+            Some(Key::KeyFile { path: path.clone() })
+        } else if self.new_key {
+            println!("Credentials for {}", self.destination);
+            Some(Key::Interactive)
+        } else {
+            self.stash.key()
+        };
+
+        let dst = crate::config::Stash::from_str(&self.destination)
+            .and_then(|s| s.open_or_new(dst_key))
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        if dst.commit_list().iter().next().is_some() {
+            fail(
+                ErrorKind::Config,
+                format!("{} is not empty", self.destination),
+            );
+        }
+
+        src.load(src.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let mut commits = src
+            .commit_list()
+            .iter()
+            .map(|c| {
+                let message = CommitMessage::load(&src, &c.metadata)
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+                (c.id, message)
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(depth) = self.depth {
+            let skip = commits.len().saturating_sub(depth.get());
+            commits.drain(..skip);
+        }
+
+        for (n, (id, mut message)) in commits.into_iter().enumerate() {
+            src.filter_commits(CommitFilter::UpTo(id));
+
+            let index = src.index();
+            index
+                .tree
+                .clear()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e.to_string()));
+            index.files.clear();
+            index.zfs_snapshots.clear();
+
+            src.load_all()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            migration(&mut src);
+
+            copy_stash(&src, &dst, APP.get_worker_threads())
+                .await
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+            message.set_previous(&dst);
+            let message = message
+                .render(&dst)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            dst.commit(message)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            dst.backend()
+                .sync()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+            println!("Copied commit {} ({id:?})", n + 1);
+        }
+    }
+}
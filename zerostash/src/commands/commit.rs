@@ -1,36 +1,540 @@
 //! `commit` subcommand
 
-use crate::{migration::migration, prelude::*};
+use super::{backend::check_before_start, watch::Root};
+use crate::{
+    commit_message::{parse_annotation, CommitMessage},
+    error::Error,
+    logging,
+    migration::migration,
+    prelude::*,
+    systemd::Operation,
+    telemetry::{Outcome, Run},
+};
+use anyhow::Context;
+use humansize::{format_size, BINARY};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{debug, warn, Instrument};
+use zerostash_files::{
+    dictionary,
+    files_cache::FilesCache,
+    pool::Pool,
+    roots::{RootSpec, Source},
+    store::Added,
+    timings::{Stage, Timings},
+};
 
-#[derive(Command, Debug)]
+#[derive(Command, Debug, Clone)]
 pub struct Commit {
     #[clap(flatten)]
-    stash: StashArgs,
+    stash: Option<StashArgs>,
 
     #[clap(flatten)]
     options: zerostash_files::store::Options,
 
-    /// Commit message to include in the changeset
+    /// Commit message to include in the changeset.
+    ///
+    /// `{date}`, `{time}`, and `{KEY}` for any annotation KEY will be substituted.
     #[clap(short = 'm', long)]
     message: Option<String>,
+
+    /// Attach a `key=value` annotation to the commit. May be repeated.
+    #[clap(short = 'a', long = "annotate", value_parser = parse_annotation)]
+    annotations: Vec<(String, String)>,
+
+    /// Show how much time was spent in each stage of the commit
+    #[clap(long)]
+    timings: bool,
+
+    /// Compare every file to the index, instead of skipping the ones
+    /// that are unchanged since the last commit on this machine
+    #[clap(long)]
+    no_files_cache: bool,
+
+    /// Don't write a probe object to the backends before starting
+    #[clap(long)]
+    no_backend_check: bool,
+
+    /// Only scan the paths listed on the standard input, one per line,
+    /// eg. from inotifywait or fswatch. Everything else is kept as it is
+    /// in the stash.
+    #[clap(long)]
+    changed_from_stdin: bool,
+
+    /// Explain which rule skips PATH, without committing anything.
+    /// May be repeated.
+    #[clap(long, value_name = "PATH")]
+    explain_skip: Vec<PathBuf>,
+
+    /// Commit the configured paths of every stash that has a `commit`
+    /// section in the configuration
+    #[clap(long, conflicts_with = "changed_from_stdin")]
+    all: bool,
+
+    /// Commit to this many stashes at the same time with `--all`
+    #[clap(long, value_name = "N", default_value_t = 1, requires = "all")]
+    parallel: usize,
+
+    /// Train a compression dictionary over the small files already in
+    /// the stash, if it doesn't have one. New chunks of small files are
+    /// compressed with it. Builds without dictionary support can't open
+    /// the stash afterwards.
+    #[clap(long)]
+    train_dictionary: bool,
+}
+
+/// What a commit stored, and how long it took
+struct Committed {
+    added: Added,
+    timings: Arc<Timings>,
+    commit_time: Duration,
+    sync_time: Duration,
 }
 
 #[async_trait]
 impl AsyncRunnable for Commit {
     /// Start the application.
     async fn run(&self) {
-        let mut stash = self.stash.open();
-        stash.load_all().unwrap();
-        migration(&mut stash);
+        if !self.explain_skip.is_empty() {
+            self.explain_skips();
+            return;
+        }
+
+        if self.all {
+            if self.stash.is_some() {
+                fail(
+                    ErrorKind::Config,
+                    "a stash can't be given together with `--all`",
+                );
+            }
+
+            self.commit_all().await;
+            return;
+        }
+
+        let Some(ref args) = self.stash else {
+            fail(
+                ErrorKind::Config,
+                "specify a stash to commit to, or use `--all`",
+            );
+        };
+
+        self.commit(args)
+            .instrument(logging::operation_span("commit"))
+            .await
+    }
+}
+
+impl Commit {
+    async fn commit(&self, args: &StashArgs) {
+        let options = if self.changed_from_stdin {
+            let paths = self.changed_paths();
+            if paths.is_empty() {
+                println!("Nothing changed");
+                return;
+            }
+
+            zerostash_files::store::Options {
+                paths,
+                ..self.options.clone()
+            }
+        } else {
+            self.options.clone()
+        };
+
+        let config = args.parse_stash();
+        if !self.no_backend_check {
+            check_before_start(&config).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        }
+
+        // stop walking on Ctrl-C, and commit what's done so far
+        let options = zerostash_files::store::Options {
+            interrupt: interrupt_on_ctrl_c(),
+            ..options
+        };
 
-        self.options
-            .add_recursive(&stash, APP.get_worker_threads())
+        let start = Instant::now();
+        let service = Operation::start("commit");
+        let committed = self
+            .commit_to(&config, || Ok(args.open()), options, None, &service)
             .await
-            .unwrap();
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        print_added(&committed.added);
+        if self.timings {
+            print_timings(&committed, start.elapsed());
+        }
+
+        if committed.added.interrupted {
+            eprintln!("The commit was interrupted, and only has some of the changes");
+            exit_with(ErrorKind::Interrupted);
+        }
+    }
+
+    /// Commit to every stash with a `commit` configuration, up to
+    /// `--parallel` at a time. Every stash is opened on its own, so a
+    /// failing one doesn't stop the others.
+    async fn commit_all(&self) {
+        let stashes = APP.config().commits();
+        if stashes.is_empty() {
+            fail(ErrorKind::Config, "no stash has paths to commit configured");
+        }
+
+        let total = stashes.len();
+        let service = Arc::new(Operation::start("commit"));
+        let interrupt = interrupt_on_ctrl_c();
+        let permits = Arc::new(Semaphore::new(self.parallel.max(1)));
+        let mut running = JoinSet::new();
+
+        for config in stashes {
+            let this = self.clone();
+            let (service, permits, interrupt) =
+                (service.clone(), permits.clone(), interrupt.clone());
+
+            running.spawn(
+                async move {
+                    let _permit = permits.acquire_owned().await.unwrap();
+                    let commit = config.commit.clone().unwrap();
+                    let options = zerostash_files::store::Options {
+                        paths: commit.paths,
+                        interrupt,
+                        ..this.options.clone()
+                    };
+
+                    let result = async {
+                        if !this.no_backend_check {
+                            check_before_start(&config)?;
+                        }
+
+                        this.commit_to(
+                            &config,
+                            || config.open_or_new(None),
+                            options,
+                            commit.message.as_deref(),
+                            &service,
+                        )
+                        .await
+                    }
+                    .await;
+
+                    (config.alias, result)
+                }
+                .instrument(logging::operation_span("commit")),
+            );
+        }
+
+        let (mut failed, mut interrupted) = (0, false);
+        while let Some(joined) = running.join_next().await {
+            match joined {
+                Ok((alias, Ok(committed))) => {
+                    println!("{alias}:");
+                    print_added(&committed.added);
+                    println!();
+                    interrupted |= committed.added.interrupted;
+                }
+                Ok((alias, Err(e))) => {
+                    status_err!("failed to commit to {}: {:#}", alias, e);
+                    failed += 1;
+                }
+                Err(e) => {
+                    status_err!("commit failed: {}", e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if interrupted {
+            eprintln!("Some commits were interrupted, and only have some of the changes");
+            exit_with(ErrorKind::Interrupted);
+        }
+        if failed > 0 {
+            fail(
+                ErrorKind::Partial,
+                format!("{failed} of {total} commits failed"),
+            );
+        }
+    }
 
+    /// Commit `options` to the stash of `config`, which `open` opens.
+    ///
+    /// Nothing in here depends on the process running a single commit,
+    /// so several can run at the same time.
+    async fn commit_to(
+        &self,
+        config: &crate::config::Stash,
+        open: impl FnOnce() -> anyhow::Result<Stash>,
+        options: zerostash_files::store::Options,
+        message: Option<&str>,
+        service: &Operation,
+    ) -> anyhow::Result<Committed> {
+        let run = Run::start(config, "commit");
+        let result = self
+            .store_and_commit(config, open, options, message, service)
+            .await;
+
+        let (outcome, logical, physical) = match result {
+            Ok(ref committed) if committed.added.interrupted => (
+                Outcome::Failed(Some(ErrorKind::Interrupted)),
+                committed.added.logical,
+                committed.added.physical,
+            ),
+            Ok(ref committed) => (
+                Outcome::Ok,
+                committed.added.logical,
+                committed.added.physical,
+            ),
+            Err(ref e) => (Outcome::Failed(Error::kind_of(e.as_ref())), 0, 0),
+        };
+        run.finish(logical, physical, outcome).await;
+
+        result
+    }
+
+    async fn store_and_commit(
+        &self,
+        config: &crate::config::Stash,
+        open: impl FnOnce() -> anyhow::Result<Stash>,
+        options: zerostash_files::store::Options,
+        message: Option<&str>,
+        service: &Operation,
+    ) -> anyhow::Result<Committed> {
+        service.status("loading the index");
+        let mut stash = open()?;
+        stash.load_all()?;
+        migration(&mut stash);
+        zerostash_files::object_size::record(&stash, config.object_size)
+            .map_err(|e| ErrorKind::Config.error(e))?;
+        zerostash_files::digest_key::record(&stash)?;
+        if self.train_dictionary && dictionary::get(&stash)?.is_none() {
+            service.status("training a compression dictionary");
+            if dictionary::train(&stash)?.is_none() {
+                warn!("too few small files in the stash to train a dictionary");
+            }
+        }
+
+        let pool = match config.pool {
+            Some(ref config) => {
+                let pool = Pool::open(&config.catalog).context("Failed to open pool catalog")?;
+                let imported = pool
+                    .seed(&config.member, stash.index())
+                    .context("Failed to read pool catalog")?;
+                debug!(imported, "seeded chunks from the pool");
+
+                Some((pool, config.member.clone()))
+            }
+            None => None,
+        };
+
+        let cache_path = config.files_cache_path();
+        let cache =
+            (!self.no_files_cache).then(|| FilesCache::load(&cache_path, &last_commit(&stash)));
+
+        service.status(format!("storing {} paths", options.paths.len()));
+        let options = zerostash_files::store::Options {
+            heartbeat: service.heartbeat(),
+            ..options
+        };
+        let timings = Arc::new(Timings::default());
+        let threads = APP.get_worker_threads();
+        let (added, seen) = match cache.as_ref() {
+            Some(cache) => {
+                options
+                    .add_recursive_cached(&stash, threads, timings.clone(), cache)
+                    .await?
+            }
+            None => (
+                options
+                    .add_recursive_timed(&stash, threads, timings.clone())
+                    .await?,
+                Default::default(),
+            ),
+        };
+
+        service.status(format!(
+            "writing the index, {} added",
+            format_size(added.logical, BINARY)
+        ));
+        let commit_start = Instant::now();
+        let message = message.or(self.message.as_deref());
+        let mut message = CommitMessage::new(message, self.annotations.clone());
+        message.set_added(&added);
+        message.set_roots(&roots(&options));
+        if options.metadata_only {
+            message.set_metadata_only();
+        }
+        if added.interrupted {
+            message.set_interrupted();
+        }
+        if let Some(quantum) = config.commit_time_quantum_secs {
+            message.quantize_time(SystemTime::now(), Duration::from_secs(quantum.get()));
+        }
+        message.set_previous(&stash);
+        stash
+            .commit(message.render(&stash)?)
+            .context("Failed to write metadata")?;
+        let commit_time = commit_start.elapsed();
+
+        let sync_start = Instant::now();
         stash
-            .commit(self.message.clone())
-            .expect("Failed to write metadata");
-        stash.backend().sync().expect("Failed to write to storage");
+            .backend()
+            .sync()
+            .context("Failed to write to storage")?;
+        let sync_time = sync_start.elapsed();
+
+        // only complete walks are cached
+        if cache.is_some() && !added.interrupted {
+            let cache = FilesCache::from_walk(seen, &stash.index().tree, last_commit(&stash));
+            if let Err(error) = cache.save(&cache_path) {
+                warn!(%error, "failed to save the files cache");
+            }
+        }
+
+        // only publish chunks once they're safely in the storage
+        if let Some((pool, member)) = pool {
+            pool.publish(&member, stash.index())
+                .context("Failed to update pool catalog")?;
+        }
+
+        Ok(Committed {
+            added,
+            timings,
+            commit_time,
+            sync_time,
+        })
     }
+
+    /// Read changed paths from the standard input, and keep the
+    /// outermost ones under the committed paths.
+    fn changed_paths(&self) -> Vec<PathBuf> {
+        let roots = self
+            .options
+            .paths
+            .iter()
+            .map(|path| Root::new(path))
+            .collect::<Vec<_>>();
+        let cwd = std::env::current_dir().unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let mut paths = std::io::stdin()
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| {
+                let path = cwd.join(line.trim_end());
+                let source = roots.iter().find_map(|root| root.source_path(&path));
+                if source.is_none() {
+                    warn!(%line, "not under any of the committed paths; skipping");
+                }
+                source
+            })
+            .collect::<Vec<_>>();
+
+        // parents sort before their children
+        paths.sort_by_key(|path| Source::parse(path).path);
+        paths.dedup_by(|child, parent| {
+            Source::parse(child)
+                .path
+                .starts_with(Source::parse(parent).path)
+        });
+        paths
+    }
+
+    fn explain_skips(&self) {
+        for path in self.explain_skip.iter() {
+            match self.options.explain_skip(path) {
+                Ok(Some(reason)) => println!("{}: skipped, {reason}", path.display()),
+                Ok(None) => println!("{}: included", path.display()),
+                Err(e) => println!("{}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+/// How the committed paths were given, so they can be restored to
+/// the same place
+fn roots(options: &zerostash_files::store::Options) -> Vec<RootSpec> {
+    options
+        .sources()
+        .iter()
+        .filter_map(|source| match RootSpec::new(source) {
+            Ok(root) => Some(root),
+            Err(error) => {
+                warn!(%error, path = ?source.path, "failed to record the committed path");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Identifies the last commit for the files cache
+fn last_commit(stash: &Stash) -> String {
+    stash
+        .commit_list()
+        .last()
+        .map(|commit| format!("{:?}", commit.id))
+        .unwrap_or_default()
+}
+
+/// How much of the scanned data was new, in total and for each
+/// committed path
+fn print_added(added: &Added) {
+    let row = |name: &str, added: &Added| {
+        println!(
+            "{:<28}{:>12}{:>12}{:>12}{:>12}",
+            name,
+            format_size(added.logical, BINARY),
+            format_size(added.physical, BINARY),
+            added.new_chunks,
+            added.reused_chunks
+        )
+    };
+
+    println!(
+        "{:<28}{:>12}{:>12}{:>12}{:>12}",
+        "path", "scanned", "written", "new chunks", "reused"
+    );
+    if added.sources.len() > 1 {
+        for (path, source) in added.sources.iter() {
+            row(&path.to_string_lossy(), source);
+        }
+    }
+    row("total", added);
+}
+
+fn print_timings(committed: &Committed, total: Duration) {
+    let timings = &committed.timings;
+    println!(
+        "{:<28}{:>12}{:>24}",
+        "stage", "total", "per worker (min/max)"
+    );
+
+    for stage in Stage::ALL {
+        let per_worker = timings.per_worker(stage);
+        let range = match (per_worker.iter().min(), per_worker.iter().max()) {
+            (Some(min), Some(max)) if stage != Stage::Walk => format!("{min:.2?} / {max:.2?}"),
+            _ => String::new(),
+        };
+
+        println!(
+            "{:<28}{:>12}{:>24}",
+            stage.name(),
+            format!("{:.2?}", timings.total(stage)),
+            range
+        );
+    }
+
+    println!(
+        "{:<28}{:>12}",
+        "index serialize",
+        format!("{:.2?}", committed.commit_time)
+    );
+    println!(
+        "{:<28}{:>12}",
+        "flush to storage",
+        format!("{:.2?}", committed.sync_time)
+    );
+    println!("{:<28}{:>12}", "wall clock", format!("{total:.2?}"));
 }
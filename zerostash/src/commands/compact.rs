@@ -0,0 +1,182 @@
+//! `compact` subcommand
+
+use crate::{
+    commit_message::CommitMessage,
+    migration::{migration, reload},
+    prelude::*,
+};
+use infinitree::backends::Backend;
+use std::{env, fs, num::NonZeroUsize, path::PathBuf, process, sync::Arc};
+use zerostash_files::{
+    compact::{dangling, publish, ReadLog, Replay},
+    list::Unlisted,
+    spool::Spool,
+};
+
+#[derive(Command, Debug)]
+pub struct Compact {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Number of latest commits to keep. Older commits are dropped,
+    /// together with the removal markers they recorded.
+    #[clap(long, value_name = "N")]
+    keep: NonZeroUsize,
+
+    /// Empty directory to stage the new index in until it's complete
+    #[clap(long, value_name = "PATH")]
+    staging: Option<PathBuf>,
+
+    /// Show which commits would be dropped, without writing anything
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Compact {
+    /// Start the application.
+    async fn run(&self) {
+        if self.dry_run {
+            return self.dry_run();
+        }
+
+        let staging = self.staging.clone().unwrap_or_else(|| {
+            env::temp_dir().join(format!("zerostash-compact-{}", process::id()))
+        });
+        if fs::read_dir(&staging).is_ok_and(|mut d| d.next().is_some()) {
+            fail(
+                ErrorKind::Config,
+                format!("{} is not empty", staging.display()),
+            );
+        }
+
+        let mut read = None;
+        let rewrite = self
+            .stash
+            .parse_stash()
+            .open_for_rewrite(self.stash.key(), |backend| {
+                let log: Arc<dyn Backend> = read.insert(ReadLog::new(backend.clone())).clone();
+                let spool: Arc<dyn Backend> = Spool::new(&staging, Unlisted::new(backend))?;
+                Ok((log, spool))
+            })
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let read = read.expect("the stash was opened");
+        let (mut src, dst) = (rewrite.stash, rewrite.rewritten);
+
+        let commits = src
+            .commit_list()
+            .iter()
+            .map(|c| (c.id, c.metadata.time))
+            .collect::<Vec<_>>();
+
+        let skip = commits.len().saturating_sub(self.keep.get());
+        if skip == 0 {
+            println!("The stash has {} commits, nothing to drop", commits.len());
+            _ = fs::remove_dir(&staging);
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        src.load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut src);
+        let messages = src
+            .commit_list()
+            .iter()
+            .map(|c| {
+                CommitMessage::load(&src, &c.metadata)
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+            })
+            .collect::<Vec<_>>();
+        let kept = commits[skip..]
+            .iter()
+            .map(|(id, ..)| *id)
+            .collect::<Vec<_>>();
+        let dangling = dangling(&src, &kept).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        if !dangling.is_empty() {
+            _ = fs::remove_dir_all(&staging);
+            fail(
+                ErrorKind::Config,
+                format!(
+                    "{} would point to a dropped commit. Remove them, or keep more commits",
+                    dangling.join(", ")
+                ),
+            );
+        }
+
+        let mut replay = Replay::default();
+        let replayed = commits[skip..].iter().zip(messages[skip..].iter().cloned());
+        for (n, ((id, time), mut message)) in replayed.enumerate() {
+            replay.apply(&mut src, &dst, *id, reload);
+
+            // the commit is stored with the time it's replayed at
+            message.set_previous(&dst);
+            message.set_original_time(*time);
+            if n == 0 {
+                message.set_compacted(skip);
+            }
+
+            let message = message
+                .render(&dst)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            dst.commit(message)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            dst.backend()
+                .sync()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            replay.committed(*id, &dst);
+
+            println!("Replayed commit {} of {} ({id:?})", n + 1, kept.len());
+        }
+
+        // a commit made since the stash was opened would be lost
+        let stored = rewrite
+            .stored_commits()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        if stored.iter().ne(commits.iter().map(|(id, ..)| id)) {
+            _ = fs::remove_dir_all(&staging);
+            fail(
+                ErrorKind::Interrupted,
+                "the stash was changed during compaction, nothing was written",
+            );
+        }
+
+        let old = replay.index_objects(read.objects());
+        let published = publish(&staging, src.backend().as_ref(), &old)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        println!(
+            "Dropped {skip} commits, wrote {} and deleted {} index objects",
+            published.objects, published.deleted
+        );
+    }
+}
+
+impl Compact {
+    fn dry_run(&self) {
+        let mut stash = self.stash.open();
+        let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let skip = commits.len().saturating_sub(self.keep.get());
+        for id in commits[..skip].iter() {
+            println!("Would drop commit {id:?}");
+        }
+        if skip == 0 {
+            println!("The stash has {} commits, nothing to drop", commits.len());
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+        let dangling =
+            dangling(&stash, &commits[skip..]).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        for name in dangling.iter() {
+            println!("{name} would point to a dropped commit");
+        }
+
+        println!(
+            "Would drop {skip} commits, and keep {}",
+            commits.len() - skip
+        );
+    }
+}
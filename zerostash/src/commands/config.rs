@@ -0,0 +1,113 @@
+//! `config` subcommands
+
+use crate::{config::encrypted, prelude::*};
+use abscissa_core::Config;
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Parser)]
+pub enum ConfigCmd {
+    /// Encrypt the configuration file with a passphrase
+    Encrypt(Encrypt),
+
+    /// Replace an encrypted configuration file with its plain text
+    Decrypt(Decrypt),
+}
+
+#[async_trait]
+impl AsyncRunnable for ConfigCmd {
+    async fn run(&self) {
+        use ConfigCmd::*;
+        match self {
+            Encrypt(c) => c.run().await,
+            Decrypt(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Encrypt {
+    /// Configuration file. Defaults to the standard location.
+    path: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Encrypt {
+    /// Start the application.
+    async fn run(&self) {
+        let path = self.path.clone().unwrap_or_else(ZerostashConfig::path);
+        let contents = fs::read(&path).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        if encrypted::is_encrypted(&contents) {
+            fail(
+                ErrorKind::NothingToDo,
+                format!("{} is already encrypted", path.display()),
+            );
+        }
+
+        // don't lock users out with a configuration that can't be loaded
+        let toml = String::from_utf8(contents).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        if let Err(e) = ZerostashConfig::load_toml(&toml) {
+            fail(ErrorKind::Config, format!("invalid configuration: {e}"));
+        }
+
+        let encrypted = encrypted::passphrase(true)
+            .and_then(|pw| encrypted::encrypt(toml.as_bytes(), &pw))
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+
+        replace_private(&path, &encrypted);
+        println!("Encrypted {}", path.display());
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Decrypt {
+    /// Configuration file. Defaults to the standard location.
+    path: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Decrypt {
+    /// Start the application.
+    async fn run(&self) {
+        let path = self.path.clone().unwrap_or_else(ZerostashConfig::path);
+        let contents = fs::read(&path).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let plaintext = encrypted::passphrase(false)
+            .and_then(|pw| encrypted::decrypt(&contents, &pw))
+            .unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+
+        replace_private(&path, &plaintext);
+        println!("Decrypted {}", path.display());
+    }
+}
+
+/// Atomically replace the file at `path` with one that's only
+/// accessible by the current user.
+fn replace_private(path: &Path, contents: &[u8]) {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+
+    if let Err(e) = result {
+        _ = fs::remove_file(&tmp);
+        fail(ErrorKind::Io, e);
+    }
+}
@@ -0,0 +1,119 @@
+//! `diff` subcommand
+
+use super::{manifest::ManifestEntry, rollback::Generation};
+use crate::{migration::reload, prelude::*};
+use std::io::{self, BufWriter};
+use zerostash_files::diff::{diff, DiffEntry, Kind, State};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Format {
+    /// A line per change, like `git diff --name-status`
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Command, Debug)]
+pub struct Diff {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// The old commit: its ID, or its number in `0s log`, starting from 1
+    #[clap(value_name = "FROM")]
+    from: Generation,
+
+    /// The new commit. The latest commit if omitted.
+    #[clap(value_name = "TO")]
+    to: Option<Generation>,
+
+    /// Format of the output
+    #[clap(long, value_enum, default_value_t)]
+    format: Format,
+}
+
+#[derive(serde::Serialize)]
+struct Change<'a> {
+    path: &'a str,
+    kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_path: Option<&'a str>,
+    old: Option<ManifestEntry<'a>>,
+    new: Option<ManifestEntry<'a>>,
+}
+
+impl<'a> Change<'a> {
+    fn new(change: &'a DiffEntry) -> Self {
+        let old_path = change.old_path.as_deref().unwrap_or(&change.path);
+
+        Self {
+            path: &change.path,
+            kind: change.kind,
+            old_path: change.old_path.as_deref(),
+            old: change
+                .old
+                .as_deref()
+                .map(|e| ManifestEntry::new(old_path, e)),
+            new: change
+                .new
+                .as_deref()
+                .map(|e| ManifestEntry::new(&change.path, e)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncRunnable for Diff {
+    /// Start the application.
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let from = self.from.resolve(&commits);
+        let to = match self.to {
+            Some(to) => to.resolve(&commits),
+            None => *commits
+                .last()
+                .unwrap_or_else(|| fail(ErrorKind::NothingToDo, "the stash has no commits")),
+        };
+
+        let mut state_at = |commit| {
+            State::at(&mut stash, commit, reload).unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+        };
+        let old = state_at(from);
+        let new = state_at(to);
+
+        let mut output = BufWriter::new(io::stdout().lock());
+        if let Err(e) = self.write(&old, &new, &mut output) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                fail(ErrorKind::Io, e);
+            }
+        }
+    }
+}
+
+impl Diff {
+    fn write(&self, old: &State, new: &State, output: &mut impl Write) -> io::Result<()> {
+        for change in diff(old, new) {
+            match self.format {
+                Format::Text => match change.kind {
+                    Kind::Added => writeln!(output, "A\t{}", change.path)?,
+                    Kind::Removed => writeln!(output, "D\t{}", change.path)?,
+                    Kind::Modified => writeln!(output, "M\t{}", change.path)?,
+                    Kind::Renamed => writeln!(
+                        output,
+                        "R\t{}\t{}",
+                        change.old_path.as_deref().unwrap_or_default(),
+                        change.path
+                    )?,
+                },
+                Format::Json => {
+                    serde_json::to_writer(&mut *output, &Change::new(&change))?;
+                    output.write_all(b"\n")?;
+                }
+            }
+        }
+
+        output.flush()
+    }
+}
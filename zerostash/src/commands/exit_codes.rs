@@ -0,0 +1,50 @@
+//! `exit-codes` subcommand
+//!
+//! 0s exits with 0 on success, 1 on any error without a more specific
+//! code, and 2 if the command line arguments are invalid. The other
+//! codes are listed in [`EXIT_CODES`], so scripts can tell apart eg. a
+//! wrong key from an unreachable backend.
+//!
+//! The same table is shown by `0s help exit-codes`.
+
+use crate::{error::EXIT_CODES, prelude::*};
+
+#[derive(Command, Debug)]
+#[clap(after_long_help = format!("Exit codes:\n{}", table()))]
+pub struct ExitCodes {}
+
+#[async_trait]
+impl AsyncRunnable for ExitCodes {
+    /// Start the application.
+    async fn run(&self) {
+        print!("{}", table());
+    }
+}
+
+/// Every exit code and what it means, a line each
+fn table() -> String {
+    let mut table = format!(
+        "{:>4}  success\n{:>4}  error\n{:>4}  invalid arguments\n",
+        0, 1, 2
+    );
+
+    for (kind, code) in EXIT_CODES.iter() {
+        table.push_str(&format!("{code:>4}  {kind}\n"));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn help_lists_every_code() {
+        use super::ExitCodes;
+        use crate::error::EXIT_CODES;
+        use clap::CommandFactory;
+
+        let help = ExitCodes::command().render_long_help().to_string();
+        for (kind, code) in EXIT_CODES.iter() {
+            assert!(help.contains(&format!("{code:>4}  {kind}")), "{help}");
+        }
+    }
+}
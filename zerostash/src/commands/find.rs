@@ -0,0 +1,88 @@
+//! `find` subcommand
+
+use crate::{commit_message::parse_annotation, prelude::*};
+use abscissa_core::terminal::stdout;
+use humansize::{format_size, BINARY};
+use std::collections::HashMap;
+
+const UNKNOWN_TYPE: &str = "unknown";
+
+#[derive(Command, Debug)]
+pub struct Find {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Only show files with a matching content type, eg. `image/*`.
+    ///
+    /// Content types are only available for files that were committed with `--sniff-types`.
+    #[clap(long = "type", value_name = "GLOB")]
+    content_type: Option<glob::Pattern>,
+
+    /// Only show files with the `key=value` annotation from a file hook. May be repeated.
+    #[clap(long = "annotation", value_parser = parse_annotation)]
+    annotations: Vec<(String, String)>,
+
+    /// Show the number and total size of matching files by content type
+    #[clap(long)]
+    by_type: bool,
+
+    #[clap(flatten)]
+    options: zerostash_files::restore::Options,
+}
+
+#[async_trait]
+impl AsyncRunnable for Find {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let mut stdout = stdout().lock();
+        let mut by_type = HashMap::<String, (usize, u64)>::new();
+
+        let matching = self.options.list(&stash).filter(|(_, entry)| {
+            let type_matches = match (&self.content_type, &entry.content_type) {
+                (None, _) => true,
+                (Some(pattern), Some(ct)) => pattern.matches(ct),
+                (Some(_), None) => false,
+            };
+
+            let annotations = entry.annotations();
+            type_matches
+                && self
+                    .annotations
+                    .iter()
+                    .all(|(k, v)| annotations.get(k) == Some(v))
+        });
+
+        for (path, entry) in matching {
+            if self.by_type {
+                let content_type = entry.content_type.as_deref().unwrap_or(UNKNOWN_TYPE);
+                let stats = by_type.entry(content_type.to_string()).or_default();
+                stats.0 += 1;
+                stats.1 += entry.size;
+            } else if writeln!(stdout, "{path}").is_err() {
+                return;
+            }
+        }
+
+        if self.by_type {
+            let mut by_type = by_type.into_iter().collect::<Vec<_>>();
+            by_type.sort_unstable_by(|a, b| b.1 .1.cmp(&a.1 .1));
+
+            for (content_type, (count, size)) in by_type {
+                if writeln!(
+                    stdout,
+                    "{:<8}\t{count}\t{content_type}",
+                    format_size(size, BINARY)
+                )
+                .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
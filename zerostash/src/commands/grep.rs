@@ -0,0 +1,105 @@
+//! `grep` subcommand
+
+use crate::prelude::*;
+use abscissa_core::terminal::stdout;
+use regex::bytes::RegexBuilder;
+use std::ops::ControlFlow;
+use tracing::warn;
+use zerostash_files::{grep::lines, restore};
+
+#[derive(Command, Debug)]
+pub struct Grep {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Regular expression to search for
+    pattern: String,
+
+    /// Only search files that match the glob. May be repeated.
+    #[clap(long = "glob", value_name = "GLOB")]
+    globs: Vec<String>,
+
+    /// Match regardless of case
+    #[clap(short, long)]
+    ignore_case: bool,
+
+    /// Only print the paths of matching files
+    #[clap(short = 'l', long)]
+    files_with_matches: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Grep {
+    /// Start the application.
+    async fn run(&self) {
+        let regex = RegexBuilder::new(&self.pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let options = restore::Options {
+            globs: self.globs.clone(),
+            ..Default::default()
+        };
+
+        let mut reader = stash
+            .storage_reader()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let mut stdout = stdout().lock();
+        let mut matched = false;
+        let mut unreadable = 0;
+        let mut closed = false;
+
+        for (path, entry) in options.list(&stash) {
+            let result = lines(&mut reader, &stash.index().dictionaries, &entry, |line| {
+                if !regex.is_match(line.text) {
+                    return ControlFlow::Continue(());
+                }
+                matched = true;
+
+                let written = if self.files_with_matches {
+                    writeln!(stdout, "{path}")
+                } else if line.text.contains(&0) {
+                    writeln!(stdout, "{path}: binary file matches")
+                } else {
+                    writeln!(
+                        stdout,
+                        "{path}:{}:{}",
+                        line.number,
+                        String::from_utf8_lossy(line.text)
+                    )
+                };
+
+                closed = written.is_err();
+                if closed || self.files_with_matches || line.text.contains(&0) {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            });
+
+            if closed {
+                return;
+            }
+            if let Err(error) = result {
+                warn!(%error, %path, "failed to read file");
+                unreadable += 1;
+            }
+        }
+
+        if unreadable > 0 {
+            fail(
+                ErrorKind::Verification,
+                format!("{unreadable} files could not be read"),
+            );
+        }
+        if !matched {
+            exit_with(ErrorKind::NothingToDo);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! `index` subcommands
+
+use crate::{commit_message::chained, prelude::*};
+use clap::Parser;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter},
+    path::PathBuf,
+};
+use zerostash_files::chunk_index::{verify_chunk, ChunkRecord};
+
+#[derive(Debug, Parser)]
+pub enum Index {
+    /// Register chunks that already exist in the backend
+    ImportChunks(ImportChunks),
+
+    /// Write the chunk index as JSON lines
+    ExportChunks(ExportChunks),
+}
+
+#[async_trait]
+impl AsyncRunnable for Index {
+    async fn run(&self) {
+        use Index::*;
+        match self {
+            ImportChunks(c) => c.run().await,
+            ExportChunks(c) => c.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ImportChunks {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// File with one JSON chunk record per line. Reads stdin if omitted.
+    #[clap(short = 'f', long = "file")]
+    file: Option<PathBuf>,
+
+    /// Don't read the chunks from the backend before importing them.
+    ///
+    /// Every new chunk is checked by default: its object has to exist, and
+    /// the chunk has to decrypt to the digest in the record. Records that
+    /// aren't checked are trusted as they are.
+    #[clap(long = "no-verify")]
+    no_verify: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for ImportChunks {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().chunks())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let input: Box<dyn BufRead> = match self.file {
+            Some(ref path) => Box::new(BufReader::new(
+                fs::File::open(path).unwrap_or_else(|e| fail(ErrorKind::Io, e)),
+            )),
+            None => Box::new(io::stdin().lock()),
+        };
+
+        let mut reader = stash
+            .storage_reader()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let mut hasher = zerostash_files::digest_key::hasher(&stash)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let mut buf = vec![];
+
+        let (mut imported, mut skipped) = (0, 0);
+        for (lineno, line) in input.lines().enumerate() {
+            let line =
+                line.unwrap_or_else(|e| fail(ErrorKind::Io, format!("line {}: {e}", lineno + 1)));
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: ChunkRecord = serde_json::from_str(&line)
+                .unwrap_or_else(|e| fail(ErrorKind::Config, format!("line {}: {e}", lineno + 1)));
+
+            if stash.index().chunks.contains(&record.digest) {
+                skipped += 1;
+                continue;
+            }
+
+            if !self.no_verify {
+                if let Err(e) = verify_chunk(
+                    &mut reader,
+                    &stash.index().dictionaries,
+                    &mut hasher,
+                    &record,
+                    &mut buf,
+                ) {
+                    fail(ErrorKind::Verification, format!("line {}: {e}", lineno + 1));
+                }
+            }
+
+            if stash.index().import_chunk(record) {
+                imported += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+
+        println!("Imported {imported} chunks, {skipped} already known");
+
+        if imported > 0 {
+            stash
+                .commit(
+                    chained(&stash, format!("Imported {imported} chunks"))
+                        .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+                )
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            stash
+                .backend()
+                .sync()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ExportChunks {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Output file. Writes to stdout if omitted.
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for ExportChunks {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().chunks())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let mut output: Box<dyn Write> = match self.output {
+            Some(ref path) => Box::new(BufWriter::new(
+                fs::File::create(path).unwrap_or_else(|e| fail(ErrorKind::Io, e)),
+            )),
+            None => Box::new(BufWriter::new(io::stdout().lock())),
+        };
+
+        // stop at the first failed write, eg. to a closed pipe
+        let mut result = Ok(());
+        stash.index().export_chunks(|record| {
+            if result.is_ok() {
+                result = serde_json::to_writer(&mut output, &record)
+                    .map_err(io::Error::from)
+                    .and_then(|_| writeln!(output));
+            }
+        });
+
+        result
+            .and_then(|_| output.flush())
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+    }
+}
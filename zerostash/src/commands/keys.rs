@@ -1,9 +1,10 @@
-use crate::config::Key;
-use crate::keygen::{GenKeyCmd, Generate, GenerateKey};
+use crate::config::{Backend, Key, KeyToSource, Stash, SymmetricKey};
+use crate::keygen::{GenKeyCmd, Generate, GenerateKey, WriteToFile};
 use crate::prelude::*;
+use crate::recovery;
 use anyhow::{anyhow, bail};
 use clap::ArgGroup;
-use std::path::PathBuf;
+use std::{io::BufRead, path::PathBuf};
 
 #[derive(Command, Debug)]
 pub struct Keys {
@@ -26,6 +27,12 @@ pub enum KeyCommand {
     /// Change the keys for an existing stash
     #[clap(alias = "ch")]
     Change(Change),
+    /// Print a recovery code for the key of a stash
+    ExportRecovery(ExportRecovery),
+    /// Write a keyfile from a recovery code
+    ImportRecovery(ImportRecovery),
+    /// Check that the key opens the stash, without changing anything
+    Test(Test),
 }
 
 #[async_trait]
@@ -35,6 +42,9 @@ impl AsyncRunnable for KeyCommand {
         match self {
             Generate(g) => g.run().await,
             Change(c) => c.run().await,
+            ExportRecovery(e) => e.run().await,
+            ImportRecovery(i) => i.run().await,
+            Test(t) => t.run().await,
         }
     }
 }
@@ -56,11 +66,13 @@ impl AsyncRunnable for Change {
         let key = self
             .cmd
             .key(old_key, &stash_cfg.alias)
-            .unwrap_or_else(|_| fatal_error("Invalid new key"));
+            .unwrap_or_else(|_| fail(ErrorKind::Config, "Invalid new key"));
 
-        let stash = stash_cfg.try_open(Some(key)).unwrap();
-        if stash.reseal().is_err() {
-            fatal_error("Failed to change key");
+        let stash = stash_cfg
+            .try_open(Some(key))
+            .unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+        if let Err(e) = stash.reseal() {
+            fail(ErrorKind::Backend, format!("Failed to change key: {e}"));
         }
     }
 }
@@ -145,3 +157,306 @@ impl ChangeTo {
         unreachable!()
     }
 }
+
+#[derive(Command, Debug)]
+pub struct ExportRecovery {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Split the code into this many parts. All of them are needed to
+    /// recover the key, so they can be stored in different places.
+    #[clap(long, value_name = "N", default_value_t = 1,
+           value_parser = clap::value_parser!(u8).range(1..=16))]
+    split: u8,
+
+    /// Also print every part as a QR code
+    #[clap(long)]
+    qr: bool,
+
+    /// Don't ask for confirmation
+    #[clap(long)]
+    yes: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for ExportRecovery {
+    async fn run(&self) {
+        let stash_cfg = self.stash.parse_stash();
+        let key = self.stash.key().unwrap_or_else(|| stash_cfg.key.clone());
+        let key = recoverable(key, &stash_cfg.alias).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+
+        // a code that doesn't open the stash is worse than no code
+        if let Err(e) = stash_cfg.try_open(Some(key.clone())) {
+            fail(ErrorKind::Auth, e);
+        }
+
+        eprintln!(
+            "WARNING: the recovery code contains the credentials of `{}` in plain text.\n\
+             Anyone who has it can read and change everything in the stash.\n\
+             Print it or write it down, and don't keep it on this machine.\n",
+            stash_cfg.alias
+        );
+        if !self.yes {
+            confirm();
+        }
+
+        let secret = toml::to_string(&key).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        let parts = recovery::split(secret.as_bytes(), self.split as usize);
+
+        for (i, part) in parts.iter().enumerate() {
+            let words = recovery::encode(part);
+
+            if parts.len() > 1 {
+                println!("Part {} of {}:\n", i + 1, parts.len());
+            }
+            println!("{}\n", recovery::format(&words));
+
+            if self.qr {
+                let code = recovery::qr_code(&words).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+                println!("{code}\n");
+            }
+        }
+    }
+}
+
+/// Fill in everything that's needed to open the stash without the
+/// configuration, eg. passwords in the Keychain.
+fn recoverable(key: Key, stash: &str) -> anyhow::Result<Key> {
+    let complete = |key: SymmetricKey| -> anyhow::Result<SymmetricKey> {
+        let (user, password) = key.interactive_credentials(stash)?;
+        Ok(SymmetricKey {
+            user: Some(user),
+            password: Some(password),
+            keychain: false,
+        })
+    };
+
+    Ok(match key.resolve()? {
+        Key::Interactive => Key::Userpass(complete(SymmetricKey::default())?),
+        Key::Userpass(k) => Key::Userpass(complete(k)?),
+        Key::SplitKeyStorage(mut k) => {
+            k.credentials = complete(k.credentials)?;
+            Key::SplitKeyStorage(k)
+        }
+        Key::Yubikey(_) => bail!("keys that need a Yubikey can't be recovered from a code"),
+        Key::KeyFile { .. } | Key::ChangeTo { .. } => bail!("unsupported key configuration"),
+    })
+}
+
+#[derive(Command, Debug)]
+pub struct ImportRecovery {
+    /// Write the recovered key to this keyfile
+    #[clap(short, long, value_name = "PATH")]
+    output: PathBuf,
+
+    /// Number of parts the code was split into
+    #[clap(long, value_name = "N", default_value_t = 1)]
+    parts: u8,
+
+    /// Check that the key opens this stash. The backend path or url
+    /// also works if the configuration is lost.
+    #[clap(long, value_name = "STASH")]
+    check: Option<String>,
+
+    /// Don't ask for confirmation
+    #[clap(long)]
+    yes: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for ImportRecovery {
+    async fn run(&self) {
+        if self.output.exists() {
+            fail(
+                ErrorKind::Config,
+                format!(
+                    "{} already exists, refusing to overwrite it",
+                    self.output.display()
+                ),
+            );
+        }
+
+        eprintln!(
+            "WARNING: the recovered key will be written to {} in plain text.\n\
+             Keep the file safe, or change the key of the stash once you have access.\n",
+            self.output.display()
+        );
+        if !self.yes {
+            confirm();
+        }
+
+        let mut parts = vec![];
+        for i in 1..=self.parts {
+            if self.parts > 1 {
+                println!("Part {i} of {}:", self.parts);
+            }
+            println!("Enter the recovery code, followed by an empty line:");
+
+            let code = read_paragraph().unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            parts.push(recovery::decode(&code).unwrap_or_else(|e| fail(ErrorKind::Config, e)));
+        }
+
+        let secret = recovery::combine(&parts).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        let key = String::from_utf8(secret)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(toml::from_str::<Key>(&s)?))
+            .unwrap_or_else(|_| {
+                fail(
+                    ErrorKind::Config,
+                    "the parts are not from the same recovery code",
+                )
+            });
+
+        if let Some(ref stash) = self.check {
+            let stash: Stash = stash.parse().unwrap_or_else(|e| fail(ErrorKind::Config, e));
+            if let Err(e) = stash.try_open(Some(key.clone())) {
+                fail(ErrorKind::Auth, e);
+            }
+            println!("The recovered key opens {}", stash.alias);
+        }
+
+        WriteToFile {
+            obj: key,
+            file: self.output.clone(),
+        }
+        .write();
+
+        println!(
+            "\nUse the key in the configuration of the stash:\n\n\
+             key = {{ source = \"file\", path = {:?} }}",
+            self.output
+        );
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct Test {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for Test {
+    /// Start the application.
+    async fn run(&self) {
+        let stash_cfg = self.stash.parse_stash();
+        let key = self.stash.key().unwrap_or_else(|| stash_cfg.key.clone());
+
+        let keyfile = match key {
+            Key::KeyFile { ref path } => Some(path.display().to_string()),
+            _ => None,
+        };
+        let key = key.resolve().unwrap_or_else(|e| {
+            fail(
+                ErrorKind::Config,
+                format!("the keyfile can't be read: {e:#}"),
+            )
+        });
+
+        let backend = stash_cfg
+            .backend
+            .to_infinitree()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        // a local stash can be checked without the key
+        if let Backend::Filesystem { .. } = stash_cfg.backend {
+            match stash_cfg.backend.list_objects().map(|mut l| l.next()) {
+                Ok(Some(Ok(_))) => {}
+                Ok(None) => fail(
+                    ErrorKind::Config,
+                    format!("there is no stash at {}", stash_cfg.backend),
+                ),
+                Ok(Some(Err(e))) | Err(e) => fail(ErrorKind::Backend, e),
+            }
+        }
+
+        let yubikey = matches!(key, Key::Yubikey(_));
+        let keysource = key.to_keysource(&stash_cfg.alias).unwrap_or_else(|e| {
+            let message = match yubikey {
+                true => format!("no usable Yubikey: {e:#}"),
+                false => format!("{e:#}"),
+            };
+            fail(ErrorKind::Auth, message)
+        });
+
+        // only the root object is read, the index stays where it is
+        if let Err(e) = crate::prelude::Stash::open(backend, keysource) {
+            let e = anyhow::Error::from(e);
+            if !is_missing_root(&e) {
+                fail(ErrorKind::Backend, e);
+            }
+
+            let message = match (keyfile, yubikey) {
+                (Some(path), _) => format!("the keyfile {path} doesn't open the stash"),
+                (None, true) => "the Yubikey or the password doesn't open the stash".into(),
+                (None, false) => "wrong username or password".into(),
+            };
+            fail(ErrorKind::Auth, message);
+        }
+
+        println!("The key opens {}", stash_cfg.alias);
+    }
+}
+
+/// The root object is found by the key, so with a wrong key, it
+/// doesn't exist, or doesn't decrypt
+fn is_missing_root(error: &anyhow::Error) -> bool {
+    use infinitree::backends::BackendError;
+    use std::io;
+
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<BackendError>(),
+            Some(BackendError::NotFound { .. })
+        ) || cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+            || cause.is::<infinitree::crypto::CryptoError>()
+    })
+}
+
+/// Make the user type `yes` before going on
+fn confirm() {
+    let reply = rprompt::prompt_reply("Type `yes` to continue: ").unwrap_or_default();
+    if reply.trim() != "yes" {
+        fail(ErrorKind::Interrupted, "aborted");
+    }
+}
+
+/// Read lines from the standard input until an empty one
+fn read_paragraph() -> std::io::Result<String> {
+    let mut text = String::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() && !text.is_empty() {
+            break;
+        }
+        text.push_str(&line);
+        text.push(' ');
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn missing_root_by_error_type() {
+        use super::is_missing_root;
+        use infinitree::backends::BackendError;
+        use std::io;
+
+        let not_found = || io::Error::from(io::ErrorKind::NotFound);
+        assert!(is_missing_root(
+            &anyhow::Error::from(not_found()).context("failed to open stash")
+        ));
+        assert!(is_missing_root(&anyhow::Error::from(BackendError::from(
+            not_found()
+        ))));
+
+        // only the type of the error decides, and not the message
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "not found");
+        assert!(!is_missing_root(&anyhow::Error::from(denied)));
+        assert!(!is_missing_root(&anyhow::anyhow!("object not found")));
+    }
+}
@@ -1,12 +1,30 @@
 //! `log` subcommand
 
-use crate::prelude::*;
+use crate::{
+    commit_message::{parse_annotation, verify_chain, ChainError, CommitMessage},
+    prelude::*,
+};
 use chrono::{DateTime, Utc};
+use humansize::{format_size, BINARY};
 
 #[derive(Command, Debug)]
 pub struct Log {
     #[clap(flatten)]
     stash: StashArgs,
+
+    /// Only show commits with the `key=value` annotation. May be repeated.
+    #[clap(long = "filter", value_parser = parse_annotation)]
+    filters: Vec<(String, String)>,
+
+    /// Show the size of new and changed files, and the size they took
+    /// in storage after deduplication and compression
+    #[clap(short, long)]
+    sizes: bool,
+
+    /// Check that no commits were removed or changed since they were
+    /// made, instead of showing the log
+    #[clap(long, conflicts_with_all = ["filters", "sizes"])]
+    verify_chain: bool,
 }
 
 #[async_trait]
@@ -14,23 +32,56 @@ impl AsyncRunnable for Log {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
+        stash
+            .load(stash.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        if self.verify_chain {
+            return Self::verify_chain(&stash);
+        }
+
         let mut stdout = std::io::stdout().lock();
 
         for commit in stash.commit_list().iter() {
-            let time: DateTime<Utc> = commit.metadata.time.into();
+            let message = CommitMessage::load(&stash, &commit.metadata)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            if !message.matches(&self.filters) {
+                continue;
+            }
+
+            let time: DateTime<Utc> = message.time(commit.metadata.time).into();
             let local_time = time.with_timezone(&chrono::Local);
             let formatted_time = local_time.format("%Y %b %e %H:%M:%S").to_string();
 
+            let sizes = match message.added() {
+                _ if !self.sizes => String::new(),
+                Some(added) => format!(
+                    "\t+{} ({} stored)",
+                    format_size(added.logical, BINARY),
+                    format_size(added.physical, BINARY)
+                ),
+                None => "\t-".to_string(),
+            };
+
+            let annotations = message
+                .user_annotations()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>();
+
             if writeln!(
                 stdout,
-                "{:?}\t{}\t{}",
+                "{:?}\t{}{}\t{}{}",
                 commit.id,
                 formatted_time,
-                commit
-                    .metadata
+                sizes,
+                message
                     .message
                     .as_ref()
-                    .unwrap_or(&"No commit message".to_string())
+                    .unwrap_or(&"No commit message".to_string()),
+                if annotations.is_empty() {
+                    String::new()
+                } else {
+                    format!("\t[{}]", annotations.join(", "))
+                }
             )
             .is_err()
             {
@@ -39,3 +90,43 @@ impl AsyncRunnable for Log {
         }
     }
 }
+
+impl Log {
+    fn verify_chain(stash: &Stash) {
+        let commits = stash.commit_list();
+        let report = verify_chain(commits.iter().map(|c| {
+            let message = CommitMessage::load(stash, &c.metadata)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            (&c.id, &c.metadata, message)
+        }));
+
+        for error in report.errors.iter() {
+            match error {
+                ChainError::Truncated(id) => {
+                    println!("{id:?}: links to a commit that is not in the stash")
+                }
+                ChainError::Broken(id) => {
+                    println!("{id:?}: does not link to the commit before it")
+                }
+            }
+        }
+        if !report.unlinked.is_empty() {
+            println!("{} commits were made without a link", report.unlinked.len());
+        }
+
+        if !report.errors.is_empty() {
+            fail(
+                ErrorKind::Verification,
+                format!(
+                    "the chain of commits is broken in {} places",
+                    report.errors.len()
+                ),
+            );
+        }
+
+        match report.head {
+            Some(head) => println!("Chain verified, head: {head}"),
+            None => println!("No commits in the stash"),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 //! `ls` subcommand
 
-use crate::prelude::*;
+use crate::{commit_message::stash_roots, prelude::*};
 use abscissa_core::terminal::{stderr, stdout};
 use chrono::{DateTime, Utc};
 use humansize::{format_size, BINARY};
@@ -30,7 +30,23 @@ impl AsyncRunnable for Ls {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
-        stash.load(stash.index().tree()).unwrap();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .load(stash.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        for root in stash_roots(&stash).unwrap_or_else(|e| fail(ErrorKind::Backend, e)) {
+            _ = writeln!(
+                stderr().lock(),
+                "Root: /{} from {} ({})",
+                root.stored,
+                root.original.display(),
+                root.kind
+            );
+        }
+
         let printer = match self.list {
             false => self.print_simple(),
             true => self.print_list(),
@@ -107,6 +123,19 @@ impl Ls {
                 },
             )?;
 
+            let annotations = entry.annotations();
+            if !annotations.is_empty() {
+                let annotations = annotations
+                    .iter()
+                    .map(|(k, v)| format!("{k}={v}"))
+                    .collect::<Vec<_>>();
+                print(
+                    stdout,
+                    ColorSpec::new(),
+                    format!("[{}]", annotations.join(", ")),
+                )?;
+            }
+
             writeln!(stdout)
         })
     }
@@ -0,0 +1,120 @@
+//! `manifest` subcommand
+
+use crate::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{
+    fs,
+    io::{self, BufWriter},
+    path::PathBuf,
+};
+use zerostash_files::{restore, Entry, FileType};
+
+#[derive(Command, Debug)]
+pub struct Manifest {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Output file. Writes to stdout if omitted.
+    #[clap(short = 'o', long = "output")]
+    output: Option<PathBuf>,
+
+    /// Write one JSON object per line instead of a single array
+    #[clap(long)]
+    lines: bool,
+}
+
+#[derive(serde::Serialize)]
+pub(super) struct ManifestEntry<'a> {
+    path: &'a str,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gid: Option<u32>,
+    mtime: String,
+    chunks: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'a str>,
+}
+
+impl<'a> ManifestEntry<'a> {
+    pub(super) fn new(path: &'a str, entry: &'a Entry) -> Self {
+        let (file_type, target) = match entry.file_type {
+            FileType::File => ("file", None),
+            FileType::Directory => ("directory", None),
+            FileType::Symlink(ref target) => ("symlink", Some(target.to_string_lossy().into())),
+        };
+        let mtime: DateTime<Utc> = entry.into();
+
+        Self {
+            path,
+            file_type,
+            target,
+            size: entry.size,
+            mode: entry.unix_perm.map(|m| format!("{m:o}")),
+            uid: entry.unix_uid,
+            gid: entry.unix_gid,
+            mtime: mtime.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            chunks: entry.chunks.len(),
+            content_type: entry.content_type.as_deref(),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncRunnable for Manifest {
+    /// Start the application.
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let mut output: Box<dyn Write> = match self.output {
+            Some(ref path) => Box::new(BufWriter::new(
+                fs::File::create(path).unwrap_or_else(|e| fail(ErrorKind::Io, e)),
+            )),
+            None => Box::new(BufWriter::new(io::stdout().lock())),
+        };
+
+        if let Err(e) = self.write(&stash, &mut output) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                fail(ErrorKind::Io, e);
+            }
+        }
+    }
+}
+
+impl Manifest {
+    fn write(&self, stash: &Stash, output: &mut impl Write) -> io::Result<()> {
+        let (start, separator, end) = if self.lines {
+            ("", "\n", "\n")
+        } else {
+            ("[\n", ",\n", "\n]\n")
+        };
+
+        output.write_all(start.as_bytes())?;
+
+        let mut first = true;
+        for (path, entry) in restore::Options::default().list(stash) {
+            if !first {
+                output.write_all(separator.as_bytes())?;
+            }
+            first = false;
+
+            serde_json::to_writer(&mut *output, &ManifestEntry::new(&path, &entry))?;
+        }
+
+        if !first || !self.lines {
+            output.write_all(end.as_bytes())?;
+        }
+
+        output.flush()
+    }
+}
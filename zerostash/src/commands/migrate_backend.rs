@@ -0,0 +1,132 @@
+//! `migrate-backend` subcommand
+
+use crate::{config::Backend, prelude::*};
+use std::collections::HashSet;
+use zerostash_files::migrate::copy_objects;
+
+#[derive(Command, Debug)]
+pub struct MigrateBackend {
+    /// Stash alias in the configuration
+    stash: String,
+
+    /// The new backend, as a path or an `s3://` url
+    #[clap(long, value_name = "URL", required_unless_present = "finalize")]
+    to: Option<String>,
+
+    /// Keep reading objects from the old backend as a fallback, until
+    /// the migration is finished with `--finalize`
+    #[clap(long, requires = "to")]
+    keep_mirror: bool,
+
+    /// Copy anything that's still only in the old backend, and stop
+    /// using it
+    #[clap(long, conflicts_with_all = ["to", "keep_mirror"])]
+    finalize: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for MigrateBackend {
+    /// Start the application.
+    async fn run(&self) {
+        let Some(config) = APP.config().resolve_stash(&self.stash) else {
+            fail(
+                ErrorKind::Config,
+                format!("{} is not in the configuration", self.stash),
+            );
+        };
+
+        if self.finalize {
+            let Backend::Mirror { primary, fallback } = config.backend else {
+                fail(
+                    ErrorKind::Config,
+                    format!("{} is not being migrated", self.stash),
+                );
+            };
+
+            let done = primary
+                .list_objects()
+                .and_then(|list| list.collect::<Result<HashSet<_>, _>>())
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+            migrate(&fallback, &primary, |id| !done.contains(id));
+            print_config(&config.alias, &primary);
+            return;
+        }
+
+        let to = self.to.as_ref().unwrap();
+        let destination: Backend = to.parse().unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        migrate(&config.backend, &destination, |_| true);
+
+        let backend = if self.keep_mirror {
+            Backend::Mirror {
+                primary: Box::new(destination),
+                fallback: Box::new(config.backend),
+            }
+        } else {
+            destination
+        };
+
+        print_config(&config.alias, &backend);
+    }
+}
+
+fn migrate(src: &Backend, dst: &Backend, filter: impl Fn(&infinitree::object::ObjectId) -> bool) {
+    let objects = src
+        .list_objects()
+        .and_then(|list| list.collect::<Result<HashSet<_>, _>>())
+        .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+        .into_iter()
+        .filter(filter)
+        .collect::<Vec<_>>();
+
+    println!("Copying {} objects", objects.len());
+
+    let open = |backend: &Backend| {
+        backend
+            .to_infinitree()
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e))
+    };
+    let report = copy_objects(
+        open(src).as_ref(),
+        open(dst).as_ref(),
+        &objects,
+        APP.get_worker_threads(),
+    )
+    .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+    for (id, error) in report.failed.iter() {
+        println!("{id}: {error}");
+    }
+
+    println!(
+        "Copied and verified {} objects: {} errors",
+        report.copied,
+        report.failed.len()
+    );
+
+    if !report.failed.is_empty() {
+        fail(
+            ErrorKind::Partial,
+            "migration incomplete, the configuration was not changed",
+        );
+    }
+}
+
+/// The configuration file can't be updated without losing comments, so
+/// ask the user to do it instead.
+fn print_config(alias: &str, backend: &Backend) {
+    let mut stash = toml::Table::new();
+    stash.insert(
+        "backend".into(),
+        toml::Value::try_from(backend).expect("backend can be serialized"),
+    );
+
+    let mut stashes = toml::Table::new();
+    stashes.insert(alias.into(), stash.into());
+
+    let mut config = toml::Table::new();
+    config.insert("stash".into(), stashes.into());
+
+    println!("\nUpdate the backend of {alias} in your configuration to:\n");
+    println!("{config}");
+}
@@ -1,22 +1,102 @@
 //! `mount` subcommand
 
-use crate::{migration::migration, prelude::*};
+use crate::{
+    config::{MountConfig, PermissionCheck},
+    migration::migration,
+    prelude::*,
+    systemd::Operation,
+};
+use key_rotation::KeyRotation;
+use std::{num::NonZeroU32, path::PathBuf, sync::Arc};
+use zerostash_fuse::mount::{CommitHook, Options, Permissions};
+
+mod key_rotation;
+mod scrub;
 
 #[derive(Command, Debug)]
 pub struct Mount {
     #[clap(flatten)]
-    stash: StashArgs,
+    stash: Option<StashArgs>,
 
     #[clap(flatten)]
     options: zerostash_files::restore::Options,
 
-    /// The location the filesytem will be mounted on
+    /// The location the filesytem will be mounted on.
+    ///
+    /// Defaults to the mount point in the stash configuration.
     #[clap(short = 'T', long = "target")]
-    mount_point: String,
+    mount_point: Option<PathBuf>,
+
+    /// Mount all stashes that have a mount point in the configuration
+    #[clap(long)]
+    all: bool,
+
+    /// Print a systemd unit that runs `mount --all`
+    #[clap(long)]
+    systemd_unit: bool,
+
+    /// Verify this many objects per hour in the background, taking
+    /// turns between the mounted stashes
+    #[clap(long, value_name = "OBJECTS", requires = "all")]
+    scrub: Option<NonZeroU32>,
+
+    /// Run this command when scrubbing finds a damaged object.
+    ///
+    /// The stash, the object and the error are passed in
+    /// `ZEROSTASH_STASH`, `ZEROSTASH_OBJECT` and `ZEROSTASH_ERROR`.
+    #[clap(long, value_name = "COMMAND", requires = "scrub")]
+    on_scrub_failure: Option<PathBuf>,
 
     /// Mounts the filesystem read-write
     #[clap(short = 'w', long = "read-write")]
     read_write: bool,
+
+    /// Size of the in-memory cache for remote objects in MiB
+    #[clap(
+        long = "memory-cache",
+        value_name = "MiB",
+        default_value_t = zerostash_fuse::mount::DEFAULT_MEMORY_CACHE / 1024 / 1024
+    )]
+    memory_cache: usize,
+
+    /// Preload the objects of the most recently modified files after mounting.
+    ///
+    /// The optional value limits the total size of files to preload.
+    #[clap(
+        long,
+        value_name = "MiB",
+        num_args = 0..=1,
+        default_missing_value = "1024"
+    )]
+    preload: Option<usize>,
+
+    /// Show all files as owned by this uid
+    #[clap(long, value_name = "UID")]
+    uid: Option<u32>,
+
+    /// Show all files as owned by this gid
+    #[clap(long, value_name = "GID")]
+    gid: Option<u32>,
+
+    /// Allow other users to access the mount.
+    ///
+    /// Needs `user_allow_other` in `/etc/fuse.conf` when not mounting as root.
+    #[clap(long)]
+    allow_other: bool,
+
+    /// How to check access to files in the mount [default: off]
+    #[clap(long, value_enum)]
+    permissions: Option<PermissionCheck>,
+}
+
+impl From<PermissionCheck> for Permissions {
+    fn from(check: PermissionCheck) -> Self {
+        match check {
+            PermissionCheck::Off => Self::Off,
+            PermissionCheck::Kernel => Self::Kernel,
+            PermissionCheck::Owner => Self::Owner,
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -24,16 +104,207 @@ pub struct Mount {
 impl AsyncRunnable for Mount {
     /// Start the application.
     async fn run(&self) {
-        let mut stash = self.stash.open();
-        let threads = APP.get_worker_threads();
-        stash.load(stash.index().tree()).unwrap();
-        stash.load(stash.index().files()).unwrap();
-        migration(&mut stash);
+        if self.systemd_unit {
+            self.print_systemd_unit();
+            return;
+        }
+
+        if self.all {
+            if self.stash.is_some() {
+                fail(
+                    ErrorKind::Config,
+                    "a stash can't be given together with `--all`",
+                );
+            }
+
+            self.mount_all().await;
+            return;
+        }
+
+        let Some(ref args) = self.stash else {
+            fail(
+                ErrorKind::Config,
+                "specify a stash to mount, or use `--all`",
+            );
+        };
+
+        let stash_config = args.parse_stash();
+        let config = stash_config.mount.as_ref();
+        let Some(mount_point) = self
+            .mount_point
+            .clone()
+            .or_else(|| config.map(|c| c.mountpoint.clone()))
+        else {
+            fail(
+                ErrorKind::Config,
+                "no mount point given, and none is configured for the stash",
+            );
+        };
+
+        let mut options = self.fuse_options(config);
+        // an explicitly given key is not rotated
+        if args.key().is_none() {
+            follow_key_rotation(&stash_config, &mut options);
+        }
+
+        if let Err(e) = mount_stash(args.open(), mount_point, options).await {
+            fail(ErrorKind::Io, e)
+        }
+    }
+}
+
+/// Convert a size given in MiB on the command line to bytes.
+fn mib_to_bytes(mib: usize) -> usize {
+    mib.checked_mul(1024 * 1024)
+        .unwrap_or_else(|| fail(ErrorKind::Config, format!("{mib} MiB is too large")))
+}
 
-        if let Err(e) =
-            zerostash_fuse::mount::mount(stash, &self.mount_point, threads, self.read_write).await
-        {
-            panic!("Error = {}", e)
+impl Mount {
+    /// Merge the command line flags with the stash's mount configuration.
+    /// Flags given on the command line take precedence.
+    fn fuse_options(&self, config: Option<&MountConfig>) -> Options {
+        Options {
+            read_write: self.read_write || config.is_some_and(|c| !c.read_only),
+            memory_cache: mib_to_bytes(self.memory_cache),
+            preload: self.preload.map(mib_to_bytes),
+            uid: self.uid.or_else(|| config.and_then(|c| c.uid)),
+            gid: self.gid.or_else(|| config.and_then(|c| c.gid)),
+            allow_other: self.allow_other || config.is_some_and(|c| c.allow_other),
+            permissions: self
+                .permissions
+                .or_else(|| config.map(|c| c.permissions))
+                .unwrap_or_default()
+                .into(),
+            after_commit: None,
         }
     }
+
+    async fn mount_all(&self) {
+        let mounts = APP.config().mounts();
+        if mounts.is_empty() {
+            fail(ErrorKind::Config, "no mount points are configured");
+        }
+
+        let service = Operation::start("mount");
+        let mut running = tokio::task::JoinSet::new();
+        let mut scrub_targets = vec![];
+
+        for stash_config in mounts {
+            let config = stash_config.mount.clone().unwrap();
+            let stash = match stash_config.try_open(None) {
+                Ok(stash) => stash,
+                Err(e) => {
+                    status_err!("failed to open {}: {}", stash_config.alias, e);
+                    continue;
+                }
+            };
+
+            if self.scrub.is_some() {
+                match scrub::Target::new(&stash_config, &stash) {
+                    Ok(target) => scrub_targets.push(target),
+                    Err(e) => status_err!("not scrubbing {}: {}", stash_config.alias, e),
+                }
+            }
+
+            let mut options = self.fuse_options(Some(&config));
+            follow_key_rotation(&stash_config, &mut options);
+            let alias = stash_config.alias.clone();
+
+            running.spawn(async move {
+                let result = mount_stash(stash, config.mountpoint, options).await;
+                (alias, result)
+            });
+        }
+
+        service.status(format!("mounted {} stashes", running.len()));
+        let mounted = async {
+            while let Some(result) = running.join_next().await {
+                if let Ok((alias, Err(e))) = result {
+                    status_err!("failed to mount {}: {}", alias, e);
+                }
+            }
+        };
+
+        // the file systems run on their own, so there's no progress
+        // to report here
+        let idle = async {
+            loop {
+                service.idle().await;
+            }
+        };
+
+        match self.scrub {
+            Some(per_hour) => {
+                let on_failure = self.on_scrub_failure.clone();
+                tokio::select! {
+                    _ = mounted => {}
+                    _ = idle => {}
+                    _ = scrub::run(scrub_targets, per_hour, on_failure, &service) => {}
+                }
+            }
+            None => tokio::select! {
+                _ = mounted => {}
+                _ = idle => {}
+            },
+        }
+    }
+
+    fn print_systemd_unit(&self) {
+        let exe = std::env::current_exe().expect("Failed to find the 0s executable");
+
+        println!("[Unit]");
+        println!("Description=Mount zerostash stashes");
+        println!("Wants=network-online.target");
+        println!("After=network-online.target");
+        println!();
+        println!("[Service]");
+        let mut args = String::new();
+        if let Some(per_hour) = self.scrub {
+            args.push_str(&format!(" --scrub {per_hour}"));
+        }
+        if let Some(ref command) = self.on_scrub_failure {
+            args.push_str(&format!(" --on-scrub-failure {}", command.display()));
+        }
+
+        println!("Type=notify");
+        println!("ExecStart={} mount --all{args}", exe.display());
+        // `mount` unmounts cleanly on Ctrl-C
+        println!("KillSignal=SIGINT");
+        println!("Restart=on-failure");
+        println!();
+        println!("[Install]");
+        println!("WantedBy=default.target");
+    }
+}
+
+/// Reseal the stash after every commit if its keyfile is rotated.
+fn follow_key_rotation(stash: &crate::config::Stash, options: &mut Options) {
+    if !options.read_write {
+        return;
+    }
+
+    match KeyRotation::watch(stash) {
+        Ok(Some(rotation)) => {
+            options.after_commit = Some(CommitHook(Arc::new(move || rotation.after_commit())));
+        }
+        Ok(None) => {}
+        Err(e) => status_err!("not following key changes of {}: {}", stash.alias, e),
+    }
+}
+
+async fn mount_stash(
+    mut stash: Stash,
+    mount_point: PathBuf,
+    options: Options,
+) -> anyhow::Result<()> {
+    let threads = APP.get_worker_threads();
+    stash
+        .load(stash.index().tree())
+        .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    stash
+        .load(stash.index().files())
+        .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    migration(&mut stash);
+
+    zerostash_fuse::mount::mount(stash, &mount_point.to_string_lossy(), threads, &options).await
 }
@@ -0,0 +1,112 @@
+//! Follow key rotations while a stash is mounted read-write
+//!
+//! `0s keys change` reseals the stash with a new key, and writes it to
+//! the keyfile. A read-write mount still seals every commit with the
+//! key it was opened with, which would undo the rotation.
+//!
+//! When the keyfile changes, the mount picks up the new key, and
+//! reseals the stash with it after every commit. Objects are still read
+//! and written with the keys derived when the stash was opened, so
+//! rotating doesn't need a remount.
+
+use crate::config::{Key, KeyToSource, Stash};
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use tracing::{debug, warn};
+
+pub struct KeyRotation {
+    stash: Stash,
+    /// The key the stash was opened with
+    opened: Key,
+    /// The latest key in the keyfile, if it's different from `opened`
+    current: Mutex<Option<Key>>,
+    changed: Arc<AtomicBool>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl KeyRotation {
+    /// Watch the keyfile of `stash`. Returns `None` if the key isn't
+    /// stored in a file.
+    pub fn watch(stash: &Stash) -> anyhow::Result<Option<Self>> {
+        let Key::KeyFile { path } = &stash.key else {
+            return Ok(None);
+        };
+
+        let opened = stash.key.clone().resolve()?;
+
+        // the keyfile may be replaced instead of written, so watch the
+        // directory it's in
+        let name = path.file_name().context("invalid keyfile path")?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize()?,
+            _ => std::env::current_dir()?,
+        };
+        let file = dir.join(name);
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&changed);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(event) if event.paths.contains(&file) => flag.store(true, Ordering::Relaxed),
+                Ok(_) => {}
+                Err(error) => warn!(%error, "failed to watch keyfile"),
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Some(Self {
+            stash: stash.clone(),
+            opened,
+            current: Mutex::new(None),
+            changed,
+            _watcher: watcher,
+        }))
+    }
+
+    /// Reseal the stash with the latest key, if it was rotated.
+    pub fn after_commit(&self) {
+        if self.changed.swap(false, Ordering::Relaxed) {
+            self.reload();
+        }
+
+        let Some(new) = self.current.lock().unwrap().clone() else {
+            return;
+        };
+
+        let reseal = || -> anyhow::Result<()> {
+            let key = self.opened.clone().change_to(new);
+            self.stash.try_open(Some(key))?.reseal()?;
+            Ok(())
+        };
+
+        match reseal() {
+            Ok(()) => debug!(stash = %self.stash.alias, "resealed with the rotated key"),
+            Err(error) => warn!(%error, stash = %self.stash.alias, "failed to reseal stash"),
+        }
+    }
+
+    fn reload(&self) {
+        let key = match self.stash.key.clone().resolve() {
+            Ok(key) => key,
+            Err(error) => {
+                // the file may be halfway written, keep the last good key
+                warn!(%error, "failed to read the new keyfile");
+                return;
+            }
+        };
+
+        if let Err(error) = key.clone().to_keysource(&self.stash.alias) {
+            warn!(%error, "ignoring invalid key");
+            return;
+        }
+
+        let unchanged = toml::to_string(&key).ok() == toml::to_string(&self.opened).ok();
+        debug!(unchanged, "keyfile changed");
+
+        *self.current.lock().unwrap() = if unchanged { None } else { Some(key) };
+    }
+}
@@ -0,0 +1,141 @@
+//! Verify mounted stashes in the background
+//!
+//! With `mount --all --scrub N`, one object is verified at a time, `N`
+//! per hour in total, taking turns between the mounted stashes. Where
+//! each stash is at is saved after every object, so a restarted daemon
+//! continues the pass, and the whole stash is checked over time.
+use crate::{prelude::*, systemd::Operation};
+use std::{
+    collections::VecDeque,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, warn};
+use zerostash_files::scrub::{self, Objects, Progress, Scrubbed};
+
+type Step = Box<dyn FnMut(&Objects, &mut Progress) -> Option<Scrubbed> + Send>;
+
+pub(super) struct Target {
+    alias: String,
+    objects: Objects,
+    progress: Progress,
+    path: PathBuf,
+    step: Step,
+}
+
+impl Target {
+    /// Load the chunk index of `stash`, and where scrubbing it was at.
+    pub(super) fn new(config: &crate::config::Stash, stash: &Stash) -> anyhow::Result<Self> {
+        stash.load(stash.index().chunks())?;
+
+        let path = config.scrub_progress_path();
+        let mut reader = stash.storage_reader()?;
+        let mut hasher = zerostash_files::digest_key::hasher(stash)?;
+        let dictionaries = stash.index().dictionaries.clone();
+
+        Ok(Self {
+            alias: config.alias.clone(),
+            objects: Objects::of(stash),
+            progress: Progress::load(&path)?,
+            path,
+            step: Box::new(move |objects, progress| {
+                scrub::step(objects, progress, &mut reader, &dictionaries, &mut hasher)
+            }),
+        })
+    }
+
+    fn scrub_next(&mut self, on_failure: Option<&Path>) {
+        let Some(scrubbed) = (self.step)(&self.objects, &mut self.progress) else {
+            return;
+        };
+
+        if scrubbed.errors.is_empty() {
+            debug!(stash = %self.alias, object = %scrubbed.object, "scrubbed object");
+        } else {
+            for e in scrubbed.errors.iter() {
+                error!(
+                    stash = %self.alias,
+                    object = %scrubbed.object,
+                    error = %e,
+                    "scrubbing found a damaged chunk"
+                );
+            }
+            if let Some(command) = on_failure {
+                self.notify(command, &scrubbed);
+            }
+        }
+
+        if let Err(error) = self.progress.save(&self.path) {
+            warn!(%error, path = ?self.path, "failed to save scrub progress");
+        }
+    }
+
+    fn notify(&self, command: &Path, scrubbed: &Scrubbed) {
+        let status = Command::new(command)
+            .env("ZEROSTASH_STASH", &self.alias)
+            .env("ZEROSTASH_OBJECT", &scrubbed.object)
+            .env("ZEROSTASH_ERROR", scrubbed.errors[0].to_string())
+            .status();
+
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!(%status, ?command, "scrub failure command failed"),
+            Err(error) => warn!(%error, ?command, "failed to run scrub failure command"),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} {}/{} ({} failed)",
+            self.alias,
+            self.progress.checked,
+            self.objects.len(),
+            self.progress.failed
+        )
+    }
+}
+
+/// Scrub `per_hour` objects an hour, taking turns between `targets`.
+///
+/// Runs until it's cancelled, even if there's nothing to scrub.
+pub(super) async fn run(
+    targets: Vec<Target>,
+    per_hour: NonZeroU32,
+    on_failure: Option<PathBuf>,
+    service: &Operation,
+) {
+    let mut targets = targets
+        .into_iter()
+        .filter(|t| !t.objects.is_empty())
+        .collect::<VecDeque<_>>();
+    if targets.is_empty() {
+        return std::future::pending().await;
+    }
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(3600) / per_hour.get());
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        let mut target = targets.pop_front().unwrap();
+        let on_failure = on_failure.clone();
+        target = tokio::task::spawn_blocking(move || {
+            target.scrub_next(on_failure.as_deref());
+            target
+        })
+        .await
+        .unwrap();
+        targets.push_back(target);
+
+        let summary = targets
+            .iter()
+            .map(Target::summary)
+            .collect::<Vec<_>>()
+            .join(", ");
+        service.status(format!("scrubbed {summary}"));
+    }
+}
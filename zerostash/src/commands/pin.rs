@@ -0,0 +1,153 @@
+//! `pin` subcommand
+
+use crate::{
+    commit_message::chained,
+    migration::{migration, reload},
+    prelude::*,
+};
+use chrono::{DateTime, Utc};
+use infinitree::tree::CommitId;
+use std::time::{Duration, UNIX_EPOCH};
+use zerostash_files::pin::{self, PinTarget};
+
+#[derive(Command, Debug)]
+pub enum PinCommand {
+    /// Keep every version of the files under a path, or the files of a
+    /// commit, from being pruned
+    Add(AddPin),
+    /// List the pins of a stash
+    List(ListPins),
+    /// Remove a pin, so its data can be pruned again
+    Remove(RemovePin),
+}
+
+#[async_trait]
+impl AsyncRunnable for PinCommand {
+    async fn run(&self) {
+        use PinCommand::*;
+        match self {
+            Add(a) => a.run().await,
+            List(l) => l.run().await,
+            Remove(r) => r.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+#[clap(group(clap::ArgGroup::new("target").required(true).args(&["path", "commit"])))]
+pub struct AddPin {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Path in the stash, eg. `/legal/2019`
+    #[clap(long)]
+    path: Option<String>,
+
+    /// Commit ID
+    #[clap(long)]
+    commit: Option<CommitId>,
+}
+
+#[async_trait]
+impl AsyncRunnable for AddPin {
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        let target = match (&self.path, self.commit) {
+            (Some(path), _) => PinTarget::path(path).unwrap_or_else(|e| fail(ErrorKind::Config, e)),
+            (_, Some(id)) if stash.commit_list().iter().any(|c| c.id == id) => {
+                PinTarget::Commit(id)
+            }
+            (_, Some(id)) => fail(ErrorKind::Config, format!("no commit {id:?} in the stash")),
+            (None, None) => unreachable!("required by clap"),
+        };
+
+        if !pin::add(&stash, target.clone()) {
+            println!("{target} is pinned already");
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        // find the objects right away, so the pin protects them even
+        // if prune never runs with this version
+        pin::update(&mut stash, reload);
+
+        stash
+            .commit(
+                chained(&stash, format!("Pin {target}"))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        println!("Pinned {target}");
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListPins {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListPins {
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().pins())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        for pin in pin::list(&stash) {
+            let time: DateTime<Utc> = (UNIX_EPOCH + Duration::from_secs(pin.created_at)).into();
+            println!(
+                "{}\t{}\t{} objects",
+                pin.target,
+                time.with_timezone(&chrono::Local)
+                    .format("%Y %b %e %H:%M:%S"),
+                pin.objects.len()
+            );
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct RemovePin {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// The pin as shown by `0s pin list`, eg. `path:/legal/2019`
+    pin: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for RemovePin {
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        if !pin::remove(&stash, &self.pin) {
+            fail(ErrorKind::Config, format!("{} is not pinned", self.pin));
+        }
+
+        stash
+            .commit(
+                chained(&stash, format!("Unpin {}", self.pin))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    }
+}
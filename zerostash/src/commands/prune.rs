@@ -0,0 +1,169 @@
+//! `prune` subcommand
+
+use crate::{
+    commit_message::chained,
+    migration::{migration, reload},
+    prelude::*,
+};
+use humansize::{format_size, BINARY};
+use std::time::{Duration, SystemTime};
+use zerostash_files::{pin, pool::Pool, prune};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Command, Debug)]
+pub struct Prune {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Delete the objects that were pruned longer than the grace period ago
+    #[clap(long)]
+    expire_tombstones: bool,
+
+    /// How long pruned objects are kept before they can be deleted
+    #[clap(long, value_name = "DAYS", default_value_t = 7)]
+    grace_days: u64,
+
+    /// Only show what would be pruned or deleted, and how much space
+    /// each commit holds on its own. Counting the space reads the
+    /// index of every commit, so this may take a while.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Prune {
+    /// Start the application.
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        // pinned paths may have new versions since the last prune
+        pin::update(&mut stash, reload);
+
+        let now = SystemTime::now();
+        if self.expire_tombstones {
+            self.expire(&stash, now);
+        } else {
+            self.prune(&mut stash, now);
+        }
+    }
+}
+
+impl Prune {
+    fn prune(&self, stash: &mut Stash, now: SystemTime) {
+        let report = prune::prune(stash, now);
+
+        for id in report.objects.iter() {
+            println!("{id}");
+        }
+
+        println!(
+            "Pruned {} objects with {} chunks. They can be deleted with `--expire-tombstones` in {} days.",
+            report.objects.len(),
+            report.chunks,
+            self.grace_days
+        );
+
+        if self.dry_run {
+            print_attribution(stash);
+        }
+
+        if report.objects.is_empty() {
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        if self.dry_run {
+            return;
+        }
+
+        stash
+            .commit(
+                chained(&stash, format!("Prune {} objects", report.objects.len()))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        self.publish(stash);
+    }
+
+    fn expire(&self, stash: &Stash, now: SystemTime) {
+        let mut objects =
+            prune::expired(stash, Duration::from_secs(self.grace_days * DAY_SECS), now);
+
+        // objects in a shared pool may still be used by other members
+        if self.dry_run {
+            if let Some(config) = self.stash.parse_stash().pool {
+                objects = Pool::open_read_only(&config.catalog)
+                    .unreferenced_after_publish(&config.member, stash.index(), objects)
+                    .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            }
+        } else if let Some((pool, _)) = self.publish(stash) {
+            objects = pool
+                .unreferenced(objects)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        }
+
+        for id in objects.iter() {
+            println!("{id}");
+        }
+
+        println!("Deleting {} expired objects", objects.len());
+
+        if objects.is_empty() {
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        if self.dry_run {
+            return;
+        }
+
+        prune::expire(stash, &objects).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .commit(
+                chained(&stash, format!("Delete {} pruned objects", objects.len()))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    }
+
+    /// Update the pool catalog if the stash is in a pool, so other
+    /// members stop using pruned chunks.
+    fn publish(&self, stash: &Stash) -> Option<(Pool, String)> {
+        let config = self.stash.parse_stash().pool?;
+        let pool = Pool::open(&config.catalog).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        pool.publish(&config.member, stash.index())
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        Some((pool, config.member))
+    }
+}
+
+/// Show the space every commit would free if it was dropped.
+///
+/// This reloads the tree of every commit, so it must only be called
+/// when nothing is committed afterwards.
+fn print_attribution(stash: &mut Stash) {
+    let commits = prune::attribute(stash, reload);
+
+    println!("\nSpace held only by each commit:");
+    for commit in commits {
+        println!(
+            "{:?}\t{} files\t{} chunks\t{}",
+            commit.commit,
+            commit.files,
+            commit.unique_chunks,
+            format_size(commit.unique_bytes, BINARY)
+        );
+    }
+}
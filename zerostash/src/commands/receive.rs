@@ -0,0 +1,85 @@
+//! `receive` subcommand
+
+use crate::prelude::*;
+use std::{
+    fs,
+    io::{self, BufReader, Read},
+    path::PathBuf,
+};
+
+#[derive(Command, Debug)]
+pub struct Receive {
+    /// The share made by `0s share`, or `-` for the standard input
+    share: PathBuf,
+
+    /// File with age identities, as written by `age-keygen`
+    #[clap(short, long, value_name = "PATH")]
+    identity: PathBuf,
+
+    /// Directory to put the files in
+    #[clap(long, value_name = "PATH", default_value = ".")]
+    to: PathBuf,
+}
+
+#[async_trait]
+impl AsyncRunnable for Receive {
+    /// Start the application.
+    async fn run(&self) {
+        let identities = self.identities();
+
+        let input: Box<dyn Read> = if self.share.as_os_str() == "-" {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(fs::File::open(&self.share).unwrap_or_else(|e| fail(ErrorKind::Io, e)))
+        };
+
+        let decryptor = match age::Decryptor::new(BufReader::new(input)) {
+            Ok(age::Decryptor::Recipients(decryptor)) => decryptor,
+            Ok(_) => fail(ErrorKind::Config, "not a share made by `0s share`"),
+            Err(e) => fail(ErrorKind::Verification, e),
+        };
+        let reader = decryptor
+            .decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+            .unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+
+        fs::create_dir_all(&self.to).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let mut archive = tar::Archive::new(reader);
+        archive.set_overwrite(false);
+        for entry in archive
+            .entries()
+            .unwrap_or_else(|e| fail(ErrorKind::Verification, e))
+        {
+            let mut entry = entry.unwrap_or_else(|e| fail(ErrorKind::Verification, e));
+            let path = entry.path().map(|p| p.into_owned()).unwrap_or_default();
+
+            entry
+                .unpack_in(&self.to)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, format!("{}: {e}", path.display())));
+            println!("{}", self.to.join(path).display());
+        }
+    }
+}
+
+impl Receive {
+    /// Native age identities from the identity file
+    fn identities(&self) -> Vec<age::x25519::Identity> {
+        let contents =
+            fs::read_to_string(&self.identity).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let identities = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse()
+                    .unwrap_or_else(|e: &str| fail(ErrorKind::Config, e))
+            })
+            .collect::<Vec<_>>();
+
+        if identities.is_empty() {
+            fail(ErrorKind::Config, "no identities in the identity file");
+        }
+        identities
+    }
+}
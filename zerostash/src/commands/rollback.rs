@@ -0,0 +1,99 @@
+//! `rollback` subcommand
+
+use crate::{commit_message::CommitMessage, migration::reload, prelude::*};
+use infinitree::tree::CommitId;
+use std::{fmt, num::NonZeroUsize, str::FromStr};
+use zerostash_files::rollback::rollback;
+
+#[derive(Command, Debug)]
+pub struct Rollback {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// The commit to roll back to: its ID, or its number in `0s log`, starting from 1
+    #[clap(long, value_name = "GENERATION")]
+    to: Generation,
+
+    /// Commit message to include in the changeset.
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+}
+
+/// A commit, by its ID or its number in `0s log`
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Generation {
+    Number(NonZeroUsize),
+    Id(CommitId),
+}
+
+impl FromStr for Generation {
+    type Err = Box<dyn std::error::Error + Send + Sync>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(n) => Ok(Self::Number(n)),
+            Err(_) => Ok(Self::Id(CommitId::from_str(s).map_err(Into::into)?)),
+        }
+    }
+}
+
+impl Generation {
+    /// Find the commit in `commits`, or fail with a config error
+    pub(super) fn resolve(&self, commits: &[CommitId]) -> CommitId {
+        match self {
+            Self::Number(n) => commits.get(n.get() - 1).copied(),
+            Self::Id(id) => commits.iter().find(|c| *c == id).copied(),
+        }
+        .unwrap_or_else(|| fail(ErrorKind::Config, format!("no commit {self} in the stash")))
+    }
+}
+
+impl fmt::Display for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "#{n}"),
+            Self::Id(id) => write!(f, "{id:?}"),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncRunnable for Rollback {
+    /// Start the application.
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        let commits = stash.commit_list().iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let target = self.to.resolve(&commits);
+
+        if commits.last() == Some(&target) {
+            println!("{target:?} is the latest commit already");
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        let report = rollback(&mut stash, target, reload);
+
+        let default_message = format!("Roll back to {target:?}");
+        let mut message = CommitMessage::new(
+            Some(self.message.as_deref().unwrap_or(&default_message)),
+            [("rollback".to_string(), format!("{target:?}"))],
+        );
+        message.set_previous(&stash);
+        stash
+            .commit(
+                message
+                    .render(&stash)
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        println!(
+            "Rolled back to {target:?}: {} paths restored, {} removed",
+            report.restored, report.removed
+        );
+    }
+}
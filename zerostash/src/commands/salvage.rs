@@ -0,0 +1,56 @@
+//! `salvage` subcommand
+
+use crate::{commit_message::chained, migration::migration, prelude::*};
+use humansize::{format_size, BINARY};
+use zerostash_files::salvage::salvage;
+
+#[derive(Command, Debug)]
+pub struct Salvage {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for Salvage {
+    /// Start the application.
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        let report = match salvage(&stash) {
+            Ok(report) => report,
+            Err(e) => fail(ErrorKind::Backend, e),
+        };
+
+        for (path, lost) in report.damaged_files.iter() {
+            println!("{path}: lost {}", format_size(*lost, BINARY));
+        }
+
+        println!(
+            "Checked {} chunks: {} lost, {} moved to new objects",
+            report.chunks, report.lost_chunks, report.moved_chunks
+        );
+
+        if report.lost_chunks == 0 && report.moved_chunks == 0 {
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        stash
+            .commit(
+                chained(&stash, "Salvage damaged objects".to_string())
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        if report.lost_chunks > 0 {
+            exit_with(ErrorKind::Partial);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! `self-update` subcommand
+
+use crate::{config::Channel, prelude::*, update};
+
+#[derive(Command, Debug)]
+pub struct SelfUpdate {
+    /// Release channel to follow instead of the one in the config
+    #[clap(long, value_enum)]
+    channel: Option<Channel>,
+
+    /// Only check if a newer release is available
+    #[clap(long)]
+    check: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for SelfUpdate {
+    /// Start the application.
+    async fn run(&self) {
+        let config = APP.config().update.clone();
+        let channel = self.channel.unwrap_or(config.channel);
+        let current = update::current();
+
+        let release = update::latest(channel)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+            .filter(|release| release.version().is_some_and(|v| v > current));
+
+        let Some(release) = release else {
+            println!("0s {current} is the latest {channel:?} release");
+            exit_with(ErrorKind::NothingToDo);
+        };
+
+        if self.check {
+            println!("0s {} is available", release.tag_name);
+            return;
+        }
+
+        let Some(public_key) = config.public_key.as_deref().or(update::PUBLIC_KEY) else {
+            fail(
+                ErrorKind::Config,
+                "no key to check releases with, set `public_key` in the `[update]` section of the config",
+            );
+        };
+
+        let binary = update::download(&release, public_key)
+            .unwrap_or_else(|e| fail(ErrorKind::Verification, e));
+
+        let path = std::env::current_exe()
+            .and_then(|path| path.canonicalize())
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        update::replace(&path, &binary).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        println!("Updated {} to {}", path.display(), release.tag_name);
+    }
+}
@@ -0,0 +1,103 @@
+//! `serve` subcommand
+//!
+//! Answers chunk existence queries about the stash, so thin clients on
+//! other machines can skip uploading chunks it already has. See
+//! `zerostash_files::chunk_exists` for the protocol.
+
+use crate::prelude::*;
+use std::{fs, net::TcpListener, num::NonZeroUsize, path::PathBuf, thread};
+use tracing::{debug, warn};
+use zerostash_files::chunk_exists::{serve, Token};
+
+#[derive(Command, Debug)]
+pub struct Serve {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Address to listen for chunk existence queries on
+    #[clap(long, value_name = "ADDR", default_value = "127.0.0.1:4242")]
+    listen: String,
+
+    /// File with the token clients authenticate their queries with
+    #[clap(long, value_name = "PATH")]
+    token_file: PathBuf,
+
+    /// Most connections to answer at the same time. Further clients wait
+    /// until a connection closes.
+    #[clap(long, value_name = "N", default_value = "16")]
+    max_connections: NonZeroUsize,
+}
+
+#[async_trait]
+impl AsyncRunnable for Serve {
+    /// Start the application.
+    async fn run(&self) {
+        let secret = fs::read(&self.token_file).unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        let secret = trim_whitespace(&secret);
+        if secret.is_empty() {
+            fail(
+                ErrorKind::Config,
+                format!("{} is empty", self.token_file.display()),
+            );
+        }
+        let token = Token::new(secret);
+
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().chunks())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let chunks = &stash.index().chunks;
+
+        let listener = TcpListener::bind(&self.listen).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        println!("Answering chunk queries on {}", self.listen);
+
+        // every thread takes the next connection when it's done with
+        // one, so at most this many are served at once
+        thread::scope(|s| {
+            for _ in 0..self.max_connections.get() {
+                let (listener, token) = (&listener, &token);
+                s.spawn(move || loop {
+                    let (conn, peer) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(error) => {
+                            warn!(%error, "failed to accept connection");
+                            continue;
+                        }
+                    };
+
+                    match serve(chunks, token, conn) {
+                        Ok(answered) => debug!(%peer, answered, "connection closed"),
+                        Err(error) => warn!(%error, %peer, "connection failed"),
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// `bytes` without leading and trailing ASCII whitespace
+fn trim_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |last| last + 1);
+
+    &bytes[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn tokens_are_trimmed() {
+        use super::trim_whitespace;
+
+        assert_eq!(trim_whitespace(b" token\n"), b"token");
+        assert_eq!(trim_whitespace(b"to ken"), b"to ken");
+        assert_eq!(trim_whitespace(b" \r\n"), b"");
+        assert_eq!(trim_whitespace(b""), b"");
+    }
+}
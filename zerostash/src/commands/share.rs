@@ -0,0 +1,196 @@
+//! `share` subcommand
+//!
+//! A share is a tar archive of the files, encrypted to the recipients
+//! with age. It can be opened with `0s receive`, or without zerostash:
+//!
+//! ```text
+//! age -d -i key.txt share.age | tar x
+//! ```
+
+use crate::prelude::*;
+use std::{
+    fs,
+    io::{self, BufWriter, IsTerminal, Read},
+    path::PathBuf,
+};
+use zerostash_files::{browse::Browser, Entry};
+
+/// Files are read from the stash in blocks of this size
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Command, Debug)]
+pub struct Share {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Files to share, as stored in the stash
+    #[clap(required = true)]
+    paths: Vec<String>,
+
+    /// age recipient, eg. `age1...`. May be repeated.
+    #[clap(long = "to", value_name = "RECIPIENT", required = true)]
+    recipients: Vec<String>,
+
+    /// Write the share to a file instead of the standard output
+    #[clap(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+}
+
+#[async_trait]
+impl AsyncRunnable for Share {
+    /// Start the application.
+    async fn run(&self) {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|r| {
+                r.parse::<age::x25519::Recipient>()
+                    .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+                    .unwrap_or_else(|e| fail(ErrorKind::Config, format!("{r}: {e}")))
+            })
+            .collect::<Vec<_>>();
+
+        let browser =
+            Browser::new(self.stash.open()).unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let mut names = std::collections::HashSet::new();
+        let files = self
+            .paths
+            .iter()
+            .filter(|path| names.insert(archive_name(path)))
+            .map(|path| {
+                let entry = browser
+                    .file(path)
+                    .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+                (path, entry)
+            })
+            .collect::<Vec<_>>();
+
+        let out: Box<dyn Write> = match &self.output {
+            Some(path) => {
+                Box::new(fs::File::create_new(path).unwrap_or_else(|e| fail(ErrorKind::Io, e)))
+            }
+            None if io::stdout().is_terminal() => fail(
+                ErrorKind::Config,
+                "refusing to write a share to a terminal, use `--output` or redirect it",
+            ),
+            None => Box::new(io::stdout().lock()),
+        };
+
+        let mut writer = age::Encryptor::with_recipients(recipients)
+            .expect("at least one recipient is required")
+            .wrap_output(BufWriter::new(out))
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let mut archive = tar::Builder::new(&mut writer);
+        for (path, entry) in &files {
+            append(&mut archive, path, entry, StashFile::new(&browser, path))
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        }
+
+        archive
+            .into_inner()
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        writer
+            .finish()
+            .and_then(|mut out| out.flush())
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        eprintln!(
+            "Shared {} files with {} recipients",
+            files.len(),
+            self.recipients.len()
+        );
+    }
+}
+
+/// Files keep their full path in the stash, so files with the same
+/// name in different directories don't collide.
+fn archive_name(path: &str) -> &str {
+    path.trim_matches('/')
+}
+
+/// Add the file at `path` to the archive, reading its contents from `data`
+fn append(
+    archive: &mut tar::Builder<impl Write>,
+    path: &str,
+    entry: &Entry,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(entry.size);
+    header.set_mode(entry.unix_perm.unwrap_or(0o644) & 0o7777);
+    header.set_mtime(entry.unix_secs.max(0) as u64);
+
+    archive.append_data(&mut header, archive_name(path), data)
+}
+
+/// Read a file from the stash one block at a time
+struct StashFile<'a> {
+    browser: &'a Browser,
+    path: &'a str,
+    offset: u64,
+    block: io::Cursor<Vec<u8>>,
+}
+
+impl<'a> StashFile<'a> {
+    fn new(browser: &'a Browser, path: &'a str) -> Self {
+        Self {
+            browser,
+            path,
+            offset: 0,
+            block: Default::default(),
+        }
+    }
+}
+
+impl Read for StashFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.block.position() as usize == self.block.get_ref().len() {
+            let block = self
+                .browser
+                .read(self.path, self.offset, BLOCK_SIZE)
+                .map_err(io::Error::other)?;
+            self.offset += block.len() as u64;
+            self.block = io::Cursor::new(block);
+        }
+
+        self.block.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn files_are_archived_by_full_path() {
+        use super::append;
+        use std::io::Read;
+        use zerostash_files::Entry;
+
+        let mut archive = tar::Builder::new(vec![]);
+        for (path, data) in [("/a/file", "first"), ("b/file", "second")] {
+            let entry = Entry {
+                size: data.len() as u64,
+                ..Entry::default()
+            };
+            append(&mut archive, path, &entry, data.as_bytes()).unwrap();
+        }
+        let bytes = archive.into_inner().unwrap();
+
+        let mut files = vec![];
+        for file in tar::Archive::new(bytes.as_slice()).entries().unwrap() {
+            let mut file = file.unwrap();
+            let path = file.path().unwrap().to_string_lossy().into_owned();
+            let mut data = String::new();
+            file.read_to_string(&mut data).unwrap();
+            files.push((path, data));
+        }
+
+        assert_eq!(
+            files,
+            vec![
+                ("a/file".to_string(), "first".to_string()),
+                ("b/file".to_string(), "second".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! `snapshot` subcommand
+
+use crate::{commit_message::chained, migration::migration, prelude::*};
+use chrono::{DateTime, Utc};
+use infinitree::tree::CommitId;
+use std::time::{Duration, UNIX_EPOCH};
+use zerostash_files::named_snapshot;
+
+#[derive(Command, Debug)]
+pub enum SnapshotCommand {
+    /// Name the latest commit, or the given one, so it can be restored by name
+    Create(CreateSnapshot),
+    /// List the named snapshots of a stash
+    List(ListSnapshots),
+    /// Remove the name of a snapshot. Its commit is kept.
+    Delete(DeleteSnapshot),
+}
+
+#[async_trait]
+impl AsyncRunnable for SnapshotCommand {
+    async fn run(&self) {
+        use SnapshotCommand::*;
+        match self {
+            Create(c) => c.run().await,
+            List(l) => l.run().await,
+            Delete(d) => d.run().await,
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct CreateSnapshot {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the snapshot
+    name: String,
+
+    /// Commit ID, the latest commit by default
+    #[clap(long)]
+    commit: Option<CommitId>,
+}
+
+#[async_trait]
+impl AsyncRunnable for CreateSnapshot {
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        let commit = match self.commit {
+            Some(id) if stash.commit_list().iter().any(|c| c.id == id) => id,
+            Some(id) => fail(ErrorKind::Config, format!("no commit {id:?} in the stash")),
+            None => match stash.commit_list().last() {
+                Some(commit) => commit.id,
+                None => fail(ErrorKind::Config, "the stash has no commits"),
+            },
+        };
+
+        if !named_snapshot::create(&stash, &self.name, commit)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+        {
+            println!("Snapshot {} exists already", self.name);
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        stash
+            .commit(
+                chained(&stash, format!("Create snapshot {}", self.name))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        println!("Created snapshot {} of {commit:?}", self.name);
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct ListSnapshots {
+    #[clap(flatten)]
+    stash: StashArgs,
+}
+
+#[async_trait]
+impl AsyncRunnable for ListSnapshots {
+    async fn run(&self) {
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().custom_fields())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        for snapshot in named_snapshot::list(&stash).unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+        {
+            let time: DateTime<Utc> =
+                (UNIX_EPOCH + Duration::from_secs(snapshot.created_at)).into();
+            println!(
+                "{}\t{:?}\t{}",
+                snapshot.name,
+                snapshot.commit,
+                time.with_timezone(&chrono::Local)
+                    .format("%Y %b %e %H:%M:%S"),
+            );
+        }
+    }
+}
+
+#[derive(Command, Debug)]
+pub struct DeleteSnapshot {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Name of the snapshot
+    name: String,
+}
+
+#[async_trait]
+impl AsyncRunnable for DeleteSnapshot {
+    async fn run(&self) {
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        if !named_snapshot::delete(&stash, &self.name)
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+        {
+            fail(ErrorKind::Config, format!("no snapshot {}", self.name));
+        }
+
+        stash
+            .commit(
+                chained(&stash, format!("Delete snapshot {}", self.name))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    }
+}
@@ -0,0 +1,69 @@
+//! `stats` subcommand
+
+use crate::prelude::*;
+use std::collections::HashSet;
+use zerostash_files::cache::CacheStats;
+
+#[derive(Command, Debug)]
+pub struct Stats {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Show hit and miss counts of the local caches instead
+    #[clap(long)]
+    cache: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Stats {
+    /// Start the application.
+    async fn run(&self) {
+        if self.cache {
+            self.cache_stats();
+            return;
+        }
+
+        let stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let index = stash.index();
+        let mut objects = HashSet::new();
+        index
+            .chunks
+            .for_each(|_, pointer| _ = objects.insert(*pointer.object_id()));
+
+        println!("Commits:    {}", stash.commit_list().len());
+        println!("Files:      {}", index.tree.iter_files().count());
+        println!("Chunks:     {}", index.chunks.len());
+        println!("Objects:    {}", objects.len());
+    }
+}
+
+impl Stats {
+    fn cache_stats(&self) {
+        let stash = self.stash.parse_stash();
+        let paths = stash.backend.cache_paths();
+        if paths.is_empty() {
+            println!("The stash has no local cache");
+            exit_with(ErrorKind::NothingToDo);
+        }
+
+        for path in paths {
+            let stats = CacheStats::read(path).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+            println!("{path}");
+            println!("  Hits:       {}", stats.hits);
+            println!("  Misses:     {}", stats.misses);
+            println!("  Hit rate:   {:.1}%", stats.hit_rate() * 100.0);
+            println!("  Evictions:  {}", stats.evictions);
+            if stats.pinned_evictions > 0 {
+                println!(
+                    "  Pinned objects evicted: {}, consider a larger `max_size_mb`",
+                    stats.pinned_evictions
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! `thaw` subcommand
+
+use crate::{
+    glacier::{Client, State, Tier},
+    prelude::*,
+};
+use std::{collections::HashSet, time::Duration};
+
+#[derive(Command, Debug)]
+pub struct Thaw {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Only thaw the objects of files that match these globs
+    globs: Vec<String>,
+
+    /// Keep thawed copies readable for this many days
+    #[clap(long, default_value_t = 7)]
+    days: u32,
+
+    /// Restore tier. Faster tiers cost more
+    #[clap(long, value_enum, default_value_t)]
+    tier: Tier,
+
+    /// Wait until all objects are thawed
+    #[clap(short, long)]
+    wait: bool,
+
+    /// Check the progress this often while waiting, in seconds
+    #[clap(long, value_name = "SECS", default_value_t = 600)]
+    poll: u64,
+}
+
+#[async_trait]
+impl AsyncRunnable for Thaw {
+    /// Start the application.
+    async fn run(&self) {
+        let config = self.stash.parse_stash();
+        let clients = config
+            .backend
+            .storage()
+            .into_iter()
+            .filter(|b| matches!(b, crate::config::Backend::S3 { .. }))
+            .map(Client::new)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+        if clients.is_empty() {
+            fail(
+                ErrorKind::Config,
+                format!("{} has no S3 backends to thaw", config.alias),
+            );
+        }
+
+        // the index has to be readable to know which objects to thaw
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().tree())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let options = zerostash_files::restore::Options {
+            globs: self.globs.clone(),
+            ..Default::default()
+        };
+        let objects = options
+            .list(&stash)
+            .flat_map(|(_, entry)| {
+                entry
+                    .chunks
+                    .values()
+                    .map(|cp| *cp.object_id())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+
+        let (mut pending, mut requested, mut thawing) = (vec![], 0, 0);
+        for id in objects.iter() {
+            for client in clients.iter() {
+                match client
+                    .state(id)
+                    .await
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
+                {
+                    State::Available => continue,
+                    State::Archived => {
+                        client
+                            .thaw(id, self.days, self.tier)
+                            .await
+                            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+                        requested += 1;
+                    }
+                    State::Thawing => thawing += 1,
+                }
+                pending.push((client, *id));
+            }
+        }
+
+        println!(
+            "{} objects: requested {requested} restores, {thawing} in progress already",
+            objects.len(),
+        );
+
+        if !self.wait {
+            return;
+        }
+
+        let total = pending.len();
+        while !pending.is_empty() {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(self.poll.max(1))) => {}
+                _ = tokio::signal::ctrl_c() => {
+                    fail(ErrorKind::Interrupted, "the requested restores continue without waiting");
+                }
+            }
+
+            let mut left = vec![];
+            for (client, id) in pending {
+                let state = client
+                    .state(&id)
+                    .await
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+                if state != State::Available {
+                    left.push((client, id));
+                }
+            }
+            pending = left;
+            println!("{} of {total} objects thawed", total - pending.len());
+        }
+    }
+}
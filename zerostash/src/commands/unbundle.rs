@@ -0,0 +1,59 @@
+//! `unbundle` subcommand
+
+use crate::prelude::*;
+use std::{fs, path::PathBuf, str::FromStr, sync::Arc};
+use zerostash_files::bundle::Bundle;
+
+#[derive(Command, Debug)]
+pub struct Unbundle {
+    /// The bundle file
+    bundle: PathBuf,
+
+    /// Alias of the stash the bundle was made of, whose key checks it
+    #[clap(long, value_name = "STASH")]
+    stash: String,
+
+    /// Empty or new directory to extract the stash to
+    #[clap(long, value_name = "PATH")]
+    to: PathBuf,
+}
+
+#[async_trait]
+impl AsyncRunnable for Unbundle {
+    /// Start the application.
+    async fn run(&self) {
+        if fs::read_dir(&self.to).is_ok_and(|mut entries| entries.next().is_some()) {
+            fail(
+                ErrorKind::Config,
+                format!("{} is not empty", self.to.display()),
+            );
+        }
+
+        let config = crate::config::Stash::from_str(&self.stash)
+            .unwrap_or_else(|e| fail(ErrorKind::Config, e));
+
+        let file = fs::File::open(&self.bundle).unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        let bundle =
+            Arc::new(Bundle::open(file).unwrap_or_else(|e| fail(ErrorKind::Verification, e)));
+
+        // nothing is written until the index checks out with the key
+        let stash = config
+            .open_from(None, bundle.clone())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        let hasher = stash.hasher().unwrap_or_else(|e| fail(ErrorKind::Auth, e));
+        bundle
+            .verify(hasher)
+            .unwrap_or_else(|e| fail(ErrorKind::Verification, e));
+
+        let backend = infinitree::backends::Directory::new(&self.to)
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+        let count = bundle
+            .extract(backend.as_ref())
+            .unwrap_or_else(|e| fail(ErrorKind::Verification, e));
+
+        println!(
+            "Extracted {count} objects. {} can be opened as a stash with the original key.",
+            self.to.display()
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! `upload` subcommand
+
+use crate::prelude::*;
+use std::time::Duration;
+use zerostash_files::spool::{drain, SETTLE_TIME};
+
+#[derive(Command, Debug)]
+pub struct Upload {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Try failed uploads this many more times
+    #[clap(long, default_value_t = 5)]
+    retries: u32,
+
+    /// Also upload objects that were written in the last minute.
+    /// Only use this when no commit is running.
+    #[clap(long)]
+    now: bool,
+}
+
+#[async_trait]
+impl AsyncRunnable for Upload {
+    /// Start the application.
+    async fn run(&self) {
+        // objects are uploaded as they are stored, no key is needed
+        let stash = self.stash.parse_stash();
+        let spools = stash.backend.spools();
+        if spools.is_empty() {
+            fail(
+                ErrorKind::Config,
+                format!("{} has no spool to upload", stash.alias),
+            );
+        }
+
+        let settle = match self.now {
+            true => Duration::ZERO,
+            false => SETTLE_TIME,
+        };
+        let mut failed = 0;
+        for (path, upstream) in spools {
+            let backend = upstream
+                .to_infinitree()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            let drained = drain(path, backend.as_ref(), settle, self.retries)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, format!("{path}: {e}")));
+
+            println!(
+                "{upstream}: uploaded {} objects, {} failed, {} left for the next upload",
+                drained.uploaded, drained.failed, drained.settling
+            );
+            failed += drained.failed;
+        }
+
+        if failed > 0 {
+            fail(
+                ErrorKind::Partial,
+                format!("{failed} objects are still in the spool"),
+            );
+        }
+    }
+}
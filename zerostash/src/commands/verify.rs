@@ -0,0 +1,237 @@
+//! `verify` subcommand
+
+use crate::prelude::*;
+use rand::{seq::SliceRandom, SeedableRng};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use zerostash_files::chunk_index::{digest_from_hex, digest_to_hex, verify_chunk, ChunkRecord};
+
+#[derive(Command, Debug)]
+pub struct Verify {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    /// Only check a random sample of the chunks, eg. `1%`
+    #[clap(long, value_name = "PERCENT", value_parser = parse_percent)]
+    sample: Option<f64>,
+
+    /// Seed for selecting the sample. A random seed is used and printed if omitted.
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Record verified chunks in this file, and skip them in subsequent sampled runs
+    /// until all chunks have been covered.
+    #[clap(long, value_name = "PATH")]
+    state: Option<PathBuf>,
+
+    /// Only check objects against their checksums, without decrypting them.
+    ///
+    /// This needs no key, but only works for stashes with a `checksum` backend.
+    #[clap(long, conflicts_with_all = ["sample", "seed", "state"])]
+    fast: bool,
+
+    /// Only check the chunks in this object, eg. one that failed to
+    /// decrypt during a restore. Can be given multiple times.
+    #[clap(long, value_name = "ID", conflicts_with = "fast")]
+    object: Vec<String>,
+}
+
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let value = s
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .map_err(|e| e.to_string())?;
+
+    if value <= 0.0 || value > 100.0 {
+        return Err("sample size must be between 0% and 100%".into());
+    }
+
+    Ok(value / 100.0)
+}
+
+#[async_trait]
+impl AsyncRunnable for Verify {
+    /// Start the application.
+    async fn run(&self) {
+        if self.fast {
+            self.verify_checksums();
+            return;
+        }
+
+        let stash = self.stash.open();
+        stash
+            .load(stash.index().chunks())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let mut chunks = vec![];
+        stash.index().export_chunks(|record| {
+            if self.object.is_empty()
+                || self
+                    .object
+                    .contains(&record.pointer.object_id().to_string())
+            {
+                chunks.push(record)
+            }
+        });
+        chunks.sort_unstable_by(|a, b| a.digest.cmp(&b.digest));
+        let total = chunks.len();
+
+        let selected = match self.sample {
+            Some(fraction) => self.select_sample(chunks, fraction),
+            None => chunks,
+        };
+
+        let threads = APP.get_worker_threads().max(1);
+        let batch_size = selected.len().div_ceil(threads).max(1);
+        let mut workers = vec![];
+
+        for batch in selected.chunks(batch_size) {
+            let batch = batch.to_vec();
+            let mut reader = stash
+                .storage_reader()
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            let mut hasher = zerostash_files::digest_key::hasher(&stash)
+                .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+            let dictionaries = stash.index().dictionaries.clone();
+
+            workers.push(tokio::task::spawn_blocking(move || {
+                let mut buf = vec![];
+                let mut verified = vec![];
+                let mut failed = vec![];
+
+                for record in batch {
+                    match verify_chunk(&mut reader, &dictionaries, &mut hasher, &record, &mut buf) {
+                        Ok(()) => verified.push(record.digest),
+                        Err(e) => failed.push(e.to_string()),
+                    }
+                }
+
+                (verified, failed)
+            }));
+        }
+
+        let (mut verified, mut failed) = (vec![], vec![]);
+        for worker in workers {
+            let (v, f) = worker.await.unwrap();
+            verified.extend(v);
+            failed.extend(f);
+        }
+
+        for error in failed.iter() {
+            println!("{error}");
+        }
+
+        if let Some(ref path) = self.state {
+            let mut state = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+            for digest in verified.iter() {
+                writeln!(state, "{}", digest_to_hex(digest))
+                    .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            }
+        }
+
+        let checked = verified.len() + failed.len();
+        println!(
+            "Verified {checked} of {total} chunks: {} errors",
+            failed.len()
+        );
+
+        if self.sample.is_some() && checked > 0 {
+            let rate = failed.len() as f64 / checked as f64;
+            // with no failures, the "rule of three" gives the 95% upper bound
+            let upper = if failed.is_empty() {
+                3.0 / checked as f64
+            } else {
+                rate + 1.96 * (rate * (1.0 - rate) / checked as f64).sqrt()
+            };
+
+            println!(
+                "Estimated corrupt chunks: {:.4}% (95% upper bound: {:.4}%)",
+                rate * 100.0,
+                upper.min(1.0) * 100.0
+            );
+        }
+
+        if !failed.is_empty() {
+            exit_with(ErrorKind::Verification);
+        }
+    }
+}
+
+impl Verify {
+    fn verify_checksums(&self) {
+        let report = match self.stash.parse_stash().backend.verify_checksums() {
+            Ok(Some(report)) => report,
+            Ok(None) => fail(
+                ErrorKind::Config,
+                "`--fast` needs a stash with a `checksum` backend",
+            ),
+            Err(e) => fail(ErrorKind::Backend, e),
+        };
+
+        for (id, error) in report.failed.iter() {
+            println!("{id}: {error}");
+        }
+
+        println!(
+            "Verified {} objects: {} errors",
+            report.checked,
+            report.failed.len()
+        );
+
+        if !report.failed.is_empty() {
+            exit_with(ErrorKind::Verification);
+        }
+    }
+
+    fn select_sample(&self, chunks: Vec<ChunkRecord>, fraction: f64) -> Vec<ChunkRecord> {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let size = ((chunks.len() as f64 * fraction).ceil() as usize).min(chunks.len());
+
+        let covered = self.read_state();
+        let mut candidates = chunks
+            .iter()
+            .filter(|c| !covered.contains(&c.digest))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if candidates.len() < size {
+            println!("All chunks have been covered, starting a new cycle");
+            if let Some(ref path) = self.state {
+                fs::write(path, "").unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            }
+            candidates = chunks;
+        }
+
+        println!("Sampling {size} chunks with seed {seed}");
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        candidates.shuffle(&mut rng);
+        candidates.truncate(size);
+        candidates
+    }
+
+    fn read_state(&self) -> HashSet<infinitree::Digest> {
+        let Some(ref path) = self.state else {
+            return HashSet::new();
+        };
+
+        let Ok(file) = fs::File::open(path) else {
+            return HashSet::new();
+        };
+
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| digest_from_hex(line.trim()).ok())
+            .collect()
+    }
+}
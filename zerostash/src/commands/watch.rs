@@ -0,0 +1,180 @@
+//! `watch` subcommand
+
+use crate::{
+    commit_message::CommitMessage, logging, migration::migration, prelude::*, systemd::Operation,
+};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, warn, Instrument};
+use zerostash_files::roots::Source;
+
+#[derive(Command, Debug)]
+pub struct Watch {
+    #[clap(flatten)]
+    stash: StashArgs,
+
+    #[clap(flatten)]
+    options: zerostash_files::store::Options,
+
+    /// Commit changes at most this often, in seconds
+    #[clap(short = 'n', long = "interval", default_value_t = 60)]
+    interval: u64,
+
+    /// Commit message to include in every changeset.
+    ///
+    /// `{date}` and `{time}` will be substituted.
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+}
+
+/// A watched directory, as given on the command line, and its
+/// canonical path, which is what the events refer to.
+pub(crate) struct Root {
+    given: Source,
+    canonical: PathBuf,
+}
+
+impl Root {
+    pub(crate) fn new(path: &Path) -> Self {
+        let given = Source::parse(path);
+        Self {
+            canonical: given
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| given.path.clone()),
+            given,
+        }
+    }
+
+    /// The directory to watch
+    pub(crate) fn path(&self) -> &Path {
+        &self.given.path
+    }
+
+    /// Translate the path of an event to the path we'd see while
+    /// walking the directory given on the command line, so the index
+    /// keys stay the same. The prefix of the root is kept.
+    pub(crate) fn source_path(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.canonical).ok()?;
+        let path = if relative.as_os_str().is_empty() {
+            self.given.path.clone()
+        } else {
+            self.given.path.join(relative)
+        };
+
+        self.given.narrow(&path).ok()
+    }
+}
+
+#[async_trait]
+impl AsyncRunnable for Watch {
+    /// Start the application.
+    async fn run(&self) {
+        let service = Operation::start("watch");
+        service.status("loading the index");
+        let mut stash = self.stash.open();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        migration(&mut stash);
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            _ = sender.send(event);
+        })
+        .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+
+        let mut roots = vec![];
+        for path in self.options.paths.iter() {
+            let root = Root::new(path);
+            watcher
+                .watch(root.path(), RecursiveMode::Recursive)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, format!("{}: {e}", path.display())));
+
+            roots.push(root);
+        }
+
+        // start with a full pass, so we don't miss anything that
+        // changed before the watches were set up
+        service.status("committing all paths");
+        self.commit(&stash, self.options.paths.clone(), &service)
+            .await;
+        service.status(format!("watching {} paths", roots.len()));
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.interval.max(1)));
+        interval.tick().await;
+
+        loop {
+            let stop = tokio::select! {
+                _ = interval.tick() => false,
+                _ = tokio::signal::ctrl_c() => true,
+                _ = service.idle() => continue,
+            };
+
+            let mut changed = HashSet::new();
+            for event in events.try_iter() {
+                match event {
+                    Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                    Ok(event) => changed.extend(
+                        event
+                            .paths
+                            .iter()
+                            .filter_map(|p| roots.iter().find_map(|r| r.source_path(p))),
+                    ),
+                    Err(error) => warn!(%error, "failed to watch files"),
+                }
+            }
+
+            if !changed.is_empty() {
+                debug!(paths = changed.len(), "committing changes");
+                service.status(format!("committing {} changed paths", changed.len()));
+                self.commit(&stash, changed.into_iter().collect(), &service)
+                    .instrument(logging::operation_span("commit"))
+                    .await;
+                service.status(format!("watching {} paths", roots.len()));
+            }
+
+            if stop {
+                break;
+            }
+        }
+    }
+}
+
+impl Watch {
+    async fn commit(&self, stash: &Stash, paths: Vec<PathBuf>, service: &Operation) {
+        let options = zerostash_files::store::Options {
+            paths,
+            heartbeat: service.heartbeat(),
+            ..self.options.clone()
+        };
+
+        let added = options
+            .add_recursive(stash, APP.get_worker_threads())
+            .await
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+
+        let mut message = CommitMessage::new(self.message.as_deref(), vec![]);
+        message.set_added(&added);
+        if let Some(quantum) = self.stash.parse_stash().commit_time_quantum_secs {
+            message.quantize_time(SystemTime::now(), Duration::from_secs(quantum.get()));
+        }
+        message.set_previous(stash);
+        stash
+            .commit(
+                message
+                    .render(stash)
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    }
+}
@@ -17,7 +17,7 @@ impl AsyncRunnable for Wipe {
         let path = match config.resolve_stash(&self.stash) {
             None => self.stash.clone(),
             Some(stash) => match &stash.backend {
-                Filesystem { path } => path.clone(),
+                Filesystem { path, .. } => path.clone(),
                 _ => {
                     println!("Wipe: Non-local backend found, skipping...");
                     return;
@@ -25,6 +25,6 @@ impl AsyncRunnable for Wipe {
             },
         };
 
-        std::fs::remove_dir_all(path).expect("Error while wiping stash...");
+        std::fs::remove_dir_all(path).unwrap_or_else(|e| fail(ErrorKind::Io, e));
     }
 }
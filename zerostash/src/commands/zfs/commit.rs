@@ -8,7 +8,7 @@ use std::{
 use infinitree::Infinitree;
 use zerostash_files::{Files, ZfsSnapshot};
 
-use crate::prelude::*;
+use crate::{commit_message::chained, prelude::*};
 
 #[derive(Command, Debug)]
 pub struct ZfsCommit {
@@ -34,7 +34,9 @@ impl AsyncRunnable for ZfsCommit {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
-        stash.load(stash.index().zfs_snapshots()).unwrap();
+        stash
+            .load(stash.index().zfs_snapshots())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
 
         let args = {
             let mut args = self.arguments.to_vec();
@@ -48,18 +50,26 @@ impl AsyncRunnable for ZfsCommit {
 
         store_stream_from_stdout(&stash, self.name.clone(), &mut stdout).await;
 
-        let status = child.wait().expect("failed to wait for child process");
+        let status = child.wait().unwrap_or_else(|e| fail(ErrorKind::Io, e));
         let stderr = child.stderr.as_mut().expect("failed to open stderr");
         if !status.success() {
             let mut err = String::new();
-            stderr.read_to_string(&mut err).unwrap();
-            panic!("err: {}", err);
+            stderr
+                .read_to_string(&mut err)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            fail(ErrorKind::Io, format!("zfs send failed: {err}"));
         }
 
         stash
-            .commit(self.message.clone())
-            .expect("failed to write metadata");
-        stash.backend().sync().expect("failed to write to storage");
+            .commit(
+                chained(&stash, self.message.clone())
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
     }
 }
 
@@ -70,7 +80,7 @@ fn execute_command(arguments: &[String]) -> Child {
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .expect("failed to execute zfs send")
+        .unwrap_or_else(|e| fail(ErrorKind::Io, e))
 }
 
 async fn store_stream_from_stdout(
@@ -81,12 +91,18 @@ async fn store_stream_from_stdout(
     let snapshots = &stash.index().zfs_snapshots;
 
     if snapshots.get(&snapshot).is_some() {
-        panic!("cannot overwrite existing snapshot");
+        fail(
+            ErrorKind::Config,
+            format!("snapshot {snapshot} is stashed already"),
+        );
     }
 
-    let writer = stash.storage_writer().unwrap();
+    let writer = stash
+        .storage_writer()
+        .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
     let stream = abscissa_tokio::tokio::task::block_in_place(|| {
-        ZfsSnapshot::from_stdout(writer, stdout).expect("failed to capture snapshot")
+        zerostash_files::route::data(|| ZfsSnapshot::from_stdout(writer, stdout))
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e))
     });
 
     snapshots.insert(snapshot, stream);
@@ -1,6 +1,6 @@
 //! `zfs destroy` subcommand
 
-use crate::prelude::*;
+use crate::{commit_message::chained, prelude::*};
 
 #[derive(Command, Debug)]
 pub struct ZfsDestroy {
@@ -17,13 +17,21 @@ impl AsyncRunnable for ZfsDestroy {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
-        stash.load_all().unwrap();
+        stash
+            .load_all()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
 
         stash.index().zfs_snapshots.remove(self.name.clone());
 
         stash
-            .commit(format!("Destroyed snapshot '{}'", self.name))
-            .expect("failed to write metadata");
-        stash.backend().sync().expect("failed to write to storage");
+            .commit(
+                chained(&stash, format!("Destroyed snapshot '{}'", self.name))
+                    .unwrap_or_else(|e| fail(ErrorKind::Backend, e)),
+            )
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+        stash
+            .backend()
+            .sync()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
     }
 }
@@ -30,19 +30,23 @@ impl AsyncRunnable for ZfsExtract {
     /// Start the application.
     async fn run(&self) {
         let stash = self.stash.open();
-        stash.load(stash.index().zfs_snapshots()).unwrap();
+        stash
+            .load(stash.index().zfs_snapshots())
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
 
         let mut child = execute_command(&self.arguments);
         let stdin = child.stdin.as_mut().expect("failed to open stdin");
         write_stream_to_stdin(&stash, &self.name, stdin);
 
-        let status = child.wait().expect("failed to wait for child process");
+        let status = child.wait().unwrap_or_else(|e| fail(ErrorKind::Io, e));
 
         let stderr = child.stderr.as_mut().expect("failed to open stderr");
         if !status.success() {
             let mut err = String::new();
-            stderr.read_to_string(&mut err).unwrap();
-            panic!("err: {}", err);
+            stderr
+                .read_to_string(&mut err)
+                .unwrap_or_else(|e| fail(ErrorKind::Io, e));
+            fail(ErrorKind::Io, format!("zfs receive failed: {err}"));
         }
     }
 }
@@ -54,15 +58,20 @@ fn execute_command(arguments: &[String]) -> Child {
         .stdin(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .expect("failed to execute zfs receive")
+        .unwrap_or_else(|e| fail(ErrorKind::Io, e))
 }
 
 fn write_stream_to_stdin(stash: &Infinitree<Files>, snapshot: &str, stdin: &mut ChildStdin) {
     if let Some(stream) = stash.index().zfs_snapshots.get(snapshot) {
-        let reader = stash.storage_reader().unwrap();
+        let reader = stash
+            .storage_reader()
+            .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
         abscissa_tokio::tokio::task::block_in_place(|| stream.to_stdin(reader, stdin))
-            .expect("failed to write to stdin");
+            .unwrap_or_else(|e| fail(ErrorKind::Io, e));
     } else {
-        panic!("snapshot not stashed");
+        fail(
+            ErrorKind::Config,
+            format!("no snapshot {snapshot} in the stash"),
+        );
     }
 }
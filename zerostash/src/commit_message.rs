@@ -0,0 +1,574 @@
+//! Commit messages with structured annotations
+//!
+//! Annotations are recorded in the index with each commit, see
+//! [`zerostash_files::commit_annotations`]. Older versions stored them
+//! as trailer lines at the end of the commit message, which are still
+//! read.
+
+use crate::prelude::Stash;
+use infinitree::tree::{CommitId, CommitMetadata};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zerostash_files::{
+    commit_annotations::{self, Annotations},
+    custom_fields::Result,
+    roots::RootSpec,
+    store::Added,
+};
+
+/// Prefix of the trailer lines that older versions stored annotations in
+const ANNOTATION_PREFIX: &str = "Annotation: ";
+/// Annotation for the total size of new and changed files
+const ADDED_LOGICAL: &str = "added.logical";
+/// Annotation for the size of new chunks after deduplication and compression
+const ADDED_PHYSICAL: &str = "added.physical";
+/// Annotation for commits that reused stored contents without reading files
+const METADATA_ONLY: &str = "metadata-only";
+const INTERRUPTED: &str = "interrupted";
+/// Annotation for the number of files that were renamed
+const RENAMED: &str = "renamed";
+/// Prefix of the annotations that record how the paths were given
+const ROOT_PREFIX: &str = "root.";
+/// Annotation for the hash of the previous commit
+const CHAIN: &str = "chain";
+/// Annotation for the time a commit is shown with, if it's not the time
+/// it was stored with: when it was first made, if it was replayed into
+/// a new index later, or the quantized time of the commit
+const ORIGINAL_TIME: &str = "original-time";
+/// Annotation for the number of commits a compaction dropped before
+/// this one
+const COMPACTED: &str = "compacted";
+
+/// A commit message with a set of `key=value` annotations
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommitMessage {
+    pub message: Option<String>,
+    pub annotations: Annotations,
+}
+
+impl CommitMessage {
+    /// Create a new message from a template.
+    ///
+    /// `{date}`, `{time}` and `{KEY}` for every annotation `KEY` are
+    /// substituted in the template.
+    pub fn new(
+        template: Option<&str>,
+        annotations: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        let annotations = annotations.into_iter().collect::<BTreeMap<_, _>>();
+        let message = template.map(|t| expand_template(t, &annotations));
+
+        Self {
+            message,
+            annotations,
+        }
+    }
+
+    /// The message of the commit with `metadata`, and the annotations
+    /// that were recorded for it in `stash`. The custom fields of the
+    /// index must be loaded.
+    pub fn load(stash: &Stash, metadata: &CommitMetadata) -> Result<Self> {
+        let stored = commit_annotations::get(stash, metadata)?;
+        Ok(Self::parse(metadata.message.as_deref()).with_stored(stored.unwrap_or_default()))
+    }
+
+    /// Split a stored commit message into the message and the
+    /// annotations that older versions added to it.
+    pub fn parse(raw: Option<&str>) -> Self {
+        let Some(raw) = raw else {
+            return Self::default();
+        };
+
+        let mut lines = raw.lines().collect::<Vec<_>>();
+        let mut annotations = BTreeMap::new();
+
+        while let Some(annotation) = lines
+            .last()
+            .and_then(|l| l.strip_prefix(ANNOTATION_PREFIX))
+            .and_then(|l| parse_annotation(l).ok())
+        {
+            annotations.insert(annotation.0, annotation.1);
+            lines.pop();
+        }
+
+        let message = lines.join("\n").trim_end().to_string();
+
+        Self {
+            message: if message.is_empty() && !annotations.is_empty() {
+                None
+            } else {
+                Some(message)
+            },
+            annotations,
+        }
+    }
+
+    fn with_stored(mut self, annotations: Annotations) -> Self {
+        self.annotations.extend(annotations);
+        self
+    }
+
+    /// Record the annotations for the next commit of `stash`, and
+    /// return the message to commit.
+    pub fn render(&self, stash: &Stash) -> Result<Option<String>> {
+        if !self.annotations.is_empty() {
+            commit_annotations::record(stash, &self.annotations)?;
+        }
+
+        Ok(self.message.clone())
+    }
+
+    /// Record the amount of data the commit added.
+    ///
+    /// Renamed files are only recorded if there were any, and are shown
+    /// with the user's annotations, so they're visible in the log.
+    pub fn set_added(&mut self, added: &Added) {
+        self.annotations
+            .insert(ADDED_LOGICAL.into(), added.logical.to_string());
+        self.annotations
+            .insert(ADDED_PHYSICAL.into(), added.physical.to_string());
+        if added.renamed > 0 {
+            self.annotations
+                .insert(RENAMED.into(), added.renamed.to_string());
+        }
+    }
+
+    /// Mark the commit as only updating metadata. This is shown with
+    /// the user's annotations, so it's visible in the log.
+    pub fn set_metadata_only(&mut self) {
+        self.annotations.insert(METADATA_ONLY.into(), "true".into());
+    }
+
+    /// Mark the commit as interrupted, so it only has some of the
+    /// changes. This is shown with the user's annotations, so it's
+    /// visible in the log.
+    pub fn set_interrupted(&mut self) {
+        self.annotations.insert(INTERRUPTED.into(), "true".into());
+    }
+
+    /// Record how the committed paths were given on the command line.
+    pub fn set_roots(&mut self, roots: &[RootSpec]) {
+        for (i, root) in roots.iter().enumerate() {
+            self.annotations
+                .insert(format!("{ROOT_PREFIX}{i}"), root.to_string());
+        }
+    }
+
+    /// The committed paths, if they were recorded.
+    pub fn roots(&self) -> Vec<RootSpec> {
+        self.annotations
+            .iter()
+            .filter(|(k, _)| k.starts_with(ROOT_PREFIX))
+            .filter_map(|(_, v)| v.parse().ok())
+            .collect()
+    }
+
+    /// The amount of data the commit added, if it was recorded.
+    pub fn added(&self) -> Option<Added> {
+        let get = |key| self.annotations.get(key)?.parse().ok();
+
+        Some(Added {
+            logical: get(ADDED_LOGICAL)?,
+            physical: get(ADDED_PHYSICAL)?,
+            renamed: get(RENAMED).unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    /// Link the commit to the latest commit of `stash`, so removed or
+    /// reordered commits can be detected with [`verify_chain`].
+    ///
+    /// The first commit of a stash doesn't link to anything, even if
+    /// the message was copied from a commit that did.
+    pub fn set_previous(&mut self, stash: &Stash) {
+        self.link_to(stash.commit_list().last().map(|c| &c.metadata));
+    }
+
+    fn link_to(&mut self, previous: Option<&CommitMetadata>) {
+        match previous {
+            Some(metadata) => {
+                self.annotations.insert(CHAIN.into(), link(metadata));
+            }
+            None => {
+                self.annotations.remove(CHAIN);
+            }
+        }
+    }
+
+    /// Record when a replayed commit was first made. Replaying only
+    /// keeps the message, and the commit is stored with the time it
+    /// was replayed at.
+    ///
+    /// A time that's already recorded is kept, so it survives more than
+    /// one replay.
+    pub fn set_original_time(&mut self, time: SystemTime) {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.annotations
+            .entry(ORIGINAL_TIME.into())
+            .or_insert_with(|| time.as_nanos().to_string());
+    }
+
+    /// Record the time of the commit rounded down to a multiple of
+    /// `quantum`, so it's only known to that precision.
+    pub fn quantize_time(&mut self, now: SystemTime, quantum: Duration) {
+        self.set_original_time(quantize(now, quantum));
+    }
+
+    /// When the commit was first made, given the time it was stored with
+    pub fn time(&self, stored: SystemTime) -> SystemTime {
+        self.annotations
+            .get(ORIGINAL_TIME)
+            .and_then(|t| t.parse::<u64>().ok())
+            .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos))
+            .unwrap_or(stored)
+    }
+
+    /// Record that a compaction dropped `commits` before this commit, so
+    /// the history visibly starts here.
+    pub fn set_compacted(&mut self, commits: usize) {
+        self.annotations
+            .insert(COMPACTED.into(), commits.to_string());
+    }
+
+    /// The hash of the previous commit, if it was recorded.
+    pub fn previous(&self) -> Option<&str> {
+        self.annotations.get(CHAIN).map(String::as_str)
+    }
+
+    /// Annotations that were given by the user.
+    pub fn user_annotations(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.annotations.iter().filter(|(k, _)| {
+            *k != ADDED_LOGICAL
+                && *k != ADDED_PHYSICAL
+                && *k != CHAIN
+                && *k != ORIGINAL_TIME
+                && !k.starts_with(ROOT_PREFIX)
+        })
+    }
+
+    /// Returns true if all `filters` are present in the annotations.
+    pub fn matches(&self, filters: &[(String, String)]) -> bool {
+        filters
+            .iter()
+            .all(|(k, v)| self.annotations.get(k) == Some(v))
+    }
+}
+
+/// The paths committed to the stash over all commits. The custom
+/// fields of the index must be loaded.
+///
+/// If a path was committed more than once, the latest commit decides
+/// where it came from.
+pub fn stash_roots(stash: &Stash) -> Result<Vec<RootSpec>> {
+    let mut roots = BTreeMap::new();
+    for commit in stash.commit_list().iter() {
+        let message = CommitMessage::load(stash, &commit.metadata)?;
+        for root in message.roots() {
+            roots.insert(root.stored.clone(), root);
+        }
+    }
+
+    Ok(roots.into_values().collect())
+}
+
+/// Link the next commit of `stash` to its latest commit, and return
+/// `message` to commit.
+pub fn chained(stash: &Stash, message: impl Into<Option<String>>) -> Result<Option<String>> {
+    let mut message = CommitMessage::parse(message.into().as_deref());
+    message.set_previous(stash);
+    message.render(stash)
+}
+
+/// The hash of the serialized metadata of a commit, which links the
+/// commit after it
+fn link(metadata: &CommitMetadata) -> String {
+    let serialized =
+        serde_json::to_vec(metadata).expect("commit metadata can always be serialized");
+
+    let mut hasher = infinitree::Hasher::new();
+    hasher.update(&serialized);
+    hasher.finalize().to_hex().to_string()
+}
+
+/// A break in the chain of commits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChainError {
+    /// The first commit links to one that's missing
+    Truncated(CommitId),
+    /// The commit doesn't link to the one before it
+    Broken(CommitId),
+}
+
+/// The result of checking the links between commits
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainReport {
+    /// Commits that were made without a link, eg. by older versions
+    pub unlinked: Vec<CommitId>,
+    pub errors: Vec<ChainError>,
+    /// Hash of the latest commit. Keep it somewhere safe to detect if
+    /// commits are removed from the end later.
+    pub head: Option<String>,
+}
+
+/// Check that every linked commit follows the commit it links to.
+pub fn verify_chain<'a>(
+    commits: impl IntoIterator<Item = (&'a CommitId, &'a CommitMetadata, CommitMessage)>,
+) -> ChainReport {
+    let mut report = ChainReport::default();
+
+    for (id, metadata, message) in commits {
+        match (message.previous(), report.head.as_deref()) {
+            (Some(_), None) => report.errors.push(ChainError::Truncated(*id)),
+            (Some(prev), Some(head)) if prev != head => report.errors.push(ChainError::Broken(*id)),
+            (Some(_), Some(_)) => {}
+            (None, _) => report.unlinked.push(*id),
+        }
+
+        report.head = Some(link(metadata));
+    }
+
+    report
+}
+
+/// Parse a `key=value` pair on the command line.
+pub fn parse_annotation(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid annotation `{s}`: expected key=value"))?;
+
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+    {
+        return Err(format!("invalid annotation key `{key}`"));
+    }
+
+    if value.contains('\n') {
+        return Err(format!("annotation `{key}` contains a newline"));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Round `time` down to a multiple of `quantum` since the epoch.
+fn quantize(time: SystemTime, quantum: Duration) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let quantum = quantum.as_nanos().max(1);
+    let nanos = since_epoch.as_nanos() / quantum * quantum;
+
+    UNIX_EPOCH + Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+fn expand_template(template: &str, annotations: &BTreeMap<String, String>) -> String {
+    let now = chrono::Local::now();
+    let mut message = template
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{time}", &now.format("%H:%M:%S").to_string());
+
+    for (k, v) in annotations {
+        message = message.replace(&format!("{{{k}}}"), v);
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations() -> Vec<(String, String)> {
+        vec![
+            ("job".into(), "nightly".into()),
+            ("host".into(), "web1".into()),
+        ]
+    }
+
+    /// Store `message` like a commit does, and read it back.
+    fn stored(message: &CommitMessage) -> CommitMessage {
+        CommitMessage::parse(message.message.as_deref()).with_stored(message.annotations.clone())
+    }
+
+    #[test]
+    fn templates() {
+        let message = CommitMessage::new(Some("backup of {host}"), annotations());
+        assert_eq!(message.message.as_deref(), Some("backup of web1"));
+        assert_eq!(stored(&message), message);
+    }
+
+    #[test]
+    fn annotations_of_older_versions() {
+        let raw = "backup of web1\n\nAnnotation: host=web1\nAnnotation: job=nightly";
+        let message = CommitMessage::new(Some("backup of {host}"), annotations());
+        assert_eq!(CommitMessage::parse(Some(raw)), message);
+    }
+
+    #[test]
+    fn plain_messages_are_unchanged() {
+        let message = CommitMessage::new(Some("hello\n\nworld"), vec![]);
+        assert_eq!(CommitMessage::parse(Some("hello\n\nworld")), message);
+    }
+
+    #[test]
+    fn filter_annotations() {
+        let message = CommitMessage::new(None, annotations());
+        let parsed = stored(&message);
+        assert_eq!(parsed.message, None);
+
+        assert!(parsed.matches(&[("job".into(), "nightly".into())]));
+        assert!(!parsed.matches(&[("job".into(), "weekly".into())]));
+        assert!(parse_annotation("no_value").is_err());
+        assert!(parse_annotation("=value").is_err());
+    }
+
+    #[test]
+    fn added_sizes() {
+        let mut message = CommitMessage::new(None, annotations());
+        let added = Added {
+            logical: 1024,
+            physical: 300,
+            ..Default::default()
+        };
+        message.set_added(&added);
+
+        let parsed = stored(&message);
+        assert_eq!(parsed.added(), Some(added));
+        assert_eq!(parsed.user_annotations().count(), 2);
+    }
+
+    #[test]
+    fn recorded_roots() {
+        use zerostash_files::roots::{RootKind, RootSpec};
+
+        let root = RootSpec {
+            kind: RootKind::Absolute,
+            stored: "srv/data=1".into(),
+            original: "/srv/data=1".into(),
+        };
+        let mut message = CommitMessage::new(Some("backup"), annotations());
+        message.set_roots(&[root.clone()]);
+
+        let parsed = stored(&message);
+        assert_eq!(parsed.roots(), vec![root]);
+        assert_eq!(parsed.user_annotations().count(), 2);
+    }
+
+    #[test]
+    fn original_times() {
+        let first = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let replayed = UNIX_EPOCH + Duration::from_secs(2_000_000);
+
+        let mut message = CommitMessage::new(Some("backup"), annotations());
+        message.set_original_time(first);
+        message.set_original_time(replayed);
+
+        let parsed = stored(&message);
+        assert_eq!(parsed.time(replayed), first);
+        assert_eq!(parsed.user_annotations().count(), 2);
+        assert_eq!(CommitMessage::default().time(replayed), replayed);
+    }
+
+    #[test]
+    fn quantized_times() {
+        let hour = Duration::from_secs(3600);
+        let now = UNIX_EPOCH + Duration::from_secs(10 * 3600 + 1234) + Duration::from_nanos(5);
+
+        let mut message = CommitMessage::new(Some("backup"), vec![]);
+        message.quantize_time(now, hour);
+
+        let parsed = stored(&message);
+        assert_eq!(parsed.time(now), UNIX_EPOCH + 10 * hour);
+        assert_eq!(parsed.user_annotations().count(), 0);
+        assert_eq!(quantize(UNIX_EPOCH + hour, hour), UNIX_EPOCH + hour);
+    }
+
+    #[test]
+    fn chain_links() {
+        let message = "backup\n\nAnnotation: chain=abc\nAnnotation: job=nightly";
+        let parsed = CommitMessage::parse(Some(message));
+        assert_eq!(parsed.previous(), Some("abc"));
+        assert_eq!(parsed.user_annotations().count(), 1);
+    }
+
+    /// A chain of `n` linked commits, one second apart
+    fn chain(n: u8) -> Vec<(CommitId, CommitMetadata, CommitMessage)> {
+        let mut commits: Vec<(CommitId, CommitMetadata, CommitMessage)> = vec![];
+        for i in 0..n {
+            let mut message = CommitMessage::new(Some("backup"), annotations());
+            message.link_to(commits.last().map(|(_, md, _)| md));
+
+            let metadata = CommitMetadata {
+                previous: commits.last().map(|(id, ..)| *id),
+                message: message.message.clone(),
+                time: UNIX_EPOCH + Duration::from_secs(i.into()),
+            };
+            commits.push((CommitId::from_bytes([i; 32]), metadata, message));
+        }
+        commits
+    }
+
+    fn verify(commits: &[(CommitId, CommitMetadata, CommitMessage)]) -> ChainReport {
+        verify_chain(
+            commits
+                .iter()
+                .map(|(id, md, message)| (id, md, stored(message))),
+        )
+    }
+
+    #[test]
+    fn verify_good_chain() {
+        let commits = chain(3);
+        let report = verify(&commits);
+
+        assert_eq!(report.errors, vec![]);
+        assert_eq!(report.unlinked, vec![commits[0].0]);
+        assert_eq!(report.head, Some(link(&commits[2].1)));
+    }
+
+    #[test]
+    fn verify_tampered_chain() {
+        let mut commits = chain(3);
+        commits[1].1.time += Duration::from_secs(1);
+        assert_eq!(
+            verify(&commits).errors,
+            vec![ChainError::Broken(commits[2].0)]
+        );
+
+        let mut commits = chain(3);
+        let message = commits[0].1.message.take();
+        commits[0].1.message = message.map(|m| m.replace("backup", "restore"));
+        assert_eq!(
+            verify(&commits).errors,
+            vec![ChainError::Broken(commits[1].0)]
+        );
+    }
+
+    #[test]
+    fn verify_truncated_chain() {
+        let mut commits = chain(3);
+        commits.remove(0);
+        assert_eq!(
+            verify(&commits).errors,
+            vec![ChainError::Truncated(commits[0].0)]
+        );
+
+        let mut commits = chain(3);
+        commits.remove(1);
+        assert_eq!(
+            verify(&commits).errors,
+            vec![ChainError::Broken(commits[1].0)]
+        );
+
+        let mut commits = chain(3);
+        commits.swap(1, 2);
+        assert_eq!(
+            verify(&commits).errors,
+            vec![
+                ChainError::Broken(commits[1].0),
+                ChainError::Broken(commits[2].0)
+            ]
+        );
+    }
+}
@@ -4,11 +4,11 @@
 //! application's configuration file and/or command-line options
 //! for specifying it.
 
-use crate::{application::APP, prelude::Stash as InfiniStash};
+use crate::{application::APP, error::ErrorKind, prelude::Stash as InfiniStash};
 use abscissa_core::Application;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+use std::{collections::HashMap, num::NonZeroU64, path::PathBuf, str::FromStr, sync::Arc};
 
 mod crypto_box_keys;
 pub use crypto_box_keys::*;
@@ -21,6 +21,19 @@ mod key;
 pub use key::*;
 mod backend;
 pub use backend::*;
+mod commit;
+pub use commit::*;
+mod mount;
+pub use mount::*;
+mod pool;
+pub use pool::*;
+mod telemetry;
+pub use telemetry::*;
+mod update;
+pub use update::*;
+pub mod encrypted;
+mod overrides;
+pub use overrides::Override;
 
 pub trait KeyToSource {
     type Target;
@@ -34,6 +47,14 @@ pub struct ZerostashConfig {
     /// An example configuration section
     #[serde(rename = "stash", default)]
     stashes: HashMap<String, Stash>,
+
+    /// Settings for `0s self-update`
+    #[serde(default)]
+    pub update: UpdateConfig,
+
+    /// Overrides given on the command line
+    #[serde(skip)]
+    pub overrides: Vec<Override>,
 }
 
 /// Describe the configuration for a named stash
@@ -44,6 +65,26 @@ pub struct Stash {
     pub key: Key,
     /// Backend configuration for the stash
     pub backend: Backend,
+    /// Mount the stash with `0s mount --all`
+    #[serde(default)]
+    pub mount: Option<MountConfig>,
+    /// Commit to the stash with `0s commit --all`
+    #[serde(default)]
+    pub commit: Option<CommitConfig>,
+    /// Share chunks with other stashes through a common data pool
+    #[serde(default)]
+    pub pool: Option<PoolConfig>,
+    /// Opt in to reporting anonymous metrics of each run
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+    /// Size of the objects of a new stash, in bytes. An existing stash
+    /// keeps the size it was created with.
+    #[serde(default)]
+    pub object_size: Option<u64>,
+    /// Round the time of new commits down to a multiple of this many
+    /// seconds, eg. 3600 to only record the hour
+    #[serde(default)]
+    pub commit_time_quantum_secs: Option<NonZeroU64>,
 
     /// Name as referenced by the user. We can't deserialize this.
     /// However, when reading the config, `resolve_stash` will populate it.
@@ -55,53 +96,189 @@ impl FromStr for Stash {
     type Err = anyhow::Error;
 
     fn from_str(name: &str) -> Result<Self, Self::Err> {
-        let mut stash = match APP.config().resolve_stash(name) {
+        Self::resolve(&APP.config(), name)
+    }
+}
+
+impl Stash {
+    /// Find the stash `name` in `config`, or use it as the path or url
+    /// of a backend.
+    ///
+    /// Unlike parsing a stash, this doesn't need the global
+    /// configuration, so several stashes can be opened independently.
+    pub fn resolve(config: &ZerostashConfig, name: &str) -> Result<Self> {
+        let mut stash = match config.try_resolve_stash(name)? {
             Some(stash) => stash,
             None => Stash {
                 backend: name.parse()?,
                 alias: name.to_string(),
                 key: Default::default(),
+                mount: None,
+                commit: None,
+                pool: None,
+                telemetry: None,
+                object_size: None,
+                commit_time_quantum_secs: None,
             },
         };
 
-        if let Backend::Filesystem { path } = &stash.backend {
+        if let Backend::Filesystem { path, .. } = &stash.backend {
             stash.alias = path.clone();
         };
 
         Ok(stash)
     }
-}
 
-impl Stash {
+    /// Where the files cache of the stash is kept on this machine
+    #[cfg(unix)]
+    pub fn files_cache_path(&self) -> PathBuf {
+        xdg::BaseDirectories::with_prefix("zerostash")
+            .unwrap()
+            .place_cache_file(format!("files/{}", self.cache_name()))
+            .expect("cannot create cache directory")
+    }
+
+    /// Where the files cache of the stash is kept on this machine
+    #[cfg(windows)]
+    pub fn files_cache_path(&self) -> PathBuf {
+        let mut p = dirs::home_dir().expect("cannot find home directory");
+
+        p.push(".zerostash");
+        p.push("files");
+        std::fs::create_dir_all(&p).expect("failed to create cache dir");
+
+        p.push(self.cache_name());
+        p
+    }
+
+    /// Where the progress of scrubbing the stash is kept on this machine
+    #[cfg(unix)]
+    pub fn scrub_progress_path(&self) -> PathBuf {
+        xdg::BaseDirectories::with_prefix("zerostash")
+            .unwrap()
+            .place_state_file(format!("scrub/{}.json", self.cache_name()))
+            .expect("cannot create state directory")
+    }
+
+    /// Where the progress of scrubbing the stash is kept on this machine
+    #[cfg(windows)]
+    pub fn scrub_progress_path(&self) -> PathBuf {
+        let mut p = dirs::home_dir().expect("cannot find home directory");
+
+        p.push(".zerostash");
+        p.push("scrub");
+        std::fs::create_dir_all(&p).expect("failed to create state dir");
+
+        p.push(format!("{}.json", self.cache_name()));
+        p
+    }
+
+    /// A file name that's unique to the stash
+    fn cache_name(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, self.alias.as_bytes());
+        digest.as_ref()[..16]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
     fn get_locators(
         &self,
         override_key: Option<Key>,
     ) -> Result<(Arc<dyn infinitree::backends::Backend>, infinitree::Key)> {
-        let backend = self.backend.to_infinitree()?;
+        let backend = self
+            .backend
+            .to_infinitree()
+            .map_err(|e| ErrorKind::Backend.error(e))?;
 
+        Ok((backend, self.keysource(override_key)?))
+    }
+
+    fn keysource(&self, override_key: Option<Key>) -> Result<infinitree::Key> {
         // This is to use absolute paths in the FS.
         let keysource = match override_key {
             Some(key) => key,
             None => self.key.clone(),
         }
-        .to_keysource(&self.alias)?;
+        .to_keysource(&self.alias)
+        .map_err(|e| ErrorKind::Auth.error(e))?;
 
-        Ok((backend, keysource))
+        Ok(keysource)
+    }
+
+    /// Open the stash from `backend` instead of the configured one, eg.
+    /// to read it from a bundle.
+    pub fn open_from(
+        &self,
+        override_key: Option<Key>,
+        backend: Arc<dyn infinitree::backends::Backend>,
+    ) -> Result<InfiniStash> {
+        let key = self.keysource(override_key)?;
+        InfiniStash::open(backend, key).map_err(|e| ErrorKind::Auth.error(e).into())
     }
 
     /// Try to open a stash with the config-stored credentials
     pub fn try_open(&self, override_key: Option<Key>) -> Result<InfiniStash> {
         let (backend, key) = self.get_locators(override_key)?;
-        InfiniStash::open(backend, key)
+        InfiniStash::open(backend, key).map_err(|e| ErrorKind::Auth.error(e).into())
     }
 
     pub fn open_or_new(&self, override_key: Option<Key>) -> Result<InfiniStash> {
         let (backend, key) = self.get_locators(override_key)?;
         let stash = InfiniStash::open(backend.clone(), key.clone())
-            .or_else(|_| InfiniStash::empty(backend, key))?;
+            .or_else(|_| InfiniStash::empty(backend, key))
+            .map_err(|e| ErrorKind::Backend.error(e))?;
 
         Ok(stash)
     }
+
+    /// Open the stash, and start a new, empty index with the same key.
+    ///
+    /// `wrap` returns the backends to read the stash from, and to write
+    /// the new index to, for the stash backend.
+    pub fn open_for_rewrite(
+        &self,
+        override_key: Option<Key>,
+        wrap: impl FnOnce(
+            Arc<dyn infinitree::backends::Backend>,
+        ) -> Result<(
+            Arc<dyn infinitree::backends::Backend>,
+            Arc<dyn infinitree::backends::Backend>,
+        )>,
+    ) -> Result<Rewrite> {
+        let (backend, key) = self.get_locators(override_key)?;
+        let (read, write) = wrap(backend.clone())?;
+        let stash = InfiniStash::open(read, key.clone()).map_err(|e| ErrorKind::Auth.error(e))?;
+        let rewritten =
+            InfiniStash::empty(write, key.clone()).map_err(|e| ErrorKind::Backend.error(e))?;
+
+        Ok(Rewrite {
+            stash,
+            rewritten,
+            backend,
+            key,
+        })
+    }
+}
+
+/// A stash, and the new index it's rewritten into
+pub struct Rewrite {
+    pub stash: InfiniStash,
+    pub rewritten: InfiniStash,
+    backend: Arc<dyn infinitree::backends::Backend>,
+    key: infinitree::Key,
+}
+
+impl Rewrite {
+    /// The commits that are stored in the backend now, which differ
+    /// from the ones of [`Rewrite::stash`] if someone committed since it
+    /// was opened.
+    pub fn stored_commits(&self) -> Result<Vec<infinitree::tree::CommitId>> {
+        let stash = InfiniStash::open(self.backend.clone(), self.key.clone())
+            .map_err(|e| ErrorKind::Auth.error(e))?;
+        let commits = stash.commit_list().iter().map(|c| c.id).collect();
+        Ok(commits)
+    }
 }
 
 impl ZerostashConfig {
@@ -133,14 +310,53 @@ impl ZerostashConfig {
 
     /// Find a stash by name in the config, and return a read-only
     /// reference if found
+    ///
+    /// Overrides from the environment and the command line are applied,
+    /// and may define stashes that are not in the configuration file.
     pub fn resolve_stash(&self, alias: impl AsRef<str>) -> Option<Stash> {
-        match self.stashes.get(alias.as_ref()).cloned() {
-            Some(mut stash) => {
-                stash.alias = alias.as_ref().to_string();
-                Some(stash)
-            }
-            None => None,
-        }
+        self.try_resolve_stash(alias).unwrap_or_else(|e| {
+            crate::prelude::fail(crate::prelude::ErrorKind::Config, format!("{e:#}"))
+        })
+    }
+
+    /// Find a stash by name like [`Self::resolve_stash`], and return
+    /// invalid overrides as errors
+    pub fn try_resolve_stash(&self, alias: impl AsRef<str>) -> Result<Option<Stash>> {
+        let alias = alias.as_ref();
+        let stash = self.stashes.get(alias).cloned().map(|mut stash| {
+            stash.alias = alias.to_string();
+            stash
+        });
+
+        let mut overrides = overrides::from_env(alias);
+        overrides.extend(self.overrides.iter().filter(|o| o.stash == alias).cloned());
+
+        overrides::apply(stash, alias, &overrides)
+    }
+
+    /// All stashes that have a mount point configured, sorted by alias
+    pub fn mounts(&self) -> Vec<Stash> {
+        self.stashes_with(|stash| stash.mount.is_some())
+    }
+
+    /// All stashes that have paths to commit configured, sorted by alias
+    pub fn commits(&self) -> Vec<Stash> {
+        self.stashes_with(|stash| stash.commit.is_some())
+    }
+
+    fn stashes_with(&self, f: impl Fn(&Stash) -> bool) -> Vec<Stash> {
+        let mut aliases = self
+            .stashes
+            .iter()
+            .filter(|(_, stash)| f(stash))
+            .map(|(alias, _)| alias)
+            .collect::<Vec<_>>();
+        aliases.sort();
+
+        aliases
+            .into_iter()
+            .filter_map(|alias| self.resolve_stash(alias))
+            .collect()
     }
 }
 
@@ -157,9 +373,14 @@ mod tests {
 [stash.first]
 key = { source = "plaintext", user = "123", password = "123"}
 backend = { type = "fs", path = "/path/to/stash" }
+mount = { mountpoint = "/mnt/backups/first", allow_other = true, permissions = "kernel" }
+
+[update]
+channel = "nightly"
 
 [stash.second]
 key = { source = "ask"}
+pool = { catalog = "/path/to/catalog", member = "second" }
 backend = { type = "fs", path = "/path/to/stash" }
 
 [stash.yubikey]
@@ -196,6 +417,14 @@ backend = { type = "s3", bucket = "test_bucket", region = { name = "us-east-1" }
 key = { source = "ask" }
 backend = { type = "s3", bucket = "test_bucket", region = { name = "us-east-1" } }
 
+[stash.checksummed]
+key = { source = "ask" }
+backend = { type = "checksum", checksum_log = "/path/to/log", upstream = { type = "fs", path = "/path/to/stash" } }
+
+[stash.migrating]
+key = { source = "ask" }
+backend = { type = "mirror", primary = { type = "fs", path = "/path/to/new" }, fallback = { type = "fs", path = "/path/to/old" } }
+
 [stash.s3_cached]
 key = { source = "ask" }
 
@@ -277,7 +506,9 @@ backend = { type = "fs", path = "/path/to/stash" }
             Backend::S3 {
                 bucket: "bucket/path".into(),
                 region: Region::UsEast1,
-                keys: Some(("access".into(), "secret".into()))
+                keys: Some(("access".into(), "secret".into())),
+                upload_threads: None,
+                upload_jitter_secs: None
             }
         );
 
@@ -286,7 +517,9 @@ backend = { type = "fs", path = "/path/to/stash" }
             Backend::S3 {
                 bucket: "bucket/path".into(),
                 region: Region::UsEast1,
-                keys: None
+                keys: None,
+                upload_threads: None,
+                upload_jitter_secs: None
             }
         );
 
@@ -300,7 +533,9 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "us-east-1".into(),
                     endpoint: "server.com".into()
                 },
-                keys: None
+                keys: None,
+                upload_threads: None,
+                upload_jitter_secs: None
             }
         );
 
@@ -314,7 +549,9 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "".into(),
                     endpoint: "server.com".into()
                 },
-                keys: Some(("access".into(), "secret-".into()))
+                keys: Some(("access".into(), "secret-".into())),
+                upload_threads: None,
+                upload_jitter_secs: None
             }
         );
 
@@ -328,7 +565,9 @@ backend = { type = "fs", path = "/path/to/stash" }
                     region: "us-east-1".into(),
                     endpoint: "server.com".into()
                 },
-                keys: Some(("accesskey".into(), "secret+key/=".into()))
+                keys: Some(("accesskey".into(), "secret+key/=".into())),
+                upload_threads: None,
+                upload_jitter_secs: None
             }
         )
     }
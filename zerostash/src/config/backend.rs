@@ -1,13 +1,27 @@
 use super::Result;
 use anyhow::Context;
+use infinitree::object::ObjectId;
 use infinitree_backends::Region;
 use serde::{Deserialize, Serialize};
 use std::{
-    num::NonZeroUsize,
+    collections::HashSet,
+    fmt,
+    num::{NonZeroU64, NonZeroUsize},
     path::{Component, Path, PathBuf},
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
+use zerostash_files::{
+    list::{Local, Storage, Unlisted},
+    probe::{probe, Latency, ProbeError, Step},
+};
+
+const PREFETCH_THREADS: usize = 4;
+const PREFETCH_OBJECTS: usize = 32;
+/// Concurrent uploads per CPU for remote backends, unless configured
+const UPLOAD_THREADS_PER_CPU: usize = 4;
+const MAX_UPLOAD_THREADS: usize = 64;
 
 /// Backend configuration
 /// This may be specific to the backend type
@@ -17,9 +31,21 @@ use std::{
 #[non_exhaustive]
 pub enum Backend {
     /// Use a directory on a local filesystem
+    ///
+    /// Objects are memory-mapped when they're read, unless `no_mmap`
+    /// is set.
     #[serde(rename = "fs")]
     #[allow(missing_docs)]
-    Filesystem { path: String },
+    Filesystem {
+        path: String,
+        /// Split the stash into volume subdirectories, eg. on FAT disks
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        volumes: Option<Volumes>,
+        /// Read objects into memory instead of mapping them, eg. on
+        /// network filesystems. Doesn't apply to volumes.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        no_mmap: bool,
+    },
 
     /// Descriptor for S3 connection.
     #[serde(rename = "s3")]
@@ -33,6 +59,16 @@ pub enum Backend {
 
         /// ("access_key_id", "secret_access_key")
         keys: Option<(String, String)>,
+
+        /// Maximum number of concurrent uploads.
+        /// Defaults to a multiple of the available CPUs.
+        #[serde(default)]
+        upload_threads: Option<NonZeroUsize>,
+
+        /// Delay each upload by a random time of up to this many
+        /// seconds, to hide when data was written.
+        #[serde(default)]
+        upload_jitter_secs: Option<NonZeroU64>,
     },
 
     /// Cache files in a local directory, up to `max_size` in size
@@ -46,18 +82,103 @@ pub enum Backend {
         /// Long-term backend
         upstream: Box<Backend>,
     },
+
+    /// Send objects to different backends depending on what they contain
+    #[serde(rename = "route")]
+    Route {
+        /// Local file that records which backend holds each object
+        placement_log: String,
+        /// Backend for objects that don't match any route
+        default: Box<Backend>,
+        /// Objects are written to the first route for their kind
+        routes: Vec<BackendRoute>,
+    },
+
+    /// Record unkeyed checksums of all objects, and check them on read
+    #[serde(rename = "checksum")]
+    Checksum {
+        /// Local file that stores the checksum of each object
+        checksum_log: String,
+        /// Backend that stores the objects
+        upstream: Box<Backend>,
+    },
+
+    /// Read objects that are missing from `primary` from a read-only
+    /// `fallback`, eg. while migrating to a new backend
+    #[serde(rename = "mirror")]
+    Mirror {
+        /// Backend for all reads and writes
+        primary: Box<Backend>,
+        /// Backend with the objects from before the migration
+        fallback: Box<Backend>,
+    },
+
+    /// Write new objects to a local directory, and upload them to
+    /// `upstream` later with `0s upload`
+    #[serde(rename = "spool")]
+    Spool {
+        /// Directory of the objects that are not uploaded yet
+        path: String,
+        /// Backend the objects are uploaded to
+        upstream: Box<Backend>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Volumes {
+    /// Start a new volume after this many objects
+    pub max_objects: NonZeroUsize,
+    /// Directories of earlier disks of the stash. Objects are read
+    /// from the volumes in them, but only written to `path`
+    #[serde(default)]
+    pub mounts: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BackendRoute {
+    /// Kind of objects to store here
+    pub objects: zerostash_files::route::Kind,
+    /// Where to store matching objects
+    pub backend: Backend,
 }
 
 impl Backend {
-    pub(super) fn to_infinitree(&self) -> Result<Arc<dyn infinitree::backends::Backend>> {
+    pub(crate) fn to_infinitree(&self) -> Result<Arc<dyn infinitree::backends::Backend>> {
+        Ok(self.to_storage()?.into_backend())
+    }
+
+    fn to_storage(&self) -> Result<Arc<dyn Storage>> {
         use Backend::*;
 
-        let backend: Arc<dyn infinitree::backends::Backend> = match self {
-            Filesystem { path } => infinitree::backends::Directory::new(path)?,
+        let backend: Arc<dyn Storage> = match self {
+            Filesystem {
+                path,
+                volumes: None,
+                no_mmap: false,
+            } => Local::new(path)?,
+            Filesystem {
+                path,
+                volumes: None,
+                no_mmap: true,
+            } => zerostash_files::unmapped::Unmapped::new(path)?,
+            Filesystem {
+                path,
+                volumes: Some(volumes),
+                ..
+            } => zerostash_files::volumes::Volumes::new(
+                path,
+                volumes.max_objects.get(),
+                &volumes.mounts,
+            )
+            .with_context(|| format!("Failed to open the volumes of {path}"))?,
             S3 {
                 bucket,
                 region,
                 keys,
+                upload_threads,
+                upload_jitter_secs,
             } => {
                 use infinitree_backends::{Credentials, S3};
 
@@ -69,22 +190,330 @@ impl Backend {
                     ),
                     None => S3::new(region.clone(), bucket),
                 }
-                .context("Failed to connect to S3")?
+                .context("Failed to connect to S3")
+                .map(|s3| prefetch(Unlisted::new(s3)))
+                .map(|backend| upload(backend, *upload_threads, *upload_jitter_secs))?
             }
             FsCache {
                 max_size_mb,
                 path,
                 upstream,
-            } => infinitree_backends::Cache::new(
+            } => zerostash_files::cache::Cache::new(
                 path,
-                NonZeroUsize::new(max_size_mb.get() * 1024 * 1024)
-                    .expect("Deserialization should have failed if `max_size_mb` is 0"),
-                upstream.to_infinitree()?,
-            )?,
+                max_size_mb.get() * 1024 * 1024,
+                upstream.to_storage()?,
+            )
+            .with_context(|| format!("Failed to open cache in {path}"))
+            .map(prefetch)?,
+            Route {
+                placement_log,
+                default,
+                routes,
+            } => zerostash_files::route::Router::new(
+                default.to_storage()?,
+                routes
+                    .iter()
+                    .map(|r| {
+                        Ok(zerostash_files::route::Route {
+                            kind: r.objects,
+                            backend: r.backend.to_storage()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                placement_log,
+            )
+            .context("Failed to open placement log")?,
+            Checksum {
+                checksum_log,
+                upstream,
+            } => zerostash_files::checksum::Checksummed::new(upstream.to_storage()?, checksum_log)
+                .context("Failed to open checksum log")?,
+            Mirror { primary, fallback } => {
+                zerostash_files::mirror::Mirror::new(primary.to_storage()?, fallback.to_storage()?)
+            }
+            Spool { path, upstream } => {
+                zerostash_files::spool::Spool::new(path, upstream.to_storage()?)
+                    .with_context(|| format!("Failed to open spool in {path}"))?
+            }
         };
 
         Ok(backend)
     }
+
+    /// Use the given credentials for all S3 backends.
+    pub fn set_s3_keys(&mut self, access_key: &str, secret_key: &str) {
+        use Backend::*;
+
+        match self {
+            Filesystem { .. } => {}
+            S3 { keys, .. } => *keys = Some((access_key.to_string(), secret_key.to_string())),
+            FsCache { upstream, .. } | Checksum { upstream, .. } | Spool { upstream, .. } => {
+                upstream.set_s3_keys(access_key, secret_key)
+            }
+            Route {
+                default, routes, ..
+            } => {
+                default.set_s3_keys(access_key, secret_key);
+                for route in routes.iter_mut() {
+                    route.backend.set_s3_keys(access_key, secret_key);
+                }
+            }
+            Mirror { primary, fallback } => {
+                primary.set_s3_keys(access_key, secret_key);
+                fallback.set_s3_keys(access_key, secret_key);
+            }
+        }
+    }
+
+    /// Check all objects against the checksum log without decrypting
+    /// them. Returns `None` if the backend doesn't record checksums.
+    pub fn verify_checksums(&self) -> Result<Option<zerostash_files::checksum::Report>> {
+        let Backend::Checksum {
+            checksum_log,
+            upstream,
+        } = self
+        else {
+            return Ok(None);
+        };
+
+        let report =
+            zerostash_files::checksum::verify(upstream.to_infinitree()?.as_ref(), checksum_log)
+                .context("Failed to read checksum log")?;
+
+        Ok(Some(report))
+    }
+
+    /// Iterate over all objects stored in the backend.
+    ///
+    /// Objects that are stored in more than one of the underlying
+    /// backends are listed once for each.
+    pub fn list_objects(&self) -> Result<ObjectList> {
+        let list = self
+            .to_storage()?
+            .list()
+            .with_context(|| format!("Failed to list objects in {self}"))?
+            .map(|id| id.context("Failed to list objects"));
+
+        Ok(Box::new(list))
+    }
+
+    /// Objects that are stored in a local `fs_cache`, and can be read
+    /// without downloading them.
+    pub fn cached_objects(&self) -> Result<HashSet<ObjectId>> {
+        use Backend::*;
+
+        let mut cached = HashSet::new();
+        match self {
+            Filesystem { .. } | S3 { .. } => {}
+            FsCache { path, upstream, .. } => {
+                // the cache directory is only created on first use
+                if Path::new(path).exists() {
+                    for id in zerostash_files::list::Directory::new(path)
+                        .with_context(|| format!("Failed to list objects in {path}"))?
+                    {
+                        cached.insert(id?);
+                    }
+                }
+                cached.extend(upstream.cached_objects()?);
+            }
+            Checksum { upstream, .. } => cached.extend(upstream.cached_objects()?),
+            Spool { path, upstream } => {
+                // spooled objects are local until they're uploaded
+                for id in zerostash_files::list::Directory::new(path)
+                    .with_context(|| format!("Failed to list objects in {path}"))?
+                {
+                    cached.insert(id?);
+                }
+                cached.extend(upstream.cached_objects()?);
+            }
+            Route {
+                default, routes, ..
+            } => {
+                cached.extend(default.cached_objects()?);
+                for route in routes.iter() {
+                    cached.extend(route.backend.cached_objects()?);
+                }
+            }
+            Mirror { primary, fallback } => {
+                cached.extend(primary.cached_objects()?);
+                cached.extend(fallback.cached_objects()?);
+            }
+        }
+
+        Ok(cached)
+    }
+
+    /// The directories of all `fs_cache` backends in the tree.
+    pub fn cache_paths(&self) -> Vec<&str> {
+        use Backend::*;
+
+        match self {
+            Filesystem { .. } | S3 { .. } => vec![],
+            FsCache { path, upstream, .. } => {
+                let mut paths = vec![path.as_str()];
+                paths.extend(upstream.cache_paths());
+                paths
+            }
+            // the spool is not a cache, clearing it would lose objects
+            Checksum { upstream, .. } | Spool { upstream, .. } => upstream.cache_paths(),
+            Route {
+                default, routes, ..
+            } => std::iter::once(default.as_ref())
+                .chain(routes.iter().map(|r| &r.backend))
+                .flat_map(Backend::cache_paths)
+                .collect(),
+            Mirror { primary, fallback } => {
+                let mut paths = primary.cache_paths();
+                paths.extend(fallback.cache_paths());
+                paths
+            }
+        }
+    }
+
+    /// The directories of all `spool` backends in the tree, with the
+    /// backend they upload to.
+    pub fn spools(&self) -> Vec<(&str, &Backend)> {
+        use Backend::*;
+
+        match self {
+            Filesystem { .. } | S3 { .. } => vec![],
+            FsCache { upstream, .. } | Checksum { upstream, .. } => upstream.spools(),
+            Route {
+                default, routes, ..
+            } => std::iter::once(default.as_ref())
+                .chain(routes.iter().map(|r| &r.backend))
+                .flat_map(Backend::spools)
+                .collect(),
+            Mirror { primary, .. } => primary.spools(),
+            Spool { path, upstream } => {
+                let mut spools = vec![(path.as_str(), upstream.as_ref())];
+                spools.extend(upstream.spools());
+                spools
+            }
+        }
+    }
+
+    /// The backends that store objects, without the layers on top.
+    pub fn storage(&self) -> Vec<&Backend> {
+        use Backend::*;
+
+        match self {
+            Filesystem { .. } | S3 { .. } => vec![self],
+            FsCache { upstream, .. } | Checksum { upstream, .. } | Spool { upstream, .. } => {
+                upstream.storage()
+            }
+            Route {
+                default, routes, ..
+            } => std::iter::once(default.as_ref())
+                .chain(routes.iter().map(|r| &r.backend))
+                .flat_map(Backend::storage)
+                .collect(),
+            Mirror { primary, fallback } => {
+                let mut storage = primary.storage();
+                storage.extend(fallback.storage());
+                storage
+            }
+        }
+    }
+
+    /// Write, read and delete a probe object in every storage backend.
+    ///
+    /// Read-only fallbacks of a migration are skipped.
+    pub fn probe(&self) -> Vec<(&Backend, Result<Latency>)> {
+        let storage = match self {
+            Backend::Mirror { primary, .. } => primary.storage(),
+            _ => self.storage(),
+        };
+
+        storage
+            .into_iter()
+            .map(|backend| {
+                let latency = backend.to_infinitree().and_then(|infinitree| {
+                    probe(infinitree.as_ref())
+                        .map_err(|e| anyhow::anyhow!("{e}\n{}", backend.hint(&e)))
+                });
+                (backend, latency)
+            })
+            .collect()
+    }
+
+    /// What to check when probing the backend failed
+    fn hint(&self, error: &ProbeError) -> &'static str {
+        if matches!(error, ProbeError::Mismatch(_)) {
+            return "The backend returned different contents than what was written. \
+                    Check for proxies or caches in between";
+        }
+
+        let message = error.to_string().to_lowercase();
+        let denied = [
+            "accessdenied",
+            "403",
+            "invalidaccesskeyid",
+            "signaturedoesnotmatch",
+        ]
+        .iter()
+        .any(|s| message.contains(s));
+
+        match (self, error.step()) {
+            (Backend::S3 { .. }, Step::Write) if message.contains("nosuchbucket") => {
+                "The bucket does not exist. Create it, or check the bucket name"
+            }
+            (Backend::S3 { .. }, Step::Write) if denied => {
+                "The access keys were rejected, or can't write objects (s3:PutObject)"
+            }
+            (Backend::S3 { .. }, Step::Write) => {
+                "Check the region, the endpoint and the network connection"
+            }
+            (Backend::S3 { .. }, Step::Read) => "The access keys can't read objects (s3:GetObject)",
+            (Backend::S3 { .. }, Step::Delete) => {
+                "The access keys can't delete objects (s3:DeleteObject), which prune needs"
+            }
+            (_, Step::Write) => "Check that the directory exists, and this user can write to it",
+            (_, Step::Read) => "Check that this user can read the files in the directory",
+            (_, Step::Delete) => "Check that this user can delete files in the directory",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Backend::*;
+
+        match self {
+            Filesystem { path, .. } => write!(f, "{path}"),
+            S3 { bucket, .. } => write!(f, "s3 bucket {bucket}"),
+            FsCache { path, upstream, .. } => write!(f, "{upstream} (cached in {path})"),
+            Route { default, .. } => write!(f, "{default} (routed)"),
+            Checksum { upstream, .. } => write!(f, "{upstream} (checksummed)"),
+            Mirror { primary, fallback } => write!(f, "{primary} (mirroring {fallback})"),
+            Spool { path, upstream } => write!(f, "{upstream} (spooled in {path})"),
+        }
+    }
+}
+
+/// Lazily listed object ids
+pub type ObjectList = Box<dyn Iterator<Item = Result<ObjectId>>>;
+
+/// Fetch objects for remote backends in the background when asked to
+/// preload them.
+fn prefetch(backend: Arc<impl Storage + 'static>) -> Arc<dyn Storage> {
+    zerostash_files::prefetch::Prefetch::new(backend, PREFETCH_THREADS, PREFETCH_OBJECTS)
+}
+
+/// Upload objects on separate IO threads, so CPU workers don't wait
+/// on the network.
+fn upload(
+    backend: Arc<dyn Storage>,
+    threads: Option<NonZeroUsize>,
+    jitter_secs: Option<NonZeroU64>,
+) -> Arc<dyn Storage> {
+    let threads = threads.map(NonZeroUsize::get).unwrap_or_else(|| {
+        let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        (cpus * UPLOAD_THREADS_PER_CPU).min(MAX_UPLOAD_THREADS)
+    });
+
+    let jitter = Duration::from_secs(jitter_secs.map_or(0, NonZeroU64::get));
+    zerostash_files::upload::Upload::with_jitter(backend, threads, jitter)
 }
 
 impl FromStr for Backend {
@@ -128,6 +557,8 @@ impl FromStr for Backend {
                     bucket,
                     region,
                     keys,
+                    upload_threads: None,
+                    upload_jitter_secs: None,
                 })
             }
             Some(_) => anyhow::bail!("protocol not supported"),
@@ -139,7 +570,11 @@ impl FromStr for Backend {
                 .to_string_lossy()
                 .to_string();
 
-                Ok(Self::Filesystem { path })
+                Ok(Self::Filesystem {
+                    path,
+                    volumes: None,
+                    no_mmap: false,
+                })
             }
         }
     }
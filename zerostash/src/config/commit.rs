@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// What to commit to a stash with `0s commit --all`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CommitConfig {
+    /// The paths to commit, as given to `0s commit`
+    pub paths: Vec<PathBuf>,
+
+    /// Commit message. Defaults to the one given on the command line
+    #[serde(default)]
+    pub message: Option<String>,
+}
@@ -0,0 +1,126 @@
+//! Encrypt the configuration file with a passphrase
+//!
+//! The configuration may contain credentials for the stashes and their
+//! backends. `0s config encrypt` replaces the file with a copy that's
+//! encrypted with a key derived from a passphrase using Argon2id, and
+//! it's decrypted in memory whenever 0s starts.
+//!
+//! The passphrase is read from `ZEROSTASH_CONFIG_PASSPHRASE` if it is
+//! set, otherwise asked for interactively.
+use anyhow::{anyhow, bail, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use secrecy::{ExposeSecret, SecretString};
+use std::sync::OnceLock;
+
+const MAGIC: &[u8] = b"zerostash-encrypted-config-v1\n";
+const SALT_LEN: usize = 16;
+const PASSPHRASE_ENV: &str = "ZEROSTASH_CONFIG_PASSPHRASE";
+
+static PASSPHRASE: OnceLock<SecretString> = OnceLock::new();
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+fn derive_key(passphrase: &SecretString, salt: &[u8]) -> Result<LessSafeKey> {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        hash_length: 32,
+        ..argon2::Config::default()
+    };
+
+    let key = argon2::hash_raw(passphrase.expose_secret().as_bytes(), salt, &config)?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| anyhow!("invalid key"))?;
+
+    Ok(LessSafeKey::new(key))
+}
+
+/// Encrypt `plaintext`. The output starts with a header, followed by
+/// the salt, the nonce, and the ciphertext.
+pub fn encrypt(plaintext: &[u8], passphrase: &SecretString) -> Result<Vec<u8>> {
+    let salt: [u8; SALT_LEN] = rand::random();
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let key = derive_key(passphrase, &salt)?;
+
+    let mut ciphertext = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(MAGIC),
+        &mut ciphertext,
+    )
+    .map_err(|_| anyhow!("failed to encrypt configuration"))?;
+
+    Ok([MAGIC, &salt, &nonce, &ciphertext].concat())
+}
+
+pub fn decrypt(contents: &[u8], passphrase: &SecretString) -> Result<Vec<u8>> {
+    let Some(contents) = contents.strip_prefix(MAGIC) else {
+        bail!("configuration is not encrypted");
+    };
+
+    if contents.len() < SALT_LEN + NONCE_LEN {
+        bail!("encrypted configuration is truncated");
+    }
+
+    let (salt, rest) = contents.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key = derive_key(passphrase, salt)?;
+
+    let mut plaintext = ciphertext.to_vec();
+    let len = key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("invalid nonce"))?,
+            Aad::from(MAGIC),
+            &mut plaintext,
+        )
+        .map_err(|_| anyhow!("wrong passphrase, or the configuration is damaged"))?
+        .len();
+
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+/// Get the passphrase for the configuration file.
+///
+/// The passphrase is only asked for once per run. When `confirm` is
+/// set, a new passphrase is asked for twice instead.
+pub fn passphrase(confirm: bool) -> Result<SecretString> {
+    if let Ok(pw) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(pw.into());
+    }
+
+    if !confirm {
+        if let Some(pw) = PASSPHRASE.get() {
+            return Ok(pw.clone());
+        }
+    }
+
+    let pw = rpassword::prompt_password("Configuration passphrase: ")?;
+    if confirm && pw != rpassword::prompt_password("Repeat passphrase: ")? {
+        bail!("passphrases don't match");
+    }
+
+    let pw: SecretString = pw.into();
+    _ = PASSPHRASE.set(pw.clone());
+
+    Ok(pw)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn roundtrip() {
+        use super::{decrypt, encrypt, is_encrypted};
+
+        let config = b"[stash.test]\nkey = { source = \"ask\" }\n";
+        let encrypted = encrypt(config, &"correct".to_string().into()).unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(config));
+        assert!(decrypt(&encrypted, &"wrong".to_string().into()).is_err());
+        assert_eq!(
+            decrypt(&encrypted, &"correct".to_string().into()).unwrap(),
+            config
+        );
+    }
+}
@@ -43,6 +43,18 @@ impl Key {
             new: Box::new(new),
         }
     }
+
+    /// Read the key out of a keyfile, so it stays the same even if the
+    /// file changes.
+    pub(crate) fn resolve(self) -> Result<Key> {
+        match self {
+            Self::KeyFile { path } => {
+                let contents = std::fs::read_to_string(path)?;
+                toml::from_str::<Key>(&contents)?.resolve()
+            }
+            key => Ok(key),
+        }
+    }
 }
 
 macro_rules! change_key {
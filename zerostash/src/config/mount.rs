@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Where and how to mount a stash with `0s mount --all`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    /// The location the filesystem will be mounted on
+    pub mountpoint: PathBuf,
+
+    /// Mount the filesystem read-only. Defaults to `true`.
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+
+    /// Allow other users to access the mount
+    #[serde(default)]
+    pub allow_other: bool,
+
+    /// Show all files as owned by this uid
+    #[serde(default)]
+    pub uid: Option<u32>,
+
+    /// Show all files as owned by this gid
+    #[serde(default)]
+    pub gid: Option<u32>,
+
+    /// How to check access to files in the mount
+    #[serde(default)]
+    pub permissions: PermissionCheck,
+}
+
+fn default_read_only() -> bool {
+    true
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionCheck {
+    /// No checks, anyone who can reach the mount has full access
+    #[default]
+    Off,
+    /// The kernel checks the file modes against the reported owners
+    Kernel,
+    /// Only the owner of the mount and root have access
+    Owner,
+}
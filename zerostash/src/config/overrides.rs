@@ -0,0 +1,223 @@
+//! Override stash configuration from the environment or command line
+//!
+//! Every stash can be changed or defined without a configuration file,
+//! which is convenient for containers. For a stash named `NAME`, the
+//! following environment variables are used:
+//!
+//!  * `ZEROSTASH_STASH_NAME_BACKEND`: a path, an `s3://` url, or an
+//!    inline TOML table, eg. `{ type = "fs", path = "/stash" }`
+//!  * `ZEROSTASH_STASH_NAME_KEY`: an inline TOML key, eg.
+//!    `{ source = "file", path = "/run/secrets/key.toml" }`
+//!  * `ZEROSTASH_STASH_NAME_KEY_<FIELD>`: a single field of the key,
+//!    eg. `_KEY_SOURCE=plaintext`, `_KEY_USER` and `_KEY_PASSWORD`
+//!  * `ZEROSTASH_STASH_NAME_S3_ACCESS_KEY_ID` and
+//!    `ZEROSTASH_STASH_NAME_S3_SECRET_ACCESS_KEY`: credentials for all
+//!    S3 backends of the stash
+//!
+//! The name is upper-cased, and characters other than letters and
+//! digits are replaced by `_`. The same fields can be set on the command
+//! line with `--set NAME.backend=...`, `--set NAME.key.user=...`, or
+//! `--set NAME.s3.access_key_id=...`, which take precedence.
+//!
+//! When any field of the key is overridden, it replaces the configured
+//! key entirely.
+use super::{Backend, Key, Stash};
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::str::FromStr;
+
+const ENV_PREFIX: &str = "ZEROSTASH_STASH_";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Override {
+    pub stash: String,
+    pub field: String,
+    pub value: String,
+}
+
+impl FromStr for Override {
+    type Err = anyhow::Error;
+
+    /// Parse `stash.field=value`
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s.split_once('=').context("expected `STASH.FIELD=VALUE`")?;
+        let (stash, field) = name
+            .split_once('.')
+            .context("expected `STASH.FIELD=VALUE`")?;
+
+        Ok(Self {
+            stash: stash.to_string(),
+            field: field.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+fn env_name(alias: &str) -> String {
+    alias
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Map the suffix of an environment variable to a field name
+fn env_field(suffix: &str) -> Option<String> {
+    match suffix {
+        "BACKEND" => Some("backend".into()),
+        "KEY" => Some("key".into()),
+        "S3_ACCESS_KEY_ID" => Some("s3.access_key_id".into()),
+        "S3_SECRET_ACCESS_KEY" => Some("s3.secret_access_key".into()),
+        _ => suffix
+            .strip_prefix("KEY_")
+            .map(|field| format!("key.{}", field.to_ascii_lowercase())),
+    }
+}
+
+/// Collect the overrides for the stash `alias` from the environment.
+pub fn from_env(alias: &str) -> Vec<Override> {
+    let prefix = format!("{ENV_PREFIX}{}_", env_name(alias));
+
+    let mut overrides = std::env::vars()
+        .filter_map(|(name, value)| {
+            let field = env_field(name.strip_prefix(&prefix)?)?;
+            Some(Override {
+                stash: alias.to_string(),
+                field,
+                value,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // keep the order stable, so later fields don't depend on the environment
+    overrides.sort_by(|a, b| a.field.cmp(&b.field));
+    overrides
+}
+
+/// Parse an inline TOML value, eg. a table.
+fn parse_inline<T: DeserializeOwned>(value: &str) -> Result<T> {
+    #[derive(Deserialize)]
+    struct Inline<T> {
+        value: T,
+    }
+
+    Ok(toml::from_str::<Inline<T>>(&format!("value = {value}"))?.value)
+}
+
+fn parse_backend(value: &str) -> Result<Backend> {
+    if value.trim_start().starts_with('{') {
+        parse_inline(value)
+    } else {
+        value.parse()
+    }
+}
+
+/// Apply `overrides` to the configured `stash`. Returns a new stash if
+/// it's not in the configuration, but a backend is given.
+pub fn apply(stash: Option<Stash>, alias: &str, overrides: &[Override]) -> Result<Option<Stash>> {
+    let mut backend = None;
+    let mut key = None;
+    let mut key_fields = toml::Table::new();
+    let (mut access_key, mut secret_key) = (None, None);
+
+    for o in overrides {
+        let context = || format!("invalid value for {}.{}", alias, o.field);
+
+        match o.field.as_str() {
+            "backend" => backend = Some(parse_backend(&o.value).with_context(context)?),
+            "key" => key = Some(parse_inline::<Key>(&o.value).with_context(context)?),
+            "s3.access_key_id" => access_key = Some(o.value.clone()),
+            "s3.secret_access_key" => secret_key = Some(o.value.clone()),
+            field => match field.strip_prefix("key.") {
+                // all fields are strings, so passwords are never
+                // mistaken for numbers
+                Some("keychain") => {
+                    let value = o.value.parse::<bool>().with_context(context)?;
+                    key_fields.insert("keychain".into(), value.into());
+                }
+                Some(name) => {
+                    key_fields.insert(name.to_string(), o.value.clone().into());
+                }
+                None => bail!("unknown configuration field: {alias}.{field}"),
+            },
+        }
+    }
+
+    if !key_fields.is_empty() {
+        key = Some(
+            Key::deserialize(toml::Value::Table(key_fields))
+                .with_context(|| format!("invalid key for {alias}"))?,
+        );
+    }
+
+    let mut stash = match (stash, backend) {
+        (None, None) => return Ok(None),
+        (Some(mut stash), backend) => {
+            stash.backend = backend.unwrap_or(stash.backend);
+            stash
+        }
+        (None, Some(backend)) => Stash {
+            key: Default::default(),
+            backend,
+            mount: None,
+            commit: None,
+            pool: None,
+            telemetry: None,
+            object_size: None,
+            commit_time_quantum_secs: None,
+            alias: alias.to_string(),
+        },
+    };
+
+    if let Some(key) = key {
+        stash.key = key;
+    }
+
+    match (access_key, secret_key) {
+        (Some(access), Some(secret)) => stash.backend.set_s3_keys(&access, &secret),
+        (None, None) => {}
+        _ => bail!("both the S3 access key id and secret access key are needed for {alias}"),
+    }
+
+    Ok(Some(stash))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn stash_from_overrides() {
+        use super::{apply, env_field, env_name, Override};
+        use crate::config::Backend;
+
+        assert_eq!(env_name("my-stash"), "MY_STASH");
+        assert_eq!(env_field("KEY_PASSWORD").unwrap(), "key.password");
+        assert!(env_field("PASSWORD").is_none());
+
+        let overrides = [
+            "test.backend={ type = \"fs\", path = \"/stash\" }",
+            "test.key.source=plaintext",
+            "test.key.user=user",
+            "test.key.password=123",
+        ]
+        .map(|s| s.parse::<Override>().unwrap());
+
+        let stash = apply(None, "test", &overrides).unwrap().unwrap();
+        assert_eq!(
+            stash.backend,
+            Backend::Filesystem {
+                path: "/stash".into(),
+                volumes: None,
+                no_mmap: false,
+            }
+        );
+        assert!(matches!(stash.key, crate::config::Key::Userpass(_)));
+
+        assert!(apply(None, "test", &[]).unwrap().is_none());
+        assert!(apply(None, "test", &["test.nope=1".parse().unwrap()]).is_err());
+    }
+}
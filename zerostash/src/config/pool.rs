@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Share chunks with other stashes that store data in the same backend
+///
+/// All members of a pool need to use the same data key.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Directory that lists the chunks of every member.
+    /// It has to be shared by all members of the pool.
+    pub catalog: PathBuf,
+
+    /// Name of this stash in the pool. Must be unique among members.
+    pub member: String,
+}
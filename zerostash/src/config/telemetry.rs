@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// Report anonymous metrics of each run to an endpoint
+///
+/// Reports contain the command, how long it took, how much data it
+/// processed, and the class of the error if it failed. Paths, file
+/// names, stash names and keys are never sent.
+///
+/// Setting the `ZEROSTASH_NO_TELEMETRY` environment variable disables
+/// reporting for every stash.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// URL that reports are posted to as JSON
+    pub endpoint: String,
+}
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// Where `0s self-update` looks for new releases
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateConfig {
+    /// Release channel to follow
+    #[serde(default)]
+    pub channel: Channel,
+
+    /// Minisign public key that releases are signed with.
+    /// Defaults to the key the binary was built with.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Tagged releases
+    #[default]
+    Stable,
+    /// Pre-releases as well as tagged releases
+    Nightly,
+}
@@ -18,13 +18,65 @@ pub enum ErrorKind {
     /// Input/output error
     #[error("I/O error")]
     Io,
+
+    /// The stash can't be opened with the given credentials
+    #[error("authentication failed")]
+    Auth,
+
+    /// The storage backend can't be reached
+    #[error("backend unreachable")]
+    Backend,
+
+    /// The operation finished, but some items failed
+    #[error("partial failure")]
+    Partial,
+
+    /// There was nothing to do
+    #[error("nothing to do")]
+    NothingToDo,
+
+    /// Stored data failed verification
+    #[error("verification failed")]
+    Verification,
+
+    /// The operation was stopped by a signal before it finished
+    #[error("interrupted")]
+    Interrupted,
 }
 
+/// Exit codes, and what they mean
+///
+/// Exit code 1 is used for any other error, and 2 for invalid command
+/// line arguments.
+pub const EXIT_CODES: [(ErrorKind, i32); 8] = [
+    (ErrorKind::Config, 3),
+    (ErrorKind::Auth, 4),
+    (ErrorKind::Backend, 5),
+    (ErrorKind::Partial, 6),
+    (ErrorKind::NothingToDo, 7),
+    (ErrorKind::Verification, 8),
+    (ErrorKind::Io, 9),
+    (ErrorKind::Interrupted, 10),
+];
+
 impl ErrorKind {
     /// Create an error context from this error
     pub fn context(self, source: impl Into<BoxError>) -> Context<ErrorKind> {
         Context::new(self, Some(source.into()))
     }
+
+    /// Create an error of this kind, caused by `source`
+    pub fn error(self, source: impl Into<BoxError>) -> Error {
+        self.context(source).into()
+    }
+
+    /// The exit code of 0s when it fails with this kind of error
+    pub fn exit_code(self) -> i32 {
+        EXIT_CODES
+            .iter()
+            .find(|(kind, _)| *kind == self)
+            .map_or(1, |(_, code)| *code)
+    }
 }
 
 /// Error type
@@ -63,6 +115,22 @@ impl From<Context<ErrorKind>> for Error {
     }
 }
 
+impl Error {
+    /// Find the kind of the first [`Error`] in the chain of `err`'s
+    /// sources, if any
+    pub fn kind_of(err: &(dyn std::error::Error + 'static)) -> Option<ErrorKind> {
+        let mut current = Some(err);
+        while let Some(err) = current {
+            if let Some(err) = err.downcast_ref::<Error>() {
+                return Some(*err.kind());
+            }
+            current = err.source();
+        }
+
+        None
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         ErrorKind::Io.context(err).into()
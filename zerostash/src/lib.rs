@@ -21,10 +21,17 @@
 pub mod migration;
 pub mod application;
 pub mod commands;
+pub mod commit_message;
 pub mod config;
 pub mod error;
+pub mod glacier;
 pub mod keygen;
+pub mod logging;
 pub mod prelude;
+pub mod recovery;
+pub mod systemd;
+pub mod telemetry;
+pub mod update;
 #[cfg(feature = "fuse")]
 pub use zerostash_fuse;
 
@@ -34,8 +41,6 @@ use tokio as _;
 #[cfg(test)]
 use tracing as _;
 #[cfg(test)]
-use tracing_subscriber as _;
-#[cfg(test)]
 use walkdir as _;
 
 #[cfg(unix)]
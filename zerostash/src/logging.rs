@@ -0,0 +1,102 @@
+//! Log output for long running commands
+//!
+//! By default, the framework prints human readable log lines on the
+//! terminal. With `--log-format json`, `--log-format journal` or
+//! `--log-file`, the subscriber is set up here instead, so daemon runs
+//! produce logs that can be analyzed later.
+//!
+//! The journal format prefixes every line with its syslog priority,
+//! which journald strips, and uses as the priority of the message.
+//!
+//! Commits and restores run in an `operation` span with a random
+//! correlation id. Every event of the operation, from the directory
+//! walk to the upload of objects, carries the id.
+use anyhow::{anyhow, Result};
+use std::{fmt, fs::OpenOptions, io::IsTerminal, path::Path, sync::Mutex};
+use tracing::{Event, Level, Span, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format,
+        format::{FormatEvent, FormatFields, Writer},
+        writer::BoxMakeWriter,
+        FmtContext,
+    },
+    registry::LookupSpan,
+    EnvFilter,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of all current spans
+    Json,
+    /// Human readable lines with a syslog priority, for the systemd journal
+    Journal,
+}
+
+/// Install the global subscriber, writing events that match `filter`
+/// to `file`, or to stderr.
+pub fn init(filter: &str, format: LogFormat, file: Option<&Path>) -> Result<()> {
+    let (writer, ansi) = match file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        ),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+        // the journal has its own timestamps
+        LogFormat::Journal => builder
+            .with_ansi(false)
+            .event_format(Journal(format().without_time()))
+            .try_init(),
+    }
+    .map_err(|e| anyhow!("failed to set up logging: {e}"))
+}
+
+/// Prefixes the lines of `F` with the syslog priority of the event
+struct Journal<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for Journal<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let priority = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+
+        write!(writer, "<{priority}>")?;
+        self.0.format_event(ctx, writer, event)
+    }
+}
+
+/// A span for the whole lifecycle of an operation, with a new
+/// correlation id.
+pub fn operation_span(name: &'static str) -> Span {
+    let id = format!("{:016x}", rand::random::<u64>());
+    tracing::info_span!("operation", name, %id)
+}
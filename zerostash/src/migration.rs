@@ -1,6 +1,18 @@
+use crate::prelude::{fail, ErrorKind};
 use infinitree::Infinitree;
 use zerostash_files::Files;
 
+/// Load the whole index and migrate it, or fail with a backend error.
+///
+/// Operations that load the index of several commits in turn take this
+/// as their `load` function.
+pub fn reload(stash: &mut Infinitree<Files>) {
+    stash
+        .load_all()
+        .unwrap_or_else(|e| fail(ErrorKind::Backend, e));
+    migration(stash);
+}
+
 pub fn migration(stash: &mut Infinitree<Files>) {
     let mut count = 0;
 
@@ -15,7 +27,7 @@ pub fn migration(stash: &mut Infinitree<Files>) {
 
     if count > 0 {
         stash.index().files.retain(|_, _| false);
-        println!("Migrated {} files", count);
+        eprintln!("Migrated {} files", count);
     }
 }
 
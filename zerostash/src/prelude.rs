@@ -5,11 +5,16 @@
 pub use crate::application::APP;
 pub use crate::commands::{EntryPoint, StashArgs};
 pub use crate::config::ZerostashConfig;
+pub use crate::error::ErrorKind;
 pub use abscissa_core::{status_err, Application};
 pub use async_trait::async_trait;
 pub use clap::Parser as Command;
 pub use std::io::Write;
 
+use crate::error::Error;
+use abscissa_core::error::BoxError;
+use zerostash_files::interrupt::Interrupt;
+
 pub type Stash = infinitree::Infinitree<zerostash_files::Files>;
 
 #[async_trait]
@@ -17,7 +22,46 @@ pub trait AsyncRunnable {
     async fn run(&self);
 }
 
+/// Print the error and exit. The exit code depends on the
+/// [`ErrorKind`] of the error, see `0s exit-codes`.
 pub fn fatal_error(err: impl Into<Box<dyn std::error::Error>>) -> ! {
-    status_err!("{} fatal error: {}", APP.name(), err.into());
-    std::process::exit(1)
+    let err = err.into();
+    let code = Error::kind_of(err.as_ref()).map_or(1, ErrorKind::exit_code);
+
+    status_err!("{} fatal error: {}", APP.name(), err);
+    std::process::exit(code)
+}
+
+/// Fail with an error of the given kind, unless the error has a more
+/// specific kind already, eg. [`ErrorKind::Auth`] from opening a stash.
+pub fn fail(kind: ErrorKind, err: impl Into<BoxError>) -> ! {
+    let err = err.into();
+    let kind = Error::kind_of(err.as_ref()).unwrap_or(kind);
+    fatal_error(kind.error(err))
+}
+
+/// Exit with the code for `kind`, after the details were reported.
+pub fn exit_with(kind: ErrorKind) -> ! {
+    std::process::exit(kind.exit_code())
+}
+
+/// Trigger the returned [`Interrupt`] on Ctrl-C, so the operation can
+/// finish the files in progress. A second Ctrl-C exits right away.
+pub fn interrupt_on_ctrl_c() -> Interrupt {
+    let interrupt = Interrupt::default();
+    let trigger = interrupt.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!("Interrupted, finishing the files in progress. Press Ctrl-C again to stop now.");
+        trigger.trigger();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            exit_with(ErrorKind::Interrupted);
+        }
+    });
+
+    interrupt
 }
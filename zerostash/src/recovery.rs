@@ -0,0 +1,177 @@
+//! Printable recovery codes for stash keys
+//!
+//! A recovery code is a key configuration encoded as words from the
+//! BIP39 English word list, 11 bits per word. The payload is prefixed
+//! with its length and followed by a checksum, so a mistyped word is
+//! caught before anything is written.
+//!
+//! A code can be split into parts that are all needed to recover the
+//! key. Every part but the last is random, and the last one is the XOR
+//! of the key and the random parts, so fewer parts reveal nothing.
+use anyhow::{bail, ensure, Context, Result};
+use bip39::Language;
+use rand::RngCore;
+
+const CHECKSUM_LEN: usize = 4;
+const BITS_PER_WORD: usize = 11;
+const WORDS_PER_LINE: usize = 6;
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    digest.as_ref()[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+/// Split `secret` into `parts` that all need to be combined.
+pub fn split(secret: &[u8], parts: usize) -> Vec<Vec<u8>> {
+    let mut last = secret.to_vec();
+    let mut shares = vec![];
+
+    for _ in 1..parts {
+        let mut share = vec![0; secret.len()];
+        rand::thread_rng().fill_bytes(&mut share);
+
+        for (l, s) in last.iter_mut().zip(share.iter()) {
+            *l ^= s;
+        }
+        shares.push(share);
+    }
+
+    shares.push(last);
+    shares
+}
+
+/// Recover the secret from all parts of a split code.
+pub fn combine(parts: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let Some((first, rest)) = parts.split_first() else {
+        bail!("no recovery code given");
+    };
+
+    let mut secret = first.clone();
+    for part in rest {
+        ensure!(
+            part.len() == secret.len(),
+            "the parts are not from the same recovery code"
+        );
+
+        for (s, p) in secret.iter_mut().zip(part.iter()) {
+            *s ^= p;
+        }
+    }
+
+    Ok(secret)
+}
+
+/// Encode `data` as a list of words.
+pub fn encode(data: &[u8]) -> Vec<&'static str> {
+    let len = u16::try_from(data.len()).expect("recovery payload too large");
+
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(data);
+    framed.extend_from_slice(&checksum(data));
+
+    let words = Language::English.word_list();
+    let mut out = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+
+    for byte in framed {
+        acc = (acc << 8) | byte as u32;
+        bits += 8;
+
+        while bits >= BITS_PER_WORD {
+            bits -= BITS_PER_WORD;
+            out.push(words[((acc >> bits) & 0x7ff) as usize]);
+        }
+    }
+
+    if bits > 0 {
+        out.push(words[((acc << (BITS_PER_WORD - bits)) & 0x7ff) as usize]);
+    }
+
+    out
+}
+
+/// Decode a list of words separated by whitespace.
+pub fn decode(code: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+
+    for word in code.split_whitespace() {
+        let index = Language::English
+            .find_word(&word.to_lowercase())
+            .with_context(|| format!("`{word}` is not a valid word"))?;
+
+        acc = (acc << BITS_PER_WORD) | index as u32;
+        bits += BITS_PER_WORD;
+
+        while bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+
+    ensure!(
+        bytes.len() >= 2 + CHECKSUM_LEN,
+        "the recovery code is too short"
+    );
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    ensure!(
+        bytes.len() >= 2 + len + CHECKSUM_LEN,
+        "the recovery code is incomplete"
+    );
+
+    let data = &bytes[2..2 + len];
+    let sum = &bytes[2 + len..2 + len + CHECKSUM_LEN];
+    ensure!(
+        sum == checksum(data),
+        "the recovery code is invalid, check for mistyped words"
+    );
+
+    Ok(data.to_vec())
+}
+
+/// Lay out the words in short lines so they're easy to copy by hand.
+pub fn format(words: &[&str]) -> String {
+    words
+        .chunks(WORDS_PER_LINE)
+        .map(|line| line.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render the words as a QR code for the terminal.
+pub fn qr_code(words: &[&str]) -> Result<String> {
+    let code = qrcode::QrCode::new(words.join(" ").as_bytes())?;
+    Ok(code
+        .render::<qrcode::render::unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn split_codes_roundtrip() {
+        use super::{combine, decode, encode, split};
+
+        let secret = b"source = \"plaintext\"\nuser = \"a\"\npassword = \"b\"\n";
+
+        let parts = split(secret, 3)
+            .iter()
+            .map(|part| encode(part).join(" "))
+            .collect::<Vec<_>>();
+        let decoded = parts
+            .iter()
+            .map(|code| decode(code).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(combine(&decoded).unwrap(), secret);
+        assert_ne!(combine(&decoded[..2]).unwrap(), secret);
+
+        let mut words = encode(secret);
+        words[3] = if words[3] == "abandon" {
+            "zoo"
+        } else {
+            "abandon"
+        };
+        assert!(decode(&words.join(" ")).is_err());
+    }
+}
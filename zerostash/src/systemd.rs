@@ -0,0 +1,201 @@
+//! Report the state of long running commands to systemd
+//!
+//! When 0s runs in a service with `Type=notify`, systemd passes the
+//! socket to notify in `NOTIFY_SOCKET`. Commands send `READY=1` when
+//! they start working, and keep `STATUS=` up to date with what they're
+//! doing, so `systemctl status` shows it.
+//!
+//! If the service has `WatchdogSec=` set, the watchdog is pinged
+//! whenever the operation makes progress, eg. when the next file is
+//! stored, so a hung commit gets restarted instead of blocking the next
+//! one. Services that wait for something to happen ping it from their
+//! main loop while they're idle.
+//!
+//! Outside of systemd, nothing is sent.
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::debug;
+use zerostash_files::heartbeat::Heartbeat;
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+/// Send `state` to the notify socket, if there's one.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os(NOTIFY_SOCKET) else {
+        return;
+    };
+
+    if let Err(error) = send(&socket.to_string_lossy(), state) {
+        debug!(%error, "failed to notify systemd");
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let sender = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            sender.send_to(state.as_bytes(), socket)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How often the watchdog expects a ping, if it's enabled for us
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var(WATCHDOG_PID) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = env::var(WATCHDOG_USEC).ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Pings the watchdog, but not more often than a few times per interval
+struct Watchdog {
+    interval: Duration,
+    start: Instant,
+    /// Milliseconds after `start` of the last ping
+    last: AtomicU64,
+}
+
+impl Watchdog {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if a ping is due `elapsed` after the start, and
+    /// records it as sent.
+    fn due(&self, elapsed: Duration) -> bool {
+        let now = elapsed.as_millis() as u64;
+        let last = self.last.load(Ordering::Relaxed);
+
+        now.saturating_sub(last) >= (self.interval / 4).as_millis() as u64
+            && self
+                .last
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    fn ping(&self) {
+        if self.due(self.start.elapsed()) {
+            notify("WATCHDOG=1");
+        }
+    }
+}
+
+/// A running operation, as reported to systemd
+pub struct Operation {
+    name: &'static str,
+    start: Instant,
+    watchdog: Option<Arc<Watchdog>>,
+}
+
+impl Operation {
+    /// Tell systemd the service is up.
+    pub fn start(name: &'static str) -> Self {
+        notify(&format!("READY=1\nSTATUS={name}: starting"));
+
+        Self {
+            name,
+            start: Instant::now(),
+            watchdog: watchdog_interval().map(|interval| Arc::new(Watchdog::new(interval))),
+        }
+    }
+
+    /// Show what the operation is doing in `systemctl status`.
+    pub fn status(&self, status: impl AsRef<str>) {
+        notify(&format!(
+            "STATUS={}: {} ({}s)",
+            self.name,
+            status.as_ref(),
+            self.start.elapsed().as_secs()
+        ));
+        self.alive();
+    }
+
+    /// Tell the watchdog the operation made progress.
+    pub fn alive(&self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.ping();
+        }
+    }
+
+    /// Pings the watchdog every time long operations make progress.
+    pub fn heartbeat(&self) -> Heartbeat {
+        match self.watchdog.clone() {
+            Some(watchdog) => Heartbeat::new(move || watchdog.ping()),
+            None => Heartbeat::default(),
+        }
+    }
+
+    /// Wait for half the watchdog interval, then ping it. Without a
+    /// watchdog, this never returns.
+    ///
+    /// Only for services that are idle until something happens, and
+    /// not while they're working on it.
+    pub async fn idle(&self) {
+        match &self.watchdog {
+            Some(watchdog) => {
+                tokio::time::sleep(watchdog.interval / 2).await;
+                watchdog.ping();
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        notify(&format!(
+            "STOPPING=1\nSTATUS={}: done in {}s",
+            self.name,
+            self.start.elapsed().as_secs()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn watchdog_pings_are_limited() {
+        use super::Watchdog;
+        use std::time::Duration;
+
+        let watchdog = Watchdog::new(Duration::from_secs(20));
+        assert!(!watchdog.due(Duration::from_secs(1)));
+        assert!(watchdog.due(Duration::from_secs(5)));
+        assert!(!watchdog.due(Duration::from_secs(9)));
+        assert!(watchdog.due(Duration::from_secs(30)));
+        assert!(!watchdog.due(Duration::from_secs(31)));
+    }
+}
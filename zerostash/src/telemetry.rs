@@ -0,0 +1,115 @@
+//! Opt-in reporting of anonymous performance metrics
+//!
+//! Stashes with a `telemetry` section in their configuration post a
+//! [`Report`] to the configured endpoint when a command finishes.
+//! Nothing is sent otherwise, and the [`KILL_SWITCH`] environment
+//! variable turns reporting off for every stash.
+//!
+//! Reporting never fails the command: the request has a short timeout,
+//! and errors are only logged.
+
+use crate::{config::Stash, error::ErrorKind};
+use serde::Serialize;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// Disables telemetry for all stashes if set to any value
+pub const KILL_SWITCH: &str = "ZEROSTASH_NO_TELEMETRY";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The metrics of a single run
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct Report {
+    pub command: &'static str,
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub duration_ms: u64,
+    /// Size of the files that were processed
+    pub logical_bytes: u64,
+    /// Size of the data that was written to storage
+    pub physical_bytes: u64,
+    /// `ok`, `error`, or the class of the error, as listed by `0s exit-codes`
+    pub outcome: String,
+}
+
+/// How a run ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// Failed with an error of the given kind, or any other error
+    Failed(Option<ErrorKind>),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "ok"),
+            Self::Failed(Some(kind)) => write!(f, "{kind}"),
+            Self::Failed(None) => write!(f, "error"),
+        }
+    }
+}
+
+/// A command that is being measured
+pub struct Run {
+    endpoint: Option<String>,
+    command: &'static str,
+    start: Instant,
+}
+
+impl Run {
+    /// Start measuring `command` on `stash`.
+    pub fn start(stash: &Stash, command: &'static str) -> Self {
+        let endpoint = match std::env::var_os(KILL_SWITCH) {
+            Some(_) => None,
+            None => stash.telemetry.as_ref().map(|t| t.endpoint.clone()),
+        };
+
+        Self {
+            endpoint,
+            command,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.endpoint.is_some()
+    }
+
+    /// Send the report of the run, if telemetry is enabled.
+    pub async fn finish(self, logical_bytes: u64, physical_bytes: u64, outcome: Outcome) {
+        let Some(endpoint) = self.endpoint else {
+            return;
+        };
+
+        let report = Report {
+            command: self.command,
+            version: env!("CARGO_PKG_VERSION"),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            logical_bytes,
+            physical_bytes,
+            outcome: outcome.to_string(),
+        };
+
+        let sent = tokio::task::spawn_blocking(move || {
+            ureq::post(&endpoint)
+                .timeout(TIMEOUT)
+                .send_json(&report)
+                .map(|_| ())
+        })
+        .await;
+
+        match sent {
+            Ok(Ok(())) => debug!("sent telemetry report"),
+            Ok(Err(error)) => debug!(%error, "failed to send telemetry report"),
+            Err(error) => debug!(%error, "failed to send telemetry report"),
+        }
+    }
+}
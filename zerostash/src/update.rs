@@ -0,0 +1,329 @@
+//! Replace the running binary with a signed release from GitHub
+//!
+//! Every release has a tarball for each target, named
+//! `0s-<tag>-<target>.tar.gz`, and a manifest, `0s-<tag>.manifest`,
+//! which is signed with minisign, with a `.minisig` suffix. The
+//! manifest names the tag of the release, and lists the SHA-256 digest
+//! of every tarball:
+//!
+//! ```text
+//! tag v0.9.0
+//! sha256 <hex digest> 0s-v0.9.0-x86_64-linux.tar.gz
+//! ```
+//!
+//! A signature only vouches for the release it was made for, so an
+//! older signed tarball can't be passed off as a newer release. The
+//! manifest is checked before the tarball is unpacked.
+//!
+//! The new binary is written next to the running one, then renamed
+//! over it, so an interrupted update leaves the old binary in place.
+use crate::config::Channel;
+use anyhow::{bail, Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+
+const RELEASES: &str = "https://api.github.com/repos/symmetree-labs/zerostash/releases";
+
+/// Releases are well under this, anything larger is not a release
+const MAX_DOWNLOAD: u64 = 256 * 1024 * 1024;
+
+/// The key releases are signed with, if it was set at build time
+pub const PUBLIC_KEY: Option<&str> = option_env!("ZEROSTASH_UPDATE_PUBLIC_KEY");
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub prerelease: bool,
+    pub draft: bool,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    pub fn version(&self) -> Option<Version> {
+        Version::parse(self.tag_name.trim_start_matches('v')).ok()
+    }
+
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|a| a.name == name)
+    }
+
+    fn tarball_name(&self, target: &str) -> String {
+        format!("0s-{}-{target}.tar.gz", self.tag_name)
+    }
+
+    /// The manifest of the release, and its signature
+    fn manifest(&self) -> Option<(&Asset, &Asset)> {
+        let name = format!("0s-{}.manifest", self.tag_name);
+        Some((self.asset(&name)?, self.asset(&format!("{name}.minisig"))?))
+    }
+}
+
+/// The contents of a release manifest
+#[derive(Debug, PartialEq, Eq)]
+struct Manifest {
+    tag: String,
+    /// Name and SHA-256 digest of every tarball
+    tarballs: Vec<(String, [u8; 32])>,
+}
+
+impl Manifest {
+    fn parse(text: &str) -> Result<Self> {
+        let mut tag = None;
+        let mut tarballs = vec![];
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["tag", name] if tag.is_none() => tag = Some(name.to_string()),
+                ["sha256", digest, name] => {
+                    let digest = hex_digest(digest)
+                        .with_context(|| format!("Invalid digest in manifest: {digest}"))?;
+                    tarballs.push((name.to_string(), digest));
+                }
+                _ => bail!("Invalid line in manifest: {line}"),
+            }
+        }
+
+        Ok(Self {
+            tag: tag.context("The manifest doesn't name a release")?,
+            tarballs,
+        })
+    }
+
+    fn digest(&self, name: &str) -> Option<&[u8; 32]> {
+        self.tarballs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, digest)| digest)
+    }
+}
+
+fn hex_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// The version of the running binary
+pub fn current() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).unwrap()
+}
+
+/// The target name of the releases that run on this machine
+pub fn target() -> Option<&'static str> {
+    match (std::env::consts::ARCH, std::env::consts::OS) {
+        ("x86_64", "linux") => Some("x86_64-linux"),
+        ("x86_64", "macos") => Some("x86_64-apple-darwin"),
+        ("aarch64", "macos") => Some("aarch64-apple-darwin"),
+        _ => None,
+    }
+}
+
+/// The newest release on `channel`
+pub fn latest(channel: Channel) -> Result<Option<Release>> {
+    let releases = agent()
+        .get(RELEASES)
+        .call()
+        .context("Failed to list releases")?
+        .into_json()?;
+
+    Ok(newest(releases, channel))
+}
+
+fn newest(releases: Vec<Release>, channel: Channel) -> Option<Release> {
+    releases
+        .into_iter()
+        .filter(|r| !r.draft && (channel == Channel::Nightly || !r.prerelease))
+        .filter_map(|r| Some((r.version()?, r)))
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, r)| r)
+}
+
+/// Download the binary of `release` for this machine, and check its
+/// signature with `public_key`.
+///
+/// The key is either the base64 line, or the full contents of a
+/// minisign public key file.
+pub fn download(release: &Release, public_key: &str) -> Result<Vec<u8>> {
+    let public_key = PublicKey::from_base64(public_key.trim())
+        .or_else(|_| PublicKey::decode(public_key))
+        .context("Invalid public key")?;
+
+    let target = target().context("No releases are published for this platform")?;
+    let (manifest, signature) = release
+        .manifest()
+        .with_context(|| format!("Release {} has no signed manifest", release.tag_name))?;
+
+    let signature = String::from_utf8(fetch(&signature.browser_download_url)?)?;
+    let signature = Signature::decode(&signature).context("Invalid signature")?;
+    let manifest = fetch(&manifest.browser_download_url)?;
+    public_key
+        .verify(&manifest, &signature, false)
+        .context("The signature of the release manifest doesn't match")?;
+
+    let name = release.tarball_name(target);
+    let manifest = Manifest::parse(std::str::from_utf8(&manifest)?)?;
+    let expected = check_manifest(&manifest, release, &name)?;
+
+    let tarball = release
+        .asset(&name)
+        .with_context(|| format!("Release {} has no binary for {target}", release.tag_name))?;
+    let tarball = fetch(&tarball.browser_download_url)?;
+    if Sha256::digest(&tarball)[..] != expected[..] {
+        bail!("{name} doesn't match the signed manifest");
+    }
+
+    unpack(&tarball)
+}
+
+/// The digest of the tarball `name`, if `manifest` was signed for
+/// `release`
+fn check_manifest<'a>(
+    manifest: &'a Manifest,
+    release: &Release,
+    name: &str,
+) -> Result<&'a [u8; 32]> {
+    if manifest.tag != release.tag_name {
+        bail!(
+            "The manifest was signed for release {}, not {}",
+            manifest.tag,
+            release.tag_name
+        );
+    }
+
+    manifest
+        .digest(name)
+        .with_context(|| format!("{name} is not in the signed manifest"))
+}
+
+/// Atomically replace the binary at `path` with `binary`.
+pub fn replace(path: &Path, binary: &[u8]) -> Result<()> {
+    let dir = path.parent().context("Binary is not in a directory")?;
+    let tmp = dir.join(format!(".0s-update-{}", std::process::id()));
+
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create_new(&tmp)?;
+        file.write_all(binary)?;
+        file.set_permissions(fs::metadata(path)?.permissions())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    };
+
+    write().or_else(|e| {
+        _ = fs::remove_file(&tmp);
+        Err(e).with_context(|| format!("Failed to replace {}", path.display()))
+    })
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .user_agent(concat!("zerostash/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    agent()
+        .get(url)
+        .call()
+        .with_context(|| format!("Failed to download {url}"))?
+        .into_reader()
+        .take(MAX_DOWNLOAD)
+        .read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+/// Find the `0s` binary in a release tarball
+fn unpack(tarball: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name() == Some("0s".as_ref()) {
+            let mut binary = vec![];
+            entry.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+
+    bail!("The release has no `0s` binary")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn newest_release_on_channel() {
+        use super::{newest, Release};
+        use crate::config::Channel;
+
+        let release = |tag: &str, prerelease, draft| Release {
+            tag_name: tag.into(),
+            prerelease,
+            draft,
+            assets: vec![],
+        };
+        let releases = vec![
+            release("v0.8.0", false, false),
+            release("v0.10.0-nightly.20261015", true, false),
+            release("v0.9.0", false, false),
+            release("v0.11.0", false, true),
+            release("nightly", true, false),
+        ];
+
+        let tag = |channel| newest(releases.clone(), channel).unwrap().tag_name;
+        assert_eq!(tag(Channel::Stable), "v0.9.0");
+        assert_eq!(tag(Channel::Nightly), "v0.10.0-nightly.20261015");
+    }
+
+    #[test]
+    fn manifest_is_bound_to_its_release() {
+        use super::{check_manifest, Manifest, Release};
+
+        let digest = "ab".repeat(32);
+        let manifest = Manifest::parse(&format!(
+            "tag v0.9.0\nsha256 {digest} 0s-v0.9.0-x86_64-linux.tar.gz\n"
+        ))
+        .unwrap();
+        assert_eq!(manifest.tag, "v0.9.0");
+
+        let release = |tag: &str| Release {
+            tag_name: tag.into(),
+            prerelease: false,
+            draft: false,
+            assets: vec![],
+        };
+        let name = release("v0.9.0").tarball_name("x86_64-linux");
+        assert_eq!(
+            check_manifest(&manifest, &release("v0.9.0"), &name).unwrap(),
+            &[0xab; 32]
+        );
+
+        // a manifest signed for an older release can't vouch for a newer one
+        let newer = release("v0.10.0");
+        assert!(check_manifest(&manifest, &newer, &newer.tarball_name("x86_64-linux")).is_err());
+        assert!(check_manifest(&manifest, &release("v0.9.0"), "0s-v0.9.0-other.tar.gz").is_err());
+
+        assert!(Manifest::parse("sha256 00 0s.tar.gz").is_err());
+        assert!(Manifest::parse(&format!("sha256 {digest} 0s.tar.gz")).is_err());
+        assert!(Manifest::parse("tag v1\ntag v2").is_err());
+    }
+}